@@ -0,0 +1,85 @@
+use std::hint::black_box;
+
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use sha2::{Digest, Sha256};
+use steiger::image::{self, Image, layer::Layer};
+
+const PLATFORMS: &[&str] = &[
+    "linux/amd64",
+    "linux/arm64",
+    "linux/arm/v7",
+    "linux/ppc64le",
+    "linux/s390x",
+    "linux/riscv64",
+];
+
+fn layer(n: usize) -> Layer {
+    let data = format!("layer {n}").into_bytes();
+
+    Layer {
+        diff_id: format!("sha256:{}", hex::encode(Sha256::digest(&data))),
+        data,
+    }
+}
+
+/// Image with `layers` layers, comparable to the output of a large multi-stage build
+fn image(platform: &str, layers: usize) -> Image {
+    (0..layers)
+        .try_fold(Image::scratch(platform).unwrap(), |image, n| {
+            image.append_layer(layer(n), &format!("RUN step {n}"), None)
+        })
+        .unwrap()
+        .with_base(
+            "docker.io/library/debian:bookworm",
+            "sha256:0123456789abcdef",
+        )
+}
+
+fn digest(c: &mut Criterion) {
+    let mut group = c.benchmark_group("digest");
+
+    for layers in [1, 50, 250] {
+        let image = image("linux/amd64", layers);
+
+        group.bench_with_input(
+            BenchmarkId::new("compute", layers),
+            image.manifest(),
+            |b, manifest| b.iter(|| image::compute_digest(black_box(manifest)).unwrap()),
+        );
+        group.bench_with_input(BenchmarkId::new("cached", layers), &image, |b, image| {
+            b.iter(|| black_box(image).digest().len())
+        });
+    }
+
+    group.finish();
+}
+
+fn append_layer(c: &mut Criterion) {
+    let mut group = c.benchmark_group("append_layer");
+
+    for layers in [10, 100] {
+        group.bench_function(BenchmarkId::from_parameter(layers), |b| {
+            b.iter(|| image("linux/amd64", layers).digest().len())
+        });
+    }
+
+    group.finish();
+}
+
+fn match_platform(c: &mut Criterion) {
+    let images = PLATFORMS
+        .iter()
+        .map(|platform| image(platform, 1))
+        .collect::<Vec<_>>();
+    let emulated = vec!["linux/amd64".to_string()];
+
+    c.bench_function("match_platform/exact", |b| {
+        b.iter(|| image::match_platform(&images, black_box("linux/riscv64"), &emulated))
+    });
+    c.bench_function("match_platform/emulated", |b| {
+        b.iter(|| image::match_platform(&images, black_box("darwin/arm64"), &emulated))
+    });
+}
+
+criterion_group!(benches, digest, append_layer, match_platform);
+criterion_main!(benches);
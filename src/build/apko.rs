@@ -0,0 +1,110 @@
+use std::{fs::File, path::PathBuf, process::ExitStatus};
+
+use miette::Diagnostic;
+use tokio::process::Command;
+
+use crate::{
+    build::{Builder, Context, Output},
+    config::Apko,
+    exec, image, progress, tmp,
+};
+
+#[derive(Debug, Diagnostic, thiserror::Error)]
+pub enum ApkoError {
+    #[error("IO error")]
+    IO(#[from] std::io::Error),
+    #[error("failed to find apko binary")]
+    Path(#[from] which::Error),
+    #[error("failed to create tempdir")]
+    TempDir(#[from] async_tempfile::Error),
+    #[error("failed to parse image")]
+    #[diagnostic(transparent)]
+    Image(#[from] image::ImageError),
+    #[error("failed to run 'apko build': {0}")]
+    Build(ExitStatus),
+    #[error("unpacking task panicked or was cancelled")]
+    Join(#[from] tokio::task::JoinError),
+}
+
+#[derive(Clone)]
+pub struct ApkoBuilder {
+    binary: PathBuf,
+}
+
+/// Architecture as apko names it, e.g. `arm/v7` becomes `armv7`
+fn arch(platform: &str) -> String {
+    let arch = platform.split_once('/').map_or(platform, |(_, arch)| arch);
+    arch.replace('/', "")
+}
+
+impl Builder for ApkoBuilder {
+    type Error = ApkoError;
+    type Input = Apko;
+
+    fn try_init() -> Result<Self, Self::Error>
+    where
+        Self: Sized,
+    {
+        which::which("apko")
+            .map(|binary| Self { binary })
+            .map_err(|e| e.into())
+    }
+
+    async fn build(
+        self,
+        Context {
+            service_name,
+            platform,
+            mut progress,
+            ..
+        }: Context,
+        input: Self::Input,
+    ) -> Result<Output, Self::Error> {
+        progress.set_name(&service_name);
+        progress.info(format!("using apko config: {}", input.config.display()));
+
+        // apko writes an OCI layout as tarball, it's unpacked next to it
+        let dest = tmp::new_dir_with_name(&service_name).await?;
+        let tarball = dest.join("image.tar");
+        let layout = dest.join("layout");
+
+        let mut cmd = Command::new(&self.binary);
+        cmd.arg("build")
+            .arg("--arch")
+            .arg(arch(&platform))
+            .arg("--sbom=false");
+
+        for package in input.extra_packages.iter() {
+            cmd.arg("--package-append").arg(package);
+        }
+
+        let output = exec::run_with_progress(
+            cmd.arg(&input.config)
+                .arg(format!("localhost/steiger-build/{service_name}"))
+                .arg(&tarball),
+            progress::child(&mut progress, format!("{service_name} › apko")),
+        )
+        .await?;
+
+        if !output.success() {
+            progress.fail(format!("build failed with exit code: {}", output.code()));
+
+            return Err(ApkoError::Build(output.status));
+        }
+
+        let unpacked = layout.clone();
+        tokio::task::spawn_blocking(move || {
+            tar::Archive::new(File::open(tarball)?).unpack(unpacked)
+        })
+        .await??;
+
+        progress.done("build finished".to_string());
+
+        let images = image::load_from_path(layout, &mut progress).await?;
+
+        Ok(Output {
+            artifacts: vec![(service_name, images)].into_iter().collect(),
+            ..Output::default()
+        })
+    }
+}
@@ -7,7 +7,7 @@ use crate::{
     build::{Builder, Context, Output},
     config::Bazel,
     exec::{self, CmdBuilder, ExitError},
-    image,
+    image, progress,
 };
 
 #[derive(Debug, Diagnostic, thiserror::Error)]
@@ -92,6 +92,7 @@ impl Builder for BazelBuilder {
             service_name,
             platform,
             mut progress,
+            ..
         }: Context,
         input: Self::Input,
     ) -> Result<Output, Self::Error> {
@@ -107,19 +108,28 @@ impl Builder for BazelBuilder {
             progress.info(format!("using platform: {platform}"));
         }
 
-        let status = exec::run_with_progress(
+        if let Some(cpus) = input.resources.cpus {
+            cmd = cmd.arg(format!("--local_cpu_resources={cpus}"));
+        }
+
+        if let Some(memory) = input.resources.memory {
+            cmd = cmd.arg(format!("--local_ram_resources={memory}"));
+        }
+
+        if let Some(jobs) = input.resources.jobs {
+            cmd = cmd.arg(format!("--jobs={jobs}"));
+        }
+
+        let output = exec::run_with_progress(
             cmd.args(input.targets.values()),
-            progress.add_child(format!("{service_name} › bazel")),
+            progress::child(&mut progress, format!("{service_name} › bazel")),
         )
         .await?;
 
-        if !status.success() {
-            progress.fail(format!(
-                "build failed with exit code: {}",
-                status.code().unwrap_or_default()
-            ));
+        if !output.success() {
+            progress.fail(format!("build failed with exit code: {}", output.code()));
 
-            return Err(BazelError::Build(status));
+            return Err(BazelError::Build(output.status));
         }
 
         progress.done("build finished".to_string());
@@ -138,9 +148,15 @@ impl Builder for BazelBuilder {
                 .map(|(artifact, _)| artifact.clone())
                 .ok_or(BazelError::MissingArtifact(target))?;
 
-            artifacts.insert(artifact, image::load_from_path(files).await?);
+            artifacts.insert(
+                artifact,
+                image::load_from_path(exec::resolve(files), &mut progress).await?,
+            );
         }
 
-        Ok(Output { artifacts })
+        Ok(Output {
+            artifacts,
+            ..Output::default()
+        })
     }
 }
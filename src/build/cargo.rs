@@ -0,0 +1,212 @@
+use std::{path::PathBuf, process::ExitStatus};
+
+use docker_credential::CredentialRetrievalError;
+use miette::Diagnostic;
+use oci_client::Reference;
+use prodash::Progress;
+use serde::Deserialize;
+use tokio::process::Command;
+
+use crate::{
+    build::{Builder, Context, Output},
+    config::Cargo,
+    exec,
+    image::{self, Image, ImageError},
+    progress,
+    registry::{self, PullError},
+};
+
+#[derive(Debug, Diagnostic, thiserror::Error)]
+pub enum CargoError {
+    #[error("IO error")]
+    IO(#[from] std::io::Error),
+    #[error("failed to find cargo binary")]
+    Path(#[from] which::Error),
+    #[error("no target triple known for platform '{0}'")]
+    #[diagnostic(help("set the triple for the platform in `targets`"))]
+    NoTarget(String),
+    #[error("failed to parse base image reference")]
+    Parse(#[from] oci_client::ParseError),
+    #[error("failed to retrieve registry credentials")]
+    Credential(#[from] CredentialRetrievalError),
+    #[error("failed to pull base image")]
+    #[diagnostic(transparent)]
+    Pull(#[from] PullError),
+    #[error("failed to assemble image")]
+    #[diagnostic(transparent)]
+    Image(#[from] ImageError),
+    #[error("failed to run 'cargo build': {0}")]
+    Build(ExitStatus),
+    #[error("cargo didn't report an executable for binary '{0}'")]
+    NoExecutable(String),
+    #[error("packing task panicked or was cancelled")]
+    Join(#[from] tokio::task::JoinError),
+}
+
+/// Message printed by `cargo build --message-format json`, only the fields used here
+#[derive(Deserialize)]
+struct Message {
+    reason: String,
+    target: Option<Target>,
+    executable: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+struct Target {
+    name: String,
+}
+
+/// Statically linked target of the platform, so the binary runs on any base image
+fn musl_target(platform: &str) -> Option<&'static str> {
+    Some(match platform {
+        "linux/amd64" => "x86_64-unknown-linux-musl",
+        "linux/arm64" | "linux/arm64/v8" => "aarch64-unknown-linux-musl",
+        "linux/arm/v7" => "armv7-unknown-linux-musleabihf",
+        "linux/arm/v6" => "arm-unknown-linux-musleabihf",
+        "linux/386" => "i686-unknown-linux-musl",
+        "linux/riscv64" => "riscv64gc-unknown-linux-musl",
+        "linux/ppc64le" => "powerpc64le-unknown-linux-musl",
+        _ => return None,
+    })
+}
+
+#[derive(Clone)]
+pub struct CargoBuilder {
+    binary: PathBuf,
+}
+
+impl Builder for CargoBuilder {
+    type Error = CargoError;
+    type Input = Cargo;
+
+    fn try_init() -> Result<Self, Self::Error>
+    where
+        Self: Sized,
+    {
+        which::which("cargo")
+            .map(|binary| Self { binary })
+            .map_err(|e| e.into())
+    }
+
+    async fn build(
+        self,
+        Context {
+            service_name,
+            platform,
+            mut progress,
+            registries,
+        }: Context,
+        input: Self::Input,
+    ) -> Result<Output, Self::Error> {
+        progress.set_name(&service_name);
+
+        let target = match input.targets.get(&platform) {
+            Some(target) => target.as_str(),
+            None => musl_target(&platform).ok_or_else(|| CargoError::NoTarget(platform.clone()))?,
+        };
+
+        progress.info(format!("building {} for {target}", input.bin));
+
+        let mut cmd = Command::new(&self.binary);
+        cmd.arg("build")
+            .arg("--message-format")
+            .arg("json-render-diagnostics")
+            .arg("--profile")
+            .arg(&input.profile)
+            .arg("--target")
+            .arg(target)
+            .arg("--bin")
+            .arg(&input.bin)
+            .envs(&input.env);
+
+        if let Some(ref package) = input.package {
+            cmd.arg("--package").arg(package);
+        }
+
+        if !input.features.is_empty() {
+            cmd.arg("--features").arg(input.features.join(","));
+        }
+
+        // Compiler messages go to stderr, stdout only has the JSON messages
+        let keep_colors = progress::keep_colors();
+        let mut executable = None;
+        let output = exec::run_with_reporter(
+            &mut cmd,
+            progress::child(&mut progress, format!("{service_name} › cargo")),
+            |progress, line| match serde_json::from_str::<Message>(line) {
+                Ok(message) => {
+                    if message.reason == "compiler-artifact"
+                        && let Some(target) = message.target
+                    {
+                        progress.info(format!("compiled {}", target.name));
+
+                        if target.name == input.bin && message.executable.is_some() {
+                            executable = message.executable;
+                        }
+                    }
+
+                    vec![]
+                }
+                Err(_) => {
+                    progress.info(progress::sanitize(line, keep_colors));
+                    vec![progress::sanitize(line, false)]
+                }
+            },
+        )
+        .await?;
+
+        if !output.success() {
+            progress.fail(format!("build failed with exit code: {}", output.code()));
+
+            return Err(CargoError::Build(output.status));
+        }
+
+        let executable = executable.ok_or_else(|| CargoError::NoExecutable(input.bin.clone()))?;
+
+        let base = match input.base_image {
+            Some(ref base_image) => {
+                progress.info(format!("pulling base image: {base_image}"));
+
+                let image_ref = Reference::try_from(base_image.as_str())?;
+                let auth = registry::load_credentials(image_ref.resolve_registry())?;
+
+                registries.client(auth).pull(&image_ref, &platform).await?
+            }
+            None => Image::scratch(&platform)?,
+        };
+
+        progress.info(format!(
+            "adding {} at {}",
+            input.bin,
+            input.destination.display()
+        ));
+
+        let destination = input.destination.clone();
+        let layer =
+            tokio::task::spawn_blocking(move || image::layer::pack(&executable, &destination))
+                .await??;
+        // Paths inside the image always use forward slashes
+        let entrypoint = format!(
+            "{}/{}",
+            input.destination.to_string_lossy().trim_end_matches('/'),
+            input.bin
+        );
+        let base_digest = base.digest().to_string();
+        let mut image = base.append_layer(
+            layer,
+            &format!("cargo build --bin {} --target {target}", input.bin),
+            Some(vec![entrypoint]),
+        )?;
+
+        if let Some(ref base_image) = input.base_image {
+            image = image.with_base(base_image, &base_digest);
+        }
+
+        progress.done("build finished".to_string());
+
+        Ok(Output {
+            artifacts: vec![(service_name, vec![image])].into_iter().collect(),
+            ..Output::default()
+        })
+    }
+}
@@ -0,0 +1,90 @@
+use std::{path::PathBuf, process::ExitStatus};
+
+use miette::Diagnostic;
+
+use crate::{
+    build::{Builder, Context, Output},
+    config::Custom,
+    exec, image, progress, tmp,
+};
+
+#[derive(Debug, Diagnostic, thiserror::Error)]
+pub enum CustomError {
+    #[error("IO error")]
+    IO(#[from] std::io::Error),
+    #[error("failed to create tempdir")]
+    TempDir(#[from] async_tempfile::Error),
+    #[error("failed to parse image")]
+    #[diagnostic(transparent)]
+    Image(#[from] image::ImageError),
+    #[error("failed to run command: {0}")]
+    Build(ExitStatus),
+    #[error("command didn't write an OCI layout to {}", .0.display())]
+    #[diagnostic(help(
+        "the command must write an OCI image layout (with an `index.json`) to $STEIGER_OUTPUT_DIR"
+    ))]
+    NoLayout(PathBuf),
+}
+
+#[derive(Clone)]
+pub struct CustomBuilder;
+
+impl Builder for CustomBuilder {
+    type Error = CustomError;
+    type Input = Custom;
+
+    fn try_init() -> Result<Self, Self::Error>
+    where
+        Self: Sized,
+    {
+        Ok(Self)
+    }
+
+    async fn build(
+        self,
+        Context {
+            service_name,
+            platform,
+            mut progress,
+            ..
+        }: Context,
+        input: Self::Input,
+    ) -> Result<Output, Self::Error> {
+        progress.set_name(&service_name);
+        progress.info("starting builder");
+
+        let dest = tmp::new_dir_with_name(&service_name).await?;
+        let (os, arch) = platform.split_once('/').unwrap_or(("linux", &platform));
+        let output = exec::run_with_progress(
+            exec::shell(&input.command)
+                .env("STEIGER_OUTPUT_DIR", dest.as_os_str())
+                .env("STEIGER_PLATFORM", &platform)
+                .env("TARGETOS", os)
+                .env("TARGETARCH", arch)
+                .envs(&input.env),
+            progress::child(&mut progress, format!("{service_name} › custom")),
+        )
+        .await?;
+
+        if !output.success() {
+            progress.fail(format!("build failed with exit code: {}", output.code()));
+
+            return Err(CustomError::Build(output.status));
+        }
+
+        if !tokio::fs::try_exists(dest.join("index.json")).await? {
+            progress.fail("no OCI layout found".to_string());
+
+            return Err(CustomError::NoLayout(dest.to_path_buf()));
+        }
+
+        progress.done("build finished".to_string());
+
+        let images = image::load_from_path(&dest, &mut progress).await?;
+
+        Ok(Output {
+            artifacts: vec![(service_name, images)].into_iter().collect(),
+            ..Output::default()
+        })
+    }
+}
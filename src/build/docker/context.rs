@@ -0,0 +1,193 @@
+use std::path::{Component, Path, PathBuf};
+
+use globset::{GlobBuilder, GlobMatcher};
+
+/// Contexts above this size are reported as a warning before the build starts.
+pub const SIZE_WARNING_THRESHOLD: u64 = 500 * 1024 * 1024;
+
+struct Rule {
+    matcher: GlobMatcher,
+    exception: bool,
+}
+
+/// Matches paths relative to the build context using `.dockerignore` semantics:
+/// the last matching pattern wins and a `!` prefix re-includes a path.
+pub struct IgnoreMatcher {
+    rules: Vec<Rule>,
+}
+
+impl IgnoreMatcher {
+    pub fn new<'a>(patterns: impl IntoIterator<Item = &'a str>) -> Result<Self, globset::Error> {
+        let mut rules = vec![];
+
+        for line in patterns {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (pattern, exception) = match line.strip_prefix('!') {
+                Some(pattern) => (pattern.trim(), true),
+                None => (line, false),
+            };
+
+            let pattern = pattern.trim_start_matches('/').trim_end_matches('/');
+
+            if pattern.is_empty() || pattern == "." {
+                continue;
+            }
+
+            let matcher = GlobBuilder::new(pattern.strip_prefix("./").unwrap_or(pattern))
+                .literal_separator(true)
+                .build()?
+                .compile_matcher();
+
+            rules.push(Rule { matcher, exception });
+        }
+
+        Ok(Self { rules })
+    }
+
+    fn has_exceptions(&self) -> bool {
+        self.rules.iter().any(|rule| rule.exception)
+    }
+
+    /// Returns whether the path (or one of its parent directories) is excluded.
+    pub fn is_excluded(&self, path: &Path) -> bool {
+        let mut excluded = false;
+
+        for rule in self.rules.iter() {
+            if path
+                .ancestors()
+                .any(|p| !p.as_os_str().is_empty() && rule.matcher.is_match(normalize(p)))
+            {
+                excluded = !rule.exception;
+            }
+        }
+
+        excluded
+    }
+}
+
+fn normalize(path: &Path) -> String {
+    path.components()
+        .filter_map(|c| match c {
+            Component::Normal(part) => Some(part.to_string_lossy()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Reads the ignore file BuildKit would use for this build: a Dockerfile-specific
+/// `<Dockerfile>.dockerignore` takes precedence over the context's `.dockerignore`.
+pub async fn read_ignore_file(context: &Path, dockerfile: &Path) -> Result<String, std::io::Error> {
+    let mut candidates = vec![context.join(".dockerignore")];
+
+    if let Some(name) = dockerfile.file_name() {
+        let mut name = name.to_os_string();
+        name.push(".dockerignore");
+        candidates.insert(0, dockerfile.with_file_name(name));
+    }
+
+    for path in candidates {
+        match tokio::fs::read_to_string(&path).await {
+            Ok(content) => return Ok(content),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(String::new())
+}
+
+/// Computes the total size in bytes of the files that would be sent to the builder.
+pub async fn size(root: &Path, matcher: &IgnoreMatcher) -> Result<u64, std::io::Error> {
+    let descend_excluded = matcher.has_exceptions();
+    let mut stack = vec![PathBuf::new()];
+    let mut total = 0;
+
+    while let Some(dir) = stack.pop() {
+        let mut entries = tokio::fs::read_dir(root.join(&dir)).await?;
+
+        while let Some(entry) = entries.next_entry().await? {
+            let path = dir.join(entry.file_name());
+            let file_type = entry.file_type().await?;
+            let excluded = matcher.is_excluded(&path);
+
+            if file_type.is_dir() {
+                if !excluded || descend_excluded {
+                    stack.push(path);
+                }
+            } else if !excluded {
+                total += entry.metadata().await?.len();
+            }
+        }
+    }
+
+    Ok(total)
+}
+
+pub fn fmt_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = UNITS[0];
+
+    for next in UNITS.iter().skip(1) {
+        if value < 1024.0 {
+            break;
+        }
+
+        value /= 1024.0;
+        unit = next;
+    }
+
+    format!("{value:.1} {unit}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn excluded(patterns: &str, path: &str) -> bool {
+        IgnoreMatcher::new(patterns.lines())
+            .unwrap()
+            .is_excluded(Path::new(path))
+    }
+
+    #[test]
+    fn exceptions_reinclude_paths() {
+        let patterns = "*.md\n!README.md\ntarget\n!target/keep.txt";
+
+        assert!(excluded(patterns, "CHANGELOG.md"));
+        assert!(!excluded(patterns, "README.md"));
+        assert!(excluded(patterns, "target/debug/app"));
+        assert!(!excluded(patterns, "target/keep.txt"));
+    }
+
+    #[test]
+    fn double_star_matches_any_depth() {
+        let patterns = "**/*.log";
+
+        assert!(excluded(patterns, "app.log"));
+        assert!(excluded(patterns, "logs/2024/app.log"));
+        assert!(!excluded(patterns, "logs/app.txt"));
+    }
+
+    #[test]
+    fn patterns_are_anchored_at_the_root() {
+        let patterns = "/build\nnode_modules\n*.tmp";
+
+        assert!(excluded(patterns, "build/out.js"));
+        assert!(excluded(patterns, "node_modules/pkg/index.js"));
+        assert!(!excluded(patterns, "web/node_modules/pkg/index.js"));
+        assert!(excluded(patterns, "scratch.tmp"));
+        assert!(!excluded(patterns, "src/scratch.tmp"));
+    }
+}
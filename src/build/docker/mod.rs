@@ -0,0 +1,507 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    process::ExitStatus,
+};
+
+use miette::Diagnostic;
+use prodash::tree::Item;
+use steiger::ui;
+use tokio::process::Command;
+use uuid::Uuid;
+
+use crate::{
+    build::{Builder, Context, Output},
+    config::{Docker, Resources},
+    exec::{self, CmdBuilder, ExitError},
+    image, progress, tmp,
+};
+
+mod context;
+mod rawjson;
+
+#[derive(Debug, Diagnostic, thiserror::Error)]
+pub enum DockerError {
+    #[error("failed to find docker binary")]
+    Path(#[from] which::Error),
+    #[error("failed to list buildkit builders")]
+    #[diagnostic(transparent)]
+    ListBuilders(#[source] ExitError),
+    #[error("failed to create buildkit builder")]
+    #[diagnostic(transparent)]
+    CreateBuilder(#[source] ExitError),
+    #[error("IO error")]
+    IO(#[from] std::io::Error),
+    #[error("failed to create tempdir")]
+    TempDir(#[from] async_tempfile::Error),
+    #[error("failed to parse image")]
+    #[diagnostic(transparent)]
+    Image(#[from] image::ImageError),
+    #[error("failed to parse buildkit output")]
+    Serde(#[from] serde_json::Error),
+    #[error("failed to run '{0} build': {1}")]
+    Build(&'static str, ExitStatus),
+    #[error("failed to export image from {0} storage")]
+    Export(&'static str, #[source] ExitError),
+    #[error("invalid ignore pattern")]
+    Pattern(#[from] globset::Error),
+}
+
+mod buildx {
+    use serde::Deserialize;
+
+    #[derive(Deserialize)]
+    #[serde(rename_all = "PascalCase")]
+    pub struct Builder {
+        pub name: String,
+    }
+}
+
+/// Period of the CPU quota, limits are expressed in microseconds per period
+const CPU_PERIOD: u64 = 100_000;
+
+/// Name of the buildkit builder, builds with different limits need their own builder
+fn builder_name(resources: &Resources) -> String {
+    let mut name = "steiger".to_string();
+
+    if let Some(cpus) = resources.cpus {
+        name.push_str(&format!("-cpu{cpus}"));
+    }
+
+    if let Some(memory) = resources.memory {
+        name.push_str(&format!("-mem{memory}"));
+    }
+
+    if let Some(jobs) = resources.jobs {
+        name.push_str(&format!("-jobs{jobs}"));
+    }
+
+    name
+}
+
+fn fmt_map(map: HashMap<String, String>, sep: char) -> Vec<String> {
+    map.into_iter()
+        .map(|(name, value)| format!("{name}{sep}{value}"))
+        .collect::<Vec<_>>()
+}
+
+/// CLI used to build Dockerfiles, podman and buildah are used when docker isn't installed
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Engine {
+    Docker,
+    Podman,
+    Buildah,
+}
+
+impl Engine {
+    fn name(self) -> &'static str {
+        match self {
+            Engine::Docker => "docker",
+            Engine::Podman => "podman",
+            Engine::Buildah => "buildah",
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct DockerBuilder {
+    binary: PathBuf,
+    engine: Engine,
+}
+
+impl DockerBuilder {
+    async fn ensure_builder(
+        &self,
+        progress: &mut Item,
+        name: &str,
+        resources: &Resources,
+    ) -> Result<(), DockerError> {
+        let builders = self.list_builders().await?;
+
+        if builders.iter().any(|b| b.name == name) {
+            progress.info("using existing buildkit builder");
+            return Ok(());
+        }
+
+        progress.info("creating buildkit builder");
+
+        match self.create_builder(name, resources).await {
+            Err(DockerError::CreateBuilder(ExitError::Status { code: 1, stderr }))
+                if stderr.contains("ERROR: existing instance for") =>
+            {
+                progress.info("buildkit builder exists, assuming remote driver");
+            }
+            Err(e) => return Err(e),
+            Ok(()) => {}
+        }
+
+        progress.done("buildkit builder created");
+
+        Ok(())
+    }
+
+    /// Copies an image from the local storage of podman or buildah to an OCI layout and
+    /// removes it from the storage
+    async fn export(&self, tag: &str, dest: &Path) -> Result<(), DockerError> {
+        exec::run_with_output(
+            Command::new(&self.binary)
+                .arg("push")
+                .arg(tag)
+                .arg(format!("oci:{}", dest.to_string_lossy())),
+        )
+        .await
+        .map_err(|e| DockerError::Export(self.engine.name(), e))?;
+
+        let _ = exec::run_with_output(Command::new(&self.binary).arg("rmi").arg(tag)).await;
+
+        Ok(())
+    }
+
+    async fn list_builders(&self) -> Result<Vec<buildx::Builder>, DockerError> {
+        let output = exec::run_with_output(
+            Command::new(&self.binary)
+                .arg("buildx")
+                .arg("ls")
+                .arg("--format=json"),
+        )
+        .await
+        .map_err(DockerError::ListBuilders)?;
+
+        Ok(output
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<Vec<_>, _>>()?)
+    }
+
+    /// Command creating a builder running in a container, which is limited to `resources`.
+    /// `config` is the buildkitd config limiting the jobs.
+    fn create_command(
+        &self,
+        name: &str,
+        resources: &Resources,
+        config: Option<&Path>,
+    ) -> CmdBuilder {
+        let mut cmd = CmdBuilder::new(&self.binary);
+        cmd.arg("buildx")
+            .arg("create")
+            .arg("--driver=docker-container")
+            .arg(format!("--name={name}"));
+
+        if let Some(cpus) = resources.cpus {
+            cmd.flag("--driver-opt", format!("cpu-period={CPU_PERIOD}"));
+            cmd.flag(
+                "--driver-opt",
+                format!("cpu-quota={}", u64::from(cpus) * CPU_PERIOD),
+            );
+        }
+
+        if let Some(memory) = resources.memory {
+            cmd.flag("--driver-opt", format!("memory={memory}m"));
+        }
+
+        if let Some(config) = config {
+            cmd.arg("--buildkitd-config").arg(config);
+        }
+
+        cmd
+    }
+
+    /// Creates a builder running in a container, which is limited to `resources`
+    async fn create_builder(&self, name: &str, resources: &Resources) -> Result<(), DockerError> {
+        // Only read when the builder is created, the file can be removed afterwards
+        let config = match resources.jobs {
+            Some(jobs) => {
+                let dir = tmp::new_dir().await?;
+                let path = dir.join("buildkitd.toml");
+                tokio::fs::write(&path, format!("[worker.oci]\nmax-parallelism = {jobs}\n"))
+                    .await?;
+
+                Some((dir, path))
+            }
+            None => None,
+        };
+
+        let mut cmd = self.create_command(
+            name,
+            resources,
+            config.as_ref().map(|(_, path)| path.as_path()),
+        );
+        exec::run_with_output(&mut cmd)
+            .await
+            .map_err(DockerError::CreateBuilder)?;
+        drop(config);
+
+        Ok(())
+    }
+}
+
+impl Builder for DockerBuilder {
+    type Error = DockerError;
+    type Input = Docker;
+
+    fn try_init() -> Result<Self, Self::Error>
+    where
+        Self: Sized,
+    {
+        let docker = match which::which("docker") {
+            Ok(binary) => {
+                return Ok(Self {
+                    binary,
+                    engine: Engine::Docker,
+                });
+            }
+            Err(e) => e,
+        };
+
+        [Engine::Podman, Engine::Buildah]
+            .into_iter()
+            .find_map(|engine| {
+                which::which(engine.name())
+                    .ok()
+                    .map(|binary| Self { binary, engine })
+            })
+            .ok_or(DockerError::Path(docker))
+    }
+
+    async fn build(
+        self,
+        Context {
+            service_name,
+            platform,
+            mut progress,
+            ..
+        }: Context,
+        input: Self::Input,
+    ) -> Result<Output, Self::Error> {
+        progress.set_name(&service_name);
+        progress.info("starting builder");
+
+        let engine = self.engine;
+        let builder = builder_name(&input.resources);
+        let mut cmd = CmdBuilder::new(&self.binary);
+
+        match engine {
+            Engine::Docker => {
+                self.ensure_builder(&mut progress, &builder, &input.resources)
+                    .await?;
+                cmd.arg("buildx").arg("build");
+            }
+            Engine::Podman => {
+                progress.info("docker not found, building with podman");
+                cmd.arg("build");
+            }
+            Engine::Buildah => {
+                progress.info("docker not found, building with buildah");
+                cmd.arg("bud");
+            }
+        }
+
+        let build_args = fmt_map(input.build_args, '=');
+        let hosts = fmt_map(input.hosts, ':');
+
+        if let Some(target) = input.target {
+            cmd.flag("--target", target);
+        }
+
+        if input.pull {
+            cmd.arg(match engine {
+                Engine::Docker => "--pull",
+                // A plain `--pull` only pulls missing images for older versions
+                Engine::Podman | Engine::Buildah => "--pull=always",
+            });
+        }
+
+        // The buildkit builder is limited as a whole, podman and buildah limit every build
+        if engine != Engine::Docker {
+            if let Some(cpus) = input.resources.cpus {
+                cmd.flag("--cpu-period", CPU_PERIOD.to_string());
+                cmd.flag("--cpu-quota", (u64::from(cpus) * CPU_PERIOD).to_string());
+            }
+
+            if let Some(memory) = input.resources.memory {
+                cmd.flag("--memory", format!("{memory}m"));
+            }
+
+            if let Some(jobs) = input.resources.jobs {
+                cmd.flag("--jobs", jobs.to_string());
+            }
+        }
+
+        for entry in build_args.iter() {
+            cmd.flag("--build-arg", entry);
+        }
+
+        for entry in hosts.iter() {
+            cmd.flag("--add-host", entry);
+        }
+
+        let context_dir = exec::resolve(&input.context);
+        let dockerfile = match input.dockerfile {
+            Some(ref dockerfile) => exec::resolve(dockerfile),
+            None => context_dir.join("Dockerfile"),
+        };
+        let ignore = context::read_ignore_file(&context_dir, &dockerfile).await?;
+        let matcher = context::IgnoreMatcher::new(
+            ignore
+                .lines()
+                .chain(input.context_filters.iter().map(String::as_str)),
+        )?;
+
+        let size = context::size(&context_dir, &matcher).await?;
+
+        if size > context::SIZE_WARNING_THRESHOLD {
+            let warning = format!(
+                "build context is {}, consider excluding paths using .dockerignore or contextFilters",
+                context::fmt_size(size)
+            );
+            progress.info(format!("⚠ {warning}"));
+            ui::record_warning(format!("{service_name}: {warning}"));
+        } else {
+            progress.info(format!("build context size: {}", context::fmt_size(size)));
+        }
+
+        // BuildKit has no flag to exclude paths, so the filters are passed through a
+        // Dockerfile-specific ignore file placed next to a copy of the Dockerfile.
+        let filtered = if input.context_filters.is_empty() {
+            None
+        } else {
+            let dir = tmp::new_dir_with_name(format!("{service_name}-context")).await?;
+            tokio::fs::copy(&dockerfile, dir.join("Dockerfile")).await?;
+            tokio::fs::write(
+                dir.join("Dockerfile.dockerignore"),
+                [ignore.as_str(), &input.context_filters.join("\n")].join("\n"),
+            )
+            .await?;
+
+            Some(dir)
+        };
+
+        let dockerfile = filtered
+            .as_ref()
+            .map(|dir| dir.join("Dockerfile"))
+            .unwrap_or(dockerfile);
+
+        let dest = tmp::new_dir_with_name(&service_name).await?;
+        cmd.arg("--platform")
+            .arg(&platform)
+            .arg("--file")
+            .arg(&dockerfile);
+
+        let item = progress::child(&mut progress, format!("{service_name} › {}", engine.name()));
+        // Podman and buildah can't write an OCI layout directly, images go through their
+        // local storage under a unique tag
+        let tag = format!("localhost/steiger-build:{}", Uuid::new_v4().simple());
+        let output = match engine {
+            Engine::Docker => {
+                let mut reporter = rawjson::Reporter::new(&service_name);
+
+                exec::run_with_reporter(
+                    cmd.arg("--builder")
+                        .arg(&builder)
+                        .arg("--progress")
+                        .arg("rawjson")
+                        .arg("--output")
+                        .arg(format!(
+                            "type=oci,dest={},tar=false",
+                            dest.as_os_str().to_string_lossy()
+                        ))
+                        .arg(&context_dir),
+                    item,
+                    |progress, line| reporter.report(progress, line),
+                )
+                .await?
+            }
+            Engine::Podman | Engine::Buildah => {
+                exec::run_with_progress(cmd.arg("--tag").arg(&tag).arg(&context_dir), item).await?
+            }
+        };
+
+        if !output.success() {
+            progress.fail(format!("build failed with exit code: {}", output.code()));
+
+            return Err(DockerError::Build(engine.name(), output.status));
+        }
+
+        if engine != Engine::Docker {
+            self.export(&tag, &dest).await?;
+        }
+
+        progress.done("build finished".to_string());
+
+        let images = image::load_from_path(dest, &mut progress).await?;
+
+        Ok(Output {
+            artifacts: vec![(service_name, images)].into_iter().collect(),
+            ..Output::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(cmd: &Command) -> Vec<String> {
+        cmd.as_std()
+            .get_args()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn builder_names() {
+        assert_eq!(builder_name(&Resources::default()), "steiger");
+        assert_eq!(
+            builder_name(&Resources {
+                cpus: Some(2),
+                memory: Some(4096),
+                jobs: Some(4),
+            }),
+            "steiger-cpu2-mem4096-jobs4"
+        );
+    }
+
+    #[test]
+    fn create_builder_args() {
+        let docker = DockerBuilder {
+            binary: PathBuf::from("docker"),
+            engine: Engine::Docker,
+        };
+        let resources = Resources {
+            cpus: Some(2),
+            memory: Some(512),
+            jobs: Some(4),
+        };
+
+        assert_eq!(
+            args(&docker.create_command("steiger", &Resources::default(), None)),
+            [
+                "buildx",
+                "create",
+                "--driver=docker-container",
+                "--name=steiger"
+            ]
+        );
+        assert_eq!(
+            args(&docker.create_command(
+                "steiger-cpu2-mem512-jobs4",
+                &resources,
+                Some(Path::new("/tmp/buildkitd.toml")),
+            )),
+            [
+                "buildx",
+                "create",
+                "--driver=docker-container",
+                "--name=steiger-cpu2-mem512-jobs4",
+                "--driver-opt",
+                "cpu-period=100000",
+                "--driver-opt",
+                "cpu-quota=200000",
+                "--driver-opt",
+                "memory=512m",
+                "--buildkitd-config",
+                "/tmp/buildkitd.toml",
+            ]
+        );
+    }
+}
@@ -0,0 +1,226 @@
+use std::collections::HashMap;
+
+use base64::{Engine, prelude::BASE64_STANDARD};
+use prodash::{
+    Progress,
+    tree::Item,
+    unit::{self, Unit, display::Mode},
+};
+use serde::Deserialize;
+use steiger::ui;
+
+use crate::progress;
+
+/// One line of `docker buildx build --progress rawjson` output, buildkit's `SolveStatus`
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct SolveStatus {
+    vertexes: Vec<Vertex>,
+    statuses: Vec<VertexStatus>,
+    logs: Vec<VertexLog>,
+    warnings: Vec<VertexWarning>,
+}
+
+/// A step of the build, like a Dockerfile instruction of a stage or the export
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Vertex {
+    digest: String,
+    name: String,
+    started: Option<String>,
+    completed: Option<String>,
+    cached: bool,
+    error: String,
+}
+
+/// Progress of a vertex, like pulling or exporting a layer
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct VertexStatus {
+    id: String,
+    vertex: String,
+    name: String,
+    total: u64,
+    current: u64,
+    completed: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct VertexLog {
+    vertex: String,
+    /// Base64 encoded chunk of output, not necessarily ending with a newline
+    data: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct VertexWarning {
+    /// Base64 encoded summary
+    short: String,
+}
+
+fn bytes() -> Unit {
+    unit::dynamic_and_mode(unit::Bytes, Mode::with_throughput().and_percentage())
+}
+
+fn decode(data: &str) -> String {
+    BASE64_STANDARD
+        .decode(data)
+        .map(|data| String::from_utf8_lossy(&data).into_owned())
+        .unwrap_or_default()
+}
+
+#[derive(Default)]
+struct Step {
+    name: String,
+    /// Progress bar while the vertex runs
+    item: Option<Item>,
+    /// Output that isn't terminated by a newline yet
+    partial: String,
+    done: bool,
+}
+
+/// Turns buildkit's progress events into a progress bar per running step, with byte counts
+/// for transfers and exports
+pub struct Reporter {
+    service_name: String,
+    keep_colors: bool,
+    steps: HashMap<String, Step>,
+    statuses: HashMap<(String, String), Item>,
+}
+
+impl Reporter {
+    pub fn new(service_name: &str) -> Self {
+        Self {
+            service_name: service_name.to_string(),
+            keep_colors: progress::keep_colors(),
+            steps: HashMap::new(),
+            statuses: HashMap::new(),
+        }
+    }
+
+    /// Reports a line of output, returns the text kept for the log file
+    pub fn report(&mut self, progress: &mut Item, line: &str) -> Vec<String> {
+        let Ok(status) = serde_json::from_str::<SolveStatus>(line) else {
+            // Errors of the docker CLI itself are plain text
+            progress.info(progress::sanitize(line, self.keep_colors));
+            return vec![progress::sanitize(line, false)];
+        };
+
+        let mut log = vec![];
+
+        for vertex in status.vertexes {
+            self.vertex(progress, vertex, &mut log);
+        }
+
+        for status in status.statuses {
+            self.status(progress, status);
+        }
+
+        for entry in status.logs {
+            self.output(progress, entry, &mut log);
+        }
+
+        for warning in status.warnings {
+            let warning = decode(&warning.short);
+            progress.info(format!("⚠ {warning}"));
+            ui::record_warning(format!("{}: {warning}", self.service_name));
+            log.push(format!("WARNING: {warning}"));
+        }
+
+        log
+    }
+
+    fn vertex(&mut self, progress: &mut Item, vertex: Vertex, log: &mut Vec<String>) {
+        let step = self.steps.entry(vertex.digest.clone()).or_default();
+
+        if step.done {
+            return;
+        }
+
+        step.name = vertex.name;
+
+        if vertex.started.is_some() && step.item.is_none() {
+            let item = progress::child(progress, &step.name);
+            item.init(None, None);
+            step.item = Some(item);
+            log.push(format!("=> {}", step.name));
+        }
+
+        if vertex.completed.is_none() {
+            return;
+        }
+
+        step.done = true;
+
+        let message = if !vertex.error.is_empty() {
+            progress.fail(format!("{}: {}", step.name, vertex.error));
+            format!("ERROR: {}", vertex.error)
+        } else if vertex.cached {
+            progress.done(format!("{} (cached)", step.name));
+            "CACHED".to_string()
+        } else {
+            progress.done(step.name.clone());
+            "DONE".to_string()
+        };
+
+        log.push(format!("=> {} {message}", step.name));
+
+        // Dropping the items removes their progress bars
+        step.item = None;
+        self.statuses
+            .retain(|(digest, _), _| digest != &vertex.digest);
+    }
+
+    fn status(&mut self, progress: &mut Item, status: VertexStatus) {
+        let key = (status.vertex, status.id);
+
+        if status.completed.is_some() {
+            self.statuses.remove(&key);
+            return;
+        }
+
+        let item = self.statuses.entry(key).or_insert_with_key(|(digest, id)| {
+            let name = if status.name.is_empty() {
+                id
+            } else {
+                &status.name
+            };
+            let parent = match self.steps.get_mut(digest) {
+                Some(Step {
+                    item: Some(item), ..
+                }) => item,
+                _ => progress,
+            };
+
+            let item = progress::child(parent, name);
+            item.init(None, Some(bytes()));
+            item
+        });
+
+        if status.total > 0 {
+            item.set_max(Some(status.total as usize));
+        }
+
+        item.set(status.current as usize);
+    }
+
+    fn output(&mut self, progress: &mut Item, entry: VertexLog, log: &mut Vec<String>) {
+        let step = self.steps.entry(entry.vertex).or_default();
+        step.partial.push_str(&decode(&entry.data));
+
+        let Some(end) = step.partial.rfind('\n') else {
+            return;
+        };
+
+        let target = step.item.as_ref().unwrap_or(progress);
+
+        for line in step.partial[..end].lines() {
+            target.info(progress::sanitize(line, self.keep_colors));
+            log.push(progress::sanitize(line, false));
+        }
+
+        step.partial.drain(..=end);
+    }
+}
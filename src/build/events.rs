@@ -26,6 +26,8 @@ pub struct Tags {
     pub github_repo: Option<String>,
     #[serde(rename = "github.workflow")]
     pub github_workflow: Option<String>,
+    #[serde(rename = "steiger.channel", skip_serializing_if = "Option::is_none")]
+    pub channel: Option<String>,
 }
 
 impl Tags {
@@ -49,6 +51,7 @@ impl Tags {
             git_refname,
             github_repo,
             github_workflow,
+            channel: None,
         })
     }
 }
@@ -1,13 +1,16 @@
-use std::{path::PathBuf, process::ExitStatus};
+use std::{
+    env,
+    path::{Component, Path, PathBuf},
+    process::ExitStatus,
+};
 
-use async_tempfile::TempDir;
 use miette::Diagnostic;
 use tokio::process::Command;
 
 use crate::{
     build::{Builder, Context, Output},
     config::Ko,
-    exec, image,
+    exec, image, progress, tmp,
 };
 
 #[derive(Debug, Diagnostic, thiserror::Error)]
@@ -30,6 +33,107 @@ pub struct KoBuilder {
     binary: PathBuf,
 }
 
+/// Directory used as `KOCACHE` so repeated builds can reuse ko's build cache,
+/// unless the user already configured one.
+fn cache_dir() -> PathBuf {
+    if let Some(dir) = env::var_os("KOCACHE") {
+        return PathBuf::from(dir);
+    }
+
+    if let Some(dir) = exec::cache_dir() {
+        return dir.join("ko");
+    }
+
+    env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("LOCALAPPDATA").map(PathBuf::from))
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        .unwrap_or_else(env::temp_dir)
+        .join("steiger")
+        .join("ko")
+}
+
+/// Path without `.` components, so paths from the config and `go.work` compare equal
+fn normalize(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|component| component != &Component::CurDir)
+        .collect()
+}
+
+/// Module directories listed by the `use` directives of a `go.work` file
+fn workspace_modules(go_work: &str) -> Vec<PathBuf> {
+    let mut modules = vec![];
+    let mut block = false;
+
+    for line in go_work.lines() {
+        let line = line.split("//").next().unwrap_or_default().trim();
+
+        let dir = if block {
+            block = line != ")";
+            line
+        } else {
+            match line.split_once(char::is_whitespace) {
+                Some(("use", "(")) => {
+                    block = true;
+                    continue;
+                }
+                Some(("use", dir)) => dir.trim(),
+                _ => continue,
+            }
+        };
+
+        if !dir.is_empty() && dir != ")" {
+            modules.push(normalize(Path::new(dir.trim_matches('"'))));
+        }
+    }
+
+    modules
+}
+
+/// Finds the directory ko has to run in for a local import path. Go only builds packages of
+/// the main module or of the modules in `go.work`, so an import path in another module runs
+/// from that module's directory, with the import path made relative to it
+async fn locate_module(import_path: &str) -> Result<(PathBuf, String), KoError> {
+    let unchanged = || (PathBuf::from("."), import_path.to_string());
+
+    // Import paths like `github.com/org/repo/cmd/api` are resolved by go itself
+    if !import_path.starts_with('.') {
+        return Ok(unchanged());
+    }
+
+    let path = normalize(Path::new(import_path));
+    let mut module = None;
+
+    for dir in path.ancestors() {
+        if tokio::fs::try_exists(exec::resolve(dir.join("go.mod"))).await? {
+            module = Some(dir);
+            break;
+        }
+    }
+
+    let Some(module) = module.filter(|dir| !dir.as_os_str().is_empty()) else {
+        return Ok(unchanged());
+    };
+
+    let go_work = exec::resolve("go.work");
+
+    if tokio::fs::try_exists(&go_work).await?
+        && workspace_modules(&tokio::fs::read_to_string(&go_work).await?)
+            .iter()
+            .any(|dir| dir == module)
+    {
+        return Ok(unchanged());
+    }
+
+    let relative = path.strip_prefix(module).unwrap_or(&path);
+    let import_path = match relative.as_os_str().is_empty() {
+        true => ".".to_string(),
+        false => format!("./{}", relative.to_string_lossy().replace('\\', "/")),
+    };
+
+    Ok((module.to_path_buf(), import_path))
+}
+
 impl Builder for KoBuilder {
     type Error = KoError;
     type Input = Ko;
@@ -49,41 +153,102 @@ impl Builder for KoBuilder {
             service_name,
             platform,
             mut progress,
+            registries,
         }: Context,
         input: Self::Input,
     ) -> Result<Output, Self::Error> {
         progress.set_name(&service_name);
         progress.info("starting builder");
 
-        let dest = TempDir::new_with_name(&service_name).await?;
-        let status = exec::run_with_progress(
-            Command::new(&self.binary)
-                .arg("build")
+        let import_path = input.import_path.as_deref().unwrap_or(".");
+        let (dir, import_path) = match input.dir {
+            Some(ref dir) => (dir.clone(), import_path.to_string()),
+            None => locate_module(import_path).await?,
+        };
+
+        if dir != Path::new(".") {
+            progress.info(format!("using module in {}", dir.display()));
+        }
+
+        let context = Context {
+            service_name,
+            platform,
+            progress,
+            registries,
+        };
+
+        exec::within(&dir, self.run(context, input, import_path)).await
+    }
+}
+
+impl KoBuilder {
+    /// Runs `ko build` in the module directory
+    async fn run(
+        self,
+        Context {
+            service_name,
+            platform,
+            mut progress,
+            ..
+        }: Context,
+        input: Ko,
+        import_path: String,
+    ) -> Result<Output, KoError> {
+        let import_path = import_path.as_str();
+        let cache_dir = cache_dir();
+        tokio::fs::create_dir_all(&cache_dir).await?;
+
+        let mut cmd = Command::new(&self.binary);
+        cmd.env("KOCACHE", &cache_dir);
+
+        if let Some(ref base_image) = input.default_base_image {
+            progress.info(format!("using base image: {base_image}"));
+            cmd.env("KO_DEFAULTBASEIMAGE", base_image);
+        }
+
+        // ko only looks for .ko.yaml in the working directory, so pick up the one
+        // next to the import path when no explicit config is given
+        let config_path = match input.config_path {
+            Some(path) => Some(path),
+            None => {
+                let path = Path::new(import_path).join(".ko.yaml");
+                tokio::fs::try_exists(exec::resolve(&path))
+                    .await?
+                    .then_some(path)
+            }
+        };
+
+        if let Some(config_path) = config_path {
+            progress.info(format!("using ko config: {}", config_path.display()));
+            cmd.env("KO_CONFIG_PATH", config_path);
+        }
+
+        let dest = tmp::new_dir_with_name(&service_name).await?;
+        let output = exec::run_with_progress(
+            cmd.arg("build")
                 .arg("--push=false")
                 .arg("--platform")
                 .arg(&platform)
                 .arg("--oci-layout-path")
                 .arg(dest.as_os_str())
-                .arg(input.import_path.as_deref().unwrap_or(".")),
-            progress.add_child(format!("{service_name} › ko")),
+                .arg(import_path),
+            progress::child(&mut progress, format!("{service_name} › ko")),
         )
         .await?;
 
-        if !status.success() {
-            progress.fail(format!(
-                "build failed with exit code: {}",
-                status.code().unwrap_or_default()
-            ));
+        if !output.success() {
+            progress.fail(format!("build failed with exit code: {}", output.code()));
 
-            return Err(KoError::Build(status));
+            return Err(KoError::Build(output.status));
         }
 
         progress.done("build finished".to_string());
 
-        let images = image::load_from_path(dest).await?;
+        let images = image::load_from_path(dest, &mut progress).await?;
 
         Ok(Output {
             artifacts: vec![(service_name, images)].into_iter().collect(),
+            ..Output::default()
         })
     }
 }
@@ -0,0 +1,88 @@
+use std::sync::OnceLock;
+
+use prodash::tree::Item;
+use tokio::{
+    process::Command,
+    sync::Mutex,
+    time::{Duration, sleep},
+};
+
+use crate::exec;
+
+static MAX_LOAD: OnceLock<f64> = OnceLock::new();
+
+/// Held while a build waits to start, so builds start one at a time
+static START: Mutex<()> = Mutex::const_new(());
+
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Time the load average gets to pick up a started build before the next may start
+const SETTLE_TIME: Duration = Duration::from_secs(2);
+
+pub fn set_max_load(max_load: Option<f64>) {
+    if let Some(max_load) = max_load {
+        let _ = MAX_LOAD.set(max_load);
+    }
+}
+
+/// One minute load average, `None` when it can't be read
+async fn load_average() -> Option<f64> {
+    let loadavg = match tokio::fs::read_to_string("/proc/loadavg").await {
+        Ok(loadavg) => loadavg,
+        // macOS prints the averages as `{ 1.52 1.61 1.74 }`
+        Err(_) => exec::run_with_output(Command::new("sysctl").arg("-n").arg("vm.loadavg"))
+            .await
+            .ok()?,
+    };
+
+    parse_load_average(&loadavg)
+}
+
+/// First average of `/proc/loadavg` or `sysctl -n vm.loadavg`
+fn parse_load_average(loadavg: &str) -> Option<f64> {
+    loadavg
+        .split_whitespace()
+        .find(|value| *value != "{")?
+        .parse()
+        .ok()
+}
+
+/// Waits until the system load is below `--max-load`, returns immediately without it
+pub async fn wait(progress: &mut Item) {
+    let Some(&max_load) = MAX_LOAD.get() else {
+        return;
+    };
+
+    let _start = START.lock().await;
+    let mut waiting = false;
+
+    while let Some(load) = load_average().await
+        && load >= max_load
+    {
+        if !waiting {
+            progress.info(format!(
+                "waiting for system load ({load:.2}) to drop below {max_load}"
+            ));
+            waiting = true;
+        }
+
+        sleep(POLL_INTERVAL).await;
+    }
+
+    sleep(SETTLE_TIME).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_load_averages() {
+        assert_eq!(
+            parse_load_average("0.52 0.58 0.59 1/467 12345\n"),
+            Some(0.52)
+        );
+        assert_eq!(parse_load_average("{ 1.52 1.61 1.74 }\n"), Some(1.52));
+        assert_eq!(parse_load_average(""), None);
+    }
+}
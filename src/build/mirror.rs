@@ -0,0 +1,69 @@
+use docker_credential::CredentialRetrievalError;
+use miette::Diagnostic;
+use oci_client::{Reference, errors::OciDistributionError};
+
+use crate::{
+    build::{Builder, Context, Output},
+    config::Mirror,
+    registry,
+};
+
+#[derive(Debug, Diagnostic, thiserror::Error)]
+pub enum MirrorError {
+    #[error("failed to parse image reference")]
+    Parse(#[from] oci_client::ParseError),
+    #[error("failed to retrieve registry credentials")]
+    Credential(#[from] CredentialRetrievalError),
+    #[error("failed to resolve image")]
+    Resolve(#[from] OciDistributionError),
+    #[error("image '{0}' doesn't exist")]
+    NotFound(String),
+}
+
+#[derive(Clone)]
+pub struct MirrorBuilder;
+
+impl Builder for MirrorBuilder {
+    type Error = MirrorError;
+    type Input = Mirror;
+
+    fn try_init() -> Result<Self, Self::Error>
+    where
+        Self: Sized,
+    {
+        Ok(Self)
+    }
+
+    async fn build(
+        self,
+        Context {
+            service_name,
+            mut progress,
+            registries,
+            ..
+        }: Context,
+        input: Self::Input,
+    ) -> Result<Output, Self::Error> {
+        progress.set_name(&service_name);
+        progress.info(format!("resolving {}", input.image));
+
+        let image_ref = Reference::try_from(input.image.as_str())?;
+        let auth = registry::load_credentials(image_ref.resolve_registry())?;
+
+        // Pinned, so every registry receives the same image even if the tag moves meanwhile
+        let digest = registries
+            .client(auth)
+            .resolve_digest(&image_ref)
+            .await?
+            .ok_or_else(|| MirrorError::NotFound(input.image.clone()))?;
+
+        progress.done(format!("resolved {} to {digest}", input.image));
+
+        Ok(Output {
+            mirrors: vec![(service_name, image_ref.clone_with_digest(digest))]
+                .into_iter()
+                .collect(),
+            ..Output::default()
+        })
+    }
+}
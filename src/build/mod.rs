@@ -1,21 +1,55 @@
+use futures::{FutureExt, future::BoxFuture};
 use miette::Diagnostic;
+use oci_client::{Reference, secrets::RegistryAuth};
 use prodash::tree::Item;
-use tokio::task::JoinSet;
+use tokio::{task::JoinSet, time::Instant};
 
 use crate::{
     build::{
-        bazel::BazelBuilder, docker::DockerBuilder, ko::KoBuilder,
-        nix::NixBuilder,
+        apko::ApkoBuilder, bazel::BazelBuilder, cargo::CargoBuilder, custom::CustomBuilder,
+        docker::DockerBuilder, ko::KoBuilder, mirror::MirrorBuilder, nix::NixBuilder,
+        prebuilt::PrebuiltBuilder, script::ScriptBuilder,
     },
-    config::{Build, Config},
+    config::{BuildKind, Config, RegistrySettings, Test},
+    exec,
     image::Image,
+    progress,
+    registry::Registry,
+    report::{Recorder, Stage},
 };
 
+mod apko;
 mod bazel;
+mod cargo;
+mod custom;
 mod docker;
 pub(crate) mod events;
 mod ko;
+pub(crate) mod load;
+mod mirror;
 mod nix;
+mod prebuilt;
+mod script;
+pub(crate) mod test;
+
+#[derive(Debug, Diagnostic, thiserror::Error)]
+#[error("one or more builds failed")]
+pub struct MultiError {
+    #[related]
+    pub errors: Vec<EntryError>,
+}
+
+#[derive(Debug, Diagnostic, thiserror::Error)]
+#[error("failed to build '{name}'")]
+pub struct EntryError {
+    pub name: String,
+    #[source]
+    #[diagnostic_source]
+    pub error: BuildError,
+    /// Output of the last failed command of the build
+    #[help]
+    pub tail: Option<String>,
+}
 
 #[derive(Debug, Diagnostic, thiserror::Error)]
 pub enum BuildError {
@@ -31,14 +65,46 @@ pub enum BuildError {
     #[error("nix error")]
     #[diagnostic(transparent)]
     Nix(#[from] ErrorOf<NixBuilder>),
+    #[error("script error")]
+    #[diagnostic(transparent)]
+    Script(#[from] ErrorOf<ScriptBuilder>),
+    #[error("apko error")]
+    #[diagnostic(transparent)]
+    Apko(#[from] ErrorOf<ApkoBuilder>),
+    #[error("custom builder error")]
+    #[diagnostic(transparent)]
+    Custom(#[from] ErrorOf<CustomBuilder>),
+    #[error("prebuilt error")]
+    #[diagnostic(transparent)]
+    Prebuilt(#[from] ErrorOf<PrebuiltBuilder>),
+    #[error("mirror error")]
+    #[diagnostic(transparent)]
+    Mirror(#[from] ErrorOf<MirrorBuilder>),
+    #[error("cargo error")]
+    #[diagnostic(transparent)]
+    Cargo(#[from] ErrorOf<CargoBuilder>),
     #[error("build events error")]
     #[diagnostic(transparent)]
     Events(#[from] events::ClientError),
+    #[error("build task panicked or was cancelled")]
+    Join(#[from] tokio::task::JoinError),
+    #[error("failed to create build sandbox")]
+    Sandbox(#[source] std::io::Error),
+    #[error("invalid workdir '{}'", .0.display())]
+    Workdir(PathBuf, #[source] std::io::Error),
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    Multi(MultiError),
 }
 
 #[derive(Debug, Default)]
 pub struct Output {
     pub artifacts: HashMap<String, Vec<Image>>,
+    /// Artifacts that failed their tests, these are removed from `artifacts`
+    pub failed_tests: HashMap<String, test::TestError>,
+    /// Remote images copied to the registry with every platform instead of being pushed,
+    /// pinned by digest
+    pub mirrors: HashMap<String, Reference>,
 }
 
 impl Output {
@@ -46,6 +112,9 @@ impl Output {
         for (name, images) in other.artifacts {
             self.artifacts.insert(name, images);
         }
+
+        self.failed_tests.extend(other.failed_tests);
+        self.mirrors.extend(other.mirrors);
     }
 }
 
@@ -53,18 +122,45 @@ pub struct Context {
     pub service_name: String,
     pub platform: String,
     pub progress: Item,
+    pub registries: Registries,
 }
 
 impl Context {
-    pub fn new(service_name: String, platform: String, progress: Item) -> Self {
+    pub fn new(
+        service_name: String,
+        platform: String,
+        progress: Item,
+        registries: Registries,
+    ) -> Self {
         Self {
             service_name,
             platform,
             progress,
+            registries,
         }
     }
 }
 
+/// Registry settings of the config, for builders that pull images themselves
+#[derive(Clone, Default)]
+pub struct Registries {
+    insecure: Vec<String>,
+    settings: RegistrySettings,
+}
+
+impl Registries {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            insecure: config.insecure_registries.clone(),
+            settings: config.registry_settings.clone(),
+        }
+    }
+
+    pub fn client(&self, auth: RegistryAuth) -> Registry {
+        Registry::with_config(auth, &self.insecure, &self.settings)
+    }
+}
+
 pub trait Builder: Clone {
     type Error;
     type Input;
@@ -77,11 +173,14 @@ pub trait Builder: Clone {
 
 type ErrorOf<T> = <T as Builder>::Error;
 
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+};
 
 fn run_builder<B>(
     var: &mut Option<B>,
-    ctx: Context,
+    mut ctx: Context,
     input: B::Input,
 ) -> Result<impl Future<Output = Result<Output, BuildError>> + use<B>, BuildError>
 where
@@ -97,61 +196,214 @@ where
         }
     }?;
 
-    Ok(async { Ok(builder.build(ctx, input).await?) })
+    Ok(async {
+        load::wait(&mut ctx.progress).await;
+        Ok(builder.build(ctx, input).await?)
+    })
+}
+
+async fn run_entry(
+    name: String,
+    build: BoxFuture<'static, Result<Output, BuildError>>,
+    tests: Vec<Test>,
+    progress: Option<Item>,
+    platform: String,
+    recorder: Recorder,
+) -> Result<Output, BuildError> {
+    let started = Instant::now();
+    let result = build.await;
+    recorder.record(
+        Stage::Build,
+        &name,
+        started.elapsed(),
+        result.as_ref().err().map(|e| e as _),
+    );
+
+    let mut output = result?;
+
+    let Some(mut progress) = progress else {
+        return Ok(output);
+    };
+
+    let mut artifacts = output.artifacts.keys().cloned().collect::<Vec<_>>();
+    artifacts.sort();
+
+    for artifact in artifacts {
+        let images = &output.artifacts[&artifact];
+        let pb = progress::child(&mut progress, &artifact);
+        let started = Instant::now();
+        let result = test::run(pb, &tests, &artifact, images, &platform).await;
+
+        recorder.record(
+            Stage::Test,
+            &artifact,
+            started.elapsed(),
+            result.as_ref().err().map(|e| e as _),
+        );
+
+        if let Err(e) = result {
+            output.artifacts.remove(&artifact);
+            output.failed_tests.insert(artifact, e);
+        }
+    }
+
+    match output.failed_tests.is_empty() {
+        true => progress.done("tests passed"),
+        false => progress.fail("tests failed"),
+    }
+
+    Ok(output)
 }
 
 pub struct MetaBuild {
     config: Config,
+    recorder: Recorder,
     ko: Option<KoBuilder>,
     bazel: Option<BazelBuilder>,
     docker: Option<DockerBuilder>,
     nix: Option<NixBuilder>,
+    script: Option<ScriptBuilder>,
+    apko: Option<ApkoBuilder>,
+    custom: Option<CustomBuilder>,
+    prebuilt: Option<PrebuiltBuilder>,
+    mirror: Option<MirrorBuilder>,
+    cargo: Option<CargoBuilder>,
 }
 
 impl MetaBuild {
-    pub fn new(config: Config) -> Self {
+    pub fn new(config: Config, recorder: Recorder) -> Self {
         Self {
             config,
+            recorder,
             ko: None,
             bazel: None,
             docker: None,
             nix: None,
+            script: None,
+            apko: None,
+            custom: None,
+            prebuilt: None,
+            mirror: None,
+            cargo: None,
         }
     }
 
     pub async fn build(mut self, mut pb: Item, platform: &str) -> Result<Output, BuildError> {
         let mut set = JoinSet::default();
+        let mut entries = HashMap::new();
 
         pb.init(Some(self.config.build.len()), None);
         pb.info(format!("detected platform: {platform}"));
 
-        for (name, build) in self.config.build {
-            let progress = pb.add_child(&name);
-            let ctx = Context::new(name, platform.to_string(), progress);
+        let registries = Registries::new(&self.config);
 
-            match build {
-                Build::Ko(ko) => {
-                    set.spawn(run_builder(&mut self.ko, ctx, ko)?);
-                }
-                Build::Bazel(bazel) => {
-                    set.spawn(run_builder(&mut self.bazel, ctx, bazel)?);
-                }
-                Build::Docker(docker) => {
-                    set.spawn(run_builder(&mut self.docker, ctx, docker)?);
-                }
-                Build::Nix(nix) => {
-                    set.spawn(run_builder(&mut self.nix, ctx, nix)?);
+        // Sorted so tasks show up in the same order on every run
+        for (name, build) in self.config.build.into_iter().collect::<BTreeMap<_, _>>() {
+            let mut progress = progress::child(&mut pb, &name);
+            let test_progress = (!build.test.is_empty())
+                .then(|| progress::child(&mut progress, format!("{name} › test")));
+            let ctx = Context::new(
+                name.clone(),
+                platform.to_string(),
+                progress,
+                registries.clone(),
+            );
+            let sandbox = match self.config.sandbox {
+                Some(ref sandbox) => Some(
+                    exec::Sandbox::create(&name, &sandbox.allow_env)
+                        .await
+                        .map_err(BuildError::Sandbox)?,
+                ),
+                None => None,
+            };
+            // Absolute, so paths resolved against it aren't relative to it again once a command
+            // runs in it
+            let workdir = match build.workdir {
+                Some(workdir) => Some(
+                    std::path::absolute(&workdir).map_err(|e| BuildError::Workdir(workdir, e))?,
+                ),
+                None => None,
+            };
+            let env = exec::Environment {
+                workdir,
+                env: build.env,
+                sandbox: sandbox.clone(),
+                stall_detection: self.config.stall_detection.clone(),
+                failure: exec::FailureTail::default(),
+            };
+            let failure = env.failure.clone();
+
+            let future = match build.kind {
+                BuildKind::Ko(ko) => run_builder(&mut self.ko, ctx, ko)?.boxed(),
+                BuildKind::Bazel(bazel) => run_builder(&mut self.bazel, ctx, bazel)?.boxed(),
+                BuildKind::Docker(docker) => run_builder(&mut self.docker, ctx, docker)?.boxed(),
+                BuildKind::Nix(nix) => run_builder(&mut self.nix, ctx, nix)?.boxed(),
+                BuildKind::Script(script) => run_builder(&mut self.script, ctx, script)?.boxed(),
+                BuildKind::Apko(apko) => run_builder(&mut self.apko, ctx, apko)?.boxed(),
+                BuildKind::Custom(custom) => run_builder(&mut self.custom, ctx, custom)?.boxed(),
+                BuildKind::Prebuilt(prebuilt) => {
+                    run_builder(&mut self.prebuilt, ctx, prebuilt)?.boxed()
                 }
+                BuildKind::Mirror(mirror) => run_builder(&mut self.mirror, ctx, mirror)?.boxed(),
+                BuildKind::Cargo(cargo) => run_builder(&mut self.cargo, ctx, cargo)?.boxed(),
             };
+
+            let handle = set.spawn(run_entry(
+                name.clone(),
+                async move {
+                    let result = exec::scope(env, future).await;
+
+                    if let Some(sandbox) = sandbox {
+                        let _ = sandbox.remove().await;
+                    }
+
+                    result
+                }
+                .boxed(),
+                build.test,
+                test_progress,
+                platform.to_string(),
+                self.recorder.clone(),
+            ));
+            entries.insert(handle.id(), (name, failure));
         }
 
         let mut output = Output::default();
+        let mut errors = vec![];
 
-        while let Some(Ok(result)) = set.join_next().await {
+        while let Some(result) = set.join_next_with_id().await {
             pb.inc();
-            output.merge(result?);
+
+            let (id, result) = match result {
+                Ok((id, result)) => (id, result),
+                Err(e) => (e.id(), Err(BuildError::from(e))),
+            };
+
+            let error = match result {
+                Ok(result) => {
+                    output.merge(result);
+                    continue;
+                }
+                Err(error) => error,
+            };
+
+            let (name, failure) = entries.remove(&id).unwrap_or_default();
+            errors.push(EntryError {
+                name,
+                error,
+                tail: failure.take().map(|tail| format!("last output:\n{tail}")),
+            });
+
+            if self.config.fail_fast {
+                pb.fail("build failed, cancelling remaining builds");
+                set.abort_all();
+                break;
+            }
         }
 
-        Ok(output)
+        match errors.is_empty() {
+            true => Ok(output),
+            false => Err(BuildError::Multi(MultiError { errors })),
+        }
     }
 }
@@ -3,15 +3,22 @@ use std::{
     path::PathBuf,
     process::ExitStatus,
     sync::{Arc, LazyLock},
+    time::{Duration, Instant},
 };
 
 use aho_corasick::AhoCorasick;
+use chrono::Utc;
 use miette::Diagnostic;
-use prodash::{Progress, tree::Item};
+use prodash::{
+    Progress,
+    tree::Item,
+    unit::{self, Unit, display::Mode},
+};
 use serde::Deserialize;
 use serde_repr::Deserialize_repr;
+use steiger::ui;
 use tokio::{
-    io::{AsyncBufReadExt, BufReader},
+    io::{AsyncBufReadExt, AsyncRead, BufReader},
     process::Command,
     task::JoinSet,
 };
@@ -19,9 +26,9 @@ use which::which;
 
 use crate::{
     build::{Builder, Context, Output},
-    config::{Nix, PlatformStrategy},
-    exec::{self, ExitError},
-    image, progress,
+    config::{Nix, NixLogLevel, PlatformStrategy, Resources},
+    exec::{self, ExitError, Line, OutputLog, Stream},
+    image, progress, tmp,
 };
 
 #[derive(Debug, Diagnostic, thiserror::Error)]
@@ -43,6 +50,8 @@ pub enum NixError {
     Eval(String),
     #[error("failed to run nix build: {0}")]
     Build(ExitStatus),
+    #[error("failed to run nix-eval-jobs: {0}")]
+    EvalJobs(ExitStatus),
     #[error("invalid platform: {0}")]
     InvalidPlatform(String),
     #[error("failed to convert nix system to platform: {0}")]
@@ -93,6 +102,8 @@ enum Verbosity {
 #[serde(tag = "action", rename_all = "lowercase")]
 enum BuildAction {
     Result {
+        #[serde(default)]
+        id: u64,
         fields: Vec<serde_json::Value>,
         #[serde(rename = "type")]
         ty: u8,
@@ -101,8 +112,18 @@ enum BuildAction {
         level: Verbosity,
         msg: String,
     },
-    Start {},
-    Stop {},
+    Start {
+        id: u64,
+        #[serde(rename = "type")]
+        ty: u8,
+        #[serde(default)]
+        text: String,
+    },
+    Stop {
+        id: u64,
+    },
+    #[serde(other)]
+    Other,
 }
 
 impl BuildAction {
@@ -111,16 +132,19 @@ impl BuildAction {
     const UNTRUSTED_PATH: u8 = 102;
     const CORRUPTED_PATH: u8 = 103;
     const SET_PHASE: u8 = 104;
-    const _PROGRESS: u8 = 105;
-    const _SET_EXPECTED: u8 = 106;
+    const PROGRESS: u8 = 105;
+    const SET_EXPECTED: u8 = 106;
     const POST_BUILD_LOG_LINE: u8 = 107;
 
-    fn report(&self, progress: &Item) -> Option<()> {
+    fn report(&self, progress: &Item, filter: &mut LogFilter) -> Option<()> {
         match self {
             Self::Result { fields, ty, .. } => match *ty {
                 Self::BUILD_LOG_LINE | Self::POST_BUILD_LOG_LINE => {
                     let text = fields[0].as_str()?;
-                    progress.info(unescape_ansi(text));
+
+                    if filter.allow(progress) {
+                        progress.info(unescape_ansi(text));
+                    }
                 }
                 Self::FILE_LINKED => {
                     let output_path = fields[0].as_str()?;
@@ -144,7 +168,7 @@ impl BuildAction {
                 _ => {}
             },
             Self::Msg { level, msg } => {
-                if !msg.is_empty() && level <= &Verbosity::Info {
+                if !msg.is_empty() && level <= &filter.level && filter.allow(progress) {
                     progress.info(msg.to_string());
                 }
             }
@@ -152,6 +176,199 @@ impl BuildAction {
         }
         Some(())
     }
+
+    /// Build output and messages of any level, for the log file
+    fn text(&self) -> Option<&str> {
+        match self {
+            Self::Result {
+                fields,
+                ty: Self::BUILD_LOG_LINE | Self::POST_BUILD_LOG_LINE,
+                ..
+            } => fields.first()?.as_str(),
+            Self::Msg { msg, .. } => Some(msg),
+            _ => None,
+        }
+    }
+}
+
+fn bytes() -> Unit {
+    unit::dynamic_and_mode(unit::Bytes, Mode::with_throughput().and_percentage())
+}
+
+/// Progress bars of running nix activities (downloads, copies and builds) by id
+#[derive(Default)]
+struct Activities {
+    items: HashMap<u64, Item>,
+}
+
+impl Activities {
+    const COPY_PATH: u8 = 100;
+    const FILE_TRANSFER: u8 = 101;
+    const COPY_PATHS: u8 = 103;
+    const BUILDS: u8 = 104;
+    const BUILD: u8 = 105;
+    const SUBSTITUTE: u8 = 108;
+
+    fn update(&mut self, action: &BuildAction, progress: &mut Item) -> Option<()> {
+        match action {
+            BuildAction::Start { id, ty, text } => {
+                let (name, unit) = match *ty {
+                    Self::COPY_PATH => ("copying path", Some(bytes())),
+                    Self::FILE_TRANSFER => ("downloading", Some(bytes())),
+                    Self::COPY_PATHS => ("copying paths", Some(unit::label("paths"))),
+                    Self::BUILDS => ("building", Some(unit::label("derivations"))),
+                    Self::BUILD => ("building derivation", None),
+                    Self::SUBSTITUTE => ("substituting", None),
+                    _ => return Some(()),
+                };
+
+                let item = progress::child(progress, if text.is_empty() { name } else { text });
+                item.init(None, unit);
+                self.items.insert(*id, item);
+            }
+            // Fields are `done`, `expected`, `running` and `failed`
+            BuildAction::Result {
+                id,
+                fields,
+                ty: BuildAction::PROGRESS,
+            } => {
+                let item = self.items.get(id)?;
+                let expected = fields.get(1)?.as_u64()?;
+
+                if expected > 0 {
+                    item.set_max(Some(expected as usize));
+                }
+
+                item.set(fields.first()?.as_u64()? as usize);
+            }
+            // Fields are the activity type and the expected amount
+            BuildAction::Result {
+                id,
+                fields,
+                ty: BuildAction::SET_EXPECTED,
+            } => {
+                let item = self.items.get(id)?;
+                item.set_max(Some(fields.get(1)?.as_u64()? as usize));
+            }
+            // Dropping the item removes the progress bar
+            BuildAction::Stop { id } => {
+                self.items.remove(id);
+            }
+            _ => {}
+        }
+
+        Some(())
+    }
+}
+
+/// Limits the nix output shown, prodash only keeps the last 200 messages so a chatty build
+/// would push out the output of everything else
+struct LogFilter {
+    level: Verbosity,
+    /// Lines shown per second, `0` shows everything
+    rate: u32,
+    window: Instant,
+    shown: u32,
+    skipped: u64,
+}
+
+impl LogFilter {
+    fn new(level: NixLogLevel, rate: u32) -> Self {
+        let level = match level {
+            NixLogLevel::Error => Verbosity::Error,
+            NixLogLevel::Warn => Verbosity::Warn,
+            NixLogLevel::Notice => Verbosity::Notice,
+            NixLogLevel::Info => Verbosity::Info,
+            NixLogLevel::Talkative => Verbosity::Talkative,
+            NixLogLevel::Chatty => Verbosity::Chatty,
+            NixLogLevel::Debug => Verbosity::Debug,
+            NixLogLevel::Vomit => Verbosity::Vomit,
+        };
+
+        Self {
+            level,
+            rate,
+            window: Instant::now(),
+            shown: 0,
+            skipped: 0,
+        }
+    }
+
+    /// Nix only emits messages up to `info` by default, each `--verbose` adds a level
+    fn verbose_args(&self) -> impl Iterator<Item = &'static str> {
+        let count = (self.level as usize).saturating_sub(Verbosity::Info as usize);
+        std::iter::repeat_n("--verbose", count)
+    }
+
+    /// Whether another line fits in the current one second window
+    fn allow(&mut self, progress: &Item) -> bool {
+        if self.rate == 0 {
+            return true;
+        }
+
+        if self.window.elapsed() >= Duration::from_secs(1) {
+            self.flush(progress);
+            self.window = Instant::now();
+            self.shown = 0;
+        }
+
+        if self.shown < self.rate {
+            self.shown += 1;
+            true
+        } else {
+            self.skipped += 1;
+            false
+        }
+    }
+
+    /// Reports the lines skipped since the last report
+    fn flush(&mut self, progress: &Item) {
+        if self.skipped > 0 {
+            progress.info(format!("… skipped {} line(s) of output", self.skipped));
+            self.skipped = 0;
+        }
+    }
+}
+
+/// Reports the `@nix` messages read from `reader` as they come in, other lines, including
+/// messages that fail to parse, are reported as is. Every message is added to `log`, which
+/// isn't filtered
+async fn forward_log<R>(
+    reader: R,
+    progress: &mut Item,
+    mut filter: LogFilter,
+    log: &mut OutputLog,
+) -> Result<(), NixError>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut activities = Activities::default();
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        let action = match line.strip_prefix("@nix ").map(serde_json::from_str) {
+            Some(Ok(action)) => action,
+            _ => BuildAction::Msg {
+                level: Verbosity::Info,
+                msg: line,
+            },
+        };
+
+        action.report(progress, &mut filter);
+        activities.update(&action, progress);
+
+        if let Some(text) = action.text() {
+            log.push(Line {
+                time: Utc::now(),
+                stream: Stream::Stderr,
+                text: text.to_string(),
+            });
+        }
+    }
+
+    filter.flush(progress);
+
+    Ok(())
 }
 
 #[derive(Debug, Deserialize)]
@@ -169,6 +386,7 @@ impl EvalResult {
         mut self,
         nix_binary: Arc<PathBuf>,
         extra_args: Arc<[String]>,
+        filter: LogFilter,
         mut progress: Item,
     ) -> Result<OutPaths, NixError> {
         if let Some(error) = self.error.take() {
@@ -187,6 +405,7 @@ impl EvalResult {
             let mut root_cmd = Command::new(nix_binary.as_ref());
             let cmd = root_cmd
                 .arg("build")
+                .args(filter.verbose_args())
                 .args(extra_args.iter())
                 .arg("--no-link")
                 .arg("--log-format")
@@ -195,20 +414,14 @@ impl EvalResult {
 
             let mut child = exec::spawn(cmd).await?;
 
-            let progress = progress.add_child(&self.attr);
-            let reader = BufReader::new(child.stderr);
-            let mut lines = reader.lines();
-
-            while let Some(line) = lines.next_line().await? {
-                let Some(json) = line.strip_prefix("@nix ") else {
-                    continue;
-                };
-
-                let action: BuildAction = serde_json::from_str(json)?;
-                action.report(&progress);
-            }
+            let mut progress = progress::child(&mut progress, &self.attr);
+            let mut log = OutputLog::new();
+            // The log is written before a read error is returned, so what was read isn't lost
+            let forwarded = forward_log(child.stderr, &mut progress, filter, &mut log).await;
 
             let status = child.inner.wait().await?;
+            log.finish(cmd, status);
+            forwarded?;
             progress.inc();
 
             if status.success() {
@@ -225,6 +438,21 @@ impl EvalResult {
     }
 }
 
+/// Arguments of `nix build` for the resource hints, nix can't limit memory
+fn resource_args(resources: &Resources) -> Vec<String> {
+    let mut args = vec![];
+
+    if let Some(cpus) = resources.cpus {
+        args.extend(["--cores".to_string(), cpus.to_string()]);
+    }
+
+    if let Some(jobs) = resources.jobs {
+        args.extend(["--max-jobs".to_string(), jobs.to_string()]);
+    }
+
+    args
+}
+
 #[derive(Clone)]
 pub struct NixBuilder {
     nix_binary: Arc<PathBuf>,
@@ -242,7 +470,16 @@ impl NixBuilder {
         system: &str,
     ) -> Result<(), NixError> {
         let flake_path = input.flake.to_string_lossy();
-        let extra_args = Arc::<[String]>::from(input.extra_args.clone());
+        let extra_args = resource_args(&input.resources)
+            .into_iter()
+            .chain(input.extra_args.iter().cloned())
+            .collect::<Arc<[String]>>();
+
+        if input.resources.memory.is_some() {
+            progress.info("⚠ nix can't limit memory, ignoring resources.memory".to_string());
+            ui::record_warning("nix can't limit memory, ignoring resources.memory");
+        }
+
         let attr_path = match input.platform_strategy {
             PlatformStrategy::Native => IMAGE_OUTPUTS_PATH.to_string(),
             PlatformStrategy::CrossSystem => {
@@ -259,20 +496,29 @@ impl NixBuilder {
             return Err(NixError::MissingPackage(system.to_string()));
         };
 
+        let filter = LogFilter::new(input.log_level, input.log_rate);
         let mut root_cmd = Command::new(&self.eval_binary);
         let cmd = root_cmd
-            .arg("--verbose")
+            .args(filter.verbose_args())
             .arg("--log-format")
             .arg("internal-json")
             .arg("--gc-roots-dir")
-            .arg(std::env::temp_dir())
+            .arg(tmp::dir())
             .arg("--flake")
             .arg(format!("{flake_path}#{attr_path}.{system}"));
 
         progress.info(format!("using platform: {system}"));
 
-        let child = exec::spawn(cmd).await?;
-        progress::proxy_stdio(child.stderr, progress.add_child("nix").into());
+        let mut child = exec::spawn(cmd).await?;
+        let mut nix = progress::child(&mut progress, "nix");
+        let stderr = child.stderr;
+        // Drained while reading stdout, the child blocks once a pipe buffer is full
+        let forwarder = tokio::spawn(async move {
+            let mut log = OutputLog::new();
+            forward_log(stderr, &mut nix, filter, &mut log)
+                .await
+                .map(|()| log)
+        });
 
         let reader = BufReader::new(child.stdout);
         let mut lines = reader.lines();
@@ -285,11 +531,21 @@ impl NixBuilder {
                 progress.init(Some(set.len() + 1), None);
                 let binary = Arc::clone(&self.nix_binary);
                 let extra_args = Arc::clone(&extra_args);
-                let progress = progress.add_child(format!("{attr_path} › nix"));
-                set.spawn(drv.build(binary, extra_args, progress));
+                let filter = LogFilter::new(input.log_level, input.log_rate);
+                let progress = progress::child(&mut progress, format!("{attr_path} › nix"));
+                set.spawn(exec::inherit(
+                    drv.build(binary, extra_args, filter, progress),
+                ));
             }
         }
 
+        let status = child.inner.wait().await?;
+        forwarder.await??.finish(cmd, status);
+
+        if !status.success() {
+            return Err(NixError::EvalJobs(status));
+        }
+
         Ok(())
     }
 
@@ -346,6 +602,7 @@ impl Builder for NixBuilder {
             service_name,
             platform,
             mut progress,
+            ..
         }: Context,
         input: Self::Input,
     ) -> Result<Output, Self::Error> {
@@ -355,7 +612,7 @@ impl Builder for NixBuilder {
         let mut set = JoinSet::default();
 
         self.eval(
-            progress.add_child("eval"),
+            progress::child(&mut progress, "eval"),
             &mut set,
             &input,
             &try_system(&platform)?,
@@ -386,9 +643,74 @@ impl Builder for NixBuilder {
                 .map(|(artifact, _)| artifact.clone())
                 .ok_or(NixError::MissingArtifact(target))?;
 
-            artifacts.insert(artifact, image::load_from_path(files).await?);
+            artifacts.insert(
+                artifact,
+                image::load_from_path(exec::resolve(files), &mut progress).await?,
+            );
         }
 
-        Ok(Output { artifacts })
+        Ok(Output {
+            artifacts,
+            ..Output::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::process::ExitStatusExt;
+
+    use super::*;
+
+    #[test]
+    fn resource_args_of_nix() {
+        assert!(resource_args(&Resources::default()).is_empty());
+        assert_eq!(
+            resource_args(&Resources {
+                cpus: Some(4),
+                memory: Some(2048),
+                jobs: Some(2),
+            }),
+            ["--cores", "4", "--max-jobs", "2"]
+        );
+    }
+
+    #[tokio::test]
+    async fn forwards_unparseable_lines() {
+        let root = progress::tree();
+        let mut progress = progress::task(&root, "nix");
+        let stderr = concat!(
+            "@nix {\"action\":\"msg\",\"level\":3,\"msg\":\"evaluating\"}\n",
+            "@nix {\"action\":\"msg\",\n",
+            "warning: Git tree is dirty\n",
+            "@nix {\"action\":\"stop\",\"id\":1}\n",
+            "@nix {\"action\":\"msg\",\"level\":0,\"msg\":\"error: oops\"}\n",
+        );
+        let mut log = OutputLog::new();
+
+        forward_log(
+            stderr.as_bytes(),
+            &mut progress,
+            LogFilter::new(NixLogLevel::Info, 0),
+            &mut log,
+        )
+        .await
+        .unwrap();
+
+        let output = log.finish(&Command::new("nix-eval-jobs"), ExitStatus::from_raw(0));
+        let lines = output
+            .lines
+            .iter()
+            .map(|line| line.text.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            [
+                "evaluating",
+                "@nix {\"action\":\"msg\",",
+                "warning: Git tree is dirty",
+                "error: oops"
+            ]
+        );
     }
 }
@@ -0,0 +1,62 @@
+use docker_credential::CredentialRetrievalError;
+use miette::Diagnostic;
+use oci_client::Reference;
+
+use crate::{
+    build::{Builder, Context, Output},
+    config::Prebuilt,
+    registry::{self, PullError},
+};
+
+#[derive(Debug, Diagnostic, thiserror::Error)]
+pub enum PrebuiltError {
+    #[error("failed to parse image reference")]
+    Parse(#[from] oci_client::ParseError),
+    #[error("failed to retrieve registry credentials")]
+    Credential(#[from] CredentialRetrievalError),
+    #[error("failed to pull image")]
+    #[diagnostic(transparent)]
+    Pull(#[from] PullError),
+}
+
+#[derive(Clone)]
+pub struct PrebuiltBuilder;
+
+impl Builder for PrebuiltBuilder {
+    type Error = PrebuiltError;
+    type Input = Prebuilt;
+
+    fn try_init() -> Result<Self, Self::Error>
+    where
+        Self: Sized,
+    {
+        Ok(Self)
+    }
+
+    async fn build(
+        self,
+        Context {
+            service_name,
+            platform,
+            mut progress,
+            registries,
+        }: Context,
+        input: Self::Input,
+    ) -> Result<Output, Self::Error> {
+        progress.set_name(&service_name);
+        progress.info(format!("pulling {}", input.image));
+
+        let image_ref = Reference::try_from(input.image.as_str())?;
+        let auth = registry::load_credentials(image_ref.resolve_registry())?;
+
+        // The image is pushed as is, so it keeps its digest
+        let image = registries.client(auth).pull(&image_ref, &platform).await?;
+
+        progress.done(format!("pulled {}", input.image));
+
+        Ok(Output {
+            artifacts: vec![(service_name, vec![image])].into_iter().collect(),
+            ..Output::default()
+        })
+    }
+}
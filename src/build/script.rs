@@ -0,0 +1,115 @@
+use std::process::ExitStatus;
+
+use docker_credential::CredentialRetrievalError;
+use miette::Diagnostic;
+use oci_client::Reference;
+
+use crate::{
+    build::{Builder, Context, Output},
+    config::Script,
+    exec,
+    image::{self, Image, ImageError},
+    progress,
+    registry::{self, PullError},
+};
+
+#[derive(Debug, Diagnostic, thiserror::Error)]
+pub enum ScriptError {
+    #[error("IO error")]
+    IO(#[from] std::io::Error),
+    #[error("failed to parse base image reference")]
+    Parse(#[from] oci_client::ParseError),
+    #[error("failed to retrieve registry credentials")]
+    Credential(#[from] CredentialRetrievalError),
+    #[error("failed to pull base image")]
+    #[diagnostic(transparent)]
+    Pull(#[from] PullError),
+    #[error("failed to assemble image")]
+    #[diagnostic(transparent)]
+    Image(#[from] ImageError),
+    #[error("failed to run script: {0}")]
+    Build(ExitStatus),
+    #[error("packing task panicked or was cancelled")]
+    Join(#[from] tokio::task::JoinError),
+}
+
+#[derive(Clone)]
+pub struct ScriptBuilder;
+
+impl Builder for ScriptBuilder {
+    type Error = ScriptError;
+    type Input = Script;
+
+    fn try_init() -> Result<Self, Self::Error>
+    where
+        Self: Sized,
+    {
+        Ok(Self)
+    }
+
+    async fn build(
+        self,
+        Context {
+            service_name,
+            platform,
+            mut progress,
+            registries,
+        }: Context,
+        input: Self::Input,
+    ) -> Result<Output, Self::Error> {
+        progress.set_name(&service_name);
+        progress.info("starting builder");
+
+        let (os, arch) = platform.split_once('/').unwrap_or(("linux", &platform));
+        let output = exec::run_with_progress(
+            exec::shell(&input.command)
+                .env("STEIGER_PLATFORM", &platform)
+                .env("TARGETOS", os)
+                .env("TARGETARCH", arch)
+                .envs(&input.env),
+            progress::child(&mut progress, format!("{service_name} › script")),
+        )
+        .await?;
+
+        if !output.success() {
+            progress.fail(format!("build failed with exit code: {}", output.code()));
+
+            return Err(ScriptError::Build(output.status));
+        }
+
+        let base = match input.base_image {
+            Some(ref base_image) => {
+                progress.info(format!("pulling base image: {base_image}"));
+
+                let image_ref = Reference::try_from(base_image.as_str())?;
+                let auth = registry::load_credentials(image_ref.resolve_registry())?;
+
+                registries.client(auth).pull(&image_ref, &platform).await?
+            }
+            None => Image::scratch(&platform)?,
+        };
+
+        progress.info(format!(
+            "adding {} at {}",
+            input.output.display(),
+            input.destination.display()
+        ));
+
+        let (output, destination) = (exec::resolve(&input.output), input.destination);
+        let layer = tokio::task::spawn_blocking(move || image::layer::pack(&output, &destination))
+            .await??;
+        let base_digest = base.digest().to_string();
+        let mut image = base.append_layer(layer, &input.command, input.entrypoint)?;
+
+        if let Some(ref base_image) = input.base_image {
+            image = image.with_base(base_image, &base_digest);
+        }
+
+        progress.done("build finished".to_string());
+
+        Ok(Output {
+            artifacts: vec![(service_name, vec![image])].into_iter().collect(),
+            ..Output::default()
+        })
+    }
+}
@@ -0,0 +1,167 @@
+use miette::Diagnostic;
+use prodash::tree::Item;
+use tokio::process::Command;
+
+use crate::{
+    config::Test,
+    exec::{self, ExitError},
+    image::{self, Image, ImageError, archive},
+    tmp,
+};
+
+#[derive(Debug, Diagnostic, thiserror::Error)]
+pub enum TestError {
+    #[error("IO error")]
+    IO(#[from] std::io::Error),
+    #[error("failed to create tempdir")]
+    TempDir(#[from] async_tempfile::Error),
+    #[error("failed to create image archive")]
+    #[diagnostic(transparent)]
+    Image(#[from] ImageError),
+    #[error("failed to load image into docker")]
+    #[diagnostic(transparent)]
+    Load(#[from] ExitError),
+    #[error("no image found for platform '{0}'")]
+    NoImage(String),
+    #[error("test '{name}' for '{artifact}' failed with exit code {code}")]
+    #[diagnostic(help("{output}"))]
+    Failed {
+        artifact: String,
+        name: String,
+        code: i32,
+        output: String,
+    },
+}
+
+fn describe(test: &Test) -> String {
+    match test {
+        Test::Command(test) => test.command.clone(),
+        Test::Image(test) => {
+            let mut parts = vec!["docker run".to_string()];
+            parts.extend(test.entrypoint.iter().map(|e| format!("--entrypoint {e}")));
+            parts.extend(test.args.iter().cloned());
+            parts.join(" ")
+        }
+    }
+}
+
+/// Container CLI for image tests, podman is compatible for the commands used here
+fn runtime() -> &'static str {
+    if which::which("docker").is_err() && which::which("podman").is_ok() {
+        "podman"
+    } else {
+        "docker"
+    }
+}
+
+/// Loads the image into the docker daemon using a temporary tag
+async fn load(artifact: &str, image: &Image) -> Result<String, TestError> {
+    let hash = image.digest().split_once(':').map_or("", |(_, hash)| hash);
+    let tag = format!(
+        "steiger-test/{}:{}",
+        artifact.to_lowercase(),
+        &hash[..hash.len().min(12)]
+    );
+    let dir = tmp::new_dir().await?;
+    let path = dir.join("image.tar");
+
+    tokio::fs::write(&path, archive::docker_archive(image, &tag)?).await?;
+    exec::run_with_output(
+        Command::new(runtime())
+            .arg("load")
+            .arg("--quiet")
+            .arg("-i")
+            .arg(&path),
+    )
+    .await?;
+
+    Ok(tag)
+}
+
+async fn run_tests(
+    progress: &mut Item,
+    tests: &[Test],
+    artifact: &str,
+    image: &Image,
+    loaded: &mut Option<String>,
+) -> Result<(), TestError> {
+    for test in tests {
+        let name = describe(test);
+        progress.info(format!("running {name}"));
+
+        let mut cmd = match test {
+            Test::Command(test) => {
+                let mut cmd = exec::shell(&test.command);
+                cmd.env("STEIGER_ARTIFACT", artifact).envs(&test.env);
+                cmd
+            }
+            Test::Image(test) => {
+                let tag = match loaded {
+                    Some(tag) => tag,
+                    None => loaded.insert(load(artifact, image).await?),
+                };
+
+                let mut cmd = Command::new(runtime());
+                cmd.arg("run").arg("--rm");
+
+                if let Some(ref entrypoint) = test.entrypoint {
+                    cmd.arg("--entrypoint").arg(entrypoint);
+                }
+
+                cmd.arg(tag.as_str()).args(&test.args);
+                cmd
+            }
+        };
+
+        let output = exec::output(&mut cmd).await?;
+
+        if !output.status.success() {
+            progress.fail(format!("{name} failed"));
+
+            return Err(TestError::Failed {
+                artifact: artifact.to_string(),
+                name,
+                code: output.status.code().unwrap_or_default(),
+                output: [output.stdout, output.stderr]
+                    .iter()
+                    .map(|out| String::from_utf8_lossy(out).trim().to_string())
+                    .filter(|out| !out.is_empty())
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            });
+        }
+
+        progress.inc();
+    }
+
+    Ok(())
+}
+
+/// Runs the tests against the artifact's image for the given platform
+pub async fn run(
+    mut progress: Item,
+    tests: &[Test],
+    artifact: &str,
+    images: &[Image],
+    platform: &str,
+) -> Result<(), TestError> {
+    let image = image::position_for_platform(images, platform)
+        .map(|n| &images[n])
+        .ok_or_else(|| TestError::NoImage(platform.to_string()))?;
+
+    progress.init(Some(tests.len()), None);
+
+    let mut loaded = None;
+    let result = run_tests(&mut progress, tests, artifact, image, &mut loaded).await;
+
+    if let Some(tag) = loaded {
+        // Best effort cleanup, a dangling test image is harmless
+        let _ = exec::run_with_output(Command::new(runtime()).arg("rmi").arg(&tag)).await;
+    }
+
+    if result.is_ok() {
+        progress.done(format!("{} tests passed", tests.len()));
+    }
+
+    result
+}
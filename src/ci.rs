@@ -0,0 +1,170 @@
+use std::{
+    env::var,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use tokio::{fs, io::AsyncWriteExt};
+
+/// CI system steiger runs in, detected from the variables it sets
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Provider {
+    GitHub,
+    GitLab,
+    Other,
+}
+
+impl Provider {
+    fn detect() -> Self {
+        Self::detect_in(|name| var(name).ok())
+    }
+
+    /// Like [`Provider::detect`], with the lookup of variables passed in
+    fn detect_in(var: impl Fn(&str) -> Option<String>) -> Self {
+        if var("GITHUB_ACTIONS").as_deref() == Some("true") {
+            Provider::GitHub
+        } else if var("GITLAB_CI").as_deref() == Some("true") {
+            Provider::GitLab
+        } else {
+            Provider::Other
+        }
+    }
+}
+
+/// Link to the run steiger is part of, if the CI system exposes one
+pub fn run_url() -> Option<String> {
+    find_run_url(|name| var(name).ok())
+}
+
+/// Like [`run_url`], with the lookup of variables passed in
+fn find_run_url(var: impl Fn(&str) -> Option<String>) -> Option<String> {
+    match Provider::detect_in(&var) {
+        Provider::GitHub => Some(format!(
+            "{}/{}/actions/runs/{}",
+            var("GITHUB_SERVER_URL")?,
+            var("GITHUB_REPOSITORY")?,
+            var("GITHUB_RUN_ID")?
+        )),
+        Provider::GitLab => var("CI_JOB_URL"),
+        Provider::Other => var("BUILDKITE_BUILD_URL").or_else(|| var("CIRCLE_BUILD_URL")),
+    }
+}
+
+fn timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+/// Collapsible section of the job log, closed when dropped. Markers go to stderr, the same
+/// stream as the progress output they wrap.
+pub struct Group {
+    name: &'static str,
+    provider: Provider,
+}
+
+pub fn group(name: &'static str, title: &str) -> Group {
+    let provider = Provider::detect();
+
+    match provider {
+        Provider::GitHub => eprintln!("::group::{title}"),
+        Provider::GitLab => eprintln!(
+            "\x1b[0Ksection_start:{}:{name}\r\x1b[0K{title}",
+            timestamp()
+        ),
+        Provider::Other => eprintln!("==> {title}"),
+    }
+
+    Group { name, provider }
+}
+
+impl Drop for Group {
+    fn drop(&mut self) {
+        match self.provider {
+            Provider::GitHub => eprintln!("::endgroup::"),
+            Provider::GitLab => {
+                eprintln!("\x1b[0Ksection_end:{}:{}\r\x1b[0K", timestamp(), self.name)
+            }
+            Provider::Other => {}
+        }
+    }
+}
+
+/// Exposes the outcome of the run to later steps through `GITHUB_OUTPUT`, next to the image
+/// outputs written by the build
+pub async fn write_outputs(output_file: &Path, deployed: bool) -> Result<(), std::io::Error> {
+    let Ok(path) = var("GITHUB_OUTPUT") else {
+        return Ok(());
+    };
+
+    if path.is_empty() {
+        return Ok(());
+    }
+
+    let output_file = std::path::absolute(output_file)?;
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    file.write_all(
+        format!(
+            "output-file={}\ndeployed={deployed}\n",
+            output_file.display()
+        )
+        .as_bytes(),
+    )
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn run_url_of(vars: &[(&str, &str)]) -> Option<String> {
+        let vars = HashMap::<_, _>::from_iter(vars.iter().copied());
+        find_run_url(|name| vars.get(name).map(|value| value.to_string()))
+    }
+
+    #[test]
+    fn run_urls() {
+        assert_eq!(
+            run_url_of(&[
+                ("GITHUB_ACTIONS", "true"),
+                ("GITHUB_SERVER_URL", "https://github.com"),
+                ("GITHUB_REPOSITORY", "org/repo"),
+                ("GITHUB_RUN_ID", "42"),
+            ])
+            .as_deref(),
+            Some("https://github.com/org/repo/actions/runs/42")
+        );
+        assert_eq!(
+            run_url_of(&[
+                ("GITLAB_CI", "true"),
+                ("CI_JOB_URL", "https://gitlab.com/org/repo/-/jobs/7"),
+                ("CIRCLE_BUILD_URL", "https://circleci.com/gh/org/repo/1"),
+            ])
+            .as_deref(),
+            Some("https://gitlab.com/org/repo/-/jobs/7")
+        );
+        assert_eq!(
+            run_url_of(&[("CIRCLE_BUILD_URL", "https://circleci.com/gh/org/repo/1")]).as_deref(),
+            Some("https://circleci.com/gh/org/repo/1")
+        );
+
+        // Without the run id there's nothing to link to
+        assert_eq!(
+            run_url_of(&[
+                ("GITHUB_ACTIONS", "true"),
+                ("GITHUB_REPOSITORY", "org/repo")
+            ]),
+            None
+        );
+        assert_eq!(run_url_of(&[]), None);
+    }
+}
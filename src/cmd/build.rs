@@ -1,36 +1,87 @@
-use std::{collections::HashMap, mem, path::Path};
+use std::{
+    collections::{BTreeMap, HashMap},
+    mem,
+    path::{Path, PathBuf},
+};
 
 use docker_credential::CredentialRetrievalError;
+use futures::future;
+use heck::{ToKebabCase, ToShoutySnakeCase};
 use miette::Diagnostic;
 use oci_client::Reference;
-use tokio::{fs, task::JoinSet, time::Instant};
-use steiger::git;
+use steiger::{git, ui};
+use tokio::{
+    fs,
+    io::AsyncWriteExt,
+    task::JoinSet,
+    time::{Duration, Instant},
+};
+use uuid::Uuid;
 
 use crate::{
     build::{
         BuildError, MetaBuild,
         events::{Client as EventsClient, CreateBuildRequest, Event, Tags},
+        test::TestError,
+    },
+    cmd::{
+        deploy::{self, InputError},
+        inspect::BuildMetadata,
+        outdated,
+    },
+    config::{
+        self, BuildKind, Checksums, Config, OutputCleanup, PlatformFallback, Release, TagStrategy,
     },
-    config::Config,
-    image::Image,
+    exec::{self, CmdBuilder, ExitError},
+    exit,
+    image::{self, Image, ImageError, PlatformMatch, archive},
+    lock::{LockError, Lockfile},
     progress,
     registry::{self, PushError, Registry},
+    report::{Recorder, Report, Stage},
+    store::{ClusterStore, LocalCluster, RegistryStore, Store, StoreError, Target},
+    tmp,
 };
 
 pub mod output {
+    use std::collections::BTreeMap;
+
     use serde::{Deserialize, Serialize};
 
-    #[derive(Serialize, Deserialize)]
+    #[derive(Clone, Serialize, Deserialize)]
     #[serde(rename_all = "camelCase")]
     pub struct Build {
         pub image_name: String,
         pub tag: String,
+        /// References of the image in the configured mirrors
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        pub mirrors: Vec<String>,
+        /// Image the artifact was built on, as `name@digest`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub base_image: Option<String>,
     }
 
-    #[derive(Serialize, Deserialize)]
+    #[derive(Clone, Serialize, Deserialize, Default)]
     #[serde(rename_all = "camelCase")]
     pub struct Output {
         pub builds: Vec<Build>,
+        /// Artifacts that failed to test or push, only present in partial output files
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        pub failed: Vec<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub git_commit: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub build_id: Option<String>,
+        /// Release channel the images are meant for
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub channel: Option<String>,
+        /// Channel the images were promoted from by `steiger promote`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub promoted_from: Option<String>,
+        /// Path or URL of the archive of artifacts published to a directory or bucket store,
+        /// these aren't images so deploys don't use them
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        pub stored: BTreeMap<String, String>,
     }
 }
 
@@ -42,11 +93,23 @@ pub enum WriteError {
     Serde(#[from] serde_json::Error),
 }
 
+#[derive(Debug, Diagnostic, thiserror::Error)]
+pub enum ChecksumError {
+    #[error("failed to write checksums")]
+    IO(#[from] std::io::Error),
+    #[error("failed to sign checksums")]
+    #[diagnostic(help("make sure `cosign` is installed and the key is accessible"))]
+    Sign(#[source] ExitError),
+}
+
 #[derive(Debug, Diagnostic, thiserror::Error)]
 pub enum Error {
     #[error(transparent)]
     #[diagnostic(transparent)]
     Git(#[from] git::GitError),
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    Config(#[from] config::Error),
     #[error("failed to build")]
     #[diagnostic(transparent)]
     Build(#[from] BuildError),
@@ -56,8 +119,14 @@ pub enum Error {
     #[error("failed to push")]
     #[diagnostic(transparent)]
     Push(#[from] PushError),
-    #[error("failed to find image for platform")]
-    NoImage(String),
+    #[error("failed to publish")]
+    #[diagnostic(transparent)]
+    Store(#[from] StoreError),
+    #[error("no image for platform '{platform}' found for artifact '{artifact}'")]
+    #[diagnostic(help(
+        "build the artifact for this platform, or list a platform it may run emulated on in `emulatedPlatforms`"
+    ))]
+    NoImage { artifact: String, platform: String },
     #[error("failed to write output")]
     #[diagnostic(transparent)]
     WriteOutput(#[from] WriteError),
@@ -65,80 +134,857 @@ pub enum Error {
     Credential(#[from] CredentialRetrievalError),
     #[error("failed to parse reference")]
     Parse(#[from] oci_client::ParseError),
+    #[error("push task panicked or was cancelled")]
+    Join(#[from] tokio::task::JoinError),
+    #[error(
+        "not enough free space in {}: {} available, {} required",
+        dir.display(),
+        fmt_mib(*available),
+        fmt_mib(*required)
+    )]
+    #[diagnostic(help(
+        "point `tmpDir` or STEIGER_TMPDIR to a larger volume, or lower `minFreeSpace` (MiB per artifact)"
+    ))]
+    DiskSpace {
+        dir: PathBuf,
+        available: u64,
+        required: u64,
+    },
+    #[error("failed to write OCI layout of '{artifact}'")]
+    Layout {
+        artifact: String,
+        #[source]
+        source: ImageError,
+    },
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    Checksums(#[from] ChecksumError),
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    Lock(#[from] LockError),
+    #[error("failed to publish output to {reference}")]
+    PublishOutput {
+        reference: String,
+        #[source]
+        source: PushError,
+    },
+    #[error("failed to read previous output")]
+    #[diagnostic(transparent)]
+    PreviousOutput(#[from] InputError),
+    #[error("can't load images of more than one platform ('{0}') into the local cluster")]
+    #[diagnostic(help(
+        "loaded images are referred to by tag, so every platform would overwrite the previous one; pass a single platform with --platform"
+    ))]
+    LocalPlatforms(String),
+    #[error("failed to list tags for `tagStrategy`")]
+    TagStrategy(#[source] oci_client::errors::OciDistributionError),
+    #[error("tests failed, affected artifacts were not pushed")]
+    Test {
+        #[related]
+        failures: Vec<TestError>,
+        /// Whether the artifacts that passed were published
+        published: bool,
+    },
+}
+
+const MIB: u64 = 1024 * 1024;
+
+fn fmt_mib(bytes: u64) -> String {
+    format!("{} MiB", bytes / MIB)
+}
+
+/// Fails when the temp dir can't hold the OCI layouts of all artifacts, estimated at
+/// `minFreeSpace` per artifact
+async fn check_free_space(config: &Config) -> Result<(), Error> {
+    let artifacts = config
+        .build
+        .iter()
+        .map(|(name, build)| build.kind.artifacts(name).len() as u64)
+        .sum::<u64>();
+    let required = artifacts * config.min_free_space * MIB;
+
+    if required == 0 {
+        return Ok(());
+    }
+
+    match tmp::free_space().await {
+        Ok(available) if available < required => Err(Error::DiskSpace {
+            dir: tmp::dir(),
+            available,
+            required,
+        }),
+        Ok(_) => Ok(()),
+        Err(e) => {
+            ui::warn(format!("skipping disk space check: {e}"));
+            Ok(())
+        }
+    }
+}
+
+/// Build arg set to the updated base image digest, referencing it in a Dockerfile busts the
+/// cache of the steps that follow
+const BASE_DIGEST_ARG: &str = "STEIGER_BASE_DIGEST";
+
+/// Forces the entries that produce artifacts with an updated base image to pick it up
+fn rebuild_stale(config: &mut Config, stale: &[(String, String, String)]) {
+    for (name, build) in config.build.iter_mut() {
+        let artifacts = build.kind.artifacts(name);
+        let Some((_, base, digest)) = stale.iter().find(|(a, ..)| artifacts.contains(a)) else {
+            continue;
+        };
+
+        match build.kind {
+            BuildKind::Docker(ref mut docker) => {
+                ui::info(format!(
+                    "base image {base} of {name} was updated, rebuilding"
+                ));
+                docker.pull = true;
+                docker
+                    .build_args
+                    .insert(BASE_DIGEST_ARG.to_string(), digest.clone());
+            }
+            // These resolve the base image on every build
+            BuildKind::Ko(_) | BuildKind::Script(_) | BuildKind::Cargo(_) => {
+                ui::info(format!(
+                    "base image {base} of {name} was updated, rebuilding"
+                ));
+            }
+            // Neither records a base image
+            BuildKind::Apko(_) | BuildKind::Prebuilt(_) | BuildKind::Mirror(_) => {}
+            BuildKind::Bazel(_) | BuildKind::Nix(_) | BuildKind::Custom(_) => ui::warn(format!(
+                "base image {base} of {name} was updated, update the pinned digest to pick it up"
+            )),
+        }
+    }
 }
 
-fn find_image(mut images: Vec<Image>, platform: &str) -> Result<Image, Error> {
-    if let Some(n) = images.iter().position(
-        |i| matches!(i.platform, Some(ref p) if format!("{}/{}", p.os, p.architecture) == platform),
-    ) {
-        return Ok(images.remove(n));
+/// Writes a `sha256sum` compatible manifest with the digest of every produced image, named
+/// `<artifact>/<os>/<arch>`, and signs it with `cosign sign-blob` when a key is configured
+async fn write_checksums(
+    checksums: &Checksums,
+    artifacts: &HashMap<String, Vec<Image>>,
+) -> Result<(), ChecksumError> {
+    let mut lines = BTreeMap::new();
+
+    for (artifact, images) in artifacts {
+        for image in images {
+            let name = match image.platform {
+                Some(ref p) => format!("{artifact}/{}/{}", p.os, p.architecture),
+                None => artifact.clone(),
+            };
+            let hash = image.digest().trim_start_matches("sha256:");
+            lines.insert(name, hash.to_string());
+        }
+    }
+
+    let manifest = lines
+        .iter()
+        .map(|(name, hash)| format!("{hash}  {name}\n"))
+        .collect::<String>();
+
+    if let Some(parent) = checksums.path.parent() {
+        fs::create_dir_all(parent).await?;
     }
 
-    images
-        .into_iter()
-        .find(|i| i.platform.is_none())
-        .ok_or(Error::NoImage(platform.to_string()))
+    fs::write(&checksums.path, manifest).await?;
+
+    let Some(ref key) = checksums.key else {
+        return Ok(());
+    };
+
+    let mut signature = checksums.path.clone().into_os_string();
+    signature.push(".sig");
+
+    let mut cmd = CmdBuilder::new("cosign");
+    cmd.arg("sign-blob").arg("--yes");
+    cmd.flag("--key", key);
+    cmd.arg("--output-signature")
+        .arg(signature)
+        .arg(&checksums.path);
+
+    exec::run_with_output(&mut cmd)
+        .await
+        .map_err(ChecksumError::Sign)?;
+
+    Ok(())
 }
 
-pub async fn run(
-    mut config: Config,
-    platform: String,
-    repo: Option<String>,
-    output_file: Option<&Path>,
-) -> Result<(), Error> {
+/// Writes to a temporary file next to `path` first, so readers never see a partial file
+pub(crate) async fn write_output(path: &Path, output: &output::Output) -> Result<(), WriteError> {
+    let data = serde_json::to_vec(output)?;
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".tmp");
+
+    let tmp = path.with_file_name(file_name);
+    fs::write(&tmp, data).await?;
+    fs::rename(&tmp, path).await?;
+
+    Ok(())
+}
+
+/// Splits `repo/artifact:tag@digest` into its tag and digest
+fn tag_and_digest(image_ref: &str) -> (&str, &str) {
+    let (name, digest) = image_ref.split_once('@').unwrap_or((image_ref, ""));
+    let tag = name.rsplit_once(':').map_or("", |(_, tag)| tag);
+
+    (tag, digest)
+}
+
+async fn append(path: &Path, lines: &[String]) -> Result<(), WriteError> {
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    file.write_all(lines.concat().as_bytes()).await?;
+
+    Ok(())
+}
+
+/// Exposes the pushed images to later CI steps, through `GITHUB_OUTPUT` in GitHub Actions
+/// and as a dotenv file for other CI systems
+async fn write_ci_outputs(
+    artifacts: &HashMap<String, String>,
+    env_output: Option<&Path>,
+) -> Result<(), WriteError> {
+    let mut artifacts = artifacts.iter().collect::<Vec<_>>();
+    artifacts.sort();
+
+    if let Ok(path) = std::env::var("GITHUB_OUTPUT")
+        && !path.is_empty()
+    {
+        let images = artifacts.iter().cloned().collect::<BTreeMap<_, _>>();
+        let mut lines = vec![format!("images={}\n", serde_json::to_string(&images)?)];
+
+        for (artifact, image_ref) in artifacts.iter() {
+            let (tag, digest) = tag_and_digest(image_ref);
+            let name = artifact.to_kebab_case();
+
+            lines.push(format!("{name}-image={image_ref}\n"));
+            lines.push(format!("{name}-tag={tag}\n"));
+            lines.push(format!("{name}-digest={digest}\n"));
+        }
+
+        append(Path::new(&path), &lines).await?;
+    }
+
+    if let Some(path) = env_output {
+        let mut lines = vec![];
+
+        for (artifact, image_ref) in artifacts.iter() {
+            let (tag, digest) = tag_and_digest(image_ref);
+            let name = artifact.to_shouty_snake_case();
+
+            lines.push(format!("STEIGER_{name}_IMAGE={image_ref}\n"));
+            lines.push(format!("STEIGER_{name}_TAG={tag}\n"));
+            lines.push(format!("STEIGER_{name}_DIGEST={digest}\n"));
+        }
+
+        fs::write(path, lines.concat()).await?;
+    }
+
+    Ok(())
+}
+
+/// Repository the artifact is pushed to, `imageName` defaults to the artifact name
+fn image_path(repo: &str, image_names: &HashMap<String, String>, artifact: &str) -> String {
+    let name = image_names.get(artifact).map_or(artifact, String::as_str);
+    format!("{repo}/{name}")
+}
+
+/// Resolves `tagStrategy`, registry based strategies look at the tags of every artifact so
+/// all artifacts share the same tag
+async fn strategy_tag(
+    strategy: &TagStrategy,
+    registry: Option<(&Registry, &str)>,
+    artifacts: &[String],
+    image_names: &HashMap<String, String>,
+    commit: Option<&str>,
+) -> Result<String, Error> {
+    let mut existing = vec![];
+
+    match registry {
+        None if strategy.uses_registry() => {
+            ui::warn("no repo set, `tagStrategy` can't take existing tags into account")
+        }
+        _ => {}
+    }
+
+    if let Some((registry, repo)) = registry.filter(|_| strategy.uses_registry()) {
+        for artifact in artifacts {
+            let reference = Reference::try_from(image_path(repo, image_names, artifact))?;
+            existing.extend(
+                registry
+                    .list_tags(&reference)
+                    .await
+                    .map_err(Error::TagStrategy)?,
+            );
+        }
+    }
+
+    let tag = strategy.next_tag(existing.iter().map(String::as_str), commit);
+    config::validate_tag(&tag)?;
+
+    Ok(tag)
+}
+
+/// Returns the artifacts pushed by a previous run that can be reused, keyed by artifact
+async fn resumable(
+    report: &Report,
+    registry: &Registry,
+    repo: &str,
+    tag: &str,
+    tag_formats: &HashMap<String, String>,
+    image_names: &HashMap<String, String>,
+) -> Result<HashMap<String, String>, Error> {
+    let mut resumed = HashMap::new();
+
+    for (artifact, image_ref) in report.images.iter() {
+        let tag = tag_formats.get(artifact).map_or(tag, String::as_str);
+        let Some((name, digest)) = image_ref.split_once('@') else {
+            continue;
+        };
+
+        // Tags change with every commit, only reuse images that would be pushed to the same reference
+        if name != format!("{}:{tag}", image_path(repo, image_names, artifact)) {
+            continue;
+        }
+
+        let reference = Reference::try_from(name)?;
+        let current = registry
+            .resolve_digest(&reference)
+            .await
+            .map_err(PushError::from)?;
+
+        if current.as_deref() == Some(digest) {
+            resumed.insert(artifact.clone(), image_ref.clone());
+        }
+    }
+
+    Ok(resumed)
+}
+
+impl Error {
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Error::Git(_)
+            | Error::Config(_)
+            | Error::DiskSpace { .. }
+            | Error::LocalPlatforms(_)
+            | Error::PreviousOutput(_)
+            | Error::Lock(_) => exit::CONFIG,
+            Error::Build(_) => exit::BUILD,
+            Error::Push(_)
+            | Error::Store(_)
+            | Error::NoImage { .. }
+            | Error::Credential(_)
+            | Error::Parse(_)
+            | Error::TagStrategy(_)
+            | Error::PublishOutput { .. } => exit::PUSH,
+            Error::Test {
+                published: true, ..
+            } => exit::PARTIAL,
+            Error::Test { .. } => exit::BUILD,
+            Error::BuildEvent(_)
+            | Error::WriteOutput(_)
+            | Error::Layout { .. }
+            | Error::Checksums(_)
+            | Error::Join(_) => exit::FAILURE,
+        }
+    }
+}
+
+fn find_image(
+    mut images: Vec<Image>,
+    artifact: &str,
+    platform: &str,
+    fallback: PlatformFallback,
+    emulated: &[String],
+) -> Result<Image, Error> {
+    let no_image = || Error::NoImage {
+        artifact: artifact.to_string(),
+        platform: platform.to_string(),
+    };
+    let (n, found) = image::match_platform(&images, platform, emulated).ok_or_else(no_image)?;
+
+    match (found, fallback) {
+        (PlatformMatch::Exact, _) | (_, PlatformFallback::Allow) => {}
+        (_, PlatformFallback::Strict) => return Err(no_image()),
+        (PlatformMatch::Unspecified, PlatformFallback::Warn) => ui::warn(format!(
+            "no image for platform '{platform}' found for {artifact}, pushing image without platform"
+        )),
+        (PlatformMatch::Emulated(emulated), PlatformFallback::Warn) => ui::warn(format!(
+            "no image for platform '{platform}' found for {artifact}, pushing {emulated} image which runs emulated"
+        )),
+    }
+
+    Ok(images.remove(n))
+}
+
+#[derive(Default)]
+pub struct Options {
+    pub platform: String,
+    pub repo: Option<String>,
+    pub output_file: Option<PathBuf>,
+    /// Write the output file even if some artifacts failed
+    pub partial_output: bool,
+    /// Dotenv file with the image references, for CI systems other than GitHub Actions
+    pub env_output: Option<PathBuf>,
+    /// Report of a previous run, artifacts it pushed are reused
+    pub resume: Option<Report>,
+    /// Output file of a previous build, artifacts of which the base image was updated since
+    /// are forced to rebuild
+    pub rebuild_stale_bases: Option<PathBuf>,
+    /// Reference the output file is pushed to as an OCI artifact
+    pub publish_output: Option<String>,
+    /// Lockfile updated after a successful run
+    pub lockfile: Option<PathBuf>,
+    /// Fail when the resolved versions don't match the lockfile
+    pub frozen: bool,
+}
+
+pub async fn run(mut config: Config, opts: Options, recorder: Recorder) -> Result<(), Error> {
+    let Options {
+        platform,
+        repo,
+        output_file,
+        partial_output,
+        env_output,
+        resume,
+        rebuild_stale_bases,
+        publish_output,
+        lockfile: lockfile_path,
+        frozen,
+    } = opts;
+
+    if let Some(ref path) = rebuild_stale_bases {
+        let previous = deploy::read_input(path).await?;
+        let stale = outdated::stale_bases(&config, &previous.builds).await;
+        rebuild_stale(&mut config, &stale);
+    }
+
+    let mut lockfile = match lockfile_path {
+        Some(ref path) => {
+            let lockfile = Lockfile::resolve(&config).await;
+
+            if frozen {
+                Lockfile::check(path, &lockfile).await?;
+            }
+
+            Some(lockfile)
+        }
+        None => None,
+    };
+
+    // Hashed before the fields used here are taken out of the config
+    let mut metadata = BuildMetadata::new(&config).map_err(WriteError::from)?;
     let root = progress::tree();
     let handle = progress::setup_line_renderer(&root);
     let insecure_registries = mem::take(&mut config.insecure_registries);
+    let registry_settings = mem::take(&mut config.registry_settings);
+    let platform_fallback = config.platform_fallback;
+    let emulated_platforms = mem::take(&mut config.emulated_platforms);
+    let mirrors = mem::take(&mut config.mirrors);
 
     let (tag, default_repo) = (config.tag_format.clone(), config.default_repo.take());
+    let tag_formats = mem::take(&mut config.artifact_tag_formats);
+    let image_names = config.image_names()?;
+    let stores = config.artifact_stores();
+    let output_paths = config.output_paths();
+    let checksums = config.checksums.take();
+    let git_commit = config.git_commit.clone();
+    let channel = config.channel.clone();
+    let repo = repo.or(default_repo);
+    let local = config.local_artifacts();
+    // Compose releases run on the docker daemon, there's no cluster to detect
+    let compose_only = !config.deploy.is_empty()
+        && config
+            .deploy
+            .values()
+            .all(|release| matches!(release, Release::Compose(_)));
+    if !local.is_empty() && platform.contains(',') {
+        return Err(Error::LocalPlatforms(platform));
+    }
+
+    // Detected before building, so an unsupported context doesn't waste a build
+    let cluster = match (local.is_empty(), compose_only) {
+        (true, _) => None,
+        (false, true) => Some(ClusterStore::new(
+            LocalCluster::Docker("docker daemon".to_string()),
+            repo.clone(),
+        )),
+        (false, false) => Some(ClusterStore::new(
+            LocalCluster::detect().map_err(StoreError::from)?,
+            repo.clone(),
+        )),
+    };
+    let mut artifacts = HashMap::new();
+    let mut mirrored = HashMap::<String, Vec<String>>::new();
+    let mut stored = BTreeMap::new();
+    let mut bases = HashMap::new();
+
+    let tag = match config.tag_strategy.take() {
+        Some(strategy) => {
+            let registry = match repo {
+                Some(ref repo) => Some((
+                    Registry::with_config(
+                        registry::load_credentials(repo)?,
+                        &insecure_registries,
+                        &registry_settings,
+                    ),
+                    repo.as_str(),
+                )),
+                None => None,
+            };
+            // Artifacts with their own tag format don't follow the strategy, and artifacts that
+            // aren't pushed have no tags to look at
+            let strategy_artifacts = config
+                .build
+                .iter()
+                .filter(|(_, build)| build.push.unwrap_or(config.push))
+                .flat_map(|(name, build)| build.kind.artifacts(name))
+                .filter(|artifact| !tag_formats.contains_key(artifact))
+                .collect::<Vec<_>>();
+            let tag = strategy_tag(
+                &strategy,
+                registry.as_ref().map(|(registry, repo)| (registry, *repo)),
+                &strategy_artifacts,
+                &image_names,
+                git_commit.as_deref(),
+            )
+            .await?;
+
+            ui::info(format!("tagging with {tag} (tagStrategy)"));
+            tag
+        }
+        None => tag,
+    };
+
+    if let Some(report) = resume {
+        match repo {
+            Some(ref repo) => {
+                let registry = Registry::with_config(
+                    registry::load_credentials(repo)?,
+                    &insecure_registries,
+                    &registry_settings,
+                );
+                let resumed =
+                    resumable(&report, &registry, repo, &tag, &tag_formats, &image_names).await?;
+
+                // Only skip entries of which all artifacts can be reused
+                config.build.retain(|name, build| {
+                    !build
+                        .kind
+                        .artifacts(name)
+                        .iter()
+                        .all(|artifact| resumed.contains_key(artifact))
+                });
+
+                ui::info(format!(
+                    "resuming, reusing {} pushed artifacts",
+                    resumed.len()
+                ));
+
+                for (artifact, image_ref) in resumed {
+                    recorder.record(Stage::Push, &artifact, Duration::ZERO, None);
+                    recorder.record_image(&artifact, &image_ref);
+                    artifacts.insert(artifact, image_ref);
+                }
+            }
+            None => ui::warn("no repo set, nothing to resume"),
+        }
+    }
+
+    check_free_space(&config).await?;
+
     let events = EventsClient::from_env();
-    let builder = MetaBuild::new(config);
+    let builder = MetaBuild::new(config, recorder.clone());
 
     let now = Instant::now();
-    let output = builder.build(root.add_child("build"), &platform).await?;
+    let mut output = builder
+        .build(progress::task(&root, "build"), &platform)
+        .await?;
+    let failures = mem::take(&mut output.failed_tests);
+
+    if let Some(ref mut lockfile) = lockfile {
+        lockfile.base_images = output
+            .artifacts
+            .iter()
+            .filter_map(|(artifact, images)| {
+                Some((artifact.clone(), images.iter().find_map(Image::base)?))
+            })
+            .collect();
+
+        if frozen && let Some(ref path) = lockfile_path {
+            Lockfile::check(path, lockfile).await?;
+        }
+    }
+
+    // A frozen run matches the lockfile, there is nothing to update
+    if frozen {
+        lockfile = None;
+    }
+
+    for (artifact, images) in output.artifacts.iter() {
+        let Some((dir, _)) = output_paths.get(artifact) else {
+            continue;
+        };
+
+        let tag = tag_formats.get(artifact).unwrap_or(&tag).clone();
+        let (dir, images) = (dir.clone(), images.clone());
+        tokio::task::spawn_blocking(move || archive::oci_layout(&dir, &images, &tag))
+            .await?
+            .map_err(|source| Error::Layout {
+                artifact: artifact.clone(),
+                source,
+            })?;
+    }
+
+    if let Some(ref checksums) = checksums {
+        write_checksums(checksums, &output.artifacts).await?;
+        ui::info(format!("checksums written to {}", checksums.path.display()));
+    }
 
     let mut build_id = None;
     if let Some(ref client) = events
-        && let Ok(tags) = Tags::try_discover()
+        && let Ok(mut tags) = Tags::try_discover()
         && let Ok(target) = std::env::var("BUILD_EVENTS_TARGET")
     {
+        tags.channel = channel.clone();
         let response = client
             .create_build(&CreateBuildRequest { target, tags })
-            .await.unwrap();
+            .await
+            .unwrap();
 
         build_id = Some(response.id);
     }
 
-    let Some(repo) = repo.or(default_repo) else {
+    // Prefer the id known to the build events API, so deployments can be traced to it
+    let run_id = build_id.unwrap_or_else(Uuid::new_v4);
+    metadata.build_id = Some(run_id.to_string());
+    let metadata = serde_json::to_vec(&metadata).map_err(WriteError::from)?;
+
+    if repo.is_none() && stores.is_empty() && local.is_empty() {
         handle.shutdown_and_wait();
-        println!("no repo set, skipping push");
-        return Ok(());
-    };
+        ui::info("no repo set, skipping push");
 
-    let mut progress = root.add_child("push");
-    progress.init(Some(output.artifacts.len()), None);
+        if !failures.is_empty() {
+            return Err(Error::Test {
+                failures: failures.into_values().collect(),
+                published: false,
+            });
+        }
 
-    let auth = registry::load_credentials(&repo)?;
-    let registry = Registry::with_config(auth, &insecure_registries);
-    let mut artifacts = HashMap::new();
-    let mut set = JoinSet::<Result<_, PushError>>::new();
+        return update_lockfile(lockfile, lockfile_path.as_deref()).await;
+    }
+
+    let mut progress = progress::task(&root, "push");
+    progress.init(Some(output.artifacts.len() + output.mirrors.len()), None);
+
+    // Every registry keeps track of the blobs pushed to it, so layers shared between
+    // artifacts are only uploaded once per registry
+    let mut registries = vec![];
+    for repo in repo.iter().chain(mirrors.iter()) {
+        let auth = registry::load_credentials(repo)?;
+        let registry = Registry::with_config(auth, &insecure_registries, &registry_settings);
+        registries.push(RegistryStore::new(registry, repo.clone()));
+    }
+
+    let mut set = JoinSet::<Result<_, (String, StoreError)>>::new();
+
+    for (artifact, images) in output.artifacts.into_iter().collect::<BTreeMap<_, _>>() {
+        let image = find_image(
+            images,
+            &artifact,
+            &platform,
+            platform_fallback,
+            &emulated_platforms,
+        )?;
+
+        if let Some(base) = image.base() {
+            bases.insert(artifact.clone(), base);
+        }
 
-    for (artifact, images) in output.artifacts {
-        let image = find_image(images, &platform)?;
-        let pb = progress.add_child(format!("{artifact} › push"));
-        let image_ref = Reference::try_from(format!("{repo}/{artifact}:{tag}"))?;
-        let output_ref = format!("{repo}/{artifact}:{tag}@{}", image.digest);
-        let mut registry = registry.clone();
+        let tag = tag_formats.get(&artifact).unwrap_or(&tag).clone();
+        let name = image_names.get(&artifact).unwrap_or(&artifact).clone();
+        // Mirrors only apply to artifacts pushed to the registry
+        let targets = match (stores.get(&artifact), cluster.as_ref()) {
+            (_, Some(cluster)) if local.contains(&artifact) => {
+                vec![(
+                    Target::Cluster(cluster.clone()),
+                    format!("{artifact} › load"),
+                )]
+            }
+            (Some(store), _) => Target::new(store, registries.first())
+                .map(|target| (target, format!("{artifact} › publish")))
+                .into_iter()
+                .collect(),
+            (None, _) => registries
+                .iter()
+                .enumerate()
+                .map(|(n, store)| {
+                    let label = match n {
+                        0 => format!("{artifact} › push"),
+                        _ => format!("{artifact} › mirror {}", store.repo()),
+                    };
+                    (Target::Registry(store.clone()), label)
+                })
+                .collect::<Vec<_>>(),
+        };
+
+        if targets.is_empty() {
+            ui::info(format!("no repo set, skipping push of {artifact}"));
+            continue;
+        }
+
+        // Metadata is only attached to the image in the repo, not to mirrors or other stores
+        let attach_to = match targets.first() {
+            Some((Target::Registry(store), _)) => Some(store.clone()),
+            _ => None,
+        };
+        let archived = targets
+            .first()
+            .is_some_and(|(target, _)| !target.is_image());
+        let targets = targets
+            .into_iter()
+            .map(|(target, label)| (target, progress::child(&mut progress, label)))
+            .collect::<Vec<_>>();
+        let recorder = recorder.clone();
+        let metadata = metadata.clone();
+
+        set.spawn(async move {
+            let started = Instant::now();
+            let result = future::try_join_all(
+                targets
+                    .into_iter()
+                    .map(|(target, pb)| target.publish(pb, &name, &tag, image.clone())),
+            )
+            .await;
+
+            recorder.record(
+                Stage::Push,
+                &artifact,
+                started.elapsed(),
+                result.as_ref().err().map(|e| e as _),
+            );
+
+            match result {
+                Ok(mut refs) => {
+                    let output_ref = refs.remove(0);
+
+                    if archived {
+                        return Ok((artifact, output_ref, refs, true));
+                    }
+
+                    recorder.record_image(&artifact, &output_ref);
+
+                    if let Some(store) = attach_to
+                        && let Err(e) = store
+                            .attach(&output_ref, registry::METADATA_MEDIA_TYPE, metadata)
+                            .await
+                    {
+                        ui::warn(format!("failed to attach metadata to {artifact}: {e}"));
+                    }
+
+                    Ok((artifact, output_ref, refs, false))
+                }
+                Err(e) => Err((artifact, e)),
+            }
+        });
+    }
+
+    // Mirrored images keep every platform, so they're copied between registries as is
+    for (artifact, source) in output.mirrors.into_iter().collect::<BTreeMap<_, _>>() {
+        if local.contains(&artifact) {
+            ui::info(format!(
+                "{artifact} is a mirror of a remote image, skipping it"
+            ));
+            continue;
+        }
+
+        if stores.contains_key(&artifact) {
+            ui::warn(format!(
+                "{artifact} is a mirror, ignoring its store and copying it to the registry"
+            ));
+        }
+
+        if registries.is_empty() {
+            ui::info(format!("no repo set, skipping copy of {artifact}"));
+            continue;
+        }
+
+        let tag = tag_formats.get(&artifact).unwrap_or(&tag).clone();
+        let name = image_names.get(&artifact).unwrap_or(&artifact).clone();
+        let source_auth = registry::load_credentials(source.resolve_registry())?;
+        let targets = registries
+            .iter()
+            .enumerate()
+            .map(|(n, store)| {
+                let label = match n {
+                    0 => format!("{artifact} › copy"),
+                    _ => format!("{artifact} › mirror {}", store.repo()),
+                };
+                (store.clone(), progress::child(&mut progress, label))
+            })
+            .collect::<Vec<_>>();
+        let recorder = recorder.clone();
 
         set.spawn(async move {
-            registry.push(pb, &image_ref, image).await?;
-            Ok((artifact, output_ref))
+            let started = Instant::now();
+            let result = future::try_join_all(
+                targets
+                    .into_iter()
+                    .map(|(store, pb)| store.copy(pb, &name, &tag, &source, &source_auth)),
+            )
+            .await
+            .map_err(StoreError::from);
+
+            recorder.record(
+                Stage::Push,
+                &artifact,
+                started.elapsed(),
+                result.as_ref().err().map(|e| e as _),
+            );
+
+            match result {
+                Ok(mut refs) => {
+                    let output_ref = refs.remove(0);
+                    recorder.record_image(&artifact, &output_ref);
+                    Ok((artifact, output_ref, refs, false))
+                }
+                Err(e) => Err((artifact, e)),
+            }
         });
     }
 
-    while let Some(Ok(result)) = set.join_next().await {
-        let (artifact, uri) = result?;
-        artifacts.insert(artifact, uri.clone());
+    let mut failed = failures.keys().cloned().collect::<Vec<_>>();
+    let mut push_error = None;
+
+    while let Some(result) = set.join_next().await {
+        let (artifact, uri, mirror_refs, archived) = match result? {
+            Ok(pushed) => pushed,
+            Err((artifact, e)) => {
+                failed.push(artifact);
+                push_error.get_or_insert(e);
+                continue;
+            }
+        };
+
+        if let Some((dir, OutputCleanup::AfterPush)) = output_paths.get(&artifact)
+            && let Err(e) = fs::remove_dir_all(dir).await
+        {
+            ui::warn(format!("failed to remove OCI layout of {artifact}: {e}"));
+        }
+
+        match archived {
+            true => {
+                stored.insert(artifact, uri.clone());
+            }
+            false => {
+                artifacts.insert(artifact.clone(), uri.clone());
+                mirrored.insert(artifact, mirror_refs);
+            }
+        }
 
         if let Some(ref client) = events
             && let Some(ref id) = build_id
@@ -150,7 +996,11 @@ pub async fn run(
     }
 
     let elapsed = now.elapsed();
-    progress.done(format!("build completed in {elapsed:?}"));
+
+    match failed.is_empty() {
+        true => progress.done(format!("build completed in {elapsed:?}")),
+        false => progress.fail(format!("build completed in {elapsed:?} with failures")),
+    }
 
     handle.shutdown_and_wait();
 
@@ -162,23 +1012,88 @@ pub async fn run(
             .await?;
     }
 
-    println!("\nPushed artifacts:");
+    ui::artifacts(&artifacts);
+    let published = !artifacts.is_empty() || !stored.is_empty();
 
-    for (artifact, image_ref) in artifacts.iter() {
-        println!("- {artifact}: {image_ref}");
+    for (artifact, location) in stored.iter() {
+        ui::info(format!("{artifact} archived to {location}"));
     }
 
-    if let Some(path) = output_file {
+    write_ci_outputs(&artifacts, env_output.as_deref()).await?;
+
+    if let Some(ref path) = output_file
+        && (failed.is_empty() || partial_output)
+    {
+        failed.sort();
+
         let output = output::Output {
             builds: artifacts
                 .into_iter()
-                .map(|(image_name, tag)| output::Build { image_name, tag })
+                .map(|(image_name, tag)| output::Build {
+                    mirrors: mirrored.remove(&image_name).unwrap_or_default(),
+                    base_image: bases.remove(&image_name),
+                    image_name,
+                    tag,
+                })
                 .collect(),
+            failed,
+            git_commit,
+            build_id: Some(run_id.to_string()),
+            channel,
+            promoted_from: None,
+            stored,
         };
 
-        let data = serde_json::to_vec(&output).map_err(WriteError::Serde)?;
-        fs::write(path, data).await.map_err(WriteError::IO)?;
+        write_output(path, &output).await?;
+
+        if let Some(ref reference) = publish_output {
+            let image_ref = Reference::try_from(reference.as_str())?;
+            let auth = registry::load_credentials(image_ref.resolve_registry())?;
+            let registry = Registry::with_config(auth, &insecure_registries, &registry_settings);
+            let digest = registry
+                .push_artifact(
+                    &image_ref,
+                    registry::OUTPUT_MEDIA_TYPE,
+                    serde_json::to_vec(&output).map_err(WriteError::from)?,
+                )
+                .await
+                .map_err(|source| Error::PublishOutput {
+                    reference: reference.clone(),
+                    source,
+                })?;
+
+            ui::info(format!("published output to {reference}@{digest}"));
+        }
     }
 
+    if let Some(e) = push_error {
+        return Err(e.into());
+    }
+
+    if !failures.is_empty() {
+        return Err(Error::Test {
+            failures: failures.into_values().collect(),
+            published,
+        });
+    }
+
+    update_lockfile(lockfile, lockfile_path.as_deref()).await
+}
+
+/// Writes the lockfile of a successful run, artifacts that weren't built keep their locked
+/// base image
+async fn update_lockfile(lockfile: Option<Lockfile>, path: Option<&Path>) -> Result<(), Error> {
+    let (Some(mut lockfile), Some(path)) = (lockfile, path) else {
+        return Ok(());
+    };
+
+    if let Some(previous) = Lockfile::read(path).await? {
+        for (artifact, base) in previous.base_images {
+            lockfile.base_images.entry(artifact).or_insert(base);
+        }
+    }
+
+    lockfile.write(path).await?;
+
     Ok(())
 }
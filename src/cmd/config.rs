@@ -0,0 +1,101 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use miette::Diagnostic;
+use serde_json::Value;
+use steiger::{audit, ui};
+
+use crate::config::Config;
+
+#[derive(Debug, Diagnostic, thiserror::Error)]
+pub enum Error {
+    #[error("expected two profiles, got {0}")]
+    #[diagnostic(help("pass --profile twice, e.g. `--profile staging --profile production`"))]
+    Profiles(usize),
+    #[error("failed to serialize config")]
+    Serde(#[from] serde_json::Error),
+}
+
+/// Collects the leaves of `value` keyed by their path, e.g. `deploy.api.values.replicas`.
+/// Unset fields are left out, so they equal missing ones
+fn flatten(path: String, value: Value, fields: &mut BTreeMap<String, Value>) {
+    let join = |key: &str| match path.is_empty() {
+        true => key.to_string(),
+        false => format!("{path}.{key}"),
+    };
+
+    match value {
+        Value::Null => {}
+        Value::Object(map) if !map.is_empty() => {
+            for (key, value) in map {
+                flatten(join(&key), value, fields);
+            }
+        }
+        Value::Array(items) if !items.is_empty() => {
+            for (i, item) in items.into_iter().enumerate() {
+                flatten(format!("{path}[{i}]"), item, fields);
+            }
+        }
+        value => {
+            fields.insert(path, value);
+        }
+    }
+}
+
+/// Masks the value of a secret field, the diff only shows that it differs
+fn mask(path: &str, value: Value) -> Value {
+    let key = path.rsplit('.').next().unwrap_or_default();
+    let secret = value.as_str().is_some_and(audit::contains_secret);
+
+    match audit::is_secret_name(key) || secret {
+        true => Value::String("***".to_string()),
+        false => value,
+    }
+}
+
+/// Resolved fields of the config, including template variables
+fn fields(config: &Config) -> Result<BTreeMap<String, Value>, serde_json::Error> {
+    let mut value = serde_json::to_value(config)?;
+    value["vars"] = serde_json::to_value(&config.vars)?;
+
+    let mut fields = BTreeMap::new();
+    flatten(String::new(), value, &mut fields);
+
+    Ok(fields)
+}
+
+/// Fields that differ between `a` and `b` with their value in each, sorted by path. The value
+/// is `None` when the field is only set in one of them
+fn differences(
+    a: &Config,
+    b: &Config,
+) -> Result<Vec<(String, Option<Value>, Option<Value>)>, serde_json::Error> {
+    let (mut a, mut b) = (fields(a)?, fields(b)?);
+    let paths = a.keys().chain(b.keys()).cloned().collect::<BTreeSet<_>>();
+
+    Ok(paths
+        .into_iter()
+        .filter_map(|path| {
+            // Compared before masking, two different secrets would both be `***`
+            let (a, b) = (a.remove(&path), b.remove(&path));
+            (a != b).then(|| {
+                let (a, b) = (a.map(|a| mask(&path, a)), b.map(|b| mask(&path, b)));
+                (path, a, b)
+            })
+        })
+        .collect())
+}
+
+/// Prints the fields of which the resolved configs of two profiles differ
+pub fn diff(a: &Config, b: &Config) -> Result<(), Error> {
+    let differences = differences(a, b)?;
+    let name = |config: &Config| {
+        config
+            .profile
+            .clone()
+            .unwrap_or_else(|| "default".to_string())
+    };
+
+    ui::differences(&name(a), &name(b), &differences);
+
+    Ok(())
+}
@@ -3,10 +3,11 @@ use std::{path::Path, sync::Arc};
 use miette::Diagnostic;
 
 use crate::{
-    cmd::build::output::Output,
+    cmd::{build::output::Output, verify},
     config::Config,
-    deploy::{DeployError, MetaDeployer, helm::HelmError},
+    deploy::{DeployError, MetaDeployer, github::Tracker, helm::HelmError},
     progress,
+    report::Recorder,
 };
 
 #[derive(Debug, Diagnostic, thiserror::Error)]
@@ -15,6 +16,9 @@ pub enum InputError {
     IO(#[from] std::io::Error),
     #[error("failed to parse input file")]
     Serde(#[from] serde_json::Error),
+    #[error("input file is partial, failed artifacts: {}", .0.join(", "))]
+    #[diagnostic(help("rebuild the failed artifacts before deploying"))]
+    Partial(Vec<String>),
 }
 
 #[derive(Debug, Diagnostic, thiserror::Error)]
@@ -28,25 +32,66 @@ pub enum Error {
     #[error("failed to init helm deployer")]
     #[diagnostic(transparent)]
     Helm(#[from] HelmError),
+    #[error("refusing to deploy unverified images")]
+    #[diagnostic(transparent)]
+    Verify(#[from] verify::Error),
+}
+
+pub(crate) async fn read_input(path: impl AsRef<Path>) -> Result<Output, InputError> {
+    parse_input(&tokio::fs::read(path).await?)
 }
 
-async fn read_input(path: impl AsRef<Path>) -> Result<Output, InputError> {
-    let content = tokio::fs::read(path).await?;
-    Ok(serde_json::from_slice(&content)?)
+pub(crate) fn parse_input(content: &[u8]) -> Result<Output, InputError> {
+    let output = serde_json::from_slice::<Output>(content)?;
+
+    if !output.failed.is_empty() {
+        return Err(InputError::Partial(output.failed));
+    }
+
+    Ok(output)
 }
 
-pub async fn run(config: Config, input_file: &Path) -> Result<(), Error> {
+pub async fn run(
+    config: Config,
+    input_file: &Path,
+    skip_unchanged: bool,
+    recorder: Recorder,
+) -> Result<(), Error> {
     let input = read_input(input_file).await?;
+
+    if config
+        .verify
+        .as_ref()
+        .is_some_and(|verify| verify.before_deploy)
+    {
+        let root = progress::tree();
+        let handle = progress::setup_line_renderer(&root);
+        let result =
+            verify::check(&config, &input.builds, &mut progress::task(&root, "verify")).await;
+        handle.shutdown_and_wait();
+        result?;
+    }
+
+    let tracker = match config.github_deployment {
+        Some(ref deployment) => Tracker::start(deployment).await,
+        None => None,
+    };
+
     let root = progress::tree();
     let handle = progress::setup_line_renderer(&root);
-    let mut progress = root.add_child("deploy");
+    let mut progress = progress::task(&root, "deploy");
 
-    let mut deploy = MetaDeployer::new(config, Arc::new(input));
-
-    deploy.validate(&mut progress).await?;
-    deploy.deploy(progress).await?;
+    let mut deploy = MetaDeployer::new(config, Arc::new(input), skip_unchanged, recorder);
+    let result = match deploy.validate(&mut progress).await {
+        Ok(()) => deploy.deploy(progress).await,
+        Err(e) => Err(e),
+    };
 
     handle.shutdown_and_wait();
 
-    Ok(())
+    if let Some(tracker) = tracker {
+        tracker.finish(result.is_ok()).await;
+    }
+
+    Ok(result?)
 }
@@ -0,0 +1,82 @@
+use std::{
+    io::{IsTerminal, Write},
+    sync::Arc,
+};
+
+use miette::Diagnostic;
+
+use crate::{
+    cmd::build::output::Output,
+    config::Config,
+    deploy::{DeployError, MetaDeployer},
+    progress,
+    report::Recorder,
+};
+
+#[derive(Debug, Diagnostic, thiserror::Error)]
+pub enum Error {
+    #[error("failed to read confirmation")]
+    Confirm(#[from] std::io::Error),
+    #[error("confirmation required")]
+    #[diagnostic(help("pass --yes to destroy without a terminal"))]
+    NotATerminal,
+    #[error("aborted")]
+    Aborted,
+    #[error("failed to destroy")]
+    #[diagnostic(transparent)]
+    Deploy(#[from] DeployError),
+}
+
+fn confirm(config: &Config) -> Result<(), Error> {
+    if !std::io::stdin().is_terminal() {
+        return Err(Error::NotATerminal);
+    }
+
+    let mut releases = config.deploy.keys().cloned().collect::<Vec<_>>();
+    releases.sort();
+
+    let mut stderr = std::io::stderr();
+    write!(
+        stderr,
+        "this will remove {} releases ({}), continue? [y/N] ",
+        releases.len(),
+        releases.join(", ")
+    )?;
+    stderr.flush()?;
+
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+
+    match answer.trim().to_lowercase().as_str() {
+        "y" | "yes" => Ok(()),
+        _ => Err(Error::Aborted),
+    }
+}
+
+pub async fn run(
+    config: Config,
+    yes: bool,
+    delete_namespaces: bool,
+    recorder: Recorder,
+) -> Result<(), Error> {
+    if config.deploy.is_empty() {
+        return Ok(());
+    }
+
+    if !yes {
+        confirm(&config)?;
+    }
+
+    let root = progress::tree();
+    let handle = progress::setup_line_renderer(&root);
+    let mut progress = progress::task(&root, "destroy");
+
+    let mut deploy = MetaDeployer::new(config, Arc::new(Output::default()), false, recorder);
+
+    deploy.validate(&mut progress).await?;
+    deploy.destroy(progress, delete_namespaces).await?;
+
+    handle.shutdown_and_wait();
+
+    Ok(())
+}
@@ -0,0 +1,327 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    mem,
+    path::{Path, PathBuf},
+};
+
+use miette::Diagnostic;
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+use steiger::{git, ui};
+use tokio::{
+    sync::mpsc::{self, UnboundedReceiver},
+    time::{Duration, timeout},
+};
+
+use crate::{
+    cmd::{
+        build::{self, WriteError, output::Output},
+        deploy::{self, InputError},
+    },
+    config::{self, BuildKind, Config},
+    hint::Hinted,
+    report::Recorder,
+    tmp,
+};
+
+/// Changes arriving within this time of each other are handled together, editors and
+/// formatters often write a file several times
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+#[derive(Debug, Diagnostic, thiserror::Error)]
+pub enum Error {
+    #[error("failed to watch for changes")]
+    Watch(#[from] notify::Error),
+    #[error("I/O error")]
+    IO(#[from] std::io::Error),
+    #[error("failed to create temp file")]
+    TempFile(#[from] async_tempfile::Error),
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    Build(Box<build::Error>),
+    #[error("failed to read build output")]
+    Input(#[from] InputError),
+    #[error("failed to write build output")]
+    Output(#[from] WriteError),
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    Deploy(#[from] deploy::Error),
+}
+
+impl From<build::Error> for Error {
+    fn from(e: build::Error) -> Self {
+        Error::Build(Box::new(e))
+    }
+}
+
+pub struct Options {
+    pub platform: String,
+    pub repo: Option<String>,
+    /// Config file, the config is reloaded and everything rebuilt when it changes
+    pub config_path: PathBuf,
+}
+
+/// Absolute paths watched for every build entry
+fn sources(config: &Config) -> HashMap<String, Vec<PathBuf>> {
+    config
+        .build
+        .iter()
+        .map(|(name, build)| {
+            let paths = build
+                .watch_paths()
+                .into_iter()
+                .filter_map(|path| std::path::absolute(path).ok())
+                .collect();
+
+            (name.clone(), paths)
+        })
+        .collect()
+}
+
+/// Paths written by builds, changing these would rebuild in a loop
+fn generated(config: &Config) -> Vec<PathBuf> {
+    config
+        .build
+        .values()
+        .flat_map(|build| {
+            let output = match build.kind {
+                BuildKind::Script(ref script) => Some(match build.workdir {
+                    Some(ref workdir) => workdir.join(&script.output),
+                    None => script.output.clone(),
+                }),
+                _ => None,
+            };
+
+            output.into_iter().chain(build.output_path.clone())
+        })
+        .filter_map(|path| std::path::absolute(path).ok())
+        .collect()
+}
+
+/// Watches the sources and the directory of the config, returns the watched paths
+fn watch(
+    watcher: &mut RecommendedWatcher,
+    sources: &HashMap<String, Vec<PathBuf>>,
+    config_path: &Path,
+) -> Result<Vec<PathBuf>, Error> {
+    let mut watched = Vec::<PathBuf>::new();
+
+    // Sorted so parents come first, paths inside a watched directory are covered by it
+    for path in sources.values().flatten().collect::<BTreeSet<_>>() {
+        if watched.iter().any(|dir| path.starts_with(dir)) {
+            continue;
+        }
+
+        if !path.exists() {
+            ui::warn(format!("not watching {}, it doesn't exist", path.display()));
+            continue;
+        }
+
+        watcher.watch(path, RecursiveMode::Recursive)?;
+        watched.push(path.clone());
+    }
+
+    // Editors replace files when saving, so the config is watched through its directory
+    if let Some(dir) = config_path.parent()
+        && !watched.iter().any(|watched| dir.starts_with(watched))
+    {
+        watcher.watch(dir, RecursiveMode::NonRecursive)?;
+        watched.push(dir.to_path_buf());
+    }
+
+    Ok(watched)
+}
+
+/// Waits for changes, returns the paths that changed and aren't ignored by git
+async fn changes(events: &mut UnboundedReceiver<notify::Result<Event>>) -> Vec<PathBuf> {
+    loop {
+        let mut paths = BTreeSet::new();
+        let mut next = events.recv().await;
+
+        while let Some(event) = next {
+            match event {
+                Ok(event) if !event.kind.is_access() => paths.extend(event.paths),
+                Ok(_) => {}
+                Err(e) => ui::warn(format!("failed to watch for changes: {e}")),
+            }
+
+            next = timeout(DEBOUNCE, events.recv()).await.ok().flatten();
+        }
+
+        let paths = git::without_ignored(paths.into_iter().collect());
+
+        if !paths.is_empty() {
+            return paths;
+        }
+    }
+}
+
+/// Builds `entries` (everything when `None`), merges their images into `output` and deploys
+/// the releases of which the images changed
+async fn cycle(
+    config: &Config,
+    opts: &Options,
+    entries: Option<&BTreeSet<String>>,
+    output: &mut Output,
+    files: (&Path, &Path),
+    recorder: &Recorder,
+) -> Result<(), Error> {
+    let (built_file, deploy_file) = files;
+    let mut build_config = config.clone();
+
+    if let Some(entries) = entries {
+        build_config.build.retain(|name, _| entries.contains(name));
+    }
+
+    let build_opts = build::Options {
+        platform: opts.platform.clone(),
+        repo: opts.repo.clone(),
+        output_file: Some(built_file.to_path_buf()),
+        ..Default::default()
+    };
+
+    build::run(build_config, build_opts, recorder.clone()).await?;
+
+    // Images of entries that weren't rebuilt are kept from earlier cycles
+    let mut built = deploy::read_input(built_file).await?;
+    if entries.is_some() {
+        let rebuilt = built
+            .builds
+            .iter()
+            .map(|build| build.image_name.clone())
+            .collect::<BTreeSet<_>>();
+        built.builds.extend(
+            mem::take(&mut output.builds)
+                .into_iter()
+                .filter(|build| !rebuilt.contains(&build.image_name)),
+        );
+    }
+    *output = built;
+
+    if config.deploy.is_empty() {
+        return Ok(());
+    }
+
+    // Releases would be deployed without the images of entries that never built
+    let missing = config
+        .build
+        .iter()
+        .flat_map(|(name, build)| build.kind.artifacts(name))
+        .filter(|artifact| {
+            !output
+                .builds
+                .iter()
+                .any(|build| &build.image_name == artifact)
+        })
+        .collect::<Vec<_>>();
+
+    if !missing.is_empty() {
+        ui::warn(format!(
+            "skipping deploy until {} built",
+            missing.join(", ")
+        ));
+        return Ok(());
+    }
+
+    build::write_output(deploy_file, output).await?;
+    deploy::run(config.clone(), deploy_file, true, recorder.clone()).await?;
+
+    Ok(())
+}
+
+/// Builds and deploys everything, then rebuilds the entries of which the sources change and
+/// redeploys until cancelled
+pub async fn run(
+    mut config: Config,
+    opts: Options,
+    mut reload: impl AsyncFnMut() -> Result<Config, config::Error>,
+    recorder: Recorder,
+) -> Result<(), Error> {
+    let config_path = std::path::absolute(&opts.config_path)?;
+    let (built_file, deploy_file) = (tmp::new_file().await?, tmp::new_file().await?);
+    let files = (
+        built_file.file_path().as_path(),
+        deploy_file.file_path().as_path(),
+    );
+
+    let (tx, mut events) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+        let _ = tx.send(event);
+    })?;
+    let mut sources = sources(&config);
+    let mut watched = watch(&mut watcher, &sources, &config_path)?;
+
+    let mut output = Output::default();
+    // Entries to build in the next cycle, `None` for everything
+    let mut entries: Option<BTreeSet<String>> = None;
+
+    loop {
+        match cycle(
+            &config,
+            &opts,
+            entries.as_ref(),
+            &mut output,
+            files,
+            &recorder,
+        )
+        .await
+        {
+            Ok(()) => entries = Some(BTreeSet::new()),
+            // Entries of a failed cycle are built again with the next change
+            Err(e) => eprintln!("{:?}", miette::Report::new(Hinted::new(e))),
+        }
+
+        ui::info("watching for changes, press Ctrl-C to stop");
+
+        loop {
+            let mut paths = changes(&mut events).await;
+
+            if paths.contains(&config_path) {
+                match reload().await {
+                    Ok(reloaded) => {
+                        ui::info("config changed, rebuilding everything");
+                        config = reloaded;
+                        entries = None;
+
+                        for path in watched.iter() {
+                            let _ = watcher.unwatch(path);
+                        }
+
+                        sources = self::sources(&config);
+                        watched = watch(&mut watcher, &sources, &config_path)?;
+                        break;
+                    }
+                    Err(e) => {
+                        eprintln!("{:?}", miette::Report::new(Hinted::new(e)));
+                        ui::warn("keeping the previous config until the errors are fixed");
+                        continue;
+                    }
+                }
+            }
+
+            let generated = generated(&config);
+            paths.retain(|path| !generated.iter().any(|dir| path.starts_with(dir)));
+
+            let changed = sources
+                .iter()
+                .filter(|(_, roots)| {
+                    roots
+                        .iter()
+                        .any(|root| paths.iter().any(|path| path.starts_with(root)))
+                })
+                .map(|(name, _)| name.clone())
+                .collect::<BTreeSet<_>>();
+
+            if !changed.is_empty() {
+                ui::info(format!(
+                    "{} changed, rebuilding {}",
+                    paths[0].display(),
+                    changed.iter().cloned().collect::<Vec<_>>().join(", ")
+                ));
+                if let Some(ref mut entries) = entries {
+                    entries.extend(changed);
+                }
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,65 @@
+use std::{path::Path, sync::Arc};
+
+use miette::Diagnostic;
+use steiger::ui;
+
+use crate::{
+    cmd::deploy::{InputError, read_input},
+    config::Config,
+    deploy::{Action, Change, DeployError, MetaDeployer},
+    progress,
+    report::Recorder,
+};
+
+#[derive(Debug, Diagnostic, thiserror::Error)]
+pub enum Error {
+    #[error("failed to read input file")]
+    #[diagnostic(transparent)]
+    Input(#[from] InputError),
+    #[error("failed to diff")]
+    #[diagnostic(transparent)]
+    Deploy(#[from] DeployError),
+    #[error("failed to serialize changes")]
+    Serde(#[from] serde_json::Error),
+}
+
+/// Action and resource of the change, as passed to `ui::changes`
+fn describe(change: &Change) -> (&'static str, String, &str) {
+    let action = match change.action {
+        Action::Create => "create",
+        Action::Update => "update",
+        Action::Delete => "delete",
+    };
+    let resource = match change.context {
+        Some(ref context) => format!("{}: {} ({context})", change.release, change.resource),
+        None => format!("{}: {}", change.release, change.resource),
+    };
+
+    (action, resource, &change.diff)
+}
+
+/// Prints the changes deploying the build output would make, without deploying
+pub async fn run(config: Config, input_file: &Path, recorder: Recorder) -> Result<(), Error> {
+    let input = read_input(input_file).await?;
+
+    let root = progress::tree();
+    let handle = progress::setup_line_renderer(&root);
+    let mut progress = progress::task(&root, "diff");
+
+    let mut deploy = MetaDeployer::new(config, Arc::new(input), false, recorder);
+    let result = match deploy.validate(&mut progress).await {
+        Ok(()) => deploy.diff(progress).await,
+        Err(e) => Err(e),
+    };
+
+    handle.shutdown_and_wait();
+
+    let changes = result?;
+
+    ui::changes(
+        &changes.iter().map(describe).collect::<Vec<_>>(),
+        serde_json::to_value(&changes)?,
+    );
+
+    Ok(())
+}
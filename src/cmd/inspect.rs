@@ -0,0 +1,86 @@
+use docker_credential::CredentialRetrievalError;
+use miette::Diagnostic;
+use oci_client::Reference;
+use serde::{Deserialize, Serialize};
+use steiger::ui;
+
+use crate::{
+    ci,
+    config::Config,
+    registry::{self, PullError, Registry},
+};
+
+#[derive(Debug, Diagnostic, thiserror::Error)]
+pub enum Error {
+    #[error("failed to parse reference")]
+    Parse(#[from] oci_client::ParseError),
+    #[error("failed to retrieve registry credentials")]
+    Credential(#[from] CredentialRetrievalError),
+    #[error("failed to pull metadata")]
+    #[diagnostic(help("the registry has to support the OCI referrers API"))]
+    Pull(#[from] PullError),
+    #[error("failed to parse metadata")]
+    Serde(#[from] serde_json::Error),
+    #[error("no metadata attached to {0}")]
+    #[diagnostic(help("metadata is attached when steiger pushes an image to the registry"))]
+    NotFound(String),
+}
+
+/// Build metadata attached to pushed images, so an image can be traced back to the run that
+/// built it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BuildMetadata {
+    /// SHA-256 of the resolved config
+    pub config_hash: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    pub steiger_version: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ci_run_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub git_commit: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub build_id: Option<String>,
+}
+
+impl BuildMetadata {
+    /// Metadata of a build of `config`, the build id is set once it's known
+    pub fn new(config: &Config) -> Result<Self, serde_json::Error> {
+        Ok(Self {
+            config_hash: config.hash()?,
+            profile: config.profile.clone(),
+            steiger_version: env!("CARGO_PKG_VERSION").to_string(),
+            ci_run_url: ci::run_url(),
+            git_commit: config.git_commit.clone(),
+            build_id: None,
+        })
+    }
+}
+
+/// Prints the build metadata attached to the image `reference` points to
+pub async fn metadata(config: &Config, reference: &str) -> Result<(), Error> {
+    let image_ref = Reference::try_from(reference)?;
+    let auth = registry::load_credentials(image_ref.resolve_registry())?;
+    let registry =
+        Registry::with_config(auth, &config.insecure_registries, &config.registry_settings);
+
+    let data = registry
+        .pull_attached(&image_ref, registry::METADATA_MEDIA_TYPE)
+        .await?
+        .ok_or_else(|| Error::NotFound(reference.to_string()))?;
+    let metadata = serde_json::from_slice::<BuildMetadata>(&data)?;
+
+    let fields = [
+        ("config hash", Some(&metadata.config_hash)),
+        ("profile", metadata.profile.as_ref()),
+        ("steiger version", Some(&metadata.steiger_version)),
+        ("ci run", metadata.ci_run_url.as_ref()),
+        ("git commit", metadata.git_commit.as_ref()),
+        ("build id", metadata.build_id.as_ref()),
+    ];
+
+    ui::metadata(reference, &fields, serde_json::to_value(&metadata)?);
+
+    Ok(())
+}
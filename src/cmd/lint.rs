@@ -0,0 +1,224 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+use heck::ToLowerCamelCase;
+use miette::Diagnostic;
+use serde_yml::Value;
+use steiger::ui;
+
+use crate::{
+    config::{self, Config, Release},
+    deploy::{compose, manifests},
+};
+
+/// Profiles in which insecure registries are expected
+const DEV_PROFILES: [&str; 5] = ["dev", "development", "local", "test", "ci"];
+
+#[derive(Debug, Diagnostic, thiserror::Error)]
+pub enum Error {
+    #[error("failed to read config")]
+    IO(#[from] std::io::Error),
+    #[error("failed to parse config")]
+    Yaml(#[from] serde_yml::Error),
+    #[error("found {0} issues")]
+    #[diagnostic(help("fix the issues, or pass `--allow <rule>` to ignore a rule"))]
+    Findings(usize),
+}
+
+pub struct Finding {
+    pub rule: &'static str,
+    pub message: String,
+}
+
+fn finding(rule: &'static str, message: String) -> Finding {
+    Finding { rule, message }
+}
+
+/// Tags without any variable are overwritten by every build
+fn mutable_tags(raw: &Value) -> Vec<Finding> {
+    let mut findings = vec![];
+    let is_mutable = |tag: &str| !tag.contains("${");
+
+    if let Some(tag) = raw.get("tagFormat").and_then(Value::as_str)
+        && is_mutable(tag)
+    {
+        findings.push(finding(
+            "mutable-tag",
+            format!(
+                "tagFormat '{tag}' is the same for every build, include e.g. ${{gitShortCommit}}"
+            ),
+        ));
+    }
+
+    if let Some(formats) = raw.get("artifactTagFormats").and_then(Value::as_mapping) {
+        for (artifact, tag) in formats.iter() {
+            if let (Some(artifact), Some(tag)) = (artifact.as_str(), tag.as_str())
+                && is_mutable(tag)
+            {
+                findings.push(finding(
+                    "mutable-tag",
+                    format!("tag format '{tag}' of {artifact} is the same for every build"),
+                ));
+            }
+        }
+    }
+
+    findings
+}
+
+/// Collects the text of the chart and its values files, `None` when the chart isn't local
+pub async fn chart_text(path: &Path, values_files: &[String]) -> Option<String> {
+    let mut text = String::new();
+    let mut stack = vec![PathBuf::from(path)];
+
+    if !tokio::fs::metadata(path).await.ok()?.is_dir() {
+        return None;
+    }
+
+    while let Some(dir) = stack.pop() {
+        let mut entries = tokio::fs::read_dir(dir).await.ok()?;
+
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            match entry.file_type().await {
+                Ok(ty) if ty.is_dir() => stack.push(entry.path()),
+                Ok(_) => text.push_str(
+                    &tokio::fs::read_to_string(entry.path())
+                        .await
+                        .unwrap_or_default(),
+                ),
+                Err(_) => {}
+            }
+        }
+    }
+
+    for file in values_files {
+        text.push_str(&tokio::fs::read_to_string(file).await.unwrap_or_default());
+    }
+
+    Some(text)
+}
+
+/// Build entries of which no artifact is used by any release
+async fn unreferenced_builds(config: &Config) -> Vec<Finding> {
+    if config.deploy.is_empty() {
+        return vec![];
+    }
+
+    let mut referenced = HashSet::new();
+    let mut charts = vec![];
+
+    for release in config.deploy.values() {
+        match release.source() {
+            Release::Helm(helm) => {
+                match chart_text(Path::new(&helm.path), &helm.values_files).await {
+                    Some(text) => charts.push(text),
+                    // Remote charts can't be inspected, assume they use every artifact
+                    None => return vec![],
+                }
+            }
+            Release::Rollout(rollout) => referenced.extend(rollout.artifact.clone()),
+            Release::Manifests(manifests) => {
+                referenced.extend(manifests::images(&manifests.path).await.unwrap_or_default())
+            }
+            Release::Compose(compose) => {
+                referenced.extend(compose::images(&compose.path).await.unwrap_or_default())
+            }
+            Release::Gitops(_) => {}
+        }
+    }
+
+    let mut findings = vec![];
+
+    for (name, build) in config.build.iter() {
+        let used = build.kind.artifacts(name).iter().any(|artifact| {
+            let key = format!("steiger.{}", artifact.to_lower_camel_case());
+            referenced.contains(artifact) || charts.iter().any(|text| text.contains(&key))
+        });
+
+        if !used {
+            findings.push(finding(
+                "unreferenced-build",
+                format!("build entry '{name}' isn't used by any release"),
+            ));
+        }
+    }
+
+    findings
+}
+
+pub async fn lint(
+    config: &Config,
+    path: &Path,
+    profile: Option<&str>,
+) -> Result<Vec<Finding>, Error> {
+    let raw = serde_yml::from_str::<Value>(&tokio::fs::read_to_string(path).await?)?;
+    let mut findings = vec![];
+
+    for (name, release) in config.deploy.iter() {
+        if let Release::Helm(helm) = release
+            && helm.timeout.is_none()
+        {
+            findings.push(finding(
+                "missing-timeout",
+                format!("release '{name}' has no timeout, helm waits 5m by default"),
+            ));
+        }
+    }
+
+    findings.extend(mutable_tags(&raw));
+
+    let dev = profile.is_some_and(|profile| DEV_PROFILES.contains(&profile));
+    let insecure = config
+        .insecure_registries
+        .iter()
+        .filter(|registry| !registry.starts_with("localhost") && !registry.starts_with("127.0.0.1"))
+        .collect::<Vec<_>>();
+
+    if !dev && !insecure.is_empty() {
+        findings.push(finding(
+            "insecure-registry",
+            format!(
+                "insecure registries are used outside a dev profile: {}",
+                insecure
+                    .iter()
+                    .map(|r| r.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        ));
+    }
+
+    for (profile, name) in config::unused_variables(&raw)? {
+        findings.push(finding(
+            "unused-variable",
+            format!("variable '{name}' of profile '{profile}' is never used"),
+        ));
+    }
+
+    findings.extend(unreferenced_builds(config).await);
+
+    Ok(findings)
+}
+
+pub async fn run(
+    config: Config,
+    path: &Path,
+    profile: Option<&str>,
+    allow: &[String],
+) -> Result<(), Error> {
+    let findings = lint(&config, path, profile)
+        .await?
+        .into_iter()
+        .filter(|finding| !allow.iter().any(|rule| rule == finding.rule))
+        .map(|finding| (finding.rule, finding.message))
+        .collect::<Vec<_>>();
+
+    ui::findings(&findings);
+
+    match findings.is_empty() {
+        true => Ok(()),
+        false => Err(Error::Findings(findings.len())),
+    }
+}
@@ -0,0 +1,179 @@
+use std::path::Path;
+
+use heck::ToLowerCamelCase;
+use miette::Diagnostic;
+use serde::Serialize;
+use steiger::ui;
+
+use crate::{
+    cmd::lint,
+    config::{Config, Release},
+    deploy::{compose, manifests},
+};
+
+#[derive(Debug, Diagnostic, thiserror::Error)]
+pub enum Error {
+    #[error("failed to serialize listing")]
+    Serde(#[from] serde_json::Error),
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    #[default]
+    Text,
+    /// A single JSON document on stdout, for scripting
+    Json,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct BuildEntry {
+    name: String,
+    #[serde(rename = "type")]
+    ty: &'static str,
+    artifacts: Vec<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ReleaseEntry {
+    name: String,
+    #[serde(rename = "type")]
+    ty: &'static str,
+    namespace: Option<String>,
+    depends_on: Vec<String>,
+    /// `None` when the chart isn't local, it receives every artifact
+    artifacts: Option<Vec<String>>,
+}
+
+#[derive(Serialize)]
+struct Listing {
+    build: Vec<BuildEntry>,
+    deploy: Vec<ReleaseEntry>,
+}
+
+/// Artifacts the release refers to, helm charts by their `steiger.<artifact>` values and
+/// manifests and compose files by images named after the artifact. Gitops releases consume
+/// what their source does.
+async fn consumed(release: &Release, artifacts: &[String]) -> Option<Vec<String>> {
+    let images = match release.source() {
+        Release::Helm(helm) => {
+            let text = lint::chart_text(Path::new(&helm.path), &helm.values_files).await?;
+
+            return Some(
+                artifacts
+                    .iter()
+                    .filter(|artifact| {
+                        text.contains(&format!("steiger.{}", artifact.to_lower_camel_case()))
+                    })
+                    .cloned()
+                    .collect(),
+            );
+        }
+        Release::Rollout(rollout) => return Some(rollout.artifact.iter().cloned().collect()),
+        Release::Manifests(manifests) => manifests::images(&manifests.path).await?,
+        Release::Compose(compose) => compose::images(&compose.path).await?,
+        Release::Gitops(_) => return Some(vec![]),
+    };
+
+    Some(
+        artifacts
+            .iter()
+            .filter(|artifact| images.contains(*artifact))
+            .cloned()
+            .collect(),
+    )
+}
+
+async fn listing(config: &Config) -> Listing {
+    let mut build = config
+        .build
+        .iter()
+        .map(|(name, build)| {
+            let mut artifacts = build.kind.artifacts(name);
+            artifacts.sort();
+
+            BuildEntry {
+                name: name.clone(),
+                ty: build.kind.type_name(),
+                artifacts,
+            }
+        })
+        .collect::<Vec<_>>();
+    build.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut artifacts = build
+        .iter()
+        .flat_map(|entry| entry.artifacts.iter().cloned())
+        .collect::<Vec<_>>();
+    artifacts.sort();
+
+    let mut deploy = vec![];
+
+    for (name, release) in config.deploy.iter() {
+        deploy.push(ReleaseEntry {
+            name: name.clone(),
+            ty: release.type_name(),
+            namespace: release.namespace().cloned(),
+            depends_on: release.depends_on().to_vec(),
+            artifacts: consumed(release, &artifacts).await,
+        });
+    }
+
+    deploy.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Listing { build, deploy }
+}
+
+/// Lines of the listing per section, in the order they're printed
+fn sections(listing: &Listing) -> [(&'static str, Vec<String>); 2] {
+    let build = listing
+        .build
+        .iter()
+        .map(|entry| {
+            format!(
+                "- {} ({}): {}",
+                entry.name,
+                entry.ty,
+                entry.artifacts.join(", ")
+            )
+        })
+        .collect();
+    let deploy = listing
+        .deploy
+        .iter()
+        .map(|entry| {
+            let artifacts = match entry.artifacts {
+                Some(ref artifacts) if artifacts.is_empty() => "no artifacts".to_string(),
+                Some(ref artifacts) => artifacts.join(", "),
+                None => "all artifacts (remote chart)".to_string(),
+            };
+            let namespace = entry
+                .namespace
+                .as_ref()
+                .map(|namespace| format!(" in {namespace}"))
+                .unwrap_or_default();
+            let mut line = format!("- {} ({}{namespace}): {artifacts}", entry.name, entry.ty);
+
+            if !entry.depends_on.is_empty() {
+                line.push_str(&format!("\n  depends on {}", entry.depends_on.join(", ")));
+            }
+
+            line
+        })
+        .collect();
+
+    [("Build entries", build), ("Releases", deploy)]
+}
+
+/// Prints the build entries and releases of the resolved config
+pub async fn run(config: &Config, format: Format) -> Result<(), Error> {
+    let listing = listing(config).await;
+
+    match format {
+        Format::Json => ui::document(&serde_json::to_value(&listing)?),
+        Format::Text => ui::listing(&sections(&listing), serde_json::to_value(&listing)?),
+    }
+
+    Ok(())
+}
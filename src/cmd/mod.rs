@@ -1,2 +1,12 @@
 pub mod build;
+pub mod config;
 pub mod deploy;
+pub mod destroy;
+pub mod dev;
+pub mod diff;
+pub mod inspect;
+pub mod lint;
+pub mod list;
+pub mod outdated;
+pub mod promote;
+pub mod verify;
@@ -0,0 +1,108 @@
+use std::path::Path;
+
+use futures::future;
+use miette::Diagnostic;
+use oci_client::Reference;
+use steiger::ui;
+
+use crate::{
+    cmd::{
+        build::output::Build,
+        deploy::{self, InputError},
+    },
+    config::Config,
+    registry::{self, Registry},
+};
+
+#[derive(Debug, Diagnostic, thiserror::Error)]
+pub enum Error {
+    #[error("failed to read input file")]
+    #[diagnostic(transparent)]
+    Input(#[from] InputError),
+    #[error("{0} artifact(s) built on an outdated base image")]
+    #[diagnostic(help("rebuild them to pick up the latest base images, e.g. with CVE fixes"))]
+    Outdated(usize),
+}
+
+/// Digest `name` points to now, if it no longer resolves to `digest`
+async fn updated_base(
+    config: &Config,
+    name: &str,
+    digest: &str,
+) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let reference = Reference::try_from(name)?;
+    let auth = registry::load_credentials(reference.resolve_registry())?;
+    let registry =
+        Registry::with_config(auth, &config.insecure_registries, &config.registry_settings);
+    let mut digests = registry.current_digests(&reference).await?;
+
+    match digests.iter().any(|d| d == digest) {
+        true => Ok(None),
+        false => Ok(Some(digests.swap_remove(0))),
+    }
+}
+
+/// Artifacts of which the base image was updated since they were built, with the name and
+/// current digest of the base. Bases that can't be checked are reported as warnings
+pub async fn stale_bases(config: &Config, builds: &[Build]) -> Vec<(String, String, String)> {
+    let checks = builds.iter().filter_map(|build| {
+        let (name, digest) = build.base_image.as_deref()?.rsplit_once('@')?;
+
+        Some(async move {
+            match updated_base(config, name, digest).await {
+                Ok(updated) => {
+                    updated.map(|digest| (build.image_name.clone(), name.to_string(), digest))
+                }
+                Err(e) => {
+                    ui::warn(format!(
+                        "failed to check base image {name} of {}: {e}",
+                        build.image_name
+                    ));
+                    None
+                }
+            }
+        })
+    });
+
+    let mut stale = future::join_all(checks)
+        .await
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+    stale.sort();
+
+    stale
+}
+
+/// Checks whether newer digests exist for the base images the artifacts were built on
+pub async fn run(config: Config, input_file: &Path) -> Result<(), Error> {
+    let input = deploy::read_input(input_file).await?;
+    let unknown = input
+        .builds
+        .iter()
+        .filter(|build| build.base_image.is_none())
+        .map(|build| build.image_name.as_str())
+        .collect::<Vec<_>>();
+
+    if !unknown.is_empty() {
+        ui::info(format!(
+            "base image unknown for: {} (only recorded from OCI base annotations)",
+            unknown.join(", ")
+        ));
+    }
+
+    let findings = stale_bases(&config, &input.builds)
+        .await
+        .into_iter()
+        .map(|(artifact, name, _)| {
+            let message = format!("{artifact}: base image {name} has a newer digest");
+            ("outdated-base", message)
+        })
+        .collect::<Vec<_>>();
+    ui::findings(&findings);
+
+    match findings.is_empty() {
+        true => Ok(()),
+        false => Err(Error::Outdated(findings.len())),
+    }
+}
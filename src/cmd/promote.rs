@@ -0,0 +1,149 @@
+use std::path::PathBuf;
+
+use docker_credential::CredentialRetrievalError;
+use futures::future;
+use miette::Diagnostic;
+use oci_client::Reference;
+use steiger::ui;
+
+use crate::{
+    cmd::{
+        build::{WriteError, write_output},
+        deploy::{self, InputError},
+    },
+    config::{self, Config},
+    registry::{self, PushError, Registry},
+};
+
+#[derive(Debug, Diagnostic, thiserror::Error)]
+pub enum Error {
+    #[error("failed to read input file")]
+    #[diagnostic(transparent)]
+    Input(#[from] InputError),
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    Config(#[from] config::Error),
+    #[error("can't promote from '{from}' to '{to}'")]
+    #[diagnostic(help("{help}"))]
+    Order {
+        from: String,
+        to: String,
+        help: String,
+    },
+    #[error("failed to retrieve registry credentials")]
+    Credential(#[from] CredentialRetrievalError),
+    #[error("failed to tag {reference}")]
+    Tag {
+        reference: String,
+        #[source]
+        source: PushError,
+    },
+    #[error("failed to write output")]
+    #[diagnostic(transparent)]
+    WriteOutput(#[from] WriteError),
+}
+
+pub struct Options {
+    pub input_file: PathBuf,
+    pub output_file: PathBuf,
+    /// Channel to promote to
+    pub to: String,
+    /// Tag to add to the images, defaults to the channel
+    pub tag: Option<String>,
+}
+
+/// Only allows moving one channel forward when `channels` lists the promotion order
+fn check_order(config: &Config, from: Option<&str>, to: &str) -> Result<(), Error> {
+    if config.channels.is_empty() {
+        return Ok(());
+    }
+
+    if !config.channels.iter().any(|channel| channel == to) {
+        return Err(config::Error::UnknownChannel(to.to_string()).into());
+    }
+
+    let Some(from) = from else {
+        return Ok(());
+    };
+
+    match config.next_channel(from) {
+        Some(next) if next == to => Ok(()),
+        next => Err(Error::Order {
+            from: from.to_string(),
+            to: to.to_string(),
+            help: match next {
+                Some(next) => format!("the next channel after '{from}' is '{next}'"),
+                None => format!("'{from}' is the last channel in `channels`"),
+            },
+        }),
+    }
+}
+
+/// Tags the image `image_ref` points to with `tag`, returns the new reference
+async fn promote(config: &Config, image_ref: &str, tag: &str) -> Result<String, Error> {
+    // Images published to a directory or bucket have no tags to move
+    let Ok(reference) = Reference::try_from(image_ref) else {
+        ui::warn(format!(
+            "{image_ref} is not in a registry, keeping it as is"
+        ));
+        return Ok(image_ref.to_string());
+    };
+
+    let auth = registry::load_credentials(reference.resolve_registry())?;
+    let registry =
+        Registry::with_config(auth, &config.insecure_registries, &config.registry_settings);
+    let digest = registry
+        .tag(&reference, tag)
+        .await
+        .map_err(|source| Error::Tag {
+            reference: image_ref.to_string(),
+            source,
+        })?;
+
+    Ok(format!(
+        "{}/{}:{tag}@{digest}",
+        reference.registry(),
+        reference.repository()
+    ))
+}
+
+/// Tags the images of a build output for another channel and writes a new output file
+pub async fn run(config: Config, opts: Options) -> Result<(), Error> {
+    let Options {
+        input_file,
+        output_file,
+        to,
+        tag,
+    } = opts;
+
+    let mut output = deploy::read_input(&input_file).await?;
+    check_order(&config, output.channel.as_deref(), &to)?;
+
+    let tag = tag.unwrap_or_else(|| to.clone());
+    config::validate_tag(&tag)?;
+
+    let promotions = output.builds.iter_mut().map(|build| {
+        let (config, tag) = (&config, &tag);
+
+        async move {
+            build.tag = promote(config, &build.tag, tag).await?;
+
+            for mirror in build.mirrors.iter_mut() {
+                *mirror = promote(config, mirror, tag).await?;
+            }
+
+            Ok::<_, Error>(())
+        }
+    });
+    future::try_join_all(promotions).await?;
+
+    ui::info(format!(
+        "promoted {} artifact(s) to {to}",
+        output.builds.len()
+    ));
+
+    output.promoted_from = output.channel.replace(to);
+    write_output(&output_file, &output).await?;
+
+    Ok(())
+}
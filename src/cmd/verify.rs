@@ -0,0 +1,185 @@
+use std::path::Path;
+
+use docker_credential::CredentialRetrievalError;
+use futures::future;
+use miette::Diagnostic;
+use oci_client::{Reference, errors::OciDistributionError};
+use prodash::tree::Item;
+
+use crate::{
+    cmd::{
+        build::output::Build,
+        deploy::{self, InputError},
+    },
+    config::{Config, RegistrySettings, Verify},
+    exec::{self, CmdBuilder, ExitError},
+    progress,
+    registry::{self, Registry},
+};
+
+#[derive(Debug, Diagnostic, thiserror::Error)]
+pub enum VerifyError {
+    #[error("failed to parse reference")]
+    Parse(#[from] oci_client::ParseError),
+    #[error("reference is not pinned to a digest")]
+    NoDigest,
+    #[error("failed to retrieve registry credentials")]
+    Credential(#[from] CredentialRetrievalError),
+    #[error("failed to resolve digest")]
+    Registry(#[from] OciDistributionError),
+    #[error("digest {0} not found in registry")]
+    NotFound(String),
+    #[error("signature verification failed")]
+    #[diagnostic(transparent)]
+    Signature(ExitError),
+    #[error("attestation '{0}' verification failed")]
+    Attestation(String, #[source] ExitError),
+}
+
+#[derive(Debug, Diagnostic, thiserror::Error)]
+#[error("failed to verify {artifact}")]
+pub struct ArtifactError {
+    artifact: String,
+    #[source]
+    #[diagnostic_source]
+    error: VerifyError,
+}
+
+#[derive(Debug, Diagnostic, thiserror::Error)]
+pub enum Error {
+    #[error("failed to read input file")]
+    #[diagnostic(transparent)]
+    Input(#[from] InputError),
+    #[error("no signing identity configured")]
+    #[diagnostic(help(
+        "set `verify.key`, or `verify.certificateIdentity` and `verify.certificateOidcIssuer` for keyless signatures"
+    ))]
+    NoIdentity,
+    #[error("one or more images failed verification")]
+    Failed {
+        #[related]
+        errors: Vec<ArtifactError>,
+    },
+}
+
+/// Passes the key or keyless identity to cosign
+fn identity_args(cmd: &mut CmdBuilder, verify: &Verify) {
+    if let Some(ref key) = verify.key {
+        cmd.flag("--key", key);
+    }
+
+    if let Some(ref identity) = verify.certificate_identity {
+        cmd.flag("--certificate-identity", identity);
+    }
+
+    if let Some(ref issuer) = verify.certificate_oidc_issuer {
+        cmd.flag("--certificate-oidc-issuer", issuer);
+    }
+}
+
+async fn verify_image(
+    mut progress: Item,
+    build: &Build,
+    verify: &Verify,
+    insecure_registries: &[String],
+    registry_settings: &RegistrySettings,
+) -> Result<(), VerifyError> {
+    let reference = Reference::try_from(build.tag.as_str())?;
+    let digest = reference.digest().ok_or(VerifyError::NoDigest)?.to_string();
+    let auth = registry::load_credentials(reference.resolve_registry())?;
+    let registry = Registry::with_config(auth, insecure_registries, registry_settings);
+
+    progress.init(Some(2 + verify.attestations.len()), None);
+    progress.info(format!("resolving {digest}"));
+
+    if registry.resolve_digest(&reference).await?.as_ref() != Some(&digest) {
+        return Err(VerifyError::NotFound(digest));
+    }
+
+    progress.inc();
+    progress.info("verifying signature");
+
+    let mut cmd = CmdBuilder::new("cosign");
+    cmd.arg("verify");
+    identity_args(&mut cmd, verify);
+    exec::run_with_output(cmd.arg(&build.tag))
+        .await
+        .map_err(VerifyError::Signature)?;
+
+    progress.inc();
+
+    for predicate in verify.attestations.iter() {
+        progress.info(format!("verifying {predicate} attestation"));
+
+        let mut cmd = CmdBuilder::new("cosign");
+        cmd.arg("verify-attestation");
+        cmd.flag("--type", predicate);
+        identity_args(&mut cmd, verify);
+        exec::run_with_output(cmd.arg(&build.tag))
+            .await
+            .map_err(|e| VerifyError::Attestation(predicate.clone(), e))?;
+
+        progress.inc();
+    }
+
+    progress.done("verified");
+
+    Ok(())
+}
+
+/// Checks that every image exists, is signed and has the required attestations, without
+/// pulling the images
+pub async fn check(config: &Config, builds: &[Build], progress: &mut Item) -> Result<(), Error> {
+    let verify = config.verify.as_ref().ok_or(Error::NoIdentity)?;
+
+    if verify.key.is_none() && verify.certificate_identity.is_none() {
+        return Err(Error::NoIdentity);
+    }
+
+    let results = future::join_all(builds.iter().map(|build| {
+        let pb = progress::child(progress, format!("{} › verify", build.image_name));
+
+        async {
+            verify_image(
+                pb,
+                build,
+                verify,
+                &config.insecure_registries,
+                &config.registry_settings,
+            )
+            .await
+            .map_err(|error| ArtifactError {
+                artifact: build.image_name.clone(),
+                error,
+            })
+        }
+    }))
+    .await;
+
+    let errors = results
+        .into_iter()
+        .filter_map(Result::err)
+        .collect::<Vec<_>>();
+
+    match errors.is_empty() {
+        true => {
+            progress.done("all images verified");
+            Ok(())
+        }
+        false => {
+            progress.fail("verification failed");
+            Err(Error::Failed { errors })
+        }
+    }
+}
+
+pub async fn run(config: Config, input_file: &Path) -> Result<(), Error> {
+    let input = deploy::read_input(input_file).await?;
+    let root = progress::tree();
+    let handle = progress::setup_line_renderer(&root);
+
+    let result = check(&config, &input.builds, &mut progress::task(&root, "verify")).await;
+    handle.shutdown_and_wait();
+
+    result
+}
@@ -1,29 +1,391 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    env,
+    ffi::OsString,
     mem,
     path::{Path, PathBuf},
+    str::FromStr,
 };
 
-use miette::Diagnostic;
+use base64::{Engine, prelude::BASE64_STANDARD};
+use chrono::{SecondsFormat, Utc, format::StrftimeItems};
+use miette::{Diagnostic, NamedSource, SourceSpan};
 use serde::{Deserialize, Serialize};
 use serde_yml::{Mapping, Value};
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncWriteExt;
 
-use crate::git;
+use crate::{audit, git, ui};
 
 const DEFAULT_TAG_FORMAT: &str = "${gitTag:${gitShortCommit:unknown}}${gitDirty:}";
 
+/// Resolved per artifact in `imageName`, so it's left in place when loading the config
+const ARTIFACT_VAR: &str = "artifact";
+
+/// Git variables that default to an empty string when no git state is available
+const GIT_VARS: [&str; 7] = [
+    "gitCommit",
+    "gitShortCommit",
+    "gitTag",
+    "gitRefSlug",
+    "gitDescribe",
+    "gitCommitCount",
+    "gitDirty",
+];
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 #[serde(rename_all = "camelCase")]
 pub struct Config {
+    /// Can be omitted in deploy-only projects, which deploy output files of other projects
+    #[serde(default)]
     pub build: HashMap<String, Build>,
     #[serde(default)]
     pub deploy: HashMap<String, Release>,
     #[serde(default)]
     pub insecure_registries: Vec<String>,
+    /// Network settings used for all registry requests
+    #[serde(default)]
+    pub registry_settings: RegistrySettings,
     pub default_repo: Option<String>,
+    /// Additional repos every artifact is pushed to, with the same path and tag
+    #[serde(default)]
+    pub mirrors: Vec<String>,
     #[serde(default)]
     pub tag_format: String,
+    /// Computes the tag with logic templates can't express, replaces `tagFormat`
+    pub tag_strategy: Option<TagStrategy>,
+    /// Cancel remaining builds as soon as one fails. Only the builder commands steiger started
+    /// are killed, processes they spawned themselves (e.g. by the buildx daemon) keep running
+    #[serde(default)]
+    pub fail_fast: bool,
+    #[serde(default)]
+    pub build_timestamp_format: TimestampFormat,
+    /// What to do when an artifact has no image for the target platform
+    #[serde(default)]
+    pub platform_fallback: PlatformFallback,
+    /// Platforms whose images may be pushed when the target platform has none, they run
+    /// emulated on the target (e.g. `linux/amd64` on arm64 nodes)
+    #[serde(default)]
+    pub emulated_platforms: Vec<String>,
+    /// Refuse to build or deploy from a dirty worktree or an unpushed commit
+    #[serde(default)]
+    pub forbid_dirty: bool,
+    /// Push artifacts to the registry, `false` loads them into the local cluster of the
+    /// current kube context instead (kind, k3d, minikube or Docker Desktop)
+    #[serde(default = "default_push")]
+    pub push: bool,
+    /// Tag format overrides per artifact, falls back to `tagFormat`
+    #[serde(default)]
+    pub artifact_tag_formats: HashMap<String, String>,
+    /// Environment variables that must be set before building
+    #[serde(default)]
+    pub required_env: Vec<String>,
+    /// Report deploys as GitHub deployments when running in GitHub Actions
+    pub github_deployment: Option<GithubDeployment>,
+    #[serde(default)]
+    pub notifications: Notifications,
+    /// Signing identity and attestations checked by `steiger verify`
+    pub verify: Option<Verify>,
+    /// Write a checksum manifest of every produced image after building
+    pub checksums: Option<Checksums>,
+    /// Run builder commands with a cleaned environment and a dedicated `HOME` and `TMPDIR`
+    pub sandbox: Option<Sandbox>,
+    /// Flag (and optionally kill) builder commands that stop producing output
+    pub stall_detection: Option<StallDetection>,
+    /// Retry deploys that fail on transient cluster errors
+    pub deploy_retry: Option<DeployRetry>,
+    /// Directory for temporary files like OCI layouts, `STEIGER_TMPDIR` takes precedence
+    pub tmp_dir: Option<PathBuf>,
+    /// Free space in MiB the temp dir needs per artifact before building, 0 disables the check
+    #[serde(default = "default_min_free_space")]
+    pub min_free_space: u64,
+    /// Release channel (e.g. `dev`) recorded in the build output and events
+    pub channel: Option<String>,
+    /// Release channels in promotion order, `steiger promote` only moves one channel forward
+    #[serde(default)]
+    pub channels: Vec<String>,
+    /// Commit the config was loaded for, recorded on deployed resources
+    #[serde(skip)]
+    pub git_commit: Option<String>,
+    /// Variables of the profile, its values files and `--set`, without git and build variables
+    #[serde(skip)]
+    pub vars: BTreeMap<String, String>,
+    /// Variables set with `--set`, they only apply to a single run
+    #[serde(skip)]
+    pub set_vars: BTreeSet<String>,
+    /// Variables of values files encrypted with SOPS, they're never written to disk
+    #[serde(skip)]
+    pub secret_vars: BTreeSet<String>,
+    /// Profile the config was loaded with
+    #[serde(skip)]
+    pub profile: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct RegistrySettings {
+    /// Proxy for plain HTTP registries, defaults to `HTTP_PROXY`
+    pub http_proxy: Option<String>,
+    /// Proxy for HTTPS registries, defaults to `HTTPS_PROXY`
+    pub https_proxy: Option<String>,
+    /// Comma separated hosts that bypass the proxy, defaults to `NO_PROXY`
+    pub no_proxy: Option<String>,
+    /// Seconds to wait for a connection to be established
+    pub connect_timeout: Option<u64>,
+    /// Seconds to wait for a response to be read
+    pub read_timeout: Option<u64>,
+    /// Maximum number of layers uploaded at once per image
+    pub max_concurrent_uploads: Option<usize>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct Notifications {
+    /// Post a summary comment on the pull/merge request
+    pub pr_comment: Option<PrComment>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct PrComment {
+    /// Environment variable holding the API token, defaults to `GITHUB_TOKEN` or `GITLAB_TOKEN`
+    pub token_env: Option<String>,
+    /// URL of the (preview) environment, included in the comment
+    pub url: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct GithubDeployment {
+    pub environment: String,
+    /// URL of the deployed environment, shown on the deployment
+    pub url: Option<String>,
+    /// Marks the environment as short-lived, enabled for preview environments
+    #[serde(default)]
+    pub transient: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct Checksums {
+    /// File the `sha256sum` compatible manifest is written to, e.g. `dist/SHA256SUMS`
+    pub path: PathBuf,
+    /// Key the manifest is signed with using `cosign sign-blob`, the signature is written
+    /// next to it with a `.sig` extension
+    pub key: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct Sandbox {
+    /// Environment variables passed through to builder commands, in addition to `PATH`,
+    /// locale and terminal settings
+    #[serde(default)]
+    pub allow_env: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct StallDetection {
+    /// Seconds without output after which a command is considered stalled
+    pub timeout: u64,
+    /// Kill stalled commands, failing their build, instead of only reporting them
+    #[serde(default)]
+    pub kill: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct DeployRetry {
+    /// Retries after the first attempt
+    #[serde(default = "default_retry_attempts")]
+    pub attempts: u32,
+    /// Seconds before the first retry, doubled for every next one
+    #[serde(default = "default_retry_backoff")]
+    pub backoff: u64,
+    /// Upper limit of the seconds between retries
+    #[serde(default = "default_retry_max_backoff")]
+    pub max_backoff: u64,
+    /// Parts of error messages that are retried, defaults to errors like helm's "another
+    /// operation is in progress" and lost connections to the cluster
+    #[serde(default = "default_retry_on")]
+    pub retry_on: Vec<String>,
+}
+
+fn default_retry_attempts() -> u32 {
+    3
+}
+
+fn default_retry_backoff() -> u64 {
+    5
+}
+
+fn default_retry_max_backoff() -> u64 {
+    60
+}
+
+fn default_retry_on() -> Vec<String> {
+    [
+        "another operation (install/upgrade/rollback) is in progress",
+        "the object has been modified",
+        "etcdserver: request timed out",
+        "TLS handshake timeout",
+        "i/o timeout",
+        "connection refused",
+        "connection reset by peer",
+    ]
+    .map(String::from)
+    .to_vec()
+}
+
+impl DeployRetry {
+    /// Whether an error with this message is retried
+    pub fn matches(&self, message: &str) -> bool {
+        self.retry_on
+            .iter()
+            .any(|pattern| message.contains(pattern.as_str()))
+    }
+
+    /// Seconds to wait before the retry following `attempt`, starting at 1
+    pub fn delay(&self, attempt: u32) -> u64 {
+        self.backoff
+            .saturating_mul(2u64.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_backoff)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct Verify {
+    /// Public key the images are signed with
+    pub key: Option<String>,
+    /// Identity of keyless signatures, e.g. the workflow that signed the images
+    pub certificate_identity: Option<String>,
+    pub certificate_oidc_issuer: Option<String>,
+    /// Predicate types of attestations that must be present, e.g. `slsaprovenance`
+    #[serde(default)]
+    pub attestations: Vec<String>,
+    /// Refuse to deploy unless all images pass verification
+    #[serde(default)]
+    pub before_deploy: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub enum PlatformFallback {
+    /// Only push images built for the target platform
+    Strict,
+    /// Fall back to images without platform or for an emulated platform, with a warning
+    #[default]
+    Warn,
+    /// Fall back silently
+    Allow,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub enum TimestampFormat {
+    #[default]
+    Rfc3339,
+    Epoch,
+    /// strftime-style format string, e.g. `%Y%m%d%H%M%S`
+    #[serde(untagged)]
+    Custom(String),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(tag = "strategy", rename_all = "camelCase")]
+pub enum TagStrategy {
+    /// Bumps the highest `<prefix>X.Y.Z` tag found in the repos of the artifacts
+    SemverFromTags {
+        #[serde(default = "default_semver_prefix")]
+        prefix: String,
+        #[serde(default)]
+        bump: SemverBump,
+    },
+    /// Build date followed by the short commit, e.g. `20240131-1a2b3c4`
+    DateSha {
+        /// strftime-style format of the date
+        #[serde(default = "default_date_format")]
+        format: String,
+    },
+    /// One more than the highest numeric tag found in the repos of the artifacts
+    Sequence,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub enum SemverBump {
+    Major,
+    Minor,
+    #[default]
+    Patch,
+}
+
+impl TagStrategy {
+    /// Whether the strategy needs the tags that were pushed before
+    pub fn uses_registry(&self) -> bool {
+        !matches!(self, TagStrategy::DateSha { .. })
+    }
+
+    /// Tag following the `existing` tags, `commit` is only used by `dateSha`
+    pub fn next_tag<'a>(
+        &self,
+        existing: impl IntoIterator<Item = &'a str>,
+        commit: Option<&str>,
+    ) -> String {
+        match self {
+            TagStrategy::SemverFromTags { prefix, bump } => {
+                let (major, minor, patch) = existing
+                    .into_iter()
+                    .filter_map(|tag| {
+                        let mut parts = tag.strip_prefix(prefix.as_str())?.split('.');
+                        let version = (
+                            parts.next()?.parse::<u64>().ok()?,
+                            parts.next()?.parse::<u64>().ok()?,
+                            parts.next()?.parse::<u64>().ok()?,
+                        );
+
+                        parts.next().is_none().then_some(version)
+                    })
+                    .max()
+                    .unwrap_or_default();
+
+                match bump {
+                    SemverBump::Major => format!("{prefix}{}.0.0", major + 1),
+                    SemverBump::Minor => format!("{prefix}{major}.{}.0", minor + 1),
+                    SemverBump::Patch => format!("{prefix}{major}.{minor}.{}", patch + 1),
+                }
+            }
+            TagStrategy::DateSha { format } => {
+                let commit = commit.map_or("unknown", |commit| &commit[..commit.len().min(7)]);
+                format!("{}-{commit}", Utc::now().format(format))
+            }
+            TagStrategy::Sequence => {
+                let highest = existing
+                    .into_iter()
+                    .filter_map(|tag| tag.parse::<u64>().ok())
+                    .max()
+                    .unwrap_or_default();
+
+                (highest + 1).to_string()
+            }
+        }
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -32,6 +394,22 @@ pub struct Config {
 pub struct Bazel {
     pub targets: HashMap<String, String>,
     pub platforms: HashMap<String, String>,
+    #[serde(default)]
+    pub resources: Resources,
+}
+
+/// Resource hints for builders that can limit themselves, unset fields leave the builder's
+/// defaults in place
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct Resources {
+    /// CPU cores the build may use
+    pub cpus: Option<u32>,
+    /// Memory in MiB the build may use, not supported by nix
+    pub memory: Option<u64>,
+    /// Jobs run in parallel, bazel `--jobs` and nix `--max-jobs`
+    pub jobs: Option<u32>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -46,6 +424,13 @@ pub struct Docker {
     pub hosts: HashMap<String, String>,
     #[serde(default)]
     pub target: Option<String>,
+    #[serde(default)]
+    pub context_filters: Vec<String>,
+    /// Always pull newer versions of the base images
+    #[serde(default)]
+    pub pull: bool,
+    #[serde(default)]
+    pub resources: Resources,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -53,6 +438,11 @@ pub struct Docker {
 #[serde(rename_all = "camelCase")]
 pub struct Ko {
     pub import_path: Option<String>,
+    pub default_base_image: Option<String>,
+    pub config_path: Option<PathBuf>,
+    /// Go module directory ko runs in, `importPath` and `configPath` are relative to it.
+    /// Detected from `go.mod` and `go.work` when not set
+    pub dir: Option<PathBuf>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
@@ -68,6 +458,26 @@ fn default_flake_path() -> PathBuf {
     PathBuf::from(".")
 }
 
+/// Nix message levels, from least to most verbose
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub enum NixLogLevel {
+    Error,
+    Warn,
+    Notice,
+    #[default]
+    Info,
+    Talkative,
+    Chatty,
+    Debug,
+    Vomit,
+}
+
+fn default_log_rate() -> u32 {
+    50
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 #[serde(rename_all = "camelCase")]
@@ -79,128 +489,2036 @@ pub struct Nix {
     pub platform_strategy: PlatformStrategy,
     #[serde(default)]
     pub extra_args: Vec<String>,
+    /// Most verbose messages shown, levels above `info` also make nix log more
+    #[serde(default)]
+    pub log_level: NixLogLevel,
+    /// Lines of build output shown per second for each package, `0` shows everything.
+    /// Skipped lines are still written to `--log-file`
+    #[serde(default = "default_log_rate")]
+    pub log_rate: u32,
+    #[serde(default)]
+    pub resources: Resources,
+}
+
+fn default_min_free_space() -> u64 {
+    1024
+}
+
+fn default_push() -> bool {
+    true
+}
+
+fn default_semver_prefix() -> String {
+    "v".to_string()
+}
+
+fn default_date_format() -> String {
+    "%Y%m%d".to_string()
+}
+
+fn default_destination() -> PathBuf {
+    PathBuf::from("/app")
+}
+
+fn default_cargo_profile() -> String {
+    "release".to_string()
+}
+
+fn default_cargo_destination() -> PathBuf {
+    PathBuf::from("/usr/local/bin")
 }
 
+/// Runs a command that writes an OCI layout to `$STEIGER_OUTPUT_DIR`, for toolchains without
+/// a builder of their own
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
-#[serde(tag = "type", rename_all = "camelCase")]
-pub enum Build {
-    Ko(Ko),
-    Bazel(Bazel),
-    Docker(Docker),
-    Nix(Nix),
+#[serde(rename_all = "camelCase")]
+pub struct Custom {
+    /// Shell command producing the OCI layout
+    pub command: String,
+    #[serde(default)]
+    pub env: HashMap<String, String>,
 }
 
+/// Existing image that is pushed to the target repo like a built artifact
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 #[serde(rename_all = "camelCase")]
-pub struct Helm {
-    pub path: String,
-    pub namespace: Option<String>,
-    pub timeout: Option<String>,
-    #[serde(default)]
-    pub values: HashMap<String, String>,
-    #[serde(default)]
-    pub values_files: Vec<String>,
+pub struct Prebuilt {
+    /// Reference of the image, e.g. `ghcr.io/org/image:1.2.3`
+    pub image: String,
 }
 
+/// Remote image copied into the target repo with every platform, e.g. for clusters that
+/// can only pull from an internal registry
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
-#[serde(tag = "type", rename_all = "camelCase")]
-pub enum Release {
-    Helm(Helm),
+#[serde(rename_all = "camelCase")]
+pub struct Mirror {
+    /// Reference of the image, e.g. `docker.io/library/postgres:17`
+    pub image: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Cross-compiles a Rust binary with `cargo build` and adds it to a base image, without Docker
+#[derive(Clone, Debug, Serialize, Deserialize)]
 #[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 #[serde(rename_all = "camelCase")]
-pub struct Profile {
-    #[serde(default, flatten)]
-    pub vars: HashMap<String, String>,
+pub struct Cargo {
+    /// Binary target to build
+    pub bin: String,
+    /// Workspace member containing the binary
+    pub package: Option<String>,
+    #[serde(default)]
+    pub features: Vec<String>,
+    /// Cargo profile, e.g. `dist`
+    #[serde(default = "default_cargo_profile")]
+    pub profile: String,
+    /// Target triple per platform, e.g. `linux/amd64: x86_64-unknown-linux-gnu`. Defaults to
+    /// the statically linked musl target of the platform
+    #[serde(default)]
+    pub targets: HashMap<String, String>,
+    /// Image to add the binary to, defaults to an empty image
+    pub base_image: Option<String>,
+    /// Directory of the binary inside the image, it becomes the entrypoint
+    #[serde(default = "default_cargo_destination")]
+    pub destination: PathBuf,
+    #[serde(default)]
+    pub env: HashMap<String, String>,
 }
 
-#[derive(Debug, Diagnostic, thiserror::Error)]
-pub enum Error {
-    #[error("I/O error")]
-    IO(#[from] std::io::Error),
-    #[error("substitution failed")]
-    Subst(#[from] subst::Error),
-    #[error("failed to deserialize")]
-    Yaml(#[from] serde_yml::Error),
-    #[error("failed to parse git status")]
-    Git(#[from] git::GitError),
-    #[error("profile '{0}' does not exist")]
-    Profile(String),
+/// Assembles an image from Alpine packages with `apko build`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct Apko {
+    /// apko YAML config describing the packages, accounts and entrypoint
+    pub config: PathBuf,
+    /// Packages installed in addition to the ones in the config
+    #[serde(default)]
+    pub extra_packages: Vec<String>,
 }
 
-fn template(vars: &HashMap<String, String>, config: Value) -> Result<Value, subst::Error> {
-    match config {
-        Value::String(s) => Ok(Value::String(if s.contains('$') {
-            subst::substitute(&s, vars)?
-        } else {
-            s
-        })),
-        Value::Sequence(seq) => seq
-            .into_iter()
-            .map(|c| template(vars, c))
-            .collect::<Result<Vec<_>, _>>()
-            .map(Value::Sequence),
-        Value::Mapping(map) => map
-            .into_iter()
-            .map(|(key, value)| Ok((template(vars, key)?, template(vars, value)?)))
-            .collect::<Result<Mapping, _>>()
-            .map(Value::Mapping),
-        _ => Ok(config),
-    }
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct Script {
+    /// Shell command producing `output`
+    pub command: String,
+    /// File or directory produced by the command
+    pub output: PathBuf,
+    /// Image to add the output to, defaults to an empty image
+    pub base_image: Option<String>,
+    /// Location of the output inside the image
+    #[serde(default = "default_destination")]
+    pub destination: PathBuf,
+    pub entrypoint: Option<Vec<String>>,
+    #[serde(default)]
+    pub env: HashMap<String, String>,
 }
 
-fn extract_git_vars(state: git::State) -> HashMap<String, String> {
-    let mut vars = HashMap::new();
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum BuildKind {
+    Ko(Ko),
+    Bazel(Bazel),
+    Docker(Docker),
+    Nix(Nix),
+    Script(Script),
+    Apko(Apko),
+    Custom(Custom),
+    Prebuilt(Prebuilt),
+    Mirror(Mirror),
+    Cargo(Cargo),
+}
 
-    if let Some(commit) = state.commit {
-        vars.insert("gitShortCommit".to_string(), commit[0..6].to_string());
-        vars.insert("gitCommit".to_string(), commit);
+impl BuildKind {
+    /// Value of `type` in the config
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            BuildKind::Ko(_) => "ko",
+            BuildKind::Bazel(_) => "bazel",
+            BuildKind::Docker(_) => "docker",
+            BuildKind::Nix(_) => "nix",
+            BuildKind::Script(_) => "script",
+            BuildKind::Apko(_) => "apko",
+            BuildKind::Custom(_) => "custom",
+            BuildKind::Prebuilt(_) => "prebuilt",
+            BuildKind::Mirror(_) => "mirror",
+            BuildKind::Cargo(_) => "cargo",
+        }
     }
 
-    if let Some(tag) = state.tag {
-        vars.insert("gitTag".to_string(), tag);
+    /// Names of the artifacts produced by a build entry
+    pub fn artifacts(&self, name: &str) -> Vec<String> {
+        match self {
+            BuildKind::Bazel(bazel) => bazel.targets.keys().cloned().collect(),
+            BuildKind::Nix(nix) => nix.packages.keys().cloned().collect(),
+            BuildKind::Ko(_)
+            | BuildKind::Docker(_)
+            | BuildKind::Script(_)
+            | BuildKind::Apko(_)
+            | BuildKind::Custom(_)
+            | BuildKind::Prebuilt(_)
+            | BuildKind::Mirror(_)
+            | BuildKind::Cargo(_) => vec![name.to_string()],
+        }
     }
+}
 
-    if state.dirty {
-        vars.insert("gitDirty".to_string(), "-dirty".to_string());
-    }
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct CommandTest {
+    pub command: String,
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+}
 
-    vars
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct ImageTest {
+    pub entrypoint: Option<String>,
+    #[serde(default)]
+    pub args: Vec<String>,
 }
 
-#[derive(Debug, Diagnostic, thiserror::Error)]
-pub enum LocateError {
-    #[error("I/O error")]
-    IO(#[from] std::io::Error),
-    #[error("{path} does not exist")]
-    DoesNotExist { path: PathBuf },
-    #[error("config file not found")]
-    #[diagnostic(help("make sure you're in the right directory or create a new `steiger.yml`"))]
-    NotFound,
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Test {
+    /// Runs a shell command
+    Command(CommandTest),
+    /// Runs the built image using `docker run`
+    Image(ImageTest),
 }
 
-pub fn locate(dir: Option<&PathBuf>, config: Option<&PathBuf>) -> Result<PathBuf, LocateError> {
-    let base = dir
-        .map(|path| path.as_ref())
-        .unwrap_or_else(|| Path::new("."));
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct Build {
+    #[serde(flatten)]
+    pub kind: BuildKind,
+    /// Repository path of the artifacts relative to the repo, e.g. `team/project/${artifact}`.
+    /// Defaults to the artifact name
+    pub image_name: Option<String>,
+    /// Where the artifacts are published, defaults to the registry
+    pub store: Option<ArtifactStore>,
+    /// Keep the OCI layout of every artifact at `<outputPath>/<artifact>`, e.g. for inspection
+    pub output_path: Option<PathBuf>,
+    /// When layouts in `outputPath` are removed
+    #[serde(default)]
+    pub output_cleanup: OutputCleanup,
+    /// Working directory of the builder command, relative paths of the entry resolve against it
+    pub workdir: Option<PathBuf>,
+    /// Environment variables passed to the builder command
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    /// Tests that must pass before the artifacts are pushed
+    #[serde(default)]
+    pub test: Vec<Test>,
+    /// Overrides `push` of the config for the artifacts of this entry
+    pub push: Option<bool>,
+    /// Files and directories `steiger dev` rebuilds the entry on, relative to `workdir`.
+    /// Defaults to the sources the builder reads
+    #[serde(default)]
+    pub watch: Vec<PathBuf>,
+}
 
-    if let Some(config) = config {
-        let path = base.join(config);
-        if path.try_exists()? {
-            return Ok(std::path::absolute(path)?);
-        } else {
-            return Err(LocateError::DoesNotExist { path });
+impl Build {
+    /// Paths watched by `steiger dev`, prebuilt and mirrored images are never rebuilt
+    pub fn watch_paths(&self) -> Vec<PathBuf> {
+        let paths = match (&self.kind, self.watch.is_empty()) {
+            (_, false) => self.watch.clone(),
+            (BuildKind::Prebuilt(_) | BuildKind::Mirror(_), true) => return vec![],
+            (BuildKind::Docker(docker), true) => vec![PathBuf::from(&docker.context)],
+            (BuildKind::Ko(ko), true) => vec![ko.dir.clone().unwrap_or_else(|| ".".into())],
+            (BuildKind::Nix(nix), true) => vec![nix.flake.clone()],
+            (BuildKind::Apko(apko), true) => vec![apko.config.clone()],
+            (
+                BuildKind::Bazel(_)
+                | BuildKind::Script(_)
+                | BuildKind::Custom(_)
+                | BuildKind::Cargo(_),
+                true,
+            ) => vec![PathBuf::from(".")],
+        };
+
+        match self.workdir {
+            // Collected from the components to drop the `.` of `<workdir>/.`
+            Some(ref workdir) => paths
+                .into_iter()
+                .map(|path| workdir.join(path).components().collect())
+                .collect(),
+            None => paths,
         }
     }
+}
 
-    for file_name in ["steiger.yml", "steiger.yaml"] {
-        let path = base.join(file_name);
-        if path.try_exists()? {
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub enum OutputCleanup {
+    /// Keep the layout until the next build replaces it
+    #[default]
+    Never,
+    /// Remove the layout once the artifact was pushed, so only failed artifacts remain
+    AfterPush,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum ArtifactStore {
+    /// Push to the repo and its mirrors
+    Registry,
+    /// Write OCI layout archives to `<path>/<imageName>/<tag>.tar`
+    Directory { path: PathBuf },
+    /// Upload OCI layout archives to an `s3://` or `gs://` bucket, using the `aws` or `gcloud` CLI
+    Bucket { url: String },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct Helm {
+    pub path: String,
+    pub namespace: Option<String>,
+    pub timeout: Option<String>,
+    #[serde(default)]
+    pub values: HashMap<String, String>,
+    /// Passed with `--set-string`, so values like `"0123"` or `"true"` stay strings
+    #[serde(default)]
+    pub values_string: HashMap<String, String>,
+    /// Passed with `--set-file`, the value is set to the content of the file, e.g. a certificate
+    #[serde(default)]
+    pub values_file: HashMap<String, String>,
+    #[serde(default)]
+    pub values_files: Vec<String>,
+    /// Environment variables that must be set before deploying
+    #[serde(default)]
+    pub required_env: Vec<String>,
+    /// Releases that are deployed before and destroyed after this one
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+    /// Preview environment id, set by `--preview`
+    #[serde(skip)]
+    pub preview: Option<String>,
+    #[serde(default)]
+    pub engine: HelmEngine,
+    /// Adopt existing resources that weren't installed by this release, like resources created
+    /// with `kubectl` or by another release, using `helm upgrade --take-ownership`
+    #[serde(default)]
+    pub take_ownership: bool,
+    /// Roll the release back and upgrade again when an interrupted deploy left it pending,
+    /// instead of failing on helm's lock
+    #[serde(default)]
+    pub recover_pending: bool,
+    /// Artifacts built by other steiger projects, passed to the chart as if they were built here
+    #[serde(default)]
+    pub artifacts_from: Vec<ArtifactSource>,
+    /// Clusters the release is deployed to, the current kube context when empty
+    #[serde(default)]
+    pub targets: Vec<DeployTarget>,
+    /// Targets deployed at once, all of them by default
+    pub max_parallel: Option<usize>,
+    /// Kube context of the target being deployed, set for every entry of `targets`
+    #[serde(skip)]
+    pub context: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub enum HelmEngine {
+    /// `helm upgrade --install`
+    #[default]
+    Binary,
+    /// Render with `helm template` and apply using server-side apply
+    Native,
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub enum WorkloadKind {
+    #[default]
+    Deployment,
+    StatefulSet,
+}
+
+/// Updates the image of an existing workload, or restarts it when no artifact is set
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct Rollout {
+    #[serde(default)]
+    pub kind: WorkloadKind,
+    pub name: String,
+    pub namespace: Option<String>,
+    /// Artifact whose pushed image is set on the container
+    pub artifact: Option<String>,
+    /// Container to update, can be omitted when the workload has a single container
+    pub container: Option<String>,
+    /// Environment variables that must be set before deploying
+    #[serde(default)]
+    pub required_env: Vec<String>,
+    /// Releases that are deployed before and destroyed after this one
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+    /// Artifacts built by other steiger projects, `artifact` can refer to one of them
+    #[serde(default)]
+    pub artifacts_from: Vec<ArtifactSource>,
+    /// Clusters the release is deployed to, the current kube context when empty
+    #[serde(default)]
+    pub targets: Vec<DeployTarget>,
+    /// Targets deployed at once, all of them by default
+    pub max_parallel: Option<usize>,
+    /// Kube context of the target being deployed, set for every entry of `targets`
+    #[serde(skip)]
+    pub context: Option<String>,
+}
+
+/// Applies a directory of Kubernetes manifests, images naming an artifact are replaced with
+/// the pushed image
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct Manifests {
+    /// Directory with `.yaml` and `.yml` files, applied in file name order
+    pub path: String,
+    /// Namespace of resources that don't set one, defaults to the namespace of the kube context
+    pub namespace: Option<String>,
+    /// Environment variables that must be set before deploying
+    #[serde(default)]
+    pub required_env: Vec<String>,
+    /// Releases that are deployed before and destroyed after this one
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+    /// Artifacts built by other steiger projects, images can refer to them as well
+    #[serde(default)]
+    pub artifacts_from: Vec<ArtifactSource>,
+    /// Clusters the release is deployed to, the current kube context when empty
+    #[serde(default)]
+    pub targets: Vec<DeployTarget>,
+    /// Targets deployed at once, all of them by default
+    pub max_parallel: Option<usize>,
+    /// Kube context of the target being deployed, set for every entry of `targets`
+    #[serde(skip)]
+    pub context: Option<String>,
+}
+
+/// Runs a compose file with `docker compose up`, services naming an artifact run the built image
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct Compose {
+    /// Compose file, relative paths in it are resolved from its directory
+    pub path: String,
+    /// Compose project name, defaults to the name of the release
+    pub project: Option<String>,
+    /// Wait until the services are running and healthy
+    #[serde(default)]
+    pub wait: bool,
+    /// Environment variables that must be set before deploying
+    #[serde(default)]
+    pub required_env: Vec<String>,
+    /// Releases that are deployed before and destroyed after this one
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+    /// Artifacts built by other steiger projects, services can refer to them as well
+    #[serde(default)]
+    pub artifacts_from: Vec<ArtifactSource>,
+}
+
+/// Commits the rendered manifests of a chart or manifest directory to a git repository, for
+/// ArgoCD or Flux to sync them to the cluster
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct Gitops {
+    /// Repository to push to, e.g. `git@github.com:my-org/deployments.git`
+    pub repository: String,
+    /// Branch to commit to, defaults to the default branch of the repository
+    pub branch: Option<String>,
+    /// Directory in the repository the manifests are written to, owned by this release
+    pub path: String,
+    /// Commit message, defaults to `Deploy <release>`
+    pub message: Option<String>,
+    /// Release that is rendered, either of type `helm` or `manifests`
+    pub source: Box<Release>,
+    /// Environment variables that must be set before deploying
+    #[serde(default)]
+    pub required_env: Vec<String>,
+    /// Releases that are deployed before and destroyed after this one
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+    /// Artifacts built by other steiger projects, the source can refer to them as well
+    #[serde(default)]
+    pub artifacts_from: Vec<ArtifactSource>,
+}
+
+/// Cluster a release is deployed to, e.g. one per region
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct DeployTarget {
+    /// Name shown in the progress and errors, defaults to the context and namespace
+    pub name: Option<String>,
+    /// Kube context, defaults to the current context
+    pub context: Option<String>,
+    /// Overrides `namespace` of the release
+    pub namespace: Option<String>,
+}
+
+impl DeployTarget {
+    pub fn name(&self) -> String {
+        if let Some(ref name) = self.name {
+            return name.clone();
+        }
+
+        match (&self.context, &self.namespace) {
+            (Some(context), Some(namespace)) => format!("{context}/{namespace}"),
+            (Some(context), None) => context.clone(),
+            (None, Some(namespace)) => format!("namespace {namespace}"),
+            (None, None) => "current context".to_string(),
+        }
+    }
+}
+
+/// Output of a build in another project
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(untagged, rename_all = "camelCase")]
+pub enum ArtifactSource {
+    /// File written with `steiger build --output-file`
+    Path {
+        path: PathBuf,
+        /// Artifacts to use, all of them when empty
+        #[serde(default)]
+        artifacts: Vec<String>,
+    },
+    /// Output pushed with `steiger build --publish-output`
+    Registry {
+        reference: String,
+        /// Artifacts to use, all of them when empty
+        #[serde(default)]
+        artifacts: Vec<String>,
+    },
+}
+
+impl ArtifactSource {
+    /// Path or reference, for messages
+    pub fn name(&self) -> String {
+        match self {
+            ArtifactSource::Path { path, .. } => path.display().to_string(),
+            ArtifactSource::Registry { reference, .. } => reference.clone(),
+        }
+    }
+
+    pub fn artifacts(&self) -> &[String] {
+        match self {
+            ArtifactSource::Path { artifacts, .. } | ArtifactSource::Registry { artifacts, .. } => {
+                artifacts
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Release {
+    Helm(Helm),
+    Rollout(Rollout),
+    Manifests(Manifests),
+    Compose(Compose),
+    Gitops(Gitops),
+}
+
+impl Release {
+    /// Value of `type` in the config
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Release::Helm(_) => "helm",
+            Release::Rollout(_) => "rollout",
+            Release::Manifests(_) => "manifests",
+            Release::Compose(_) => "compose",
+            Release::Gitops(_) => "gitops",
+        }
+    }
+
+    /// Release that describes the deployed resources, the rendered source for gitops
+    pub fn source(&self) -> &Release {
+        match self {
+            Release::Gitops(gitops) => gitops.source.source(),
+            release => release,
+        }
+    }
+
+    /// Namespace of the resources, if set
+    pub fn namespace(&self) -> Option<&String> {
+        match self.source() {
+            Release::Helm(helm) => helm.namespace.as_ref(),
+            Release::Rollout(rollout) => rollout.namespace.as_ref(),
+            Release::Manifests(manifests) => manifests.namespace.as_ref(),
+            Release::Compose(_) | Release::Gitops(_) => None,
+        }
+    }
+
+    pub fn required_env(&self) -> &[String] {
+        match self {
+            Release::Helm(helm) => &helm.required_env,
+            Release::Rollout(rollout) => &rollout.required_env,
+            Release::Manifests(manifests) => &manifests.required_env,
+            Release::Compose(compose) => &compose.required_env,
+            Release::Gitops(gitops) => &gitops.required_env,
+        }
+    }
+
+    pub fn depends_on(&self) -> &[String] {
+        match self {
+            Release::Helm(helm) => &helm.depends_on,
+            Release::Rollout(rollout) => &rollout.depends_on,
+            Release::Manifests(manifests) => &manifests.depends_on,
+            Release::Compose(compose) => &compose.depends_on,
+            Release::Gitops(gitops) => &gitops.depends_on,
+        }
+    }
+
+    pub fn depends_on_mut(&mut self) -> &mut Vec<String> {
+        match self {
+            Release::Helm(helm) => &mut helm.depends_on,
+            Release::Rollout(rollout) => &mut rollout.depends_on,
+            Release::Manifests(manifests) => &mut manifests.depends_on,
+            Release::Compose(compose) => &mut compose.depends_on,
+            Release::Gitops(gitops) => &mut gitops.depends_on,
+        }
+    }
+
+    pub fn artifacts_from(&self) -> &[ArtifactSource] {
+        match self {
+            Release::Helm(helm) => &helm.artifacts_from,
+            Release::Rollout(rollout) => &rollout.artifacts_from,
+            Release::Manifests(manifests) => &manifests.artifacts_from,
+            Release::Compose(compose) => &compose.artifacts_from,
+            Release::Gitops(gitops) => &gitops.artifacts_from,
+        }
+    }
+
+    pub fn targets(&self) -> &[DeployTarget] {
+        match self {
+            Release::Helm(helm) => &helm.targets,
+            Release::Rollout(rollout) => &rollout.targets,
+            Release::Manifests(manifests) => &manifests.targets,
+            // Compose runs on the local docker daemon, gitops only pushes to a repository
+            Release::Compose(_) | Release::Gitops(_) => &[],
+        }
+    }
+
+    pub fn max_parallel(&self) -> Option<usize> {
+        match self {
+            Release::Helm(helm) => helm.max_parallel,
+            Release::Rollout(rollout) => rollout.max_parallel,
+            Release::Manifests(manifests) => manifests.max_parallel,
+            Release::Compose(_) | Release::Gitops(_) => None,
+        }
+    }
+
+    /// The release as deployed to `target`
+    pub fn for_target(&self, target: &DeployTarget) -> Release {
+        let mut release = self.clone();
+        let (namespace, context) = match release {
+            Release::Helm(ref mut helm) => (&mut helm.namespace, &mut helm.context),
+            Release::Rollout(ref mut rollout) => (&mut rollout.namespace, &mut rollout.context),
+            Release::Manifests(ref mut manifests) => {
+                (&mut manifests.namespace, &mut manifests.context)
+            }
+            Release::Compose(_) | Release::Gitops(_) => return release,
+        };
+
+        if target.namespace.is_some() {
+            namespace.clone_from(&target.namespace);
+        }
+
+        context.clone_from(&target.context);
+
+        release
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct Profile {
+    pub forbid_dirty: Option<bool>,
+    /// Overrides `push`, e.g. `false` for a profile deploying to a local cluster
+    pub push: Option<bool>,
+    #[serde(default)]
+    pub required_env: Vec<String>,
+    /// Release channel of builds with this profile, overrides `channel`
+    pub channel: Option<String>,
+    /// Files with additional variables, relative to the config file. Later files take
+    /// precedence over earlier ones and over the variables of the profile itself.
+    #[serde(default)]
+    pub values_from: Vec<ValuesFrom>,
+    #[serde(default, flatten)]
+    pub vars: HashMap<String, String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(untagged)]
+pub enum ValuesFrom {
+    Path(PathBuf),
+    File {
+        path: PathBuf,
+        /// Skip the file when it doesn't exist, e.g. for git-ignored per-developer values
+        #[serde(default)]
+        optional: bool,
+    },
+}
+
+impl ValuesFrom {
+    pub fn path(&self) -> &Path {
+        match self {
+            ValuesFrom::Path(path) | ValuesFrom::File { path, .. } => path,
+        }
+    }
+
+    pub fn optional(&self) -> bool {
+        matches!(self, ValuesFrom::File { optional: true, .. })
+    }
+}
+
+#[derive(Debug, Diagnostic, thiserror::Error)]
+#[error("undefined variable '{name}'")]
+#[diagnostic(help("{help}"))]
+pub struct UndefinedVariable {
+    name: String,
+    #[source_code]
+    src: NamedSource<String>,
+    #[label("not defined")]
+    span: Option<SourceSpan>,
+    help: String,
+}
+
+#[derive(Debug, Diagnostic, thiserror::Error)]
+#[error("invalid config: {message}")]
+pub struct InvalidConfig {
+    message: String,
+    /// Left out for decrypted files, so secrets never end up in diagnostics
+    #[source_code]
+    src: Option<NamedSource<String>>,
+    #[label("{label}")]
+    span: Option<SourceSpan>,
+    label: String,
+    #[help]
+    help: Option<String>,
+}
+
+#[derive(Debug, Diagnostic, thiserror::Error)]
+pub enum Error {
+    #[error("I/O error")]
+    IO(#[from] std::io::Error),
+    #[error("substitution failed")]
+    Subst(#[from] subst::Error),
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    UndefinedVariable(Box<UndefinedVariable>),
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    Invalid(Box<InvalidConfig>),
+    #[error("failed to deserialize")]
+    Yaml(#[from] serde_yml::Error),
+    #[error("background task failed")]
+    Join(#[from] tokio::task::JoinError),
+    #[error("profile '{0}' does not exist")]
+    Profile(String),
+    #[error("invalid image tag {0:?}")]
+    #[diagnostic(help(
+        "tags must start with a letter, digit or underscore and be at most 128 characters, check `tagFormat` and `artifactTagFormats`"
+    ))]
+    InvalidTag(String),
+    #[error("invalid build timestamp format: {0:?}")]
+    TimestampFormat(TimestampFormat),
+    #[error("worktree has uncommitted changes")]
+    #[diagnostic(help("commit or stash your changes, `forbidDirty` is enabled"))]
+    Dirty,
+    #[error("commit {0} has not been pushed to a remote")]
+    #[diagnostic(help("push your changes, `forbidDirty` is enabled"))]
+    Unpushed(String),
+    #[error("missing required environment variables: {}", .0.join(", "))]
+    #[diagnostic(help("set the variables listed under `requiredEnv` before running steiger"))]
+    MissingEnv(Vec<String>),
+    #[error("no commit found for path '{0}'")]
+    #[diagnostic(help("`gitLastCommitFor` requires a git repository and a tracked path"))]
+    LastCommit(String),
+    #[error("release '{release}' depends on unknown release '{dependency}'")]
+    UnknownDependency { release: String, dependency: String },
+    #[error("releases have circular dependencies: {}", .0.join(", "))]
+    #[diagnostic(help("check `dependsOn` of the listed releases"))]
+    DependencyCycle(Vec<String>),
+    #[error("invalid preview id '{0}'")]
+    #[diagnostic(help(
+        "use at most 20 lowercase letters, digits and dashes, starting with a letter or digit"
+    ))]
+    PreviewId(String),
+    #[error("unable to verify worktree state")]
+    #[diagnostic(help(
+        "`forbidDirty` requires a git repository and can't be combined with --no-git"
+    ))]
+    NoGitState,
+    #[error("failed to read values file '{}'", .0.display())]
+    ValuesFile(PathBuf, #[source] std::io::Error),
+    #[error("failed to decrypt values file '{}': {stderr}", path.display())]
+    #[diagnostic(help(
+        "values files with a `sops` key are decrypted using `sops --decrypt`, check that it's installed and has access to the key"
+    ))]
+    Decrypt { path: PathBuf, stderr: String },
+    #[error("no age key found to decrypt '{0}'")]
+    #[diagnostic(help(
+        "set STEIGER_AGE_KEY_FILE or SOPS_AGE_KEY_FILE to the age key file, or put it in ~/.config/sops/age/keys.txt"
+    ))]
+    NoAgeKey(String),
+    #[error("failed to run age to decrypt '{0}'")]
+    #[diagnostic(help(
+        "encrypted fields are decrypted using `age --decrypt`, check that it's installed"
+    ))]
+    Age(String, #[source] std::io::Error),
+    #[error("failed to decrypt '{field}': {stderr}")]
+    #[diagnostic(help(
+        "check that the age key is one of the recipients the field was encrypted to"
+    ))]
+    DecryptField { field: String, stderr: String },
+    #[error("failed to read '{}'", .0.display())]
+    #[diagnostic(help("paths of `${{file:...}}` are relative to the config file"))]
+    TemplateFile(PathBuf, #[source] std::io::Error),
+    #[error("'{}' is outside the config directory", .0.display())]
+    #[diagnostic(help("`${{file:...}}` can only read files in the directory of the config file"))]
+    FileOutside(PathBuf),
+    #[error("'{}' is larger than {} KiB", .0.display(), MAX_TEMPLATE_FILE_SIZE / 1024)]
+    FileTooLarge(PathBuf),
+    #[error("'{}' isn't valid UTF-8", .0.display())]
+    #[diagnostic(help("embed binary files with `${{base64:${{file:...}}}}`"))]
+    FileEncoding(PathBuf),
+    #[error("'{0}' is defined more than once after substituting variables")]
+    DuplicateKey(String),
+    #[error("invalid {kind} '{name}'")]
+    #[diagnostic(help(
+        "use at most {max} lowercase letters, digits and dashes, starting and ending with a letter or digit; `${{gitRefSlug}}` is a safe form of the branch name"
+    ))]
+    InvalidName {
+        kind: &'static str,
+        name: String,
+        max: usize,
+    },
+    #[error("unknown release channel '{0}'")]
+    #[diagnostic(help("channels must be listed in `channels` when it's set"))]
+    UnknownChannel(String),
+    #[error("can't set '{0}'")]
+    #[diagnostic(help("one of its parents is set to a value that isn't a mapping"))]
+    Override(String),
+    #[error("config has no build entries or releases")]
+    #[diagnostic(help("add artifacts under `build`, releases under `deploy`, or both"))]
+    Empty,
+    #[error("config has no build entries")]
+    #[diagnostic(help(
+        "this config only deploys, use `steiger deploy --input-file` with the output file of another project"
+    ))]
+    NoBuild,
+}
+
+/// Number of single character edits to turn `a` into `b`
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut previous = row[0];
+        row[0] = i + 1;
+
+        for (j, cb) in b.iter().enumerate() {
+            let current = row[j + 1];
+            row[j + 1] = (previous + usize::from(ca != *cb))
+                .min(row[j] + 1)
+                .min(current + 1);
+            previous = current;
+        }
+    }
+
+    row[b.len()]
+}
+
+/// Points at the first use of the variable and suggests a similarly named one
+fn undefined_variable(
+    path: &Path,
+    data: &str,
+    name: String,
+    vars: &HashMap<String, String>,
+) -> Error {
+    let span = data
+        .find(&format!("${{{name}}}"))
+        .map(|offset| SourceSpan::from((offset, name.len() + 3)));
+    let help = match vars
+        .keys()
+        .map(|var| (edit_distance(&name, var), var))
+        .filter(|(distance, _)| *distance <= 2)
+        .min()
+    {
+        Some((_, var)) => format!("did you mean `${{{var}}}`?"),
+        None => {
+            format!("define it in the profile, or use a default value like `${{{name}:default}}`")
+        }
+    };
+
+    Error::UndefinedVariable(Box::new(UndefinedVariable {
+        name,
+        src: NamedSource::new(path.display().to_string(), data.to_string()),
+        span,
+        help,
+    }))
+}
+
+/// Backticked names following `prefix` in a serde error message, e.g. the expected fields in
+/// "unknown field `contxt`, expected one of `context`, `dockerfile`"
+fn quoted_after<'a>(message: &'a str, prefix: &str) -> Vec<&'a str> {
+    let Some(start) = message.find(prefix) else {
+        return vec![];
+    };
+
+    message[start + prefix.len()..]
+        .split('`')
+        .skip(1)
+        .step_by(2)
+        .collect()
+}
+
+/// Finds `key:` at the start of a line (ignoring indentation and list dashes) after `from`
+fn find_key(data: &str, from: usize, key: &str) -> Option<usize> {
+    let needle = format!("{key}:");
+
+    data[from..]
+        .match_indices(&needle)
+        .map(|(offset, _)| from + offset)
+        .find(|&offset| {
+            let line_start = data[..offset].rfind('\n').map_or(0, |i| i + 1);
+            data[line_start..offset]
+                .chars()
+                .all(|c| c == ' ' || c == '-')
+        })
+}
+
+/// Points at the deepest key of `keys` that can be found in the config file
+fn key_span(data: &str, keys: &[&str]) -> Option<SourceSpan> {
+    let mut span = None;
+    let mut from = 0;
+
+    for key in keys {
+        let Some(offset) = find_key(data, from, key) else {
+            break;
+        };
+
+        span = Some(SourceSpan::from((offset, key.len())));
+        from = offset + key.len();
+    }
+
+    span
+}
+
+/// Explains unknown keys and values by suggesting a similar one, or listing the valid ones
+fn invalid_config_help(message: &str) -> Option<String> {
+    let (kind, name) = ["field", "variant"].into_iter().find_map(|kind| {
+        quoted_after(message, &format!("unknown {kind} "))
+            .first()
+            .map(|name| (kind, *name))
+    })?;
+    let expected = quoted_after(message, "expected");
+
+    if let Some((_, suggestion)) = expected
+        .iter()
+        .map(|valid| (edit_distance(name, valid), valid))
+        .filter(|(distance, _)| *distance <= 3)
+        .min()
+    {
+        return Some(format!("did you mean `{suggestion}`?"));
+    }
+
+    let valid = match kind {
+        "field" => "keys",
+        _ => "values",
+    };
+
+    match expected.is_empty() {
+        true => Some(format!("remove `{name}`, no {valid} are expected here")),
+        false => Some(format!("valid {valid} are: {}", expected.join(", "))),
+    }
+}
+
+/// Points at the location of a deserialization error in the config file, `keys` are the
+/// mapping keys leading up to the value that failed to deserialize
+fn invalid_config(path: &Path, data: &str, keys: &[&str], field: &str, message: String) -> Error {
+    let unknown = quoted_after(&message, "unknown field ");
+    let span = match unknown.first() {
+        Some(name) => key_span(data, &[keys, &[name]].concat()),
+        None => key_span(data, keys),
+    };
+    let label = match field.is_empty() {
+        true => "here".to_string(),
+        false => format!("in `{field}`"),
+    };
+
+    Error::Invalid(Box::new(InvalidConfig {
+        help: invalid_config_help(&message),
+        message,
+        src: Some(NamedSource::new(
+            path.display().to_string(),
+            data.to_string(),
+        )),
+        span,
+        label,
+    }))
+}
+
+/// Parses the config file, pointing at the offending line on syntax errors
+fn parse(path: &Path, data: &str) -> Result<Value, Error> {
+    serde_yml::from_str::<Value>(data).map_err(|e| {
+        let message = e.to_string();
+        let span = e
+            .location()
+            .map(|location| SourceSpan::from((location.index(), 1)));
+
+        Error::Invalid(Box::new(InvalidConfig {
+            message: message
+                .split_once(" at line ")
+                .map_or(message.as_str(), |(message, _)| message)
+                .to_string(),
+            src: Some(NamedSource::new(
+                path.display().to_string(),
+                data.to_string(),
+            )),
+            span,
+            label: "invalid YAML".to_string(),
+            help: Some("check the indentation and quoting around this line".to_string()),
+        }))
+    })
+}
+
+/// Deserializes `value` found at `keys`, pointing at the offending key on errors
+fn deserialize<T: serde::de::DeserializeOwned>(
+    path: &Path,
+    data: &str,
+    keys: &[&str],
+    value: Value,
+) -> Result<T, Error> {
+    serde_path_to_error::deserialize(value).map_err(|e| {
+        let field = match e.path().iter().next() {
+            None => keys.join("."),
+            Some(_) if keys.is_empty() => e.path().to_string(),
+            Some(_) => format!("{}.{}", keys.join("."), e.path()),
+        };
+        // List items can't be told apart by key, so only the keys up to the first list are used
+        let keys = keys
+            .iter()
+            .copied()
+            .chain(e.path().iter().map_while(|segment| match segment {
+                serde_path_to_error::Segment::Map { key } => Some(key.as_str()),
+                _ => None,
+            }))
+            .collect::<Vec<_>>();
+
+        invalid_config(path, data, &keys, &field, e.inner().to_string())
+    })
+}
+
+/// Profile variables that aren't used outside of the profiles, as profile and variable name
+pub fn unused_variables(config: &Value) -> Result<Vec<(String, String)>, serde_yml::Error> {
+    let Some(profiles) = config.get("profiles").and_then(Value::as_mapping) else {
+        return Ok(vec![]);
+    };
+
+    let mut rest = config.clone();
+    if let Some(mapping) = rest.as_mapping_mut() {
+        mapping.remove("profiles");
+    }
+
+    let text = serde_yml::to_string(&rest)?;
+    // A profile without variables only has the keys of its settings, like `forbidDirty`
+    let settings = serde_yml::to_value(Profile::default())?;
+    let mut unused = vec![];
+
+    for (profile, vars) in profiles.iter() {
+        let (Some(profile), Some(vars)) = (profile.as_str(), vars.as_mapping()) else {
+            continue;
+        };
+
+        for name in vars.keys().filter_map(Value::as_str) {
+            if settings.get(name).is_some() {
+                continue;
+            }
+
+            if !uses_variable(&text, name) {
+                unused.push((profile.to_string(), name.to_string()));
+            }
+        }
+    }
+
+    Ok(unused)
+}
+
+/// Decrypts a SOPS-encrypted values file
+async fn decrypt(path: &Path) -> Result<String, Error> {
+    let mut cmd = tokio::process::Command::new("sops");
+    cmd.args(["--decrypt", "--output-type", "yaml"]).arg(path);
+
+    let audit = audit::Pending::start(cmd.as_std());
+    let output = cmd
+        .output()
+        .await
+        .map_err(|e| Error::ValuesFile(path.to_path_buf(), e))?;
+    audit.finish(output.status.code());
+
+    if !output.status.success() {
+        return Err(Error::Decrypt {
+            path: path.to_path_buf(),
+            stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        });
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// First line of values encrypted with `age --armor`
+const AGE_HEADER: &str = "-----BEGIN AGE ENCRYPTED FILE-----";
+
+/// Environment variables pointing to the age key file, the second one is shared with SOPS
+const AGE_KEY_FILE_VARS: [&str; 2] = ["STEIGER_AGE_KEY_FILE", "SOPS_AGE_KEY_FILE"];
+
+/// Age key file, from the environment or the default location of SOPS
+fn age_key_file() -> Option<PathBuf> {
+    find_age_key_file(|name| env::var_os(name), |path| path.is_file())
+}
+
+/// Like [`age_key_file`], with the lookup of variables and files passed in
+fn find_age_key_file(
+    var: impl Fn(&str) -> Option<OsString>,
+    is_file: impl Fn(&Path) -> bool,
+) -> Option<PathBuf> {
+    if let Some(path) = AGE_KEY_FILE_VARS.into_iter().find_map(&var) {
+        return Some(PathBuf::from(path));
+    }
+
+    let home = var("HOME").map(PathBuf::from);
+    let config_dirs = [
+        var("XDG_CONFIG_HOME").map(PathBuf::from),
+        home.as_ref().map(|home| home.join(".config")),
+        home.map(|home| home.join("Library/Application Support")),
+    ];
+
+    config_dirs
+        .into_iter()
+        .flatten()
+        .map(|dir| dir.join("sops/age/keys.txt"))
+        .find(|path| is_file(path))
+}
+
+/// Collects the fields holding a value encrypted with age, keyed by their path
+fn encrypted_fields<'a>(
+    field: String,
+    value: &'a mut Value,
+    fields: &mut Vec<(String, &'a mut Value)>,
+) {
+    if value
+        .as_str()
+        .is_some_and(|s| s.trim_start().starts_with(AGE_HEADER))
+    {
+        fields.push((field, value));
+        return;
+    }
+
+    let join = |key: String| match field.is_empty() {
+        true => key,
+        false => format!("{field}.{key}"),
+    };
+
+    match value {
+        Value::Mapping(map) => {
+            for (key, value) in map.iter_mut() {
+                let key = match key {
+                    Value::String(key) => key.clone(),
+                    key => serde_yml::to_string(key)
+                        .unwrap_or_default()
+                        .trim()
+                        .to_string(),
+                };
+                encrypted_fields(join(key), value, fields);
+            }
+        }
+        Value::Sequence(seq) => {
+            for (i, value) in seq.iter_mut().enumerate() {
+                encrypted_fields(format!("{field}[{i}]"), value, fields);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Decrypts an armored age value with the key in `key_file`
+async fn decrypt_field(field: &str, armored: &str, key_file: &Path) -> Result<String, Error> {
+    let mut cmd = tokio::process::Command::new("age");
+    cmd.arg("--decrypt")
+        .arg("--identity")
+        .arg(key_file)
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped());
+
+    let audit = audit::Pending::start(cmd.as_std());
+    let age = |e| Error::Age(field.to_string(), e);
+    let mut child = cmd.spawn().map_err(age)?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(armored.as_bytes()).await.map_err(age)?;
+    }
+
+    let output = child.wait_with_output().await.map_err(age)?;
+    audit.finish(output.status.code());
+
+    if !output.status.success() {
+        return Err(Error::DecryptField {
+            field: field.to_string(),
+            stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        });
+    }
+
+    let mut value = String::from_utf8_lossy(&output.stdout).into_owned();
+
+    // Values piped through `echo` end with a newline that isn't part of the secret
+    if value.ends_with('\n') {
+        value.pop();
+    }
+
+    Ok(value)
+}
+
+/// Replaces the fields encrypted with age by their decrypted value
+async fn decrypt_fields(config: &mut Value) -> Result<(), Error> {
+    let mut fields = vec![];
+    encrypted_fields(String::new(), config, &mut fields);
+
+    let Some((first, _)) = fields.first() else {
+        return Ok(());
+    };
+    let key_file = age_key_file().ok_or_else(|| Error::NoAgeKey(first.clone()))?;
+
+    for (field, value) in fields {
+        let armored = value.as_str().unwrap_or_default().to_string();
+        let decrypted = decrypt_field(&field, &armored, &key_file).await?;
+
+        audit::add_secret(&decrypted);
+        *value = Value::String(decrypted);
+    }
+
+    Ok(())
+}
+
+/// Drops the source of an error in a decrypted file, keeping the file, line and field it
+/// points at in the message
+fn without_source(error: Error) -> Error {
+    let Error::Invalid(mut invalid) = error else {
+        return error;
+    };
+    let Some(src) = invalid.src.take() else {
+        return Error::Invalid(invalid);
+    };
+
+    let location = match invalid.span.take() {
+        Some(span) => {
+            let data = src.inner();
+            let line = data[..span.offset().min(data.len())].matches('\n').count() + 1;
+            format!("{}:{line}", src.name())
+        }
+        None => src.name().to_string(),
+    };
+    invalid.message = match invalid.label.starts_with("in `") {
+        true => format!("{} {} ({location})", invalid.message, invalid.label),
+        false => format!("{} ({location})", invalid.message),
+    };
+
+    Error::Invalid(invalid)
+}
+
+/// Reads the variables of a values file, decrypting it first when it's encrypted with SOPS.
+/// Returns whether it was decrypted as well.
+async fn read_values(
+    path: &Path,
+    optional: bool,
+) -> Result<(HashMap<String, String>, bool), Error> {
+    let data = match tokio::fs::read_to_string(path).await {
+        Ok(data) => data,
+        Err(e) if optional && e.kind() == std::io::ErrorKind::NotFound => {
+            return Ok((HashMap::new(), false));
+        }
+        Err(e) => return Err(Error::ValuesFile(path.to_path_buf(), e)),
+    };
+    let values = parse(path, &data)?;
+
+    if values.get("sops").is_none() {
+        return Ok((variables(path, &data, values)?, false));
+    }
+
+    // Errors in the decrypted file must not show its content
+    let data = decrypt(path).await?;
+    let values = parse(path, &data)
+        .and_then(|values| variables(path, &data, values))
+        .map_err(without_source)?;
+
+    for value in values.values() {
+        audit::add_secret(value);
+    }
+
+    Ok((values, true))
+}
+
+/// Variables of a values file, only scalar values are allowed
+fn variables(path: &Path, data: &str, values: Value) -> Result<HashMap<String, String>, Error> {
+    if values.is_null() {
+        return Ok(HashMap::new());
+    }
+
+    // Checked here, the type error of serde would include the value
+    if !values.is_mapping() {
+        return Err(invalid_config(
+            path,
+            data,
+            &[],
+            "",
+            "expected a mapping of variables".to_string(),
+        ));
+    }
+
+    deserialize::<HashMap<String, Value>>(path, data, &[], values)?
+        .into_iter()
+        .map(|(name, value)| match value {
+            Value::String(s) => Ok((name, s)),
+            Value::Number(n) => Ok((name, n.to_string())),
+            Value::Bool(b) => Ok((name, b.to_string())),
+            _ => Err(invalid_config(
+                path,
+                data,
+                &[&name],
+                &name,
+                "expected a string, number or boolean".to_string(),
+            )),
+        })
+        .collect()
+}
+
+/// Whether `name` is referenced in the config file, as `$name` or as `${name}` with or without
+/// a default value
+fn uses_variable(data: &str, name: &str) -> bool {
+    let bare = format!("${name}");
+
+    data.contains(&format!("${{{name}}}"))
+        || data.contains(&format!("${{{name}:"))
+        || data.match_indices(&bare).any(|(i, _)| {
+            !data[i + bare.len()..].starts_with(|c: char| c.is_alphanumeric() || c == '_')
+        })
+}
+
+/// Sets the field at `keys` to `value`, returns false when one of its parents isn't a mapping.
+/// The builder type may be part of the path (`build.api.docker.target`) even though builder
+/// fields are flattened.
+fn set_field(current: &mut Value, keys: &[&str], value: Value) -> bool {
+    if current.is_null() {
+        *current = Value::Mapping(Mapping::new());
+    }
+
+    let (Value::Mapping(mapping), [key, rest @ ..]) = (&mut *current, keys) else {
+        return false;
+    };
+
+    if rest.is_empty() {
+        mapping.insert((*key).into(), value);
+        return true;
+    }
+
+    if !mapping.contains_key(*key) && mapping.get("type").and_then(Value::as_str) == Some(key) {
+        return set_field(current, rest, value);
+    }
+
+    set_field(
+        mapping.entry((*key).into()).or_insert(Value::Null),
+        rest,
+        value,
+    )
+}
+
+fn template(vars: &HashMap<String, String>, dir: &Path, config: Value) -> Result<Value, Error> {
+    match config {
+        Value::String(s) => Ok(Value::String(if s.contains('$') {
+            subst::substitute(&expand_functions(&s, vars, dir)?, vars)?
+        } else {
+            s
+        })),
+        Value::Sequence(seq) => seq
+            .into_iter()
+            .map(|c| template(vars, dir, c))
+            .collect::<Result<Vec<_>, _>>()
+            .map(Value::Sequence),
+        Value::Mapping(map) => {
+            let mut templated = Mapping::new();
+
+            for (key, value) in map {
+                let key = template(vars, dir, key)?;
+
+                // Keys can use variables too, e.g. release names, which must stay unique
+                if let Some(name) = key.as_str()
+                    && templated.contains_key(&key)
+                {
+                    return Err(Error::DuplicateKey(name.to_string()));
+                }
+
+                let value = template(vars, dir, value)?;
+                templated.insert(key, value);
+            }
+
+            Ok(Value::Mapping(templated))
+        }
+        _ => Ok(config),
+    }
+}
+
+/// Helm stores releases in secrets with the name as suffix, which limits it to 53 characters
+const MAX_RELEASE_NAME_LEN: usize = 53;
+
+/// Namespaces are DNS labels
+const MAX_NAMESPACE_LEN: usize = 63;
+
+/// Lowercase letters, digits, dashes and dots, starting and ending with a letter or digit
+fn dns_name(name: &str, max_len: usize) -> bool {
+    name.len() <= max_len
+        && name.starts_with(|c: char| c.is_ascii_lowercase() || c.is_ascii_digit())
+        && name.ends_with(|c: char| c.is_ascii_lowercase() || c.is_ascii_digit())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || matches!(c, '-' | '.'))
+}
+
+/// Suffixes the namespace, dependencies and other names of the release shared between
+/// environments with the preview id
+fn preview_release(release: &mut Release, id: &str) {
+    let suffix = |name: &str| format!("{name}-{id}");
+
+    match release {
+        Release::Helm(helm) => {
+            helm.namespace = helm.namespace.as_deref().map(suffix);
+            helm.depends_on = helm.depends_on.iter().map(|dep| suffix(dep)).collect();
+            helm.preview = Some(id.to_string());
+        }
+        Release::Rollout(rollout) => {
+            rollout.namespace = rollout.namespace.as_deref().map(suffix);
+            rollout.depends_on = rollout.depends_on.iter().map(|dep| suffix(dep)).collect();
+        }
+        Release::Manifests(manifests) => {
+            manifests.namespace = manifests.namespace.as_deref().map(suffix);
+            manifests.depends_on = manifests.depends_on.iter().map(|dep| suffix(dep)).collect();
+        }
+        Release::Compose(compose) => {
+            compose.project = compose.project.as_deref().map(suffix);
+            compose.depends_on = compose.depends_on.iter().map(|dep| suffix(dep)).collect();
+        }
+        // Preview manifests go next to the ones of the shared environment
+        Release::Gitops(gitops) => {
+            gitops.path = suffix(gitops.path.trim_end_matches('/'));
+            gitops.depends_on = gitops.depends_on.iter().map(|dep| suffix(dep)).collect();
+            preview_release(&mut gitops.source, id);
+        }
+    }
+}
+
+/// Maximum length of a tag according to the OCI distribution spec
+const MAX_TAG_LEN: usize = 128;
+
+/// Replaces characters that aren't allowed in tags (e.g. the slash in `feature/foo`) with
+/// dashes and truncates the tag to the maximum length
+fn sanitize_tag(tag: &str) -> String {
+    tag.chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '.' | '-' => c,
+            _ => '-',
+        })
+        .take(MAX_TAG_LEN)
+        .collect()
+}
+
+/// Validates the tag against `[a-zA-Z0-9_][a-zA-Z0-9._-]{0,127}` from the OCI distribution spec
+pub fn validate_tag(tag: &str) -> Result<(), Error> {
+    let valid = tag.len() <= MAX_TAG_LEN
+        && tag.starts_with(|c: char| c.is_ascii_alphanumeric() || c == '_')
+        && tag
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'));
+
+    match valid {
+        true => Ok(()),
+        false => Err(Error::InvalidTag(tag.to_string())),
+    }
+}
+
+fn extract_git_vars(state: git::State) -> HashMap<String, String> {
+    let mut vars = HashMap::new();
+
+    if let Some(commit) = state.commit {
+        let short = commit.get(0..6).unwrap_or(&commit).to_string();
+        vars.insert("gitShortCommit".to_string(), short);
+        vars.insert("gitCommit".to_string(), commit);
+    }
+
+    if let Some(name) = state.tag.as_ref().or(state.branch.as_ref()) {
+        vars.insert("gitRefSlug".to_string(), git::ref_slug(name));
+    }
+
+    if let Some(tag) = state.tag {
+        vars.insert("gitTag".to_string(), tag);
+    }
+
+    if let Some(describe) = state.describe {
+        vars.insert("gitDescribe".to_string(), describe);
+    }
+
+    if let Some(count) = state.commit_count {
+        vars.insert("gitCommitCount".to_string(), count.to_string());
+    }
+
+    if state.dirty {
+        vars.insert("gitDirty".to_string(), "-dirty".to_string());
+    }
+
+    vars
+}
+
+fn format_timestamp(format: &TimestampFormat) -> Option<String> {
+    let now = Utc::now();
+
+    Some(match format {
+        TimestampFormat::Rfc3339 => now.to_rfc3339_opts(SecondsFormat::Secs, true),
+        TimestampFormat::Epoch => now.timestamp().to_string(),
+        TimestampFormat::Custom(format) => {
+            let items = StrftimeItems::new(format).parse().ok()?;
+            now.format_with_items(items.iter()).to_string()
+        }
+    })
+}
+
+const LAST_COMMIT_PREFIX: &str = "${gitLastCommitFor:";
+
+/// Replaces `${gitLastCommitFor:path}` with the last commit touching `path`. This can't be a
+/// regular variable as the argument would be parsed as the default value.
+fn expand_last_commits(mut data: String, no_git: bool) -> Result<String, Error> {
+    while let Some(start) = data.find(LAST_COMMIT_PREFIX) {
+        let Some(len) = data[start..].find('}') else {
+            break;
+        };
+
+        let path = &data[start + LAST_COMMIT_PREFIX.len()..start + len];
+        let commit = (!no_git)
+            .then(|| git::last_commit_for(path))
+            .flatten()
+            .ok_or_else(|| Error::LastCommit(path.to_string()))?;
+
+        data.replace_range(start..=start + len, &commit);
+    }
+
+    Ok(data)
+}
+
+/// Expands `gitLastCommitFor` in every string of `value`
+fn expand_last_commits_in(value: &mut Value, no_git: bool) -> Result<(), Error> {
+    match value {
+        Value::String(s) if s.contains(LAST_COMMIT_PREFIX) => {
+            *s = expand_last_commits(mem::take(s), no_git)?;
+        }
+        Value::Sequence(items) => {
+            for item in items {
+                expand_last_commits_in(item, no_git)?;
+            }
+        }
+        Value::Mapping(mapping) => {
+            for (_, value) in mapping.iter_mut() {
+                expand_last_commits_in(value, no_git)?;
+            }
+        }
+        Value::Tagged(tagged) => expand_last_commits_in(&mut tagged.value, no_git)?,
+        _ => {}
+    }
+
+    Ok(())
+}
+
+/// Largest file `${file:path}` reads, it's meant for small scripts and certificates
+const MAX_TEMPLATE_FILE_SIZE: u64 = 1024 * 1024;
+
+const FILE_FUNCTION: &str = "${file:";
+const BASE64_FUNCTION: &str = "${base64:";
+
+/// Position of the brace closing the expression that starts at `start`
+fn closing_brace(s: &str, start: usize) -> Option<usize> {
+    let mut depth = 0;
+
+    for (i, c) in s[start..].char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' if depth == 1 => return Some(start + i),
+            '}' => depth -= 1,
+            _ => {}
+        }
+    }
+
+    None
+}
+
+/// Escapes the result of a function, so substituting variables afterwards leaves it as is
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+
+    for c in value.chars() {
+        if matches!(c, '\\' | '$' | '{' | '}') {
+            escaped.push('\\');
+        }
+
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+/// Reads a file relative to the config directory, refusing paths that resolve outside of it
+fn read_template_file(dir: &Path, path: &str) -> Result<Vec<u8>, Error> {
+    let path = dir.join(path);
+    let resolved = path
+        .canonicalize()
+        .map_err(|e| Error::TemplateFile(path.clone(), e))?;
+
+    if !resolved.starts_with(dir.canonicalize()?) {
+        return Err(Error::FileOutside(path));
+    }
+
+    let len = std::fs::metadata(&resolved)
+        .map_err(|e| Error::TemplateFile(path.clone(), e))?
+        .len();
+
+    if len > MAX_TEMPLATE_FILE_SIZE {
+        return Err(Error::FileTooLarge(path));
+    }
+
+    std::fs::read(&resolved).map_err(|e| Error::TemplateFile(path, e))
+}
+
+/// Argument of a function, with variables and nested functions evaluated
+fn function_argument(
+    arg: &str,
+    vars: &HashMap<String, String>,
+    dir: &Path,
+) -> Result<String, Error> {
+    Ok(subst::substitute(&expand_functions(arg, vars, dir)?, vars)?)
+}
+
+/// Evaluates `${file:path}` and `${base64:value}`. Unlike variables their argument isn't a
+/// default value, so they're evaluated before substituting variables.
+fn expand_functions(s: &str, vars: &HashMap<String, String>, dir: &Path) -> Result<String, Error> {
+    let mut expanded = String::new();
+    let mut rest = s;
+
+    while let Some((start, function)) = [FILE_FUNCTION, BASE64_FUNCTION]
+        .into_iter()
+        .filter_map(|function| rest.find(function).map(|start| (start, function)))
+        .min()
+    {
+        let Some(end) = closing_brace(rest, start) else {
+            break;
+        };
+        let arg = &rest[start + function.len()..end];
+
+        let value = if function == FILE_FUNCTION {
+            let path = function_argument(arg, vars, dir)?;
+            let data = read_template_file(dir, &path)?;
+
+            String::from_utf8(data).map_err(|_| Error::FileEncoding(dir.join(path)))?
+        } else if arg.starts_with(FILE_FUNCTION) && closing_brace(arg, 0) == Some(arg.len() - 1) {
+            // Encode the file itself, so binary files can be embedded as well
+            let path = function_argument(&arg[FILE_FUNCTION.len()..arg.len() - 1], vars, dir)?;
+            BASE64_STANDARD.encode(read_template_file(dir, &path)?)
+        } else {
+            BASE64_STANDARD.encode(function_argument(arg, vars, dir)?)
+        };
+
+        expanded.push_str(&rest[..start]);
+        expanded.push_str(&escape(&value));
+        rest = &rest[end + 1..];
+    }
+
+    expanded.push_str(rest);
+
+    Ok(expanded)
+}
+
+/// Environment variables holding a build number in common CI systems
+const BUILD_NUMBER_VARS: [&str; 5] = [
+    "GITHUB_RUN_NUMBER",
+    "CI_PIPELINE_IID",
+    "BUILDKITE_BUILD_NUMBER",
+    "CIRCLE_BUILD_NUM",
+    "BUILD_NUMBER",
+];
+
+/// Number of the pull request being built in common CI systems
+fn pr_number() -> Option<String> {
+    // GitHub only exposes it through the ref of the merge commit, `refs/pull/<number>/merge`
+    if let Ok(github_ref) = env::var("GITHUB_REF")
+        && let Some(number) = github_ref
+            .strip_prefix("refs/pull/")
+            .and_then(|rest| rest.split('/').next())
+    {
+        return Some(number.to_string());
+    }
+
+    [
+        "CI_MERGE_REQUEST_IID",
+        "BUILDKITE_PULL_REQUEST",
+        "CIRCLE_PR_NUMBER",
+    ]
+    .iter()
+    .filter_map(|name| env::var(name).ok())
+    // Buildkite sets `false` outside of pull requests
+    .find(|number| !number.is_empty() && number != "false")
+}
+
+/// Uses the build number provided by CI, or the local counter in `.steiger/build-number`, which
+/// is incremented when `bump` is set
+async fn build_number(dir: &Path, bump: bool) -> Result<String, std::io::Error> {
+    find_build_number(dir, bump, |name| env::var(name).ok()).await
+}
+
+/// Like [`build_number`], with the lookup of variables passed in
+async fn find_build_number(
+    dir: &Path,
+    bump: bool,
+    var: impl Fn(&str) -> Option<String>,
+) -> Result<String, std::io::Error> {
+    if let Some(number) = BUILD_NUMBER_VARS.into_iter().find_map(var) {
+        return Ok(number);
+    }
+
+    let path = dir.join(".steiger").join("build-number");
+    let current = match tokio::fs::read_to_string(&path).await {
+        Ok(content) => content.trim().parse::<u64>().unwrap_or_default(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+        Err(e) => return Err(e),
+    };
+
+    if !bump {
+        return Ok(current.to_string());
+    }
+
+    let next = (current + 1).to_string();
+
+    tokio::fs::create_dir_all(dir.join(".steiger")).await?;
+    tokio::fs::write(&path, &next).await?;
+
+    Ok(next)
+}
+
+/// Fails with all variables that are unset or empty, rather than just the first one
+pub fn check_env<'a>(names: impl IntoIterator<Item = &'a String>) -> Result<(), Error> {
+    let mut missing = names
+        .into_iter()
+        .filter(|name| !env::var(name).is_ok_and(|value| !value.is_empty()))
+        .cloned()
+        .collect::<Vec<_>>();
+
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    missing.sort();
+    missing.dedup();
+
+    Err(Error::MissingEnv(missing))
+}
+
+impl Config {
+    /// Checks that there is something to build, deploy-only configs have no `build` section
+    pub fn check_build(&self) -> Result<(), Error> {
+        match self.build.is_empty() {
+            true => Err(Error::NoBuild),
+            false => Ok(()),
+        }
+    }
+
+    /// SHA-256 of the resolved config, as recorded in the metadata attached to pushed images
+    pub fn hash(&self) -> Result<String, serde_json::Error> {
+        // Maps of a JSON value are sorted, so the hash doesn't depend on iteration order
+        let value = serde_json::to_value(self)?;
+
+        Ok(hex::encode(Sha256::digest(serde_json::to_vec(&value)?)))
+    }
+
+    /// Checks the environment required for building, `config` and `lint` work without it
+    pub fn check_build_env(&self) -> Result<(), Error> {
+        check_env(&self.required_env)
+    }
+
+    /// Checks the environment required by all releases, so deploys fail before anything is built
+    pub fn check_deploy_env(&self) -> Result<(), Error> {
+        check_env(self.deploy.values().flat_map(Release::required_env))
+    }
+
+    /// Checks that the release channel is one of `channels`, when those are listed
+    pub fn check_channel(&self) -> Result<(), Error> {
+        match self.channel {
+            Some(ref channel) if !self.channels.is_empty() && !self.channels.contains(channel) => {
+                Err(Error::UnknownChannel(channel.clone()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Release names and namespaces can come from template variables, so they're checked
+    /// before anything is built rather than when helm or the cluster rejects them
+    pub fn check_names(&self) -> Result<(), Error> {
+        for (name, release) in self.deploy.iter() {
+            if let Release::Helm(_) = release.source()
+                && !dns_name(name, MAX_RELEASE_NAME_LEN)
+            {
+                return Err(Error::InvalidName {
+                    kind: "release name",
+                    name: name.clone(),
+                    max: MAX_RELEASE_NAME_LEN,
+                });
+            }
+
+            let namespaces = release.namespace().into_iter().chain(
+                release
+                    .targets()
+                    .iter()
+                    .filter_map(|t| t.namespace.as_ref()),
+            );
+
+            for namespace in namespaces {
+                if !dns_name(namespace, MAX_NAMESPACE_LEN) || namespace.contains('.') {
+                    return Err(Error::InvalidName {
+                        kind: "namespace",
+                        name: namespace.clone(),
+                        max: MAX_NAMESPACE_LEN,
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Channel that follows `channel` in the promotion order, `None` for the last channel
+    pub fn next_channel(&self, channel: &str) -> Option<&str> {
+        self.channels
+            .iter()
+            .skip_while(|c| *c != channel)
+            .nth(1)
+            .map(String::as_str)
+    }
+
+    /// Isolates releases and tags for a preview environment by suffixing them with `id`
+    pub fn apply_preview(&mut self, id: &str) -> Result<(), Error> {
+        // The suffix ends up in release names and namespaces, which are limited DNS labels
+        let valid = id.len() <= 20
+            && id.starts_with(|c: char| c.is_ascii_lowercase() || c.is_ascii_digit())
+            && id
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+
+        if !valid {
+            return Err(Error::PreviewId(id.to_string()));
+        }
+
+        let suffix = |name: &str| format!("{name}-{id}");
+
+        self.tag_format = suffix(&self.tag_format);
+        validate_tag(&self.tag_format)?;
+
+        if let Some(ref mut deployment) = self.github_deployment {
+            deployment.environment = suffix(&deployment.environment);
+            deployment.transient = true;
+        }
+
+        for tag in self.artifact_tag_formats.values_mut() {
+            *tag = suffix(tag);
+            validate_tag(tag)?;
+        }
+
+        self.deploy = mem::take(&mut self.deploy)
+            .into_iter()
+            .map(|(name, mut release)| {
+                preview_release(&mut release, id);
+                (suffix(&name), release)
+            })
+            .collect();
+
+        self.check_names()
+    }
+
+    /// Repository paths of artifacts with a custom `imageName`, keyed by artifact
+    pub fn image_names(&self) -> Result<HashMap<String, String>, Error> {
+        let mut names = HashMap::new();
+
+        for (name, build) in self.build.iter() {
+            let Some(ref image_name) = build.image_name else {
+                continue;
+            };
+
+            for artifact in build.kind.artifacts(name) {
+                let vars = HashMap::from([(ARTIFACT_VAR.to_string(), artifact.clone())]);
+                names.insert(artifact, subst::substitute(image_name, &vars)?);
+            }
+        }
+
+        Ok(names)
+    }
+
+    /// Directories the OCI layouts of artifacts are kept in, keyed by artifact
+    pub fn output_paths(&self) -> HashMap<String, (PathBuf, OutputCleanup)> {
+        self.build
+            .iter()
+            .filter_map(|(name, build)| Some((name, build.output_path.as_ref()?, build)))
+            .flat_map(|(name, path, build)| {
+                build.kind.artifacts(name).into_iter().map(|artifact| {
+                    let dir = path.join(&artifact);
+                    (artifact, (dir, build.output_cleanup))
+                })
+            })
+            .collect()
+    }
+
+    /// Stores of artifacts that aren't pushed to the registry, keyed by artifact
+    pub fn artifact_stores(&self) -> HashMap<String, ArtifactStore> {
+        self.build
+            .iter()
+            .filter(|(_, build)| !matches!(build.store, None | Some(ArtifactStore::Registry)))
+            .flat_map(|(name, build)| {
+                build
+                    .kind
+                    .artifacts(name)
+                    .into_iter()
+                    .filter_map(|artifact| Some((artifact, build.store.clone()?)))
+            })
+            .collect()
+    }
+
+    /// Artifacts that are loaded into the local cluster instead of pushed, see `push`
+    pub fn local_artifacts(&self) -> BTreeSet<String> {
+        self.build
+            .iter()
+            .filter(|(_, build)| !build.push.unwrap_or(self.push))
+            .flat_map(|(name, build)| build.kind.artifacts(name))
+            .collect()
+    }
+
+    /// Keeps the build entries producing any of `artifacts`, an entry still builds all of its
+    /// artifacts
+    pub fn retain_artifacts(&mut self, artifacts: &BTreeSet<String>) {
+        self.build.retain(|name, build| {
+            build
+                .kind
+                .artifacts(name)
+                .iter()
+                .any(|artifact| artifacts.contains(artifact))
+        });
+    }
+
+    /// Keeps the releases in `releases`, dependencies on other releases are dropped
+    pub fn retain_releases(&mut self, releases: &BTreeSet<String>) {
+        self.deploy.retain(|name, _| releases.contains(name));
+
+        for release in self.deploy.values_mut() {
+            release
+                .depends_on_mut()
+                .retain(|dependency| releases.contains(dependency));
+        }
+    }
+
+    /// Groups releases in waves, every release only depends on releases in earlier waves
+    pub fn release_order(&self) -> Result<Vec<Vec<String>>, Error> {
+        for (name, release) in self.deploy.iter() {
+            if let Some(dependency) = release
+                .depends_on()
+                .iter()
+                .find(|dependency| !self.deploy.contains_key(*dependency))
+            {
+                return Err(Error::UnknownDependency {
+                    release: name.clone(),
+                    dependency: dependency.clone(),
+                });
+            }
+        }
+
+        let mut remaining = self.deploy.keys().cloned().collect::<BTreeSet<_>>();
+        let mut waves = vec![];
+
+        while !remaining.is_empty() {
+            let wave = remaining
+                .iter()
+                .filter(|name| {
+                    self.deploy[*name]
+                        .depends_on()
+                        .iter()
+                        .all(|dependency| !remaining.contains(dependency))
+                })
+                .cloned()
+                .collect::<Vec<_>>();
+
+            if wave.is_empty() {
+                return Err(Error::DependencyCycle(remaining.into_iter().collect()));
+            }
+
+            for name in wave.iter() {
+                remaining.remove(name);
+            }
+
+            waves.push(wave);
+        }
+
+        Ok(waves)
+    }
+}
+
+#[derive(Debug, Diagnostic, thiserror::Error)]
+pub enum LocateError {
+    #[error("I/O error")]
+    IO(#[from] std::io::Error),
+    #[error("{path} does not exist")]
+    DoesNotExist { path: PathBuf },
+    #[error("config file not found")]
+    #[diagnostic(help("make sure you're in the right directory or create a new `steiger.yml`"))]
+    NotFound,
+}
+
+pub fn locate(dir: Option<&PathBuf>, config: Option<&PathBuf>) -> Result<PathBuf, LocateError> {
+    let base = dir
+        .map(|path| path.as_ref())
+        .unwrap_or_else(|| Path::new("."));
+
+    if let Some(config) = config {
+        let path = base.join(config);
+        if path.try_exists()? {
+            return Ok(std::path::absolute(path)?);
+        } else {
+            return Err(LocateError::DoesNotExist { path });
+        }
+    }
+
+    for file_name in ["steiger.yml", "steiger.yaml"] {
+        let path = base.join(file_name);
+        if path.try_exists()? {
             return Ok(std::path::absolute(path)?);
         }
     }
@@ -208,30 +2526,462 @@ pub fn locate(dir: Option<&PathBuf>, config: Option<&PathBuf>) -> Result<PathBuf
     Err(LocateError::NotFound)
 }
 
+async fn git_state(no_git: bool, history: git::History) -> git::State {
+    if no_git {
+        return git::State::default();
+    }
+
+    match git::state(history).await {
+        Ok(state) if state.commit.is_some() => state,
+        Ok(state) => {
+            ui::warn(
+                "no git repository found, git variables will be empty (use --no-git to silence)",
+            );
+            state
+        }
+        Err(e) => {
+            ui::warn(format!(
+                "failed to read git state, git variables will be empty: {e}"
+            ));
+            git::State::default()
+        }
+    }
+}
+
+/// Current time in the configured `buildTimestampFormat`
+fn build_timestamp(path: &Path, data: &str, config: &Value) -> Result<String, Error> {
+    let format = config
+        .get("buildTimestampFormat")
+        .cloned()
+        .map(|value| deserialize::<TimestampFormat>(path, data, &["buildTimestampFormat"], value))
+        .transpose()?
+        .unwrap_or_default();
+
+    format_timestamp(&format).ok_or(Error::TimestampFormat(format))
+}
+
+/// Templates the parsed config with `vars` and validates the result
+fn resolve(
+    path: &Path,
+    data: &str,
+    config: Value,
+    vars: &HashMap<String, String>,
+) -> Result<Config, Error> {
+    let config = substitute(path, data, config, vars)?;
+
+    into_config(path, data, config, vars)
+}
+
+/// Fills in the variables and functions of the config
+fn substitute(
+    path: &Path,
+    data: &str,
+    config: Value,
+    vars: &HashMap<String, String>,
+) -> Result<Value, Error> {
+    // Fill in empty git variables so `${gitCommit}` still resolves outside a repository,
+    // the default tag format relies on them being unset to fall back to `unknown`
+    let mut template_vars = GIT_VARS
+        .iter()
+        .map(|name| (name.to_string(), String::new()))
+        .collect::<HashMap<_, _>>();
+    template_vars.extend(vars.clone());
+    template_vars.insert(ARTIFACT_VAR.to_string(), format!("${{{ARTIFACT_VAR}}}"));
+
+    // Files read by `${file:path}` are relative to the config
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    match template(&template_vars, dir, config) {
+        Ok(config) => Ok(config),
+        Err(Error::Subst(subst::Error::NoSuchVariable(e))) => {
+            Err(undefined_variable(path, data, e.name, &template_vars))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Deserializes and validates the substituted config
+fn into_config(
+    path: &Path,
+    data: &str,
+    config: Value,
+    vars: &HashMap<String, String>,
+) -> Result<Config, Error> {
+    let mut config = deserialize::<Config>(path, data, &[], config)?;
+
+    // Checked here, formatting the date with an invalid format panics while building
+    if let Some(TagStrategy::DateSha { ref format }) = config.tag_strategy
+        && StrftimeItems::new(format).parse().is_err()
+    {
+        return Err(invalid_config(
+            path,
+            data,
+            &["tagStrategy", "format"],
+            "format",
+            format!("invalid date format `{format}`"),
+        ));
+    }
+
+    if config.tag_format.is_empty() {
+        config.tag_format = subst::substitute(DEFAULT_TAG_FORMAT, vars)?;
+    }
+
+    config.tag_format = sanitize_tag(&config.tag_format);
+    validate_tag(&config.tag_format)?;
+
+    for tag in config.artifact_tag_formats.values_mut() {
+        *tag = sanitize_tag(tag);
+        validate_tag(tag)?;
+    }
+
+    if config.build.is_empty() && config.deploy.is_empty() {
+        return Err(Error::Empty);
+    }
+
+    config.release_order()?;
+    config.check_names()?;
+    config.image_names()?;
+
+    Ok(config)
+}
+
+impl FromStr for Config {
+    type Err = Error;
+
+    /// Parses a config like `load_from_path` does, without a profile, git state or build
+    /// number. Meant for validating configs, e.g. in tests of other tools.
+    fn from_str(data: &str) -> Result<Self, Error> {
+        let path = Path::new("steiger.yml");
+        let config = parse(path, data)?;
+        let vars = HashMap::from([
+            (
+                "buildTimestamp".to_string(),
+                build_timestamp(path, data, &config)?,
+            ),
+            ("buildNumber".to_string(), "0".to_string()),
+        ]);
+
+        let config = resolve(path, data, config, &vars)?;
+        config.check_channel()?;
+
+        Ok(config)
+    }
+}
+
+/// What a command loads the config for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Purpose {
+    /// Only reading the config, e.g. `config diff` or `lint`. Values encrypted with age are
+    /// left encrypted, so no key is needed
+    Read,
+    /// Deploying or inspecting the output of a build, the build number isn't incremented
+    Deploy,
+    /// Building with the config, increments the local build number when the config uses it
+    Build,
+}
+
 pub async fn load_from_path(
     profile: Option<&str>,
     path: impl AsRef<Path>,
+    no_git: bool,
+    overrides: &[(String, String)],
+    purpose: Purpose,
 ) -> Result<Config, Error> {
-    let mut vars = extract_git_vars(git::state().await?);
-    let data = tokio::fs::read(path).await?;
-    let mut config = serde_yml::from_slice::<Value>(&data)?;
+    let path = path.as_ref();
+    let data = tokio::fs::read_to_string(path).await?;
+    let mut config = parse(path, &data)?;
+    // Variables are looked up in the parsed config, so comments and keys with the name of a
+    // variable don't count
+    let text = serde_yml::to_string(&config)?;
+    let used = |name: &str| {
+        uses_variable(&text, name)
+            || overrides
+                .iter()
+                .any(|(_, value)| uses_variable(value, name))
+    };
+    let history = git::History {
+        describe: used("gitDescribe"),
+        commit_count: used("gitCommitCount"),
+    };
+    let state = git_state(no_git, history).await;
+    let (dirty, pushed, commit) = (state.dirty, state.pushed, state.commit.clone());
+    let mut vars = extract_git_vars(state);
+
+    vars.insert(
+        "buildTimestamp".to_string(),
+        build_timestamp(path, &data, &config)?,
+    );
+
+    // Only builds bump the local counter, and only when a template uses it
+    if uses_variable(&text, "buildNumber") {
+        let dir = path.parent().unwrap_or_else(|| Path::new("."));
+        let bump = purpose == Purpose::Build;
+        vars.insert("buildNumber".to_string(), build_number(dir, bump).await?);
+    }
+
+    let mut forbid_dirty = None;
+    let mut push = None;
+    let mut secret_vars = BTreeSet::new();
+    let mut required_env = vec![];
+    let mut channel = None;
+
+    if let Some(number) = pr_number() {
+        vars.insert("prNumber".to_string(), number);
+    }
 
     if let Some(profile) = profile {
-        let profile = serde_yml::from_value::<Profile>(mem::take(
-            config
-                .get_mut("profiles")
-                .and_then(|profiles| profiles.get_mut(profile))
-                .ok_or_else(|| Error::Profile(profile.to_string()))?,
-        ))?;
+        vars.insert("profile".to_string(), profile.to_string());
+
+        for (_, name) in unused_variables(&config)?
+            .into_iter()
+            .filter(|(unused_in, _)| unused_in == profile)
+        {
+            ui::warn(format!(
+                "variable '{name}' of profile '{profile}' is never used"
+            ));
+        }
 
+        let profile = deserialize::<Profile>(
+            path,
+            &data,
+            &["profiles", profile],
+            mem::take(
+                config
+                    .get_mut("profiles")
+                    .and_then(|profiles| profiles.get_mut(profile))
+                    .ok_or_else(|| Error::Profile(profile.to_string()))?,
+            ),
+        )?;
+
+        forbid_dirty = profile.forbid_dirty;
+        push = profile.push;
+        required_env = profile.required_env;
+        channel = profile.channel;
         vars.extend(profile.vars);
+
+        let dir = path.parent().unwrap_or_else(|| Path::new("."));
+        for values in &profile.values_from {
+            let (values, decrypted) =
+                read_values(&dir.join(values.path()), values.optional()).await?;
+
+            // Later files take precedence, a plain value replaces a secret one
+            match decrypted {
+                true => secret_vars.extend(values.keys().cloned()),
+                false => secret_vars.retain(|name| !values.contains_key(name)),
+            }
+
+            vars.extend(values);
+        }
     }
 
-    let mut config = serde_yml::from_value::<Config>(template(&vars, config)?)?;
+    let mut set_vars = BTreeSet::new();
 
-    if config.tag_format.is_empty() {
-        config.tag_format = subst::substitute(DEFAULT_TAG_FORMAT, &vars)?;
+    // Overrides from `--set` replace variables that are defined or used, other keys are fields
+    for (key, value) in overrides {
+        if vars.contains_key(key) || uses_variable(&data, key) {
+            vars.insert(key.clone(), value.clone());
+            set_vars.insert(key.clone());
+        } else {
+            let value = serde_yml::from_str(value).unwrap_or_else(|_| value.as_str().into());
+
+            if !set_field(&mut config, &key.split('.').collect::<Vec<_>>(), value) {
+                return Err(Error::Override(key.clone()));
+            }
+        }
+    }
+
+    // The selected profile was merged above. The others aren't used, the history isn't
+    // walked for their paths and their secrets can't be decrypted without their keys.
+    if let Some(mapping) = config.as_mapping_mut() {
+        mapping.remove("profiles");
+    }
+
+    // Walking the history for `gitLastCommitFor` can take a while in large repositories
+    let (config, vars) = tokio::task::spawn_blocking(move || {
+        let mut config = config;
+        expand_last_commits_in(&mut config, no_git)?;
+
+        let mut vars = vars;
+        for value in vars.values_mut() {
+            if value.contains(LAST_COMMIT_PREFIX) {
+                *value = expand_last_commits(mem::take(value), no_git)?;
+            }
+        }
+
+        Ok::<_, Error>((config, vars))
+    })
+    .await??;
+
+    // Decrypted after substituting, so variables in secrets are left as is
+    let mut config = substitute(path, &data, config, &vars)?;
+
+    if purpose != Purpose::Read {
+        decrypt_fields(&mut config).await?;
+    }
+
+    let mut config = into_config(path, &data, config, &vars)?;
+
+    config.required_env.extend(required_env);
+
+    if let Some(forbid_dirty) = forbid_dirty {
+        config.forbid_dirty = forbid_dirty;
+    }
+
+    if let Some(push) = push {
+        config.push = push;
+    }
+
+    if channel.is_some() {
+        config.channel = channel;
     }
 
+    config.check_channel()?;
+
+    if config.forbid_dirty {
+        match commit {
+            None => return Err(Error::NoGitState),
+            Some(_) if dirty => return Err(Error::Dirty),
+            Some(ref commit) if !pushed => return Err(Error::Unpushed(commit.clone())),
+            Some(_) => {}
+        }
+    }
+
+    config.git_commit = commit;
+    config.profile = profile.map(str::to_string);
+    config.set_vars = set_vars;
+    config.secret_vars = secret_vars;
+    config.vars = vars
+        .into_iter()
+        .filter(|(name, _)| {
+            !GIT_VARS.contains(&name.as_str())
+                && !matches!(
+                    name.as_str(),
+                    "buildTimestamp" | "buildNumber" | "profile" | "prNumber"
+                )
+        })
+        .collect();
+
     Ok(config)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ARMORED: &str =
+        "-----BEGIN AGE ENCRYPTED FILE-----\nYWdl\n-----END AGE ENCRYPTED FILE-----\n";
+
+    #[test]
+    fn finds_encrypted_fields() {
+        let mut config = serde_yml::from_str::<Value>(&format!(
+            "deploy:\n  api:\n    values:\n      password: {ARMORED:?}\n      replicas: 2\nregistrySettings:\n  headers: [plain, {ARMORED:?}]\n"
+        ))
+        .unwrap();
+        let mut fields = vec![];
+
+        encrypted_fields(String::new(), &mut config, &mut fields);
+
+        let paths = fields.into_iter().map(|(path, _)| path).collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            ["deploy.api.values.password", "registrySettings.headers[1]"]
+        );
+    }
+
+    #[test]
+    fn hash_ignores_the_order_of_maps() {
+        let config = |args: &str| {
+            Config::from_str(&format!(
+                "tagFormat: v1\nbuild:\n  api:\n    type: docker\n    context: .\n    buildArgs: {args}\n"
+            ))
+            .unwrap()
+            .hash()
+            .unwrap()
+        };
+
+        assert_eq!(config("{ A: a, B: b }"), config("{ B: b, A: a }"));
+        assert_ne!(config("{ A: a, B: b }"), config("{ A: a, B: c }"));
+    }
+
+    #[tokio::test]
+    async fn build_number_only_bumped_for_builds() {
+        let dir = env::temp_dir().join(format!("steiger-build-number-{}", std::process::id()));
+        let local = |bump| find_build_number(&dir, bump, |_| None);
+
+        assert_eq!(local(false).await.unwrap(), "0");
+        assert_eq!(local(true).await.unwrap(), "1");
+        assert_eq!(local(false).await.unwrap(), "1");
+        assert_eq!(local(true).await.unwrap(), "2");
+
+        // The number of CI wins, the local counter is left alone
+        let ci = |name: &str| (name == "CI_PIPELINE_IID").then(|| "42".to_string());
+        assert_eq!(find_build_number(&dir, true, ci).await.unwrap(), "42");
+        assert_eq!(local(false).await.unwrap(), "2");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn finds_used_variables() {
+        assert!(uses_variable("tag: ${gitDescribe}", "gitDescribe"));
+        assert!(uses_variable("tag: ${gitDescribe:-dev}", "gitDescribe"));
+        assert!(uses_variable("tag: v$gitCommitCount", "gitCommitCount"));
+        assert!(uses_variable("tag: $gitCommitCount-rc", "gitCommitCount"));
+        assert!(!uses_variable(
+            "tag: $gitCommitCountTotal",
+            "gitCommitCount"
+        ));
+        assert!(!uses_variable("tag: ${gitDescribeLong}", "gitDescribe"));
+    }
+
+    #[test]
+    fn profile_settings_are_not_unused_variables() {
+        let config = serde_yml::from_str::<Value>(
+            "profiles:\n  local:\n    push: false\n    channel: dev\n    forbidDirty: true\n    replicas: 1\n    image: api\ndeploy:\n  api:\n    values:\n      image: ${image}\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            unused_variables(&config).unwrap(),
+            [("local".to_string(), "replicas".to_string())]
+        );
+    }
+
+    #[test]
+    fn age_key_file_from_env() {
+        let vars = HashMap::from([
+            ("SOPS_AGE_KEY_FILE", "/sops/keys.txt"),
+            ("STEIGER_AGE_KEY_FILE", "/steiger/keys.txt"),
+            ("HOME", "/home/dev"),
+        ]);
+        let key_file = find_age_key_file(|name| vars.get(name).map(OsString::from), |_| true);
+
+        assert_eq!(key_file, Some(PathBuf::from("/steiger/keys.txt")));
+    }
+
+    #[test]
+    fn age_key_file_from_sops_locations() {
+        let vars = HashMap::from([("HOME", "/home/dev")]);
+        let var = |name: &str| vars.get(name).map(OsString::from);
+
+        assert_eq!(
+            find_age_key_file(var, |path| path.starts_with("/home/dev/Library")),
+            Some(PathBuf::from(
+                "/home/dev/Library/Application Support/sops/age/keys.txt"
+            ))
+        );
+        assert_eq!(
+            find_age_key_file(var, |_| true),
+            Some(PathBuf::from("/home/dev/.config/sops/age/keys.txt"))
+        );
+        assert_eq!(find_age_key_file(var, |_| false), None);
+
+        let vars = HashMap::from([("HOME", "/home/dev"), ("XDG_CONFIG_HOME", "/xdg")]);
+        assert_eq!(
+            find_age_key_file(|name| vars.get(name).map(OsString::from), |_| true),
+            Some(PathBuf::from("/xdg/sops/age/keys.txt"))
+        );
+    }
+}
@@ -0,0 +1,236 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    path::{Path, PathBuf},
+    process::ExitStatus,
+};
+
+use miette::Diagnostic;
+use prodash::tree::Item;
+use serde_yml::Value;
+use steiger::ui;
+
+use crate::{
+    config::Compose,
+    deploy::{Change, Context, Deployer, manifests},
+    exec::{self, CmdBuilder},
+    progress, tmp,
+};
+
+#[derive(Debug, Diagnostic, thiserror::Error)]
+pub enum ComposeError {
+    #[error("failed to find docker binary")]
+    Path(#[from] which::Error),
+    #[error("IO error")]
+    IO(#[from] std::io::Error),
+    #[error("failed to create tempdir")]
+    TempDir(#[from] async_tempfile::Error),
+    #[error("failed to read compose file '{0}'")]
+    Read(String, #[source] std::io::Error),
+    #[error("failed to parse compose file '{0}'")]
+    Parse(String, #[source] serde_yml::Error),
+    #[error("compose file '{0}' has no services")]
+    NoServices(String),
+    #[error("failed to serialize compose file")]
+    Serialize(#[from] serde_yml::Error),
+    #[error("failed to run 'docker compose up': {0}")]
+    Up(ExitStatus),
+    #[error("failed to run 'docker compose down': {0}")]
+    Down(ExitStatus),
+}
+
+async fn read(path: &str) -> Result<Value, ComposeError> {
+    let data = tokio::fs::read_to_string(path)
+        .await
+        .map_err(|e| ComposeError::Read(path.to_string(), e))?;
+    let compose = serde_yml::from_str::<Value>(&data)
+        .map_err(|e| ComposeError::Parse(path.to_string(), e))?;
+
+    if !compose["services"].is_mapping() {
+        return Err(ComposeError::NoServices(path.to_string()));
+    }
+
+    Ok(compose)
+}
+
+/// Image of the service without its tag, or the service name when it has no image
+fn image<'a>(name: &'a str, service: &'a Value) -> &'a str {
+    match service.get("image").and_then(Value::as_str) {
+        Some(image) => manifests::repository(image),
+        None => name,
+    }
+}
+
+/// Images the services refer to, without tags. `None` when the file can't be read.
+pub async fn images(path: &str) -> Option<BTreeSet<String>> {
+    let compose = read(path).await.ok()?;
+
+    Some(
+        compose["services"]
+            .as_mapping()?
+            .iter()
+            .filter_map(|(name, service)| Some(image(name.as_str()?, service).to_string()))
+            .collect(),
+    )
+}
+
+/// Sets the pushed image on services whose `image` names an artifact, or that are named after
+/// an artifact and have no image. Their `build` section is dropped so compose doesn't build
+/// them again.
+fn rewrite(compose: &mut Value, images: &HashMap<&str, &str>) -> Vec<String> {
+    let mut replaced = vec![];
+    let Some(services) = compose["services"].as_mapping_mut() else {
+        return replaced;
+    };
+
+    for (name, service) in services.iter_mut() {
+        let Some(name) = name.as_str() else {
+            continue;
+        };
+
+        if let Some(pushed) = images.get(image(name, service))
+            && let Some(service) = service.as_mapping_mut()
+        {
+            service.insert("image".into(), Value::String(pushed.to_string()));
+            service.remove("build");
+            replaced.push(name.to_string());
+        }
+    }
+
+    replaced
+}
+
+#[derive(Clone)]
+pub struct ComposeDeployer {
+    binary: PathBuf,
+}
+
+impl ComposeDeployer {
+    /// `docker compose` for the project of the release, relative paths resolve like they would
+    /// for the original file
+    fn command(&self, release: &str, input: &Compose, file: &Path) -> CmdBuilder {
+        let dir = Path::new(&input.path)
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or_else(|| Path::new("."));
+
+        let mut cmd = CmdBuilder::new(&self.binary);
+        cmd.arg("compose");
+        cmd.flag(
+            "--project-name",
+            input.project.as_deref().unwrap_or(release),
+        );
+        cmd.flag("--project-directory", dir.to_string_lossy().to_string());
+        cmd.flag("--file", file.to_string_lossy().to_string());
+        cmd
+    }
+}
+
+impl Deployer for ComposeDeployer {
+    type Error = ComposeError;
+    type Input = Compose;
+
+    fn try_init() -> Result<Self, Self::Error>
+    where
+        Self: Sized,
+    {
+        which::which("docker")
+            .map(|binary| Self { binary })
+            .map_err(|e| e.into())
+    }
+
+    async fn validate(&self, input: &Self::Input) -> Result<(), Self::Error> {
+        read(&input.path).await.map(|_| ())
+    }
+
+    async fn deploy(
+        self,
+        mut progress: Item,
+        release: String,
+        ctx: Context<Self::Input>,
+    ) -> Result<(), Self::Error> {
+        let input = &ctx.input;
+        let mut compose = read(&input.path).await?;
+        let replaced = rewrite(&mut compose, &manifests::pushed_images(&ctx.output));
+
+        if !replaced.is_empty() {
+            progress.info(format!("using built images for {}", replaced.join(", ")));
+        }
+
+        let dir = tmp::new_dir().await?;
+        let file = dir.join("compose.yml");
+        tokio::fs::write(&file, serde_yml::to_string(&compose)?).await?;
+
+        progress.info("starting services");
+
+        let mut cmd = self.command(&release, input, &file);
+        cmd.arg("up")
+            .arg("--detach")
+            .arg("--no-build")
+            .arg("--remove-orphans");
+
+        if input.wait {
+            cmd.arg("--wait");
+        }
+
+        let output = exec::run_with_progress(
+            &mut cmd,
+            progress::child(&mut progress, format!("{release} › compose")),
+        )
+        .await?;
+
+        if !output.success() {
+            progress.fail(format!(
+                "deployment failed with exit code: {}",
+                output.code()
+            ));
+
+            return Err(ComposeError::Up(output.status));
+        }
+
+        progress.done("deployment finished".to_string());
+
+        Ok(())
+    }
+
+    async fn destroy(
+        self,
+        mut progress: Item,
+        release: String,
+        input: Self::Input,
+    ) -> Result<(), Self::Error> {
+        progress.info("stopping services");
+
+        let mut cmd = self.command(&release, &input, Path::new(&input.path));
+        cmd.arg("down").arg("--remove-orphans");
+
+        let output = exec::run_with_progress(
+            &mut cmd,
+            progress::child(&mut progress, format!("{release} › compose")),
+        )
+        .await?;
+
+        if !output.success() {
+            progress.fail(format!("teardown failed with exit code: {}", output.code()));
+
+            return Err(ComposeError::Down(output.status));
+        }
+
+        progress.done("release removed".to_string());
+
+        Ok(())
+    }
+
+    async fn diff(
+        self,
+        mut progress: Item,
+        release: String,
+        _ctx: Context<Self::Input>,
+    ) -> Result<Vec<Change>, Self::Error> {
+        progress.done("diff is not supported for compose releases, skipping".to_string());
+        ui::record_warning(format!(
+            "{release} was not compared, compose releases can't be diffed"
+        ));
+
+        Ok(vec![])
+    }
+}
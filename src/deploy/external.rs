@@ -0,0 +1,90 @@
+use docker_credential::CredentialRetrievalError;
+use miette::Diagnostic;
+use oci_client::Reference;
+use steiger::ui;
+
+use crate::{
+    cmd::{
+        build::output::{Build, Output},
+        deploy::{self, InputError},
+    },
+    config::{ArtifactSource, Config},
+    registry::{self, PullError, Registry},
+};
+
+#[derive(Debug, Diagnostic, thiserror::Error)]
+pub enum ExternalError {
+    #[error("failed to read output")]
+    #[diagnostic(transparent)]
+    Input(#[from] InputError),
+    #[error("failed to parse reference")]
+    Parse(#[from] oci_client::ParseError),
+    #[error("failed to retrieve registry credentials")]
+    Credential(#[from] CredentialRetrievalError),
+    #[error("failed to pull output")]
+    #[diagnostic(transparent)]
+    Pull(#[from] PullError),
+    #[error("artifact '{0}' is not in the output")]
+    Missing(String),
+}
+
+/// Loads the builds of `source` that the release asked for
+pub async fn load(config: &Config, source: &ArtifactSource) -> Result<Vec<Build>, ExternalError> {
+    let output = match source {
+        ArtifactSource::Path { path, .. } => deploy::read_input(path).await?,
+        ArtifactSource::Registry { reference, .. } => {
+            let reference = Reference::try_from(reference.as_str())?;
+            let auth = registry::load_credentials(reference.resolve_registry())?;
+            let registry =
+                Registry::with_config(auth, &config.insecure_registries, &config.registry_settings);
+            let content = registry
+                .pull_artifact(&reference, registry::OUTPUT_MEDIA_TYPE)
+                .await?;
+
+            deploy::parse_input(&content)?
+        }
+    };
+
+    if source.artifacts().is_empty() {
+        return Ok(output.builds);
+    }
+
+    if let Some(missing) = source
+        .artifacts()
+        .iter()
+        .find(|artifact| !output.builds.iter().any(|b| &b.image_name == *artifact))
+    {
+        return Err(ExternalError::Missing(missing.clone()));
+    }
+
+    Ok(output
+        .builds
+        .into_iter()
+        .filter(|build| source.artifacts().contains(&build.image_name))
+        .collect())
+}
+
+/// Adds external builds to the output of this project, artifacts built here take precedence
+pub fn merge(output: &Output, external: Vec<(String, Vec<Build>)>) -> Output {
+    let mut merged = output.clone();
+
+    for (source, builds) in external {
+        for build in builds {
+            if merged
+                .builds
+                .iter()
+                .any(|existing| existing.image_name == build.image_name)
+            {
+                ui::warn(format!(
+                    "artifact '{}' from {source} is already defined, ignoring it",
+                    build.image_name
+                ));
+                continue;
+            }
+
+            merged.builds.push(build);
+        }
+    }
+
+    merged
+}
@@ -0,0 +1,139 @@
+use serde::{Deserialize, Serialize};
+use steiger::ui;
+
+use crate::{
+    config::GithubDeployment,
+    github::{self, Client, GithubError, var},
+};
+
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum State {
+    InProgress,
+    Success,
+    Failure,
+}
+
+#[derive(Serialize)]
+struct CreateDeploymentRequest<'a> {
+    #[serde(rename = "ref")]
+    git_ref: &'a str,
+    environment: &'a str,
+    description: &'a str,
+    auto_merge: bool,
+    required_contexts: [&'a str; 0],
+    transient_environment: bool,
+}
+
+#[derive(Deserialize)]
+struct CreateDeploymentResponse {
+    id: u64,
+}
+
+#[derive(Serialize)]
+struct CreateStatusRequest<'a> {
+    state: State,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    environment_url: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    log_url: Option<&'a str>,
+    auto_inactive: bool,
+}
+
+async fn create_deployment(
+    client: &Client,
+    deployment: &GithubDeployment,
+    git_ref: &str,
+) -> Result<u64, GithubError> {
+    let request = CreateDeploymentRequest {
+        git_ref,
+        environment: &deployment.environment,
+        description: "deployed by steiger",
+        auto_merge: false,
+        required_contexts: [],
+        transient_environment: deployment.transient,
+    };
+
+    let response = client
+        .post("deployments")
+        .json(&request)
+        .send()
+        .await?
+        .error_for_status()?;
+
+    Ok(response.json::<CreateDeploymentResponse>().await?.id)
+}
+
+/// Reports the deploy phase as a GitHub deployment, failing to reach GitHub never fails a deploy
+pub struct Tracker {
+    client: Client,
+    id: u64,
+    url: Option<String>,
+    log_url: Option<String>,
+}
+
+impl Tracker {
+    /// Only available when running in GitHub Actions with `GITHUB_TOKEN` set
+    pub async fn start(deployment: &GithubDeployment) -> Option<Self> {
+        let repository = github::repository()?;
+        let client = Client::new(&repository, &var("GITHUB_TOKEN")?).ok()?;
+        // In pull requests `GITHUB_SHA` is a merge commit, the head branch shows up in the PR
+        let git_ref = var("GITHUB_HEAD_REF").or_else(|| var("GITHUB_SHA"))?;
+        let log_url = match (var("GITHUB_SERVER_URL"), var("GITHUB_RUN_ID")) {
+            (Some(server_url), Some(run_id)) => {
+                Some(format!("{server_url}/{repository}/actions/runs/{run_id}"))
+            }
+            _ => None,
+        };
+
+        let id = match create_deployment(&client, deployment, &git_ref).await {
+            Ok(id) => id,
+            Err(e) => {
+                ui::warn(format!("failed to create GitHub deployment: {e}"));
+                return None;
+            }
+        };
+
+        let tracker = Self {
+            client,
+            id,
+            url: deployment.url.clone(),
+            log_url,
+        };
+        tracker.update(State::InProgress).await;
+
+        Some(tracker)
+    }
+
+    async fn create_status(&self, state: State) -> Result<(), GithubError> {
+        let request = CreateStatusRequest {
+            state,
+            environment_url: self.url.as_deref(),
+            log_url: self.log_url.as_deref(),
+            auto_inactive: true,
+        };
+
+        self.client
+            .post(&format!("deployments/{}/statuses", self.id))
+            .json(&request)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+
+    async fn update(&self, state: State) {
+        if let Err(e) = self.create_status(state).await {
+            ui::warn(format!("failed to update GitHub deployment status: {e}"));
+        }
+    }
+
+    pub async fn finish(self, success: bool) {
+        self.update(match success {
+            true => State::Success,
+            false => State::Failure,
+        })
+        .await;
+    }
+}
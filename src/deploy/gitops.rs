@@ -0,0 +1,499 @@
+use std::{
+    collections::BTreeMap,
+    path::{Component, Path, PathBuf},
+    process::ExitStatus,
+    sync::Arc,
+};
+
+use async_tempfile::TempDir;
+use miette::Diagnostic;
+use prodash::tree::Item;
+use serde::Deserialize;
+use serde_yml::Value;
+use steiger::ui;
+
+use crate::{
+    config::{Gitops, Release},
+    deploy::{
+        Change, Context, Deployer,
+        helm::{HelmDeployer, HelmError},
+        manifests::{self, ManifestsError},
+        metadata::Metadata,
+    },
+    exec::{self, CmdBuilder, ExitError},
+    progress, tmp,
+};
+
+/// Pushes are retried after rebasing on commits that landed in the meantime, e.g. of
+/// other releases pushing to the same branch
+const PUSH_ATTEMPTS: usize = 3;
+
+#[derive(Debug, Diagnostic, thiserror::Error)]
+pub enum GitopsError {
+    #[error("failed to find git binary")]
+    Path(#[from] which::Error),
+    #[error("IO error")]
+    IO(#[from] std::io::Error),
+    #[error("failed to create tempdir")]
+    TempDir(#[from] async_tempfile::Error),
+    #[error("gitops releases can't render a release of type '{0}'")]
+    #[diagnostic(help("set `source` to a release of type `helm` or `manifests`"))]
+    Source(&'static str),
+    #[error("invalid path '{0}'")]
+    #[diagnostic(help("`path` is a directory relative to the root of the repository"))]
+    InvalidPath(String),
+    #[error("failed to parse rendered manifests")]
+    Manifest(#[from] serde_yml::Error),
+    #[error("failed to run 'git {0}': {1}")]
+    Git(&'static str, ExitStatus),
+    #[error("failed to push after {PUSH_ATTEMPTS} attempts")]
+    #[diagnostic(help("check the output of git push, the remote may reject pushes to the branch"))]
+    Push,
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    Helm(#[from] HelmError),
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    Manifests(#[from] ManifestsError),
+}
+
+/// Kinds that aren't namespaced. Their scope can't be looked up without a cluster, other
+/// kinds get the namespace of the release like the native engine sets it.
+const CLUSTER_SCOPED: [&str; 14] = [
+    "Namespace",
+    "Node",
+    "PersistentVolume",
+    "StorageClass",
+    "CustomResourceDefinition",
+    "ClusterRole",
+    "ClusterRoleBinding",
+    "IngressClass",
+    "PriorityClass",
+    "RuntimeClass",
+    "APIService",
+    "MutatingWebhookConfiguration",
+    "ValidatingWebhookConfiguration",
+    "CSIDriver",
+];
+
+/// Directory in the repository, it can't point outside of it or to its root
+fn repo_path(path: &str) -> Result<&Path, GitopsError> {
+    let path = Path::new(path);
+    let components = path.components().collect::<Vec<_>>();
+
+    if components
+        .iter()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+        && components.iter().any(|c| matches!(c, Component::Normal(_)))
+    {
+        return Ok(path);
+    }
+
+    Err(GitopsError::InvalidPath(path.display().to_string()))
+}
+
+/// File name of a resource without extension, e.g. `deployment-api`
+fn file_stem(resource: &Value) -> Option<String> {
+    let kind = resource["kind"].as_str()?;
+    let name = resource["metadata"]["name"].as_str()?;
+
+    Some(
+        format!("{kind}-{name}")
+            .to_lowercase()
+            .chars()
+            .map(|c| match c {
+                'a'..='z' | '0'..='9' | '-' | '.' => c,
+                _ => '-',
+            })
+            .collect(),
+    )
+}
+
+/// Sets the namespace of a namespaced resource that doesn't have one
+fn set_namespace(resource: &mut Value, namespace: &str) {
+    let kind = resource["kind"].as_str().unwrap_or_default();
+
+    if CLUSTER_SCOPED.contains(&kind) || resource["metadata"]["namespace"].is_string() {
+        return;
+    }
+
+    if let Some(metadata) = resource.get_mut("metadata").and_then(Value::as_mapping_mut) {
+        metadata.insert("namespace".into(), namespace.into());
+    }
+}
+
+/// Labeled resources keyed by file name, one file per resource keeps the diffs reviewable.
+/// Resources without a namespace get `namespace`, the cluster syncing them doesn't know it.
+fn files(
+    manifest: &str,
+    metadata: &Metadata,
+    namespace: Option<&str>,
+) -> Result<BTreeMap<String, String>, GitopsError> {
+    let mut files = BTreeMap::new();
+
+    for (n, document) in serde_yml::Deserializer::from_str(manifest).enumerate() {
+        let mut resource = Value::deserialize(document)?;
+
+        if resource.is_null() {
+            continue;
+        }
+
+        metadata.apply(&mut resource);
+
+        if let Some(namespace) = namespace {
+            set_namespace(&mut resource, namespace);
+        }
+
+        let stem = file_stem(&resource).unwrap_or_else(|| format!("resource-{n}"));
+        let name = (1..)
+            .map(|i| match i {
+                1 => format!("{stem}.yaml"),
+                i => format!("{stem}-{i}.yaml"),
+            })
+            .find(|name| !files.contains_key(name))
+            .expect("name is unique");
+
+        files.insert(name, serde_yml::to_string(&resource)?);
+    }
+
+    Ok(files)
+}
+
+/// Whether git knows who commits, otherwise commits are made as steiger
+async fn has_identity(cmd: &mut CmdBuilder) -> bool {
+    std::env::var_os("GIT_COMMITTER_EMAIL").is_some()
+        || exec::run_with_output(cmd.arg("config").arg("user.email"))
+            .await
+            .is_ok_and(|email| !email.trim().is_empty())
+}
+
+#[derive(Clone)]
+pub struct GitopsDeployer {
+    binary: PathBuf,
+}
+
+impl GitopsDeployer {
+    fn git(&self, repo: &Path) -> CmdBuilder {
+        let mut cmd = CmdBuilder::new(&self.binary);
+        cmd.arg("-C").arg(repo);
+        cmd
+    }
+
+    /// Runs a git command with its output under `progress`
+    async fn run(
+        &self,
+        progress: &mut Item,
+        release: &str,
+        command: &'static str,
+        cmd: &mut CmdBuilder,
+    ) -> Result<(), GitopsError> {
+        let output = exec::run_with_progress(
+            cmd,
+            progress::child(progress, format!("{release} › git {command}")),
+        )
+        .await?;
+
+        match output.success() {
+            true => Ok(()),
+            false => Err(GitopsError::Git(command, output.status)),
+        }
+    }
+
+    /// Shallow clone of the branch, removed when dropped
+    async fn checkout(
+        &self,
+        progress: &mut Item,
+        release: &str,
+        input: &Gitops,
+    ) -> Result<TempDir, GitopsError> {
+        let dir = tmp::new_dir().await?;
+        let mut cmd = CmdBuilder::new(&self.binary);
+        cmd.arg("clone")
+            .arg("--depth")
+            .arg("1")
+            .arg("--single-branch");
+
+        if let Some(ref branch) = input.branch {
+            cmd.flag("--branch", branch);
+        }
+
+        cmd.arg(&input.repository).arg(dir.dir_path());
+        self.run(progress, release, "clone", &mut cmd).await?;
+
+        Ok(dir)
+    }
+
+    /// Commits the changes to `path` and pushes them, returns whether anything changed
+    async fn publish(
+        &self,
+        progress: &mut Item,
+        release: &str,
+        repo: &Path,
+        path: &Path,
+        message: &str,
+    ) -> Result<bool, GitopsError> {
+        let mut add = self.git(repo);
+        add.arg("add").arg("--all").arg("--").arg(path);
+        self.run(progress, release, "add", &mut add).await?;
+
+        let mut diff = self.git(repo);
+        diff.arg("diff").arg("--cached").arg("--quiet");
+
+        match exec::run_with_output(&mut diff).await {
+            Ok(_) => return Ok(false),
+            Err(ExitError::Status { .. }) => {}
+            Err(ExitError::IO(e)) => return Err(e.into()),
+        }
+
+        let mut commit = self.git(repo);
+
+        if !has_identity(&mut self.git(repo)).await {
+            commit.flag("-c", "user.name=steiger");
+            commit.flag("-c", "user.email=steiger@localhost");
+        }
+
+        commit.arg("commit");
+        commit.flag("--message", message);
+        self.run(progress, release, "commit", &mut commit).await?;
+
+        for _ in 0..PUSH_ATTEMPTS {
+            let mut push = self.git(repo);
+            push.arg("push").arg("origin").arg("HEAD");
+
+            match self.run(progress, release, "push", &mut push).await {
+                Ok(()) => return Ok(true),
+                Err(GitopsError::Git(..)) => {}
+                Err(e) => return Err(e),
+            }
+
+            let mut pull = self.git(repo);
+            pull.arg("pull").arg("--rebase");
+            self.run(progress, release, "pull", &mut pull).await?;
+        }
+
+        Err(GitopsError::Push)
+    }
+}
+
+impl Deployer for GitopsDeployer {
+    type Error = GitopsError;
+    type Input = Gitops;
+
+    fn try_init() -> Result<Self, Self::Error>
+    where
+        Self: Sized,
+    {
+        which::which("git")
+            .map(|binary| Self { binary })
+            .map_err(|e| e.into())
+    }
+
+    async fn validate(&self, input: &Self::Input) -> Result<(), Self::Error> {
+        repo_path(&input.path)?;
+
+        match input.source.as_ref() {
+            Release::Helm(helm) => HelmDeployer::try_init()?.validate(helm).await?,
+            Release::Manifests(manifests) => {
+                manifests::ManifestsDeployer::try_init()?
+                    .validate(manifests)
+                    .await?
+            }
+            release => return Err(GitopsError::Source(release.type_name())),
+        }
+
+        Ok(())
+    }
+
+    async fn deploy(
+        self,
+        mut progress: Item,
+        release: String,
+        ctx: Context<Self::Input>,
+    ) -> Result<(), Self::Error> {
+        let input = &ctx.input;
+        let path = repo_path(&input.path)?;
+
+        progress.info("rendering manifests");
+
+        let manifest = match input.source.as_ref() {
+            Release::Helm(helm) => {
+                let source = Context::new(helm.clone(), Arc::clone(&ctx.output), false);
+                HelmDeployer::try_init()?
+                    .template(&release, &source)
+                    .await?
+            }
+            Release::Manifests(manifests) => {
+                manifests::render(&manifests.path, &ctx.output).await?
+            }
+            release => return Err(GitopsError::Source(release.type_name())),
+        };
+        let files = files(
+            &manifest,
+            &Metadata::from_output(&ctx.output),
+            input.source.namespace().map(String::as_str),
+        )?;
+
+        let repo = self.checkout(&mut progress, &release, input).await?;
+        let dir = repo.dir_path().join(path);
+
+        // The directory is owned by the release, resources that are gone are removed
+        if tokio::fs::try_exists(&dir).await? {
+            tokio::fs::remove_dir_all(&dir).await?;
+        }
+
+        tokio::fs::create_dir_all(&dir).await?;
+
+        for (name, content) in files.iter() {
+            tokio::fs::write(dir.join(name), content).await?;
+        }
+
+        let mut message = input
+            .message
+            .clone()
+            .unwrap_or_else(|| format!("Deploy {release}"));
+
+        if let Some(ref commit) = ctx.output.git_commit {
+            message.push_str(&format!("\n\nBuilt from {commit}"));
+        }
+
+        progress.info(format!("committing {} resources", files.len()));
+
+        match self
+            .publish(&mut progress, &release, repo.dir_path(), path, &message)
+            .await
+        {
+            Ok(true) => progress.done(format!("pushed manifests to {}", input.repository)),
+            Ok(false) => progress.done("manifests are up-to-date, nothing to commit".to_string()),
+            Err(e) => {
+                progress.fail(format!("deployment failed: {e}"));
+                return Err(e);
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn destroy(
+        self,
+        mut progress: Item,
+        release: String,
+        input: Self::Input,
+    ) -> Result<(), Self::Error> {
+        let path = repo_path(&input.path)?;
+        let repo = self.checkout(&mut progress, &release, &input).await?;
+        let dir = repo.dir_path().join(path);
+
+        if !tokio::fs::try_exists(&dir).await? {
+            progress.done("release removed".to_string());
+            return Ok(());
+        }
+
+        progress.info("removing manifests");
+        tokio::fs::remove_dir_all(&dir).await?;
+
+        self.publish(
+            &mut progress,
+            &release,
+            repo.dir_path(),
+            path,
+            &format!("Remove {release}"),
+        )
+        .await?;
+        progress.done("release removed".to_string());
+
+        Ok(())
+    }
+
+    async fn diff(
+        self,
+        mut progress: Item,
+        release: String,
+        _ctx: Context<Self::Input>,
+    ) -> Result<Vec<Change>, Self::Error> {
+        progress.done("diff is not supported for gitops releases, skipping".to_string());
+        ui::record_warning(format!(
+            "{release} was not compared, gitops releases can't be diffed"
+        ));
+
+        Ok(vec![])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repo_paths_stay_inside_the_repository() {
+        assert_eq!(
+            repo_path("clusters/prod/api").unwrap(),
+            Path::new("clusters/prod/api")
+        );
+        assert_eq!(repo_path("./api").unwrap(), Path::new("./api"));
+
+        for path in ["", ".", "../api", "clusters/../../api", "/clusters/api"] {
+            assert!(
+                matches!(repo_path(path), Err(GitopsError::InvalidPath(_))),
+                "{path}"
+            );
+        }
+    }
+
+    #[test]
+    fn file_stems_of_resources() {
+        let resource = serde_yml::from_str::<Value>(
+            "kind: ServiceAccount\nmetadata:\n  name: API_worker:v1\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            file_stem(&resource).as_deref(),
+            Some("serviceaccount-api-worker-v1")
+        );
+        assert_eq!(
+            file_stem(&serde_yml::from_str("kind: Service\n").unwrap()),
+            None
+        );
+    }
+
+    #[test]
+    fn one_file_per_resource() {
+        let manifest = "\
+kind: Service
+metadata:
+  name: api
+---
+kind: Service
+metadata:
+  name: api
+  namespace: shared
+---
+kind: ClusterRole
+metadata:
+  name: api
+---
+data: {}
+";
+        let files = files(manifest, &Metadata::default(), Some("backend")).unwrap();
+
+        assert_eq!(
+            files.keys().collect::<Vec<_>>(),
+            [
+                "clusterrole-api.yaml",
+                "resource-3.yaml",
+                "service-api-2.yaml",
+                "service-api.yaml",
+            ]
+        );
+
+        let namespace = |name: &str| {
+            serde_yml::from_str::<Value>(&files[name]).unwrap()["metadata"]["namespace"]
+                .as_str()
+                .map(str::to_string)
+        };
+
+        assert_eq!(namespace("service-api.yaml").as_deref(), Some("backend"));
+        assert_eq!(namespace("service-api-2.yaml").as_deref(), Some("shared"));
+        assert_eq!(namespace("clusterrole-api.yaml"), None);
+    }
+}
@@ -0,0 +1,934 @@
+use std::{
+    path::{Path, PathBuf},
+    process::ExitStatus,
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use heck::ToLowerCamelCase;
+use miette::Diagnostic;
+use prodash::tree::Item;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use steiger::ui;
+
+use crate::{
+    cmd::lint,
+    config::{Helm, HelmEngine},
+    deploy::{
+        Change, Context, Deployer,
+        metadata::Metadata,
+        native::{Native, NativeError},
+    },
+    exec::{self, CmdBuilder, CommandOutput, ExitError},
+    progress,
+};
+
+mod schema;
+
+#[derive(Debug, Diagnostic, thiserror::Error)]
+pub enum HelmError {
+    #[error("failed to find helm binary")]
+    Path(#[from] which::Error),
+    #[error("failed to locate helm chart")]
+    Chart(#[from] std::io::Error),
+    #[error("helm chart at '{0}' is not a directory")]
+    NotADir(String),
+    #[error("failed to run 'helm upgrade': {0}")]
+    #[diagnostic(help("{1}"))]
+    Install(ExitStatus, String),
+    #[error("failed to run 'helm uninstall': {0}")]
+    Uninstall(ExitStatus),
+    #[error("failed to read release inputs")]
+    Inputs(#[source] std::io::Error),
+    #[error("failed to serialize release config")]
+    Serde(#[from] serde_json::Error),
+    #[error("values don't match the values.schema.json of chart '{chart}'")]
+    #[diagnostic(help("{errors}"))]
+    Schema { chart: String, errors: String },
+    #[error("invalid values.schema.json: {0}")]
+    InvalidSchema(String),
+    #[error("failed to run 'helm template': {0}")]
+    #[diagnostic(help("{1}"))]
+    Render(ExitStatus, String),
+    #[error("failed to parse values")]
+    Manifest(#[from] serde_yml::Error),
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    Native(#[from] NativeError),
+    #[error("another operation (install/upgrade/rollback) is in progress on release '{0}'")]
+    #[diagnostic(help(
+        "if an earlier deploy was interrupted, set `recoverPending: true` to roll the release back and upgrade again, or run `helm rollback {0}`"
+    ))]
+    Pending(String),
+    #[error("release '{0}' has been {1} for {2}s, another deploy is likely still running")]
+    #[diagnostic(help(
+        "pending revisions are only rolled back once they're older than the release timeout"
+    ))]
+    InProgress(String, String, i64),
+    #[error("release '{0}' can't take over resources that weren't installed by it")]
+    #[diagnostic(help(
+        "{1}\nset `takeOwnership: true` to adopt them, this requires helm 3.17 or newer"
+    ))]
+    Ownership(String, String),
+    #[error("release '{0}' is {1} and has no deployed revision to roll back to")]
+    #[diagnostic(help("roll the release back or uninstall it with helm, then deploy again"))]
+    NoRevision(String, String),
+    #[error("failed to recover release '{0}' from {1}: {2}")]
+    Recover(String, String, ExitStatus),
+    #[error("`{0}` is only supported by the helm binary")]
+    #[diagnostic(help("remove it or use `engine: binary`"))]
+    Unsupported(&'static str),
+    #[error("failed to run 'helm get manifest'")]
+    #[diagnostic(transparent)]
+    GetManifest(#[from] ExitError),
+}
+
+const FINGERPRINT_PREFIX: &str = "steiger fingerprint: ";
+
+const PENDING_PREFIX: &str = "pending-";
+
+/// Revisions read to find the one to roll back to, helm keeps 10 by default
+const RECOVERY_REVISIONS: usize = 10;
+
+/// Timeout helm uses when a release doesn't set one
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Deserialize)]
+struct Revision {
+    revision: u32,
+    status: String,
+    description: String,
+    /// RFC 3339 time of the last change
+    #[serde(default)]
+    updated: String,
+}
+
+#[derive(Debug, PartialEq)]
+enum Recovery {
+    Rollback(u32),
+    Uninstall,
+}
+
+/// Parses a helm timeout like `10m` or `1m30s`
+fn parse_timeout(timeout: &str) -> Option<Duration> {
+    let mut total = 0.0;
+    let mut rest = timeout.trim();
+
+    while !rest.is_empty() {
+        let end = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(rest.len());
+        let value = rest[..end].parse::<f64>().ok()?;
+        rest = &rest[end..];
+
+        let unit = rest
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        total += value
+            * match &rest[..unit] {
+                "h" => 3600.0,
+                "m" => 60.0,
+                "s" => 1.0,
+                "ms" => 0.001,
+                _ => return None,
+            };
+        rest = &rest[unit..];
+    }
+
+    Some(Duration::from_secs_f64(total))
+}
+
+/// Picks how to recover a release whose latest revision is pending. Revisions younger than
+/// `stale_after` may belong to a deploy that is still running and are left alone
+fn recovery(
+    release: &str,
+    revisions: &[Revision],
+    now: DateTime<Utc>,
+    stale_after: Duration,
+) -> Result<Option<Recovery>, HelmError> {
+    let Some(latest) = revisions
+        .last()
+        .filter(|revision| revision.status.starts_with(PENDING_PREFIX))
+    else {
+        return Ok(None);
+    };
+
+    // Without a timestamp the revision can't be told apart from a running deploy
+    let age = DateTime::parse_from_rfc3339(&latest.updated)
+        .map_or(0, |updated| (now - updated.to_utc()).num_seconds());
+
+    if age < stale_after.as_secs() as i64 {
+        return Err(HelmError::InProgress(
+            release.to_string(),
+            latest.status.clone(),
+            age,
+        ));
+    }
+
+    let deployed = revisions
+        .iter()
+        .rev()
+        .find(|revision| revision.status == "deployed" || revision.status == "superseded");
+
+    match deployed {
+        Some(deployed) => Ok(Some(Recovery::Rollback(deployed.revision))),
+        None if latest.status == "pending-install" => Ok(Some(Recovery::Uninstall)),
+        None => Err(HelmError::NoRevision(
+            release.to_string(),
+            latest.status.clone(),
+        )),
+    }
+}
+
+async fn hash_dir(hasher: &mut Sha256, root: &Path) -> Result<(), std::io::Error> {
+    let mut files = vec![];
+    let mut stack = vec![PathBuf::new()];
+
+    while let Some(dir) = stack.pop() {
+        let mut entries = tokio::fs::read_dir(root.join(&dir)).await?;
+
+        while let Some(entry) = entries.next_entry().await? {
+            let path = dir.join(entry.file_name());
+
+            if entry.file_type().await?.is_dir() {
+                stack.push(path);
+            } else {
+                files.push(path);
+            }
+        }
+    }
+
+    files.sort();
+
+    for file in files {
+        hasher.update(file.to_string_lossy().as_bytes());
+        hasher.update(tokio::fs::read(root.join(file)).await?);
+    }
+
+    Ok(())
+}
+
+/// Hash of everything that determines the outcome of a release: its config, the
+/// injected images it refers to, the values files and the chart itself.
+async fn fingerprint(release: &str, ctx: &Context<Helm>) -> Result<String, HelmError> {
+    let mut hasher = Sha256::new();
+    hasher.update(release.as_bytes());
+    let input = serde_json::to_value(&ctx.input)?.to_string();
+    hasher.update(input.as_bytes());
+
+    // Images are referred to as `steiger.<artifact>`, a rebuild of an image the release
+    // doesn't use leaves it as is. Remote charts can't be searched, they use every image.
+    let chart = lint::chart_text(Path::new(&ctx.input.path), &ctx.input.values_files).await;
+    let referenced = |name: &str| {
+        let value = format!("steiger.{}", name.to_lower_camel_case());
+        input.contains(&value) || chart.as_ref().is_none_or(|chart| chart.contains(&value))
+    };
+
+    let mut builds = ctx
+        .output
+        .builds
+        .iter()
+        .filter(|build| {
+            ctx.input
+                .artifacts_from
+                .iter()
+                .any(|source| source.artifacts().contains(&build.image_name))
+                || referenced(&build.image_name)
+        })
+        .map(|build| [build.image_name.as_str(), build.tag.as_str()].join("="))
+        .collect::<Vec<_>>();
+    builds.sort();
+    hasher.update(builds.join("\n"));
+
+    for file in &ctx.input.values_files {
+        hasher.update(tokio::fs::read(file).await.map_err(HelmError::Inputs)?);
+    }
+
+    let mut files = ctx.input.values_file.values().collect::<Vec<_>>();
+    files.sort();
+
+    for file in files {
+        hasher.update(tokio::fs::read(file).await.map_err(HelmError::Inputs)?);
+    }
+
+    hash_dir(&mut hasher, Path::new(&ctx.input.path))
+        .await
+        .map_err(HelmError::Inputs)?;
+
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Flags shared by `helm upgrade` and `helm template`
+fn chart_args(cmd: &mut CmdBuilder, ctx: &Context<Helm>) {
+    if let Some(id) = &ctx.input.preview {
+        cmd.flag("--set-string", format!("steiger.preview={id}"));
+    }
+
+    for build in ctx.output.builds.iter() {
+        cmd.flag(
+            "--set",
+            format!(
+                "steiger.{}.image={}",
+                build.image_name.to_lower_camel_case(),
+                build.tag
+            ),
+        );
+    }
+
+    if let Some(namespace) = &ctx.input.namespace {
+        cmd.flag("--namespace", namespace);
+    }
+
+    if let Some(context) = &ctx.input.context {
+        cmd.flag("--kube-context", context);
+    }
+
+    for (key, value) in &ctx.input.values {
+        cmd.flag("--set", format!("{key}={value}"));
+    }
+
+    for (key, value) in &ctx.input.values_string {
+        cmd.flag("--set-string", format!("{key}={value}"));
+    }
+
+    for (key, file) in &ctx.input.values_file {
+        cmd.flag("--set-file", format!("{key}={file}"));
+    }
+
+    for file in &ctx.input.values_files {
+        cmd.flag("--values", file);
+    }
+}
+
+/// Flags selecting the release in the cluster
+fn target_args(cmd: &mut CmdBuilder, input: &Helm) {
+    if let Some(namespace) = &input.namespace {
+        cmd.flag("--namespace", namespace);
+    }
+
+    if let Some(context) = &input.context {
+        cmd.flag("--kube-context", context);
+    }
+}
+
+/// Turns the conflicts helm reports when upgrading into errors explaining how to resolve them
+fn conflict(release: &str, output: &CommandOutput) -> Option<HelmError> {
+    output.lines.iter().find_map(|line| {
+        if line
+            .text
+            .contains("another operation (install/upgrade/rollback) is in progress")
+        {
+            Some(HelmError::Pending(release.to_string()))
+        } else if line.text.contains("invalid ownership metadata")
+            || line
+                .text
+                .contains("cannot be imported into the current release")
+        {
+            Some(HelmError::Ownership(
+                release.to_string(),
+                line.text.trim().to_string(),
+            ))
+        } else {
+            None
+        }
+    })
+}
+
+/// Lines of output kept in a failed install, helm prints the error at the end
+const INSTALL_TAIL_LINES: usize = 20;
+
+/// Release description, also records the preview environment in the release history
+fn description(fingerprint: &str, preview: Option<&str>) -> String {
+    match preview {
+        Some(id) => format!("{FINGERPRINT_PREFIX}{fingerprint}, preview: {id}"),
+        None => format!("{FINGERPRINT_PREFIX}{fingerprint}"),
+    }
+}
+
+#[derive(Clone)]
+pub struct HelmDeployer {
+    binary: PathBuf,
+}
+
+impl HelmDeployer {
+    /// Revisions of the release, oldest first. A missing release or unreadable history gives
+    /// no revisions
+    async fn history(&self, release: &str, input: &Helm, max: usize) -> Vec<Revision> {
+        let mut cmd = CmdBuilder::new(&self.binary);
+        cmd.arg("history").arg(release).arg("--output").arg("json");
+        cmd.flag("--max", max.to_string());
+        target_args(&mut cmd, input);
+
+        exec::run_with_output(&mut cmd)
+            .await
+            .ok()
+            .and_then(|output| serde_json::from_str(&output).ok())
+            .unwrap_or_default()
+    }
+
+    /// Returns whether the latest revision of the release was deployed with the same fingerprint.
+    async fn is_current(&self, release: &str, ctx: &Context<Helm>, fingerprint: &str) -> bool {
+        self.history(release, &ctx.input, 1)
+            .await
+            .into_iter()
+            .last()
+            .is_some_and(|revision| {
+                revision.status == "deployed"
+                    && revision.description
+                        == description(fingerprint, ctx.input.preview.as_deref())
+            })
+    }
+
+    /// Rolls back a release left pending by an interrupted deploy to its last deployed
+    /// revision, helm refuses to upgrade it otherwise. A release that was never deployed is
+    /// uninstalled instead
+    async fn recover(
+        &self,
+        progress: &mut Item,
+        release: &str,
+        input: &Helm,
+    ) -> Result<(), HelmError> {
+        let revisions = self.history(release, input, RECOVERY_REVISIONS).await;
+        let stale_after = input
+            .timeout
+            .as_deref()
+            .and_then(parse_timeout)
+            .unwrap_or(DEFAULT_TIMEOUT);
+
+        let Some(recovery) = recovery(release, &revisions, Utc::now(), stale_after)? else {
+            return Ok(());
+        };
+        let status = revisions
+            .last()
+            .map(|revision| revision.status.clone())
+            .unwrap_or_default();
+
+        let mut cmd = CmdBuilder::new(&self.binary);
+
+        match recovery {
+            Recovery::Rollback(revision) => {
+                progress.info(format!(
+                    "release is {status}, rolling back to revision {revision}"
+                ));
+                cmd.arg("rollback").arg(release).arg(revision.to_string());
+            }
+            Recovery::Uninstall => {
+                progress.info("release is pending-install, uninstalling it");
+                cmd.arg("uninstall").arg(release);
+            }
+        }
+
+        cmd.arg("--wait");
+
+        if let Some(timeout) = &input.timeout {
+            cmd.flag("--timeout", timeout);
+        }
+
+        target_args(&mut cmd, input);
+
+        let output = exec::run_with_progress(
+            &mut cmd,
+            progress::child(progress, format!("{release} › helm")),
+        )
+        .await?;
+
+        if !output.success() {
+            progress.fail(format!("recovery failed with exit code: {}", output.code()));
+
+            return Err(HelmError::Recover(
+                release.to_string(),
+                status,
+                output.status,
+            ));
+        }
+
+        ui::record_warning(format!(
+            "release {release} was {status}, it was recovered before retrying the upgrade"
+        ));
+
+        Ok(())
+    }
+
+    async fn upgrade(
+        &mut self,
+        progress: &mut Item,
+        release: &str,
+        ctx: &Context<Helm>,
+        fingerprint: &str,
+    ) -> Result<(), HelmError> {
+        progress.info("upgrade/install helm release");
+
+        let mut cmd = CmdBuilder::new(&self.binary);
+        cmd.flag(
+            "--description",
+            description(fingerprint, ctx.input.preview.as_deref()),
+        );
+
+        if let Some(timeout) = &ctx.input.timeout {
+            cmd.flag("--timeout", timeout);
+        }
+
+        if ctx.input.take_ownership {
+            cmd.arg("--take-ownership");
+        }
+
+        chart_args(&mut cmd, ctx);
+
+        // Steiger labels the rendered resources itself, see `steiger post-render`
+        match std::env::current_exe() {
+            Ok(exe) => {
+                cmd.flag("--post-renderer", exe.to_string_lossy().to_string());
+                cmd.flag("--post-renderer-args", "post-render");
+                cmd.envs(Metadata::from_output(&ctx.output).to_env());
+            }
+            Err(e) => ui::warn(format!("not labeling resources of {release}: {e}")),
+        }
+
+        let output = exec::run_with_progress(
+            cmd.arg("upgrade")
+                .arg("--install")
+                .arg(release)
+                .arg(&ctx.input.path),
+            progress::child(progress, format!("{release} › helm")),
+        )
+        .await?;
+
+        if !output.success() {
+            progress.fail(format!(
+                "deployment failed with exit code: {}",
+                output.code()
+            ));
+
+            return Err(conflict(release, &output).unwrap_or_else(|| {
+                HelmError::Install(output.status, output.tail(INSTALL_TAIL_LINES))
+            }));
+        }
+
+        Ok(())
+    }
+
+    /// Validated and rendered chart, for releases applied by something other than helm
+    pub async fn template(&self, release: &str, ctx: &Context<Helm>) -> Result<String, HelmError> {
+        schema::validate(ctx).await?;
+        self.render(release, ctx).await
+    }
+
+    async fn render(&self, release: &str, ctx: &Context<Helm>) -> Result<String, HelmError> {
+        let mut cmd = CmdBuilder::new(&self.binary);
+        chart_args(&mut cmd, ctx);
+        exec::apply_cache_dir(&mut cmd);
+
+        // Read stdout while waiting, rendered manifests easily exceed the pipe buffer
+        let output = exec::output(
+            cmd.arg("template")
+                .arg(release)
+                .arg(&ctx.input.path)
+                .arg("--skip-tests"),
+        )
+        .await?;
+
+        if !output.status.success() {
+            return Err(HelmError::Render(
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            ));
+        }
+
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    /// Manifest of the deployed release, empty when it isn't installed
+    async fn manifest(&self, release: &str, input: &Helm) -> Result<String, HelmError> {
+        let mut cmd = CmdBuilder::new(&self.binary);
+        cmd.arg("get").arg("manifest").arg(release);
+        target_args(&mut cmd, input);
+
+        // Read stdout while waiting, the manifest of a release easily exceeds the pipe buffer
+        let output = exec::output(&mut cmd).await.map_err(ExitError::from)?;
+        let stderr = String::from_utf8_lossy(&output.stderr);
+
+        match output.status.success() {
+            true => Ok(String::from_utf8_lossy(&output.stdout).into_owned()),
+            false if stderr.contains("release: not found") => Ok(String::new()),
+            false => Err(ExitError::Status {
+                code: output.status.code().unwrap_or_default(),
+                stderr: stderr.trim().to_string(),
+            }
+            .into()),
+        }
+    }
+
+    /// Renders the chart and applies it without creating a helm release
+    async fn apply(
+        &self,
+        mut progress: Item,
+        release: &str,
+        ctx: &Context<Helm>,
+        fingerprint: &str,
+    ) -> Result<(), HelmError> {
+        progress.info("rendering helm chart");
+
+        let manifest = self.render(release, ctx).await?;
+        let mut native =
+            Native::try_new(ctx.input.namespace.as_deref(), ctx.input.context.as_deref()).await?;
+
+        if ctx.skip_unchanged
+            && let Some(inventory) = native.inventory(release).await?
+            && inventory.fingerprint == fingerprint
+        {
+            progress.done("release is up-to-date, skipping deployment".to_string());
+            return Ok(());
+        }
+
+        let mut resources = progress::child(&mut progress, format!("{release} › apply"));
+
+        let metadata = Metadata::from_output(&ctx.output);
+
+        match native
+            .apply(&mut resources, release, &manifest, &metadata, fingerprint)
+            .await
+        {
+            Ok(()) => progress.done("deployment finished".to_string()),
+            Err(e) => {
+                progress.fail(format!("deployment failed: {e}"));
+                return Err(e.into());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Deployer for HelmDeployer {
+    type Error = HelmError;
+    type Input = Helm;
+
+    fn try_init() -> Result<Self, Self::Error>
+    where
+        Self: Sized,
+    {
+        which::which("helm")
+            .map(|binary| Self { binary })
+            .map_err(|e| e.into())
+    }
+
+    async fn validate(&self, input: &Self::Input) -> Result<(), Self::Error> {
+        let meta = tokio::fs::metadata(&input.path).await?;
+
+        if !meta.is_dir() {
+            return Err(HelmError::NotADir(input.path.clone()));
+        }
+
+        if input.engine == HelmEngine::Native {
+            if input.take_ownership {
+                return Err(HelmError::Unsupported("takeOwnership"));
+            }
+
+            if input.recover_pending {
+                return Err(HelmError::Unsupported("recoverPending"));
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn deploy(
+        mut self,
+        mut progress: Item,
+        release: String,
+        ctx: Context<Self::Input>,
+    ) -> Result<(), Self::Error> {
+        schema::validate(&ctx).await?;
+
+        let fingerprint = fingerprint(&release, &ctx).await?;
+
+        if ctx.input.engine == HelmEngine::Native {
+            return self.apply(progress, &release, &ctx, &fingerprint).await;
+        }
+
+        if ctx.skip_unchanged && self.is_current(&release, &ctx, &fingerprint).await {
+            progress.done("release is up-to-date, skipping deployment".to_string());
+            return Ok(());
+        }
+
+        match self
+            .upgrade(&mut progress, &release, &ctx, &fingerprint)
+            .await
+        {
+            Err(HelmError::Pending(_)) if ctx.input.recover_pending => {
+                self.recover(&mut progress, &release, &ctx.input).await?;
+                self.upgrade(&mut progress, &release, &ctx, &fingerprint)
+                    .await?;
+            }
+            result => result?,
+        }
+
+        progress.done("deployment finished".to_string());
+
+        Ok(())
+    }
+
+    async fn destroy(
+        self,
+        mut progress: Item,
+        release: String,
+        input: Self::Input,
+    ) -> Result<(), Self::Error> {
+        if input.engine == HelmEngine::Native {
+            progress.info("deleting release resources");
+
+            Native::try_new(input.namespace.as_deref(), input.context.as_deref())
+                .await?
+                .remove(&mut progress, &release)
+                .await?;
+            progress.done("release removed".to_string());
+
+            return Ok(());
+        }
+
+        progress.info("uninstall helm release");
+
+        let mut cmd = CmdBuilder::new(&self.binary);
+        cmd.arg("uninstall")
+            .arg(&release)
+            .arg("--ignore-not-found")
+            .arg("--wait");
+
+        if let Some(timeout) = &input.timeout {
+            cmd.flag("--timeout", timeout);
+        }
+
+        target_args(&mut cmd, &input);
+
+        let output = exec::run_with_progress(
+            &mut cmd,
+            progress::child(&mut progress, format!("{release} › helm")),
+        )
+        .await?;
+
+        if !output.success() {
+            progress.fail(format!(
+                "uninstall failed with exit code: {}",
+                output.code()
+            ));
+
+            return Err(HelmError::Uninstall(output.status));
+        }
+
+        progress.done("release removed".to_string());
+
+        Ok(())
+    }
+
+    async fn diff(
+        self,
+        mut progress: Item,
+        release: String,
+        ctx: Context<Self::Input>,
+    ) -> Result<Vec<Change>, Self::Error> {
+        progress.info("rendering helm chart");
+
+        let manifest = self.template(&release, &ctx).await?;
+        // Releases installed by helm are tracked by helm instead of an inventory
+        let previous = match ctx.input.engine {
+            HelmEngine::Binary => Some(self.manifest(&release, &ctx.input).await?),
+            HelmEngine::Native => None,
+        };
+
+        progress.info("comparing with the cluster");
+
+        let changes = Native::try_new(ctx.input.namespace.as_deref(), ctx.input.context.as_deref())
+            .await?
+            .diff(
+                &release,
+                ctx.input.context.as_deref(),
+                &manifest,
+                &Metadata::from_output(&ctx.output),
+                previous.as_deref(),
+            )
+            .await?;
+        progress.done(format!("{} resources would change", changes.len()));
+
+        Ok(changes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{os::unix::process::ExitStatusExt, sync::Arc};
+
+    use crate::{
+        cmd::build::output::{Build, Output},
+        exec::{Line, Stream},
+    };
+
+    use super::*;
+
+    fn output(stderr: &[&str]) -> CommandOutput {
+        CommandOutput {
+            status: ExitStatus::from_raw(256),
+            lines: stderr
+                .iter()
+                .map(|text| Line {
+                    time: Utc::now(),
+                    stream: Stream::Stderr,
+                    text: text.to_string(),
+                })
+                .collect(),
+        }
+    }
+
+    fn revision(revision: u32, status: &str, updated: &str) -> Revision {
+        Revision {
+            revision,
+            status: status.to_string(),
+            description: String::new(),
+            updated: updated.to_string(),
+        }
+    }
+
+    fn now() -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339("2026-10-17T12:00:00Z")
+            .unwrap()
+            .to_utc()
+    }
+
+    #[test]
+    fn detects_pending_release() {
+        let output = output(&[
+            "Release \"api\" does not exist. Installing it now.",
+            "Error: UPGRADE FAILED: another operation (install/upgrade/rollback) is in progress",
+        ]);
+
+        assert!(matches!(
+            conflict("api", &output),
+            Some(HelmError::Pending(release)) if release == "api"
+        ));
+    }
+
+    #[test]
+    fn detects_ownership_conflict() {
+        let output = output(&[
+            "Error: Unable to continue with install: Service \"api\" in namespace \"default\" exists and cannot be imported into the current release: invalid ownership metadata",
+        ]);
+
+        assert!(matches!(
+            conflict("api", &output),
+            Some(HelmError::Ownership(release, reason))
+                if release == "api" && reason.starts_with("Error: Unable to continue")
+        ));
+        assert!(
+            conflict(
+                "api",
+                &self::output(&["Error: timed out waiting for the condition"])
+            )
+            .is_none()
+        );
+    }
+
+    #[test]
+    fn rolls_back_to_last_deployed_revision() {
+        let revisions = [
+            revision(1, "superseded", "2026-10-16T10:00:00Z"),
+            revision(2, "deployed", "2026-10-16T11:00:00Z"),
+            revision(3, "failed", "2026-10-17T09:00:00Z"),
+            revision(4, "pending-upgrade", "2026-10-17T11:00:00.123456789+00:00"),
+        ];
+
+        let recovery = recovery("api", &revisions, now(), DEFAULT_TIMEOUT).unwrap();
+
+        assert_eq!(recovery, Some(Recovery::Rollback(2)));
+    }
+
+    #[test]
+    fn uninstalls_pending_install() {
+        let revisions = [revision(1, "pending-install", "2026-10-17T11:00:00+02:00")];
+
+        let recovery = recovery("api", &revisions, now(), DEFAULT_TIMEOUT).unwrap();
+
+        assert_eq!(recovery, Some(Recovery::Uninstall));
+    }
+
+    #[test]
+    fn leaves_recent_pending_revision() {
+        let revisions = [
+            revision(1, "deployed", "2026-10-16T10:00:00Z"),
+            revision(2, "pending-upgrade", "2026-10-17T11:58:00Z"),
+        ];
+
+        assert!(matches!(
+            recovery("api", &revisions, now(), DEFAULT_TIMEOUT),
+            Err(HelmError::InProgress(_, _, 120))
+        ));
+        assert_eq!(
+            recovery("api", &revisions, now(), Duration::from_secs(60)).unwrap(),
+            Some(Recovery::Rollback(1))
+        );
+    }
+
+    #[test]
+    fn recovery_without_pending_revision() {
+        let revisions = [revision(1, "deployed", "2026-10-16T10:00:00Z")];
+
+        assert_eq!(
+            recovery("api", &revisions, now(), DEFAULT_TIMEOUT).unwrap(),
+            None
+        );
+        assert!(matches!(
+            recovery(
+                "api",
+                &[revision(1, "pending-rollback", "2026-10-16T10:00:00Z")],
+                now(),
+                DEFAULT_TIMEOUT
+            ),
+            Err(HelmError::NoRevision(_, status)) if status == "pending-rollback"
+        ));
+    }
+
+    #[test]
+    fn parses_timeouts() {
+        assert_eq!(parse_timeout("10m"), Some(Duration::from_secs(600)));
+        assert_eq!(parse_timeout("1m30s"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_timeout("1h"), Some(Duration::from_secs(3600)));
+        assert_eq!(parse_timeout("10 minutes"), None);
+    }
+
+    #[tokio::test]
+    async fn fingerprint_covers_referenced_images() {
+        let dir = std::env::temp_dir().join(format!("steiger-fingerprint-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("templates")).unwrap();
+        std::fs::write(
+            dir.join("templates/deployment.yaml"),
+            "image: {{ .Values.steiger.api.image }}\n",
+        )
+        .unwrap();
+
+        let helm = serde_yml::from_str::<Helm>(&format!("path: {}", dir.display())).unwrap();
+        let hash = async |api: &str, worker: &str| {
+            let builds = [("api", api), ("worker", worker)]
+                .into_iter()
+                .map(|(image_name, tag)| Build {
+                    image_name: image_name.to_string(),
+                    tag: tag.to_string(),
+                    mirrors: vec![],
+                    base_image: None,
+                })
+                .collect();
+            let output = Output {
+                builds,
+                ..Default::default()
+            };
+
+            fingerprint("api", &Context::new(helm.clone(), Arc::new(output), true))
+                .await
+                .unwrap()
+        };
+
+        let current = hash("api:1", "worker:1").await;
+        assert_eq!(hash("api:1", "worker:2").await, current);
+        assert_ne!(hash("api:2", "worker:1").await, current);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,134 @@
+use std::path::Path;
+
+use heck::ToLowerCamelCase;
+use serde_json::{Map, Value};
+
+use crate::{
+    config::Helm,
+    deploy::{Context, helm::HelmError},
+};
+
+/// Merges `overlay` into `base` the way helm merges values, maps are merged recursively and
+/// everything else is replaced
+fn merge(base: &mut Value, overlay: Value) {
+    match (base, overlay) {
+        (Value::Object(base), Value::Object(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+/// Turns `a.b.c=value` into `{"a": {"b": {"c": value}}}`, escaped dots (`a\.b`) are kept
+fn set(path: &str, value: Value) -> Value {
+    let mut keys = vec![String::new()];
+    let mut chars = path.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => keys.last_mut().unwrap().extend(chars.next()),
+            '.' => keys.push(String::new()),
+            c => keys.last_mut().unwrap().push(c),
+        }
+    }
+
+    keys.into_iter().rev().fold(value, |value, key| {
+        Value::Object(Map::from_iter([(key, value)]))
+    })
+}
+
+async fn read_yaml(path: &Path) -> Result<Value, HelmError> {
+    let data = tokio::fs::read_to_string(path)
+        .await
+        .map_err(HelmError::Inputs)?;
+
+    Ok(serde_yml::from_str::<Option<Value>>(&data)?.unwrap_or(Value::Null))
+}
+
+/// Approximates the values helm will render the chart with: the chart defaults, values files,
+/// the injected images, `values`, `valuesString` and `valuesFile`, in that order
+async fn merged_values(ctx: &Context<Helm>) -> Result<Value, HelmError> {
+    let chart = Path::new(&ctx.input.path);
+    let mut values = Value::Object(Map::new());
+
+    if chart.join("values.yaml").exists() {
+        merge(&mut values, read_yaml(&chart.join("values.yaml")).await?);
+    }
+
+    for file in ctx.input.values_files.iter() {
+        merge(&mut values, read_yaml(Path::new(file)).await?);
+    }
+
+    if let Some(ref id) = ctx.input.preview {
+        merge(
+            &mut values,
+            set("steiger.preview", Value::String(id.clone())),
+        );
+    }
+
+    for build in ctx.output.builds.iter() {
+        let path = format!("steiger.{}.image", build.image_name.to_lower_camel_case());
+        merge(&mut values, set(&path, Value::String(build.tag.clone())));
+    }
+
+    for (key, value) in ctx.input.values.iter() {
+        // `--set` infers scalar types, e.g. `replicas=2` is a number
+        let value = serde_yml::from_str::<Value>(value)
+            .ok()
+            .filter(|value| !value.is_object() && !value.is_array())
+            .unwrap_or_else(|| Value::String(value.clone()));
+        merge(&mut values, set(key, value));
+    }
+
+    for (key, value) in ctx.input.values_string.iter() {
+        merge(&mut values, set(key, Value::String(value.clone())));
+    }
+
+    for (key, file) in ctx.input.values_file.iter() {
+        let content = tokio::fs::read_to_string(file)
+            .await
+            .map_err(HelmError::Inputs)?;
+        merge(&mut values, set(key, Value::String(content)));
+    }
+
+    Ok(values)
+}
+
+/// Validates the values against the chart's `values.schema.json` before helm is invoked
+pub async fn validate(ctx: &Context<Helm>) -> Result<(), HelmError> {
+    let path = Path::new(&ctx.input.path).join("values.schema.json");
+
+    if !path.exists() {
+        return Ok(());
+    }
+
+    let schema =
+        serde_json::from_slice::<Value>(&tokio::fs::read(&path).await.map_err(HelmError::Inputs)?)?;
+    let validator =
+        jsonschema::validator_for(&schema).map_err(|e| HelmError::InvalidSchema(e.to_string()))?;
+    let values = merged_values(ctx).await?;
+
+    let errors = validator
+        .iter_errors(&values)
+        .map(|e| match e.instance_path.as_str() {
+            "" => e.to_string(),
+            pointer => format!("{pointer}: {e}"),
+        })
+        .collect::<Vec<_>>();
+
+    if errors.is_empty() {
+        return Ok(());
+    }
+
+    Err(HelmError::Schema {
+        chart: ctx.input.path.clone(),
+        errors: errors.join("\n"),
+    })
+}
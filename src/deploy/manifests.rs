@@ -0,0 +1,343 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    path::{Path, PathBuf},
+};
+
+use miette::Diagnostic;
+use prodash::tree::Item;
+use serde::Deserialize;
+use serde_yml::Value;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    cmd::build::output::Output,
+    config::Manifests,
+    deploy::{
+        Change, Context, Deployer,
+        metadata::Metadata,
+        native::{Native, NativeError},
+    },
+    progress,
+};
+
+#[derive(Debug, Diagnostic, thiserror::Error)]
+pub enum ManifestsError {
+    #[error("failed to read manifests from '{0}'")]
+    Read(String, #[source] std::io::Error),
+    #[error("no manifests found in '{0}'")]
+    #[diagnostic(help("manifests are read from the `.yaml` and `.yml` files in the directory"))]
+    Empty(String),
+    #[error("failed to parse manifest '{0}'")]
+    Parse(String, #[source] serde_yml::Error),
+    #[error("failed to serialize manifest")]
+    Serialize(#[from] serde_yml::Error),
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    Native(#[from] NativeError),
+}
+
+/// Manifest files in the directory, sorted so they're applied in a stable order
+async fn files(path: &Path) -> Result<Vec<PathBuf>, std::io::Error> {
+    let mut files = vec![];
+    let mut entries = tokio::fs::read_dir(path).await?;
+
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+
+        if entry.file_type().await?.is_file()
+            && path
+                .extension()
+                .is_some_and(|ext| ext == "yaml" || ext == "yml")
+        {
+            files.push(path);
+        }
+    }
+
+    files.sort();
+
+    Ok(files)
+}
+
+/// Documents of all manifest files, empty documents are skipped
+async fn documents(path: &str) -> Result<Vec<Value>, ManifestsError> {
+    let read_error = |e| ManifestsError::Read(path.to_string(), e);
+    let mut documents = vec![];
+
+    for file in files(Path::new(path)).await.map_err(read_error)? {
+        let data = tokio::fs::read_to_string(&file).await.map_err(read_error)?;
+
+        for document in serde_yml::Deserializer::from_str(&data) {
+            let value = Value::deserialize(document)
+                .map_err(|e| ManifestsError::Parse(file.display().to_string(), e))?;
+
+            if !value.is_null() {
+                documents.push(value);
+            }
+        }
+    }
+
+    if documents.is_empty() {
+        return Err(ManifestsError::Empty(path.to_string()));
+    }
+
+    Ok(documents)
+}
+
+/// Image without its tag and digest
+pub fn repository(image: &str) -> &str {
+    let image = image.split_once('@').map_or(image, |(name, _)| name);
+
+    match image.rsplit_once(':') {
+        // A colon before the last slash belongs to the registry port
+        Some((name, tag)) if !tag.contains('/') => name,
+        _ => image,
+    }
+}
+
+/// Replaces every `image` naming an artifact, either by the artifact name or the repository
+/// it's pushed to, with the pushed image. Custom resources with an `image` field are
+/// included, only images of this build are touched.
+fn rewrite(value: &mut Value, images: &HashMap<&str, &str>) {
+    match value {
+        Value::Mapping(mapping) => {
+            for (key, value) in mapping.iter_mut() {
+                if key.as_str() == Some("image")
+                    && let Some(image) = value.as_str()
+                    && let Some(pushed) = images.get(repository(image))
+                {
+                    *value = Value::String(pushed.to_string());
+                } else {
+                    rewrite(value, images);
+                }
+            }
+        }
+        Value::Sequence(sequence) => {
+            for value in sequence.iter_mut() {
+                rewrite(value, images);
+            }
+        }
+        Value::Tagged(tagged) => rewrite(&mut tagged.value, images),
+        _ => {}
+    }
+}
+
+/// Pushed images, keyed by artifact name and by the repository they're pushed to
+pub fn pushed_images(output: &Output) -> HashMap<&str, &str> {
+    output
+        .builds
+        .iter()
+        .flat_map(|build| {
+            [
+                (build.image_name.as_str(), build.tag.as_str()),
+                (repository(&build.tag), build.tag.as_str()),
+            ]
+        })
+        .collect()
+}
+
+/// Manifests with the images of the build output, as a multi-document manifest
+pub async fn render(path: &str, output: &Output) -> Result<String, ManifestsError> {
+    let images = pushed_images(output);
+    let mut rendered = vec![];
+
+    for mut document in documents(path).await? {
+        rewrite(&mut document, &images);
+        rendered.push(serde_yml::to_string(&document)?);
+    }
+
+    Ok(rendered.join("---\n"))
+}
+
+/// Images referenced by the manifests, without tags. `None` when they can't be read.
+pub async fn images(path: &str) -> Option<BTreeSet<String>> {
+    fn collect(value: &Value, images: &mut BTreeSet<String>) {
+        match value {
+            Value::Mapping(mapping) => {
+                for (key, value) in mapping.iter() {
+                    match value.as_str() {
+                        Some(image) if key.as_str() == Some("image") => {
+                            images.insert(repository(image).to_string());
+                        }
+                        _ => collect(value, images),
+                    }
+                }
+            }
+            Value::Sequence(sequence) => sequence.iter().for_each(|value| collect(value, images)),
+            Value::Tagged(tagged) => collect(&tagged.value, images),
+            _ => {}
+        }
+    }
+
+    let mut images = BTreeSet::new();
+
+    for document in documents(path).await.ok()? {
+        collect(&document, &mut images);
+    }
+
+    Some(images)
+}
+
+#[derive(Clone)]
+pub struct ManifestsDeployer;
+
+impl Deployer for ManifestsDeployer {
+    type Error = ManifestsError;
+    type Input = Manifests;
+
+    fn try_init() -> Result<Self, Self::Error>
+    where
+        Self: Sized,
+    {
+        Ok(Self)
+    }
+
+    async fn validate(&self, input: &Self::Input) -> Result<(), Self::Error> {
+        documents(&input.path).await.map(|_| ())
+    }
+
+    async fn deploy(
+        self,
+        mut progress: Item,
+        release: String,
+        ctx: Context<Self::Input>,
+    ) -> Result<(), Self::Error> {
+        let input = &ctx.input;
+        let manifest = render(&input.path, &ctx.output).await?;
+
+        let mut hasher = Sha256::new();
+        hasher.update(release.as_bytes());
+        hasher.update(input.namespace.as_deref().unwrap_or_default());
+        hasher.update(&manifest);
+        let fingerprint = hex::encode(hasher.finalize());
+
+        let mut native =
+            Native::try_new(input.namespace.as_deref(), input.context.as_deref()).await?;
+
+        if ctx.skip_unchanged
+            && let Some(inventory) = native.inventory(&release).await?
+            && inventory.fingerprint == fingerprint
+        {
+            progress.done("release is up-to-date, skipping deployment".to_string());
+            return Ok(());
+        }
+
+        let mut resources = progress::child(&mut progress, format!("{release} › apply"));
+        let metadata = Metadata::from_output(&ctx.output);
+
+        match native
+            .apply(&mut resources, &release, &manifest, &metadata, &fingerprint)
+            .await
+        {
+            Ok(()) => progress.done("deployment finished".to_string()),
+            Err(e) => {
+                progress.fail(format!("deployment failed: {e}"));
+                return Err(e.into());
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn destroy(
+        self,
+        mut progress: Item,
+        release: String,
+        input: Self::Input,
+    ) -> Result<(), Self::Error> {
+        progress.info("deleting release resources");
+
+        Native::try_new(input.namespace.as_deref(), input.context.as_deref())
+            .await?
+            .remove(&mut progress, &release)
+            .await?;
+        progress.done("release removed".to_string());
+
+        Ok(())
+    }
+
+    async fn diff(
+        self,
+        mut progress: Item,
+        release: String,
+        ctx: Context<Self::Input>,
+    ) -> Result<Vec<Change>, Self::Error> {
+        let input = &ctx.input;
+        let manifest = render(&input.path, &ctx.output).await?;
+
+        progress.info("comparing with the cluster");
+
+        let changes = Native::try_new(input.namespace.as_deref(), input.context.as_deref())
+            .await?
+            .diff(
+                &release,
+                input.context.as_deref(),
+                &manifest,
+                &Metadata::from_output(&ctx.output),
+                None,
+            )
+            .await?;
+        progress.done(format!("{} resources would change", changes.len()));
+
+        Ok(changes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repositories() {
+        assert_eq!(repository("api"), "api");
+        assert_eq!(repository("api:v1"), "api");
+        assert_eq!(repository("org/api:v1@sha256:abc"), "org/api");
+        assert_eq!(repository("localhost:5000/api"), "localhost:5000/api");
+        assert_eq!(repository("localhost:5000/api:v1"), "localhost:5000/api");
+        assert_eq!(
+            repository("registry.example.com:443/org/api@sha256:abc"),
+            "registry.example.com:443/org/api"
+        );
+    }
+
+    #[test]
+    fn rewrites_images_of_the_build() {
+        let images = HashMap::from([
+            ("api", "localhost:5000/api:v2"),
+            ("localhost:5000/api", "localhost:5000/api:v2"),
+        ]);
+        let mut manifest = serde_yml::from_str::<Value>(
+            "
+spec:
+  containers:
+    - image: api
+    - image: localhost:5000/api:v1
+    - image: localhost:5000/worker:v1
+    - image: redis:7
+  initContainers:
+    - image: api:dev
+",
+        )
+        .unwrap();
+
+        rewrite(&mut manifest, &images);
+
+        let images = |field: &str| {
+            manifest["spec"][field]
+                .as_sequence()
+                .unwrap()
+                .iter()
+                .map(|container| container["image"].as_str().unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            images("containers"),
+            [
+                "localhost:5000/api:v2",
+                "localhost:5000/api:v2",
+                "localhost:5000/worker:v1",
+                "redis:7",
+            ]
+        );
+        assert_eq!(images("initContainers"), ["localhost:5000/api:v2"]);
+    }
+}
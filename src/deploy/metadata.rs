@@ -0,0 +1,148 @@
+use std::{collections::BTreeMap, env};
+
+use serde::Deserialize;
+use serde_yml::{Mapping, Value};
+
+use crate::cmd::build::output::Output;
+
+pub const ARTIFACT: &str = "steiger.io/artifact";
+pub const GIT_COMMIT: &str = "steiger.io/git-commit";
+pub const BUILD_ID: &str = "steiger.io/build-id";
+
+const ENV_GIT_COMMIT: &str = "STEIGER_GIT_COMMIT";
+const ENV_BUILD_ID: &str = "STEIGER_BUILD_ID";
+const ENV_IMAGES: &str = "STEIGER_IMAGES";
+
+/// Traces deployed resources back to the build that produced them
+#[derive(Default)]
+pub struct Metadata {
+    pub git_commit: Option<String>,
+    pub build_id: Option<String>,
+    /// Pushed image reference to artifact
+    pub images: BTreeMap<String, String>,
+}
+
+impl Metadata {
+    pub fn from_output(output: &Output) -> Self {
+        Self {
+            git_commit: output.git_commit.clone(),
+            build_id: output.build_id.clone(),
+            // Charts may pull from a mirror, so those references are traced back as well
+            images: output
+                .builds
+                .iter()
+                .flat_map(|build| {
+                    std::iter::once(&build.tag)
+                        .chain(build.mirrors.iter())
+                        .map(|image| (image.clone(), build.image_name.clone()))
+                })
+                .collect(),
+        }
+    }
+
+    /// Passed to `steiger post-render` through helm, which runs it with its own environment
+    pub fn to_env(&self) -> Vec<(&'static str, String)> {
+        let mut vars = vec![];
+        vars.extend(
+            self.git_commit
+                .clone()
+                .map(|commit| (ENV_GIT_COMMIT, commit)),
+        );
+        vars.extend(self.build_id.clone().map(|id| (ENV_BUILD_ID, id)));
+
+        if let Ok(images) = serde_json::to_string(&self.images) {
+            vars.push((ENV_IMAGES, images));
+        }
+
+        vars
+    }
+
+    pub fn from_env() -> Self {
+        Self {
+            git_commit: env::var(ENV_GIT_COMMIT).ok(),
+            build_id: env::var(ENV_BUILD_ID).ok(),
+            images: env::var(ENV_IMAGES)
+                .ok()
+                .and_then(|images| serde_json::from_str(&images).ok())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Labels so resources can be selected by commit or build
+    pub fn labels(&self) -> BTreeMap<&'static str, String> {
+        let mut labels = BTreeMap::new();
+        labels.extend(self.git_commit.clone().map(|commit| (GIT_COMMIT, commit)));
+        labels.extend(self.build_id.clone().map(|id| (BUILD_ID, id)));
+        labels
+    }
+
+    /// Artifacts the given images were built from, for the `steiger.io/artifact` annotation
+    pub fn artifacts(&self, images: &[&str]) -> Option<String> {
+        let mut artifacts = images
+            .iter()
+            .filter_map(|image| self.images.get(*image))
+            .cloned()
+            .collect::<Vec<_>>();
+        artifacts.sort();
+        artifacts.dedup();
+
+        (!artifacts.is_empty()).then(|| artifacts.join(","))
+    }
+
+    /// Labels the resource, workloads are also annotated with the artifacts of their
+    /// containers. Pod templates are left alone, changing them would restart pods on every
+    /// deploy.
+    pub fn apply(&self, resource: &mut Value) {
+        let spec = &resource["spec"];
+        // CronJobs nest the pod template in a job template
+        let template = match spec.get("jobTemplate") {
+            Some(job) => &job["spec"]["template"],
+            None => &spec["template"],
+        };
+        let images = template["spec"]["containers"]
+            .as_sequence()
+            .map(|containers| {
+                containers
+                    .iter()
+                    .filter_map(|container| container["image"].as_str())
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        let artifacts = self.artifacts(&images);
+        let metadata = &mut resource["metadata"];
+
+        for (key, value) in self.labels() {
+            insert(metadata, "labels", key, value);
+        }
+
+        if let Some(artifacts) = artifacts {
+            insert(metadata, "annotations", ARTIFACT, artifacts);
+        }
+    }
+}
+
+fn insert(metadata: &mut Value, field: &str, key: &str, value: String) {
+    if !metadata[field].is_mapping() {
+        metadata[field] = Value::Mapping(Mapping::new());
+    }
+
+    metadata[field][key] = Value::String(value);
+}
+
+/// Annotates every resource in a multi-document manifest, used as helm post-renderer
+pub fn post_render(manifest: &str, metadata: &Metadata) -> Result<String, serde_yml::Error> {
+    let mut documents = vec![];
+
+    for document in serde_yml::Deserializer::from_str(manifest) {
+        let mut value = Value::deserialize(document)?;
+
+        if value.is_null() {
+            continue;
+        }
+
+        metadata.apply(&mut value);
+        documents.push(serde_yml::to_string(&value)?);
+    }
+
+    Ok(documents.join("---\n"))
+}
@@ -1,29 +1,84 @@
-use std::sync::Arc;
+use std::{
+    cell::RefCell,
+    collections::{BTreeSet, HashMap},
+    sync::{Arc, Mutex},
+};
 
-use futures::TryFutureExt;
+use futures::{FutureExt, StreamExt, TryFutureExt, future::BoxFuture, stream};
+use k8s_openapi::api::core::v1::Namespace;
+use kube::{
+    api::{Api, DeleteParams},
+    config::{KubeConfigOptions, KubeconfigError},
+};
 use miette::Diagnostic;
 use prodash::tree::Item;
-use tokio::{task::JoinSet, time::Instant};
+use serde::Serialize;
+use steiger::ui;
+use tokio::{
+    task::JoinSet,
+    time::{Duration, Instant, sleep},
+};
 
 use crate::{
     cmd::build::output::Output,
-    config::{Config, Release},
-    deploy::helm::HelmDeployer,
+    config::{self, Config, DeployRetry, Release},
+    deploy::{
+        compose::ComposeDeployer, external::ExternalError, gitops::GitopsDeployer,
+        helm::HelmDeployer, manifests::ManifestsDeployer, rollout::RolloutDeployer,
+    },
+    progress,
+    report::{Recorder, Stage},
 };
 
+pub mod compose;
+pub mod external;
+pub mod github;
+pub mod gitops;
 pub mod helm;
+pub mod manifests;
+pub mod metadata;
+mod native;
+pub mod rollout;
 
 pub struct Context<T> {
     pub input: T,
     pub output: Arc<Output>,
+    pub skip_unchanged: bool,
 }
 
 impl<T> Context<T> {
-    pub fn new(input: T, output: Arc<Output>) -> Self {
-        Self { input, output }
+    pub fn new(input: T, output: Arc<Output>, skip_unchanged: bool) -> Self {
+        Self {
+            input,
+            output,
+            skip_unchanged,
+        }
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Action {
+    Create,
+    Update,
+    Delete,
+}
+
+/// Change a deploy would make to a resource in the cluster
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Change {
+    pub release: String,
+    /// Kube context of the target, `None` for the current context
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context: Option<String>,
+    /// Resource like `Deployment/api in backend`
+    pub resource: String,
+    pub action: Action,
+    /// Unified diff of the live resource and the resource after deploying, as YAML
+    pub diff: String,
+}
+
 pub trait Deployer: Clone {
     type Error;
     type Input;
@@ -38,6 +93,19 @@ pub trait Deployer: Clone {
         release: String,
         input: Context<Self::Input>,
     ) -> Result<(), Self::Error>;
+    async fn destroy(
+        self,
+        progress: Item,
+        release: String,
+        input: Self::Input,
+    ) -> Result<(), Self::Error>;
+    /// Changes deploying the release would make, without changing anything
+    async fn diff(
+        self,
+        progress: Item,
+        release: String,
+        input: Context<Self::Input>,
+    ) -> Result<Vec<Change>, Self::Error>;
 }
 
 type ErrorOf<T> = <T as Deployer>::Error;
@@ -49,14 +117,95 @@ pub struct MultiError {
     pub errors: Vec<DeployError>,
 }
 
+#[derive(Debug, Diagnostic, thiserror::Error)]
+#[error("release '{release}' failed on {} of {total} targets", errors.len())]
+pub struct TargetsError {
+    pub release: String,
+    pub total: usize,
+    #[related]
+    pub errors: Vec<TargetError>,
+}
+
+#[derive(Debug, Diagnostic, thiserror::Error)]
+#[error("failed to deploy to {target}")]
+pub struct TargetError {
+    pub target: String,
+    #[source]
+    #[diagnostic_source]
+    pub error: DeployError,
+}
+
+#[derive(Debug, Diagnostic, thiserror::Error)]
+pub enum ClientError {
+    #[error("failed to load kube context '{0}'")]
+    Context(String, #[source] KubeconfigError),
+    #[error("failed to connect to cluster")]
+    Kube(#[source] Box<kube::Error>),
+}
+
+/// Client for the kube context, or for the current context when it isn't set
+pub async fn client(context: Option<&str>) -> Result<kube::Client, ClientError> {
+    let config = match context {
+        Some(context) => {
+            let options = KubeConfigOptions {
+                context: Some(context.to_string()),
+                ..KubeConfigOptions::default()
+            };
+
+            kube::Config::from_kubeconfig(&options)
+                .await
+                .map_err(|e| ClientError::Context(context.to_string(), e))?
+        }
+        None => {
+            return kube::Client::try_default()
+                .await
+                .map_err(|e| ClientError::Kube(Box::new(e)));
+        }
+    };
+
+    kube::Client::try_from(config).map_err(|e| ClientError::Kube(Box::new(e)))
+}
+
 #[derive(Debug, Diagnostic, thiserror::Error)]
 pub enum DeployError {
     #[error("helm error")]
     #[diagnostic(transparent)]
     Helm(#[from] ErrorOf<HelmDeployer>),
+    #[error("rollout error")]
+    #[diagnostic(transparent)]
+    Rollout(#[from] ErrorOf<RolloutDeployer>),
+    #[error("manifests error")]
+    #[diagnostic(transparent)]
+    Manifests(#[from] ErrorOf<ManifestsDeployer>),
+    #[error("compose error")]
+    #[diagnostic(transparent)]
+    Compose(#[from] ErrorOf<ComposeDeployer>),
+    #[error("gitops error")]
+    #[diagnostic(transparent)]
+    Gitops(#[from] ErrorOf<GitopsDeployer>),
     #[error(transparent)]
     #[diagnostic(transparent)]
     Multi(MultiError),
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    Targets(TargetsError),
+    #[error("deploy task panicked or was cancelled")]
+    Join(#[from] tokio::task::JoinError),
+    #[error("invalid release order")]
+    #[diagnostic(transparent)]
+    Order(#[from] config::Error),
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    Client(#[from] ClientError),
+    #[error("failed to delete namespace '{0}'")]
+    Namespace(String, #[source] Box<kube::Error>),
+    #[error("failed to load artifacts from {source_name}")]
+    External {
+        source_name: String,
+        #[source]
+        #[diagnostic_source]
+        error: ExternalError,
+    },
 }
 
 fn ensure<T: Deployer>(deploy: &Option<T>) -> T {
@@ -66,25 +215,274 @@ fn ensure<T: Deployer>(deploy: &Option<T>) -> T {
     }
 }
 
+/// Runs the release on each of its targets, at most `maxParallel` at once.
+/// Releases without targets run once on the current context.
+fn fan_out(
+    mut progress: Item,
+    name: &str,
+    release: Release,
+    run: impl Fn(Item, Release) -> BoxFuture<'static, Result<(), DeployError>>,
+) -> BoxFuture<'static, Result<(), DeployError>> {
+    let targets = release.targets();
+
+    if targets.is_empty() {
+        return run(progress, release);
+    }
+
+    let total = targets.len();
+    let limit = release.max_parallel().unwrap_or(total).max(1);
+    let futures = targets
+        .iter()
+        .map(|target| {
+            let label = target.name();
+            let future = run(
+                progress::child(&mut progress, format!("{name} › {label}")),
+                release.for_target(target),
+            );
+
+            future.map_err(move |error| TargetError {
+                target: label,
+                error,
+            })
+        })
+        .collect::<Vec<_>>();
+    let release = name.to_string();
+
+    async move {
+        progress.init(Some(total), None);
+
+        let mut results = stream::iter(futures).buffer_unordered(limit);
+        let mut errors = vec![];
+
+        while let Some(result) = results.next().await {
+            progress.inc();
+
+            if let Err(e) = result {
+                errors.push(e);
+            }
+        }
+
+        if errors.is_empty() {
+            progress.done(format!("finished on {total} targets"));
+            return Ok(());
+        }
+
+        progress.fail(format!("failed on {} of {total} targets", errors.len()));
+
+        Err(DeployError::Targets(TargetsError {
+            release,
+            total,
+            errors,
+        }))
+    }
+    .boxed()
+}
+
+/// Deployers of the release types in the config, cloned into the deploy tasks so failed
+/// deploys can be started again
+#[derive(Clone)]
+struct Deployers {
+    helm: Option<HelmDeployer>,
+    rollout: Option<RolloutDeployer>,
+    manifests: Option<ManifestsDeployer>,
+    compose: Option<ComposeDeployer>,
+    gitops: Option<GitopsDeployer>,
+    skip_unchanged: bool,
+}
+
+impl Deployers {
+    fn start(
+        &self,
+        progress: Item,
+        name: &str,
+        release: Release,
+        output: Arc<Output>,
+    ) -> BoxFuture<'static, Result<(), DeployError>> {
+        let name = name.to_string();
+
+        match release {
+            Release::Helm(helm) => ensure(&self.helm)
+                .deploy(
+                    progress,
+                    name,
+                    Context::new(helm, output, self.skip_unchanged),
+                )
+                .map_err(DeployError::Helm)
+                .boxed(),
+            Release::Rollout(rollout) => ensure(&self.rollout)
+                .deploy(
+                    progress,
+                    name,
+                    Context::new(rollout, output, self.skip_unchanged),
+                )
+                .map_err(DeployError::Rollout)
+                .boxed(),
+            Release::Manifests(manifests) => ensure(&self.manifests)
+                .deploy(
+                    progress,
+                    name,
+                    Context::new(manifests, output, self.skip_unchanged),
+                )
+                .map_err(DeployError::Manifests)
+                .boxed(),
+            Release::Compose(compose) => ensure(&self.compose)
+                .deploy(
+                    progress,
+                    name,
+                    Context::new(compose, output, self.skip_unchanged),
+                )
+                .map_err(DeployError::Compose)
+                .boxed(),
+            Release::Gitops(gitops) => ensure(&self.gitops)
+                .deploy(
+                    progress,
+                    name,
+                    Context::new(gitops, output, self.skip_unchanged),
+                )
+                .map_err(DeployError::Gitops)
+                .boxed(),
+        }
+    }
+}
+
+/// Messages of the error and its sources, the command output of failed helm and kubectl
+/// calls is part of these
+fn error_messages(error: &DeployError) -> String {
+    let mut messages = vec![];
+    let mut source = Some(error as &(dyn std::error::Error + 'static));
+
+    while let Some(e) = source {
+        messages.push(e.to_string());
+        source = e.source();
+    }
+
+    // The output of a failed command is in the help, e.g. why `helm upgrade` failed
+    if let Some(help) = error.help() {
+        messages.push(help.to_string());
+    }
+
+    messages.join(": ")
+}
+
+/// Deploys the release, attempts failing with an error in `retryOn` are started again after
+/// the backoff. Every attempt gets its own progress item
+fn with_retry(
+    mut progress: Item,
+    name: String,
+    release: Release,
+    output: Arc<Output>,
+    deployers: Deployers,
+    retry: Option<DeployRetry>,
+) -> BoxFuture<'static, Result<(), DeployError>> {
+    let Some(retry) = retry else {
+        return deployers.start(progress, &name, release, output);
+    };
+
+    async move {
+        let mut attempt = 1;
+
+        loop {
+            let item = progress::child(&mut progress, format!("attempt {attempt}"));
+            let result = deployers
+                .start(item, &name, release.clone(), Arc::clone(&output))
+                .await;
+
+            match result {
+                Err(e) if attempt <= retry.attempts && retry.matches(&error_messages(&e)) => {
+                    let delay = retry.delay(attempt);
+
+                    progress.info(format!("attempt {attempt} failed, retrying in {delay}s"));
+                    ui::record_warning(format!(
+                        "deploy of {name} was retried after: {}",
+                        error_messages(&e)
+                    ));
+                    sleep(Duration::from_secs(delay)).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+    .boxed()
+}
+
+/// Waits for all releases in the set, returns whether all of them succeeded
+async fn join_all(
+    pb: &mut Item,
+    set: &mut JoinSet<Result<(), DeployError>>,
+    errors: &mut Vec<DeployError>,
+) -> bool {
+    let failures = errors.len();
+
+    while let Some(result) = set.join_next().await {
+        pb.inc();
+
+        if let Err(e) = result.map_err(DeployError::Join).and_then(|r| r) {
+            errors.push(e);
+        }
+    }
+
+    errors.len() == failures
+}
+
 pub struct MetaDeployer {
     config: Config,
     output: Arc<Output>,
+    /// Output of releases with `artifactsFrom`, merged with the builds of this project
+    outputs: HashMap<String, Arc<Output>>,
+    skip_unchanged: bool,
+    recorder: Recorder,
     helm: Option<HelmDeployer>,
+    rollout: Option<RolloutDeployer>,
+    manifests: Option<ManifestsDeployer>,
+    compose: Option<ComposeDeployer>,
+    gitops: Option<GitopsDeployer>,
 }
 
 impl MetaDeployer {
-    pub fn new(config: Config, output: Arc<Output>) -> Self {
+    pub fn new(
+        config: Config,
+        output: Arc<Output>,
+        skip_unchanged: bool,
+        recorder: Recorder,
+    ) -> Self {
         Self {
             config,
             output,
+            outputs: HashMap::new(),
+            skip_unchanged,
+            recorder,
             helm: None,
+            rollout: None,
+            manifests: None,
+            compose: None,
+            gitops: None,
         }
     }
 
     pub async fn validate(&mut self, pb: &mut Item) -> Result<(), DeployError> {
         pb.info("validating releases");
 
-        for release in self.config.deploy.values() {
+        for (name, release) in self.config.deploy.iter() {
+            if !release.artifacts_from().is_empty() {
+                let mut external = vec![];
+
+                for source in release.artifacts_from() {
+                    pb.info(format!("loading artifacts from {}", source.name()));
+
+                    let builds = external::load(&self.config, source)
+                        .await
+                        .map_err(|error| DeployError::External {
+                            source_name: source.name(),
+                            error,
+                        })?;
+                    external.push((source.name(), builds));
+                }
+
+                let output = external::merge(&self.output, external);
+                self.outputs.insert(name.clone(), Arc::new(output));
+            }
+
             match release {
                 Release::Helm(helm) => {
                     if self.helm.is_none() {
@@ -93,41 +491,108 @@ impl MetaDeployer {
 
                     ensure(&self.helm).validate(helm).await?;
                 }
+                Release::Rollout(rollout) => {
+                    if self.rollout.is_none() {
+                        self.rollout = Some(RolloutDeployer::try_init()?)
+                    }
+
+                    ensure(&self.rollout).validate(rollout).await?;
+                }
+                Release::Manifests(manifests) => {
+                    if self.manifests.is_none() {
+                        self.manifests = Some(ManifestsDeployer::try_init()?)
+                    }
+
+                    ensure(&self.manifests).validate(manifests).await?;
+                }
+                Release::Compose(compose) => {
+                    if self.compose.is_none() {
+                        self.compose = Some(ComposeDeployer::try_init()?)
+                    }
+
+                    ensure(&self.compose).validate(compose).await?;
+                }
+                Release::Gitops(gitops) => {
+                    if self.gitops.is_none() {
+                        self.gitops = Some(GitopsDeployer::try_init()?)
+                    }
+
+                    ensure(&self.gitops).validate(gitops).await?;
+                }
             }
         }
 
         Ok(())
     }
 
-    pub async fn deploy(self, mut pb: Item) -> Result<(), DeployError> {
+    fn spawn(
+        &self,
+        set: &mut JoinSet<Result<(), DeployError>>,
+        name: String,
+        future: impl Future<Output = Result<(), DeployError>> + Send + 'static,
+    ) {
+        let recorder = self.recorder.clone();
+
+        set.spawn(async move {
+            let started = Instant::now();
+            let result = future.await;
+
+            recorder.record(
+                Stage::Deploy,
+                &name,
+                started.elapsed(),
+                result.as_ref().err().map(|e| e as _),
+            );
+
+            result
+        });
+    }
+
+    pub async fn deploy(mut self, mut pb: Item) -> Result<(), DeployError> {
         let instant = Instant::now();
-        let mut set = JoinSet::<Result<_, DeployError>>::new();
+        let order = self.config.release_order()?;
+        let mut releases = std::mem::take(&mut self.config.deploy);
+        let mut errors = vec![];
+        let deployers = Deployers {
+            helm: self.helm.clone(),
+            rollout: self.rollout.clone(),
+            manifests: self.manifests.clone(),
+            compose: self.compose.clone(),
+            gitops: self.gitops.clone(),
+            skip_unchanged: self.skip_unchanged,
+        };
 
-        pb.init(Some(self.config.deploy.len()), None);
+        pb.init(Some(releases.len()), None);
         pb.info("starting deployment");
 
-        for (name, release) in self.config.deploy {
-            let progress = pb.add_child(&name);
+        // Releases in a wave are deployed in parallel, later waves wait for their dependencies
+        for wave in order {
+            let mut set = JoinSet::new();
 
-            match release {
-                Release::Helm(helm) => {
-                    set.spawn(
-                        ensure(&self.helm)
-                            .deploy(progress, name, Context::new(helm, Arc::clone(&self.output)))
-                            .map_err(DeployError::Helm),
-                    );
-                }
-            }
-        }
+            for name in wave {
+                let Some(release) = releases.remove(&name) else {
+                    continue;
+                };
+                let progress = progress::child(&mut pb, &name);
+                let output = Arc::clone(self.outputs.get(&name).unwrap_or(&self.output));
 
-        let mut errors = vec![];
+                let future = fan_out(progress, &name, release, |progress, release| {
+                    with_retry(
+                        progress,
+                        name.clone(),
+                        release,
+                        Arc::clone(&output),
+                        deployers.clone(),
+                        self.config.deploy_retry.clone(),
+                    )
+                });
 
-        while let Some(Ok(result)) = set.join_next().await {
-            pb.inc();
+                self.spawn(&mut set, name, future);
+            }
 
-            if let Err(e) = result {
-                pb.fail("deployment failed");
-                errors.push(e);
+            if !join_all(&mut pb, &mut set, &mut errors).await {
+                pb.fail("deployment failed, skipping dependent releases");
+                break;
             }
         }
 
@@ -141,4 +606,226 @@ impl MetaDeployer {
 
         Ok(())
     }
+
+    /// Removes all releases, dependents before their dependencies
+    pub async fn destroy(
+        mut self,
+        mut pb: Item,
+        delete_namespaces: bool,
+    ) -> Result<(), DeployError> {
+        let instant = Instant::now();
+        let order = self.config.release_order()?;
+        let mut releases = std::mem::take(&mut self.config.deploy);
+        // Namespaces are per cluster, keyed by kube context
+        let namespaces = RefCell::new(BTreeSet::new());
+        let mut errors = vec![];
+
+        pb.init(Some(releases.len()), None);
+        pb.info("starting teardown");
+
+        for wave in order.into_iter().rev() {
+            let mut set = JoinSet::new();
+
+            for name in wave {
+                let Some(release) = releases.remove(&name) else {
+                    continue;
+                };
+                let progress = progress::child(&mut pb, &name);
+
+                let future = fan_out(
+                    progress,
+                    &name,
+                    release,
+                    |progress, release| match release {
+                        Release::Helm(helm) => {
+                            if let Some(ref namespace) = helm.namespace {
+                                namespaces
+                                    .borrow_mut()
+                                    .insert((helm.context.clone(), namespace.clone()));
+                            }
+
+                            ensure(&self.helm)
+                                .destroy(progress, name.clone(), helm)
+                                .map_err(DeployError::Helm)
+                                .boxed()
+                        }
+                        Release::Rollout(rollout) => ensure(&self.rollout)
+                            .destroy(progress, name.clone(), rollout)
+                            .map_err(DeployError::Rollout)
+                            .boxed(),
+                        Release::Manifests(manifests) => {
+                            if let Some(ref namespace) = manifests.namespace {
+                                namespaces
+                                    .borrow_mut()
+                                    .insert((manifests.context.clone(), namespace.clone()));
+                            }
+
+                            ensure(&self.manifests)
+                                .destroy(progress, name.clone(), manifests)
+                                .map_err(DeployError::Manifests)
+                                .boxed()
+                        }
+                        Release::Compose(compose) => ensure(&self.compose)
+                            .destroy(progress, name.clone(), compose)
+                            .map_err(DeployError::Compose)
+                            .boxed(),
+                        Release::Gitops(gitops) => ensure(&self.gitops)
+                            .destroy(progress, name.clone(), gitops)
+                            .map_err(DeployError::Gitops)
+                            .boxed(),
+                    },
+                );
+
+                self.spawn(&mut set, name, future);
+            }
+
+            if !join_all(&mut pb, &mut set, &mut errors).await {
+                pb.fail("teardown failed, keeping dependencies");
+                return Err(DeployError::Multi(MultiError { errors }));
+            }
+        }
+
+        if delete_namespaces {
+            for (context, namespace) in namespaces.into_inner() {
+                let api = Api::<Namespace>::all(client(context.as_deref()).await?);
+
+                match context {
+                    Some(context) => {
+                        pb.info(format!("deleting namespace {namespace} in {context}"))
+                    }
+                    None => pb.info(format!("deleting namespace {namespace}")),
+                }
+
+                match api.delete(&namespace, &DeleteParams::default()).await {
+                    Ok(_) => {}
+                    Err(kube::Error::Api(e)) if e.code == 404 => {}
+                    Err(e) => return Err(DeployError::Namespace(namespace, Box::new(e))),
+                }
+            }
+        }
+
+        let elapsed = instant.elapsed();
+
+        pb.done(format!("teardown completed in {elapsed:?}"));
+
+        Ok(())
+    }
+
+    /// Changes deploying all releases would make, sorted by release and target. Releases
+    /// are compared in parallel, nothing is changed so dependencies don't matter
+    pub async fn diff(mut self, mut pb: Item) -> Result<Vec<Change>, DeployError> {
+        let releases = std::mem::take(&mut self.config.deploy);
+        let changes = Arc::new(Mutex::new(vec![]));
+        let mut set = JoinSet::new();
+        let mut errors = vec![];
+
+        pb.init(Some(releases.len()), None);
+        pb.info("comparing releases");
+
+        for (name, release) in releases {
+            let progress = progress::child(&mut pb, &name);
+            let output = Arc::clone(self.outputs.get(&name).unwrap_or(&self.output));
+
+            let future = fan_out(progress, &name, release, |progress, release| {
+                let name = name.clone();
+                let future = match release {
+                    Release::Helm(helm) => ensure(&self.helm)
+                        .diff(
+                            progress,
+                            name,
+                            Context::new(helm, Arc::clone(&output), false),
+                        )
+                        .map_err(DeployError::Helm)
+                        .boxed(),
+                    Release::Rollout(rollout) => ensure(&self.rollout)
+                        .diff(
+                            progress,
+                            name,
+                            Context::new(rollout, Arc::clone(&output), false),
+                        )
+                        .map_err(DeployError::Rollout)
+                        .boxed(),
+                    Release::Manifests(manifests) => ensure(&self.manifests)
+                        .diff(
+                            progress,
+                            name,
+                            Context::new(manifests, Arc::clone(&output), false),
+                        )
+                        .map_err(DeployError::Manifests)
+                        .boxed(),
+                    Release::Compose(compose) => ensure(&self.compose)
+                        .diff(
+                            progress,
+                            name,
+                            Context::new(compose, Arc::clone(&output), false),
+                        )
+                        .map_err(DeployError::Compose)
+                        .boxed(),
+                    Release::Gitops(gitops) => ensure(&self.gitops)
+                        .diff(
+                            progress,
+                            name,
+                            Context::new(gitops, Arc::clone(&output), false),
+                        )
+                        .map_err(DeployError::Gitops)
+                        .boxed(),
+                };
+                let changes = Arc::clone(&changes);
+
+                async move {
+                    let found = future.await?;
+                    changes.lock().unwrap().extend(found);
+                    Ok(())
+                }
+                .boxed()
+            });
+
+            set.spawn(future);
+        }
+
+        join_all(&mut pb, &mut set, &mut errors).await;
+
+        if !errors.is_empty() {
+            pb.fail("diff failed");
+            return Err(DeployError::Multi(MultiError { errors }));
+        }
+
+        let mut changes = std::mem::take(&mut *changes.lock().unwrap());
+        // Stable sort, resources keep the order they're applied in
+        changes.sort_by(|a, b| (&a.release, &a.context).cmp(&(&b.release, &b.context)));
+
+        pb.done(format!("{} resources would change", changes.len()));
+
+        Ok(changes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{os::unix::process::ExitStatusExt, process::ExitStatus};
+
+    use crate::{config::DeployRetry, deploy::helm::HelmError};
+
+    use super::*;
+
+    fn install_error(output: &str) -> DeployError {
+        DeployError::Helm(HelmError::Install(
+            ExitStatus::from_raw(256),
+            output.to_string(),
+        ))
+    }
+
+    #[test]
+    fn retries_helm_failures_by_their_output() {
+        let retry = serde_yml::from_str::<DeployRetry>("{}").unwrap();
+        let refused = install_error(
+            "Error: Kubernetes cluster unreachable: Get \"https://10.0.0.1:6443/version\": dial tcp 10.0.0.1:6443: connect: connection refused",
+        );
+        let invalid = install_error(
+            "Error: INSTALLATION FAILED: chart requires kubeVersion: >=1.30 which is incompatible with Kubernetes v1.29.4",
+        );
+
+        assert!(retry.matches(&error_messages(&refused)));
+        assert!(!retry.matches(&error_messages(&invalid)));
+    }
 }
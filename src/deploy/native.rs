@@ -0,0 +1,696 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::{self, Display},
+};
+
+use k8s_openapi::{api::core::v1::ConfigMap, apimachinery::pkg::apis::meta::v1::ObjectMeta};
+use kube::{
+    Api, Client, ResourceExt,
+    api::{DeleteParams, DynamicObject, Patch, PatchParams},
+    core::GroupVersionKind,
+    discovery::{self, ApiCapabilities, ApiResource, Scope},
+};
+use miette::Diagnostic;
+use prodash::tree::Item;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value as JsonValue};
+use similar::TextDiff;
+
+use crate::deploy::{self, Action, Change, ClientError, metadata::Metadata};
+
+#[derive(Debug, Diagnostic, thiserror::Error)]
+pub enum NativeError {
+    #[error("failed to parse manifest")]
+    Manifest(#[from] serde_yml::Error),
+    #[error("failed to serialize inventory")]
+    Inventory(#[from] serde_json::Error),
+    #[error("resource '{0}' has no apiVersion or kind")]
+    InvalidResource(String),
+    #[error("kubernetes API error")]
+    Kube(#[source] Box<kube::Error>),
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    Client(#[from] ClientError),
+    #[error("unknown resource kind for {0}")]
+    #[diagnostic(help("make sure the CRD is installed before it's used"))]
+    UnknownKind(String, #[source] Box<kube::Error>),
+    #[error("failed to apply {0}")]
+    Apply(String, #[source] Box<kube::Error>),
+}
+
+const FIELD_MANAGER: &str = "steiger";
+const RELEASE_LABEL: &str = "steiger.dev/release";
+const INVENTORY_KEY: &str = "inventory";
+
+/// Resources are applied in this order (like Helm does), unlisted kinds go last
+const KIND_ORDER: [&str; 27] = [
+    "Namespace",
+    "NetworkPolicy",
+    "ResourceQuota",
+    "LimitRange",
+    "PodDisruptionBudget",
+    "ServiceAccount",
+    "Secret",
+    "ConfigMap",
+    "StorageClass",
+    "PersistentVolume",
+    "PersistentVolumeClaim",
+    "CustomResourceDefinition",
+    "ClusterRole",
+    "ClusterRoleBinding",
+    "Role",
+    "RoleBinding",
+    "Service",
+    "DaemonSet",
+    "Pod",
+    "ReplicaSet",
+    "Deployment",
+    "HorizontalPodAutoscaler",
+    "StatefulSet",
+    "Job",
+    "CronJob",
+    "IngressClass",
+    "Ingress",
+];
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ResourceRef {
+    api_version: String,
+    kind: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    namespace: Option<String>,
+    name: String,
+}
+
+impl ResourceRef {
+    fn gvk(&self) -> GroupVersionKind {
+        let (group, version) = self
+            .api_version
+            .split_once('/')
+            .unwrap_or(("", &self.api_version));
+
+        GroupVersionKind::gvk(group, version, &self.kind)
+    }
+}
+
+impl Display for ResourceRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.namespace {
+            Some(ref namespace) => write!(f, "{}/{} in {namespace}", self.kind, self.name),
+            None => write!(f, "{}/{}", self.kind, self.name),
+        }
+    }
+}
+
+/// Resources applied by the last deployment in apply order, used to prune resources removed
+/// from the chart
+#[derive(Default, Serialize, Deserialize)]
+pub struct Inventory {
+    pub fingerprint: String,
+    resources: Vec<ResourceRef>,
+}
+
+fn kube_error(e: kube::Error) -> NativeError {
+    NativeError::Kube(Box::new(e))
+}
+
+/// Parses a multi-document manifest, like the output of `helm template`
+fn parse(manifest: &str, metadata: &Metadata) -> Result<Vec<DynamicObject>, NativeError> {
+    let mut objects = vec![];
+
+    for document in serde_yml::Deserializer::from_str(manifest) {
+        let mut value = serde_yml::Value::deserialize(document)?;
+
+        if value.is_null() {
+            continue;
+        }
+
+        metadata.apply(&mut value);
+        objects.push(serde_yml::from_value::<DynamicObject>(value)?);
+    }
+
+    objects.sort_by_key(|object| {
+        let kind = object
+            .types
+            .as_ref()
+            .map_or("", |types| types.kind.as_str());
+        KIND_ORDER
+            .iter()
+            .position(|k| *k == kind)
+            .unwrap_or(KIND_ORDER.len())
+    });
+
+    Ok(objects)
+}
+
+fn resource(object: &DynamicObject) -> Result<ResourceRef, NativeError> {
+    let types = object
+        .types
+        .clone()
+        .ok_or_else(|| NativeError::InvalidResource(object.name_any()))?;
+
+    Ok(ResourceRef {
+        api_version: types.api_version,
+        kind: types.kind,
+        namespace: object.metadata.namespace.clone(),
+        name: object.name_any(),
+    })
+}
+
+/// Fields of a Secret holding its values
+const SECRET_FIELDS: [&str; 2] = ["data", "stringData"];
+
+/// Annotation `kubectl apply` stores the whole applied object in, values included
+const LAST_APPLIED: &str = "kubectl.kubernetes.io/last-applied-configuration";
+
+fn is_secret(object: &Option<&mut DynamicObject>) -> bool {
+    object
+        .as_ref()
+        .and_then(|object| object.types.as_ref())
+        .is_some_and(|types| types.kind == "Secret")
+}
+
+/// Values of a Secret field
+fn secret_values(object: &Option<&mut DynamicObject>, field: &str) -> Map<String, JsonValue> {
+    object
+        .as_ref()
+        .and_then(|object| object.data.get(field))
+        .and_then(JsonValue::as_object)
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// Masks the values of a Secret field, values that differ from `other` get the marker
+fn mask(
+    object: Option<&mut DynamicObject>,
+    field: &str,
+    other: &Map<String, JsonValue>,
+    marker: &str,
+) {
+    let Some(values) = object
+        .and_then(|object| object.data.get_mut(field))
+        .and_then(JsonValue::as_object_mut)
+    else {
+        return;
+    };
+
+    for (key, value) in values.iter_mut() {
+        *value = match other.get(key) == Some(value) {
+            true => JsonValue::from("***"),
+            false => JsonValue::from(format!("*** ({marker})")),
+        };
+    }
+}
+
+/// Replaces the values of Secrets like `kubectl diff` does, values that differ are marked
+/// so the diff still shows which keys change
+fn mask_secrets(mut live: Option<&mut DynamicObject>, mut applied: Option<&mut DynamicObject>) {
+    if !is_secret(&live) && !is_secret(&applied) {
+        return;
+    }
+
+    for field in SECRET_FIELDS {
+        let (before, after) = (secret_values(&live, field), secret_values(&applied, field));
+
+        mask(live.as_deref_mut(), field, &after, "before");
+        mask(applied.as_deref_mut(), field, &before, "after");
+    }
+
+    // Secrets applied with kubectl before carry their values in plain text here
+    for object in [live, applied].into_iter().flatten() {
+        let annotations = &mut object.metadata.annotations;
+
+        if let Some(values) = annotations.as_mut() {
+            values.remove(LAST_APPLIED);
+        }
+        if annotations.as_ref().is_some_and(BTreeMap::is_empty) {
+            *annotations = None;
+        }
+    }
+}
+
+/// Object as YAML without the fields maintained by the API server
+fn to_yaml(object: Option<DynamicObject>) -> Result<String, serde_yml::Error> {
+    let Some(mut object) = object else {
+        return Ok(String::new());
+    };
+    let metadata = &mut object.metadata;
+
+    metadata.managed_fields = None;
+    metadata.resource_version = None;
+    metadata.generation = None;
+    metadata.uid = None;
+    metadata.creation_timestamp = None;
+
+    if let Some(data) = object.data.as_object_mut() {
+        data.remove("status");
+    }
+
+    serde_yml::to_string(&object)
+}
+
+/// Compares the live object with the object after deploying, `None` when they're the same.
+/// Secret values are masked
+pub fn compare(
+    release: &str,
+    context: Option<&str>,
+    resource: String,
+    mut live: Option<DynamicObject>,
+    mut applied: Option<DynamicObject>,
+) -> Result<Option<Change>, serde_yml::Error> {
+    mask_secrets(live.as_mut(), applied.as_mut());
+
+    let action = match (&live, &applied) {
+        (None, _) => Action::Create,
+        (_, None) => Action::Delete,
+        _ => Action::Update,
+    };
+    let (live, applied) = (to_yaml(live)?, to_yaml(applied)?);
+
+    if live == applied {
+        return Ok(None);
+    }
+
+    Ok(Some(Change {
+        release: release.to_string(),
+        context: context.map(str::to_string),
+        resource,
+        action,
+        diff: TextDiff::from_lines(&live, &applied)
+            .unified_diff()
+            .header("live", "deployed")
+            .to_string(),
+    }))
+}
+
+pub struct Native {
+    client: Client,
+    namespace: String,
+    kinds: HashMap<GroupVersionKind, (ApiResource, ApiCapabilities)>,
+}
+
+impl Native {
+    pub async fn try_new(
+        namespace: Option<&str>,
+        context: Option<&str>,
+    ) -> Result<Self, NativeError> {
+        let client = deploy::client(context).await?;
+        let namespace = namespace.unwrap_or(client.default_namespace()).to_string();
+
+        Ok(Self {
+            client,
+            namespace,
+            kinds: HashMap::new(),
+        })
+    }
+
+    /// Resolves the API of a resource, discovery is done per kind so CRDs applied earlier
+    /// in the same release are picked up
+    async fn api(&mut self, resource: &ResourceRef) -> Result<Api<DynamicObject>, NativeError> {
+        let gvk = resource.gvk();
+
+        if !self.kinds.contains_key(&gvk) {
+            let kind = discovery::pinned_kind(&self.client, &gvk)
+                .await
+                .map_err(|e| NativeError::UnknownKind(resource.to_string(), Box::new(e)))?;
+            self.kinds.insert(gvk.clone(), kind);
+        }
+
+        let (api_resource, capabilities) = &self.kinds[&gvk];
+        let client = self.client.clone();
+
+        Ok(match (&capabilities.scope, &resource.namespace) {
+            (Scope::Cluster, _) => Api::all_with(client, api_resource),
+            (Scope::Namespaced, Some(namespace)) => {
+                Api::namespaced_with(client, namespace, api_resource)
+            }
+            (Scope::Namespaced, None) => {
+                Api::namespaced_with(client, &self.namespace, api_resource)
+            }
+        })
+    }
+
+    fn inventory_api(&self) -> Api<ConfigMap> {
+        Api::namespaced(self.client.clone(), &self.namespace)
+    }
+
+    pub async fn inventory(&self, release: &str) -> Result<Option<Inventory>, NativeError> {
+        let Some(config_map) = self
+            .inventory_api()
+            .get_opt(&format!("steiger.{release}"))
+            .await
+            .map_err(kube_error)?
+        else {
+            return Ok(None);
+        };
+
+        Ok(config_map
+            .data
+            .and_then(|mut data| data.remove(INVENTORY_KEY))
+            .map(|data| serde_json::from_str(&data))
+            .transpose()?)
+    }
+
+    async fn save_inventory(
+        &self,
+        release: &str,
+        inventory: &Inventory,
+    ) -> Result<(), NativeError> {
+        let name = format!("steiger.{release}");
+        let config_map = ConfigMap {
+            metadata: ObjectMeta {
+                name: Some(name.clone()),
+                namespace: Some(self.namespace.clone()),
+                labels: Some(BTreeMap::from([(
+                    RELEASE_LABEL.to_string(),
+                    release.to_string(),
+                )])),
+                ..Default::default()
+            },
+            data: Some(BTreeMap::from([(
+                INVENTORY_KEY.to_string(),
+                serde_json::to_string(inventory)?,
+            )])),
+            ..Default::default()
+        };
+
+        self.inventory_api()
+            .patch(
+                &name,
+                &PatchParams::apply(FIELD_MANAGER).force(),
+                &Patch::Apply(&config_map),
+            )
+            .await
+            .map_err(kube_error)?;
+
+        Ok(())
+    }
+
+    /// Resolves the API of the object and sets its namespace, and the release label when
+    /// `release` is set, like applying would
+    async fn prepare(
+        &mut self,
+        release: Option<&str>,
+        object: &mut DynamicObject,
+        resource: &mut ResourceRef,
+    ) -> Result<Api<DynamicObject>, NativeError> {
+        let api = self.api(resource).await?;
+
+        if let Some((_, capabilities)) = self.kinds.get(&resource.gvk())
+            && capabilities.scope == Scope::Namespaced
+        {
+            let namespace = resource.namespace.get_or_insert(self.namespace.clone());
+            object.metadata.namespace = Some(namespace.clone());
+        }
+
+        if let Some(release) = release {
+            object
+                .labels_mut()
+                .insert(RELEASE_LABEL.to_string(), release.to_string());
+        }
+
+        Ok(api)
+    }
+
+    async fn delete(
+        &mut self,
+        progress: &mut Item,
+        resource: &ResourceRef,
+    ) -> Result<(), NativeError> {
+        progress.info(format!("deleting {resource}"));
+
+        match self
+            .api(resource)
+            .await?
+            .delete(&resource.name, &DeleteParams::background())
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(kube::Error::Api(e)) if e.code == 404 => Ok(()),
+            Err(e) => Err(NativeError::Apply(resource.to_string(), Box::new(e))),
+        }
+    }
+
+    /// Applies the rendered manifest using server-side apply and prunes resources that were
+    /// part of the previous deployment but no longer are
+    pub async fn apply(
+        &mut self,
+        progress: &mut Item,
+        release: &str,
+        manifest: &str,
+        metadata: &Metadata,
+        fingerprint: &str,
+    ) -> Result<(), NativeError> {
+        let objects = parse(manifest, metadata)?;
+        let previous = self.inventory(release).await?.unwrap_or_default();
+        let mut inventory = Inventory {
+            fingerprint: fingerprint.to_string(),
+            resources: vec![],
+        };
+
+        progress.init(Some(objects.len()), None);
+
+        for mut object in objects {
+            let mut resource = resource(&object)?;
+            let api = self
+                .prepare(Some(release), &mut object, &mut resource)
+                .await?;
+
+            progress.info(format!("applying {resource}"));
+
+            api.patch(
+                &resource.name,
+                &PatchParams::apply(FIELD_MANAGER).force(),
+                &Patch::Apply(&object),
+            )
+            .await
+            .map_err(|e| NativeError::Apply(resource.to_string(), Box::new(e)))?;
+
+            inventory.resources.push(resource);
+            progress.inc();
+        }
+
+        // Delete in reverse order, so workloads go before the resources they depend on
+        for resource in previous
+            .resources
+            .iter()
+            .rev()
+            .filter(|resource| !inventory.resources.contains(resource))
+        {
+            self.delete(progress, resource).await?;
+        }
+
+        self.save_inventory(release, &inventory).await
+    }
+
+    /// Changes applying the manifest would make, using a server-side dry run. Resources are
+    /// pruned when they're part of `previous`, the manifest of the last deployment, or of the
+    /// inventory when it's `None`. Releases with a previous manifest are tracked by helm,
+    /// their resources don't get the release label
+    pub async fn diff(
+        &mut self,
+        release: &str,
+        context: Option<&str>,
+        manifest: &str,
+        metadata: &Metadata,
+        previous: Option<&str>,
+    ) -> Result<Vec<Change>, NativeError> {
+        let label = previous.is_none().then_some(release);
+        let previous = match previous {
+            Some(previous) => parse(previous, metadata)?
+                .iter()
+                .map(resource)
+                .collect::<Result<Vec<_>, _>>()?,
+            None => self.inventory(release).await?.unwrap_or_default().resources,
+        };
+        let mut applied = vec![];
+        let mut changes = vec![];
+
+        for mut object in parse(manifest, metadata)? {
+            let mut resource = resource(&object)?;
+
+            let (live, after) = match self.prepare(label, &mut object, &mut resource).await {
+                Ok(api) => {
+                    let live = api.get_opt(&resource.name).await.map_err(kube_error)?;
+                    let after = api
+                        .patch(
+                            &resource.name,
+                            &PatchParams::apply(FIELD_MANAGER).force().dry_run(),
+                            &Patch::Apply(&object),
+                        )
+                        .await;
+
+                    match after {
+                        Ok(after) => (live, after),
+                        // The namespace doesn't exist yet, it's created by the deploy
+                        Err(kube::Error::Api(e)) if e.code == 404 => (live, object),
+                        Err(e) => {
+                            return Err(NativeError::Apply(resource.to_string(), Box::new(e)));
+                        }
+                    }
+                }
+                // The CRD is part of the same deploy
+                Err(NativeError::UnknownKind(..)) => (None, object),
+                Err(e) => return Err(e),
+            };
+
+            changes.extend(compare(
+                release,
+                context,
+                resource.to_string(),
+                live,
+                Some(after),
+            )?);
+            applied.push(resource);
+        }
+
+        for resource in previous.iter().rev() {
+            // Resources rendered without a namespace are in the default namespace of the release
+            let namespaced = ResourceRef {
+                namespace: Some(self.namespace.clone()),
+                ..resource.clone()
+            };
+
+            if applied.contains(resource) || applied.contains(&namespaced) {
+                continue;
+            }
+
+            let live = match self.api(resource).await {
+                Ok(api) => api.get_opt(&resource.name).await.map_err(kube_error)?,
+                Err(NativeError::UnknownKind(..)) => None,
+                Err(e) => return Err(e),
+            };
+
+            changes.extend(compare(release, context, resource.to_string(), live, None)?);
+        }
+
+        Ok(changes)
+    }
+
+    /// Deletes all resources of the release and its inventory
+    pub async fn remove(&mut self, progress: &mut Item, release: &str) -> Result<(), NativeError> {
+        let Some(inventory) = self.inventory(release).await? else {
+            return Ok(());
+        };
+
+        for resource in inventory.resources.iter().rev() {
+            self.delete(progress, resource).await?;
+        }
+
+        match self
+            .inventory_api()
+            .delete(&format!("steiger.{release}"), &DeleteParams::default())
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(kube::Error::Api(e)) if e.code == 404 => Ok(()),
+            Err(e) => Err(kube_error(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn secret(data: serde_json::Value) -> DynamicObject {
+        serde_json::from_value(json!({
+            "apiVersion": "v1",
+            "kind": "Secret",
+            "metadata": { "name": "db", "namespace": "backend" },
+            "data": data,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn secret_values_are_masked() {
+        let live = secret(json!({ "user": "YWRtaW4=", "password": "b2xk" }));
+        let applied = secret(json!({ "user": "YWRtaW4=", "password": "bmV3" }));
+
+        let change = compare(
+            "db",
+            None,
+            "Secret/db".to_string(),
+            Some(live),
+            Some(applied),
+        )
+        .unwrap()
+        .unwrap();
+
+        for value in ["YWRtaW4=", "b2xk", "bmV3"] {
+            assert!(!change.diff.contains(value), "{value} in {}", change.diff);
+        }
+        assert!(change.diff.contains("-  password: '*** (before)'"));
+        assert!(change.diff.contains("+  password: '*** (after)'"));
+        assert!(change.diff.contains("   user: '***'"));
+    }
+
+    #[test]
+    fn created_and_deleted_secrets_are_masked() {
+        let mut applied = secret(json!({}));
+        applied.data["stringData"] = json!({ "token": "hunter2" });
+
+        let created = compare("db", None, "Secret/db".to_string(), None, Some(applied))
+            .unwrap()
+            .unwrap();
+        let deleted = compare(
+            "db",
+            None,
+            "Secret/db".to_string(),
+            Some(secret(json!({ "token": "aHVudGVyMg==" }))),
+            None,
+        )
+        .unwrap()
+        .unwrap();
+
+        assert!(!created.diff.contains("hunter2"), "{}", created.diff);
+        assert!(!deleted.diff.contains("aHVudGVyMg=="), "{}", deleted.diff);
+    }
+
+    #[test]
+    fn last_applied_configuration_of_secrets_is_removed() {
+        let mut live = secret(json!({ "password": "b2xk" }));
+        live.metadata.annotations = Some(
+            [(
+                LAST_APPLIED.to_string(),
+                r#"{"data":{"password":"b2xk"}}"#.to_string(),
+            )]
+            .into(),
+        );
+        let applied = secret(json!({ "password": "bmV3" }));
+
+        let change = compare(
+            "db",
+            None,
+            "Secret/db".to_string(),
+            Some(live),
+            Some(applied),
+        )
+        .unwrap()
+        .unwrap();
+
+        assert!(!change.diff.contains("b2xk"), "{}", change.diff);
+        assert!(!change.diff.contains(LAST_APPLIED), "{}", change.diff);
+    }
+
+    #[test]
+    fn unchanged_secrets_have_no_diff() {
+        let data = json!({ "password": "b2xk" });
+
+        assert!(
+            compare(
+                "db",
+                None,
+                "Secret/db".to_string(),
+                Some(secret(data.clone())),
+                Some(secret(data)),
+            )
+            .unwrap()
+            .is_none()
+        );
+    }
+}
@@ -0,0 +1,244 @@
+use k8s_openapi::api::apps::v1::{Deployment, StatefulSet};
+use kube::{
+    Api,
+    api::{ApiResource, DynamicObject, Patch, PatchParams},
+};
+use miette::Diagnostic;
+use prodash::tree::Item;
+use serde_json::json;
+
+use crate::{
+    cmd::build::output::Output,
+    config::{Rollout, WorkloadKind},
+    deploy::{
+        self, Change, ClientError, Context, Deployer,
+        metadata::{self, Metadata},
+        native,
+    },
+};
+
+#[derive(Debug, Diagnostic, thiserror::Error)]
+pub enum RolloutError {
+    #[error("kubernetes API error")]
+    Kube(#[from] Box<kube::Error>),
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    Client(#[from] ClientError),
+    #[error("no image was built for artifact '{0}'")]
+    #[diagnostic(help("check `artifact` refers to an artifact in the build section"))]
+    NoImage(String),
+    #[error("{0} has no container named '{1}'")]
+    NoContainer(String, String),
+    #[error("{0} has multiple containers")]
+    #[diagnostic(help("set `container` to pick the container to update"))]
+    AmbiguousContainer(String),
+    #[error("failed to serialize workload")]
+    Serialize(#[from] serde_yml::Error),
+}
+
+impl From<kube::Error> for RolloutError {
+    fn from(e: kube::Error) -> Self {
+        RolloutError::Kube(Box::new(e))
+    }
+}
+
+fn describe(input: &Rollout) -> String {
+    format!("{:?}/{}", input.kind, input.name)
+}
+
+#[derive(Clone)]
+pub struct RolloutDeployer;
+
+impl RolloutDeployer {
+    async fn api(input: &Rollout) -> Result<Api<DynamicObject>, RolloutError> {
+        let client = deploy::client(input.context.as_deref()).await?;
+        let resource = match input.kind {
+            WorkloadKind::Deployment => ApiResource::erase::<Deployment>(&()),
+            WorkloadKind::StatefulSet => ApiResource::erase::<StatefulSet>(&()),
+        };
+
+        Ok(match input.namespace {
+            Some(ref namespace) => Api::namespaced_with(client, namespace, &resource),
+            None => Api::default_namespaced_with(client, &resource),
+        })
+    }
+
+    /// Returns the container to update and its current image
+    fn container(
+        input: &Rollout,
+        workload: &DynamicObject,
+    ) -> Result<(String, String), RolloutError> {
+        let containers = workload.data["spec"]["template"]["spec"]["containers"]
+            .as_array()
+            .cloned()
+            .unwrap_or_default();
+        let field = |container: &serde_json::Value, key: &str| {
+            container[key].as_str().unwrap_or_default().to_string()
+        };
+
+        let container = match input.container {
+            Some(ref name) => containers
+                .iter()
+                .find(|container| container["name"] == name.as_str())
+                .ok_or_else(|| RolloutError::NoContainer(describe(input), name.clone()))?,
+            None if containers.len() == 1 => &containers[0],
+            None => return Err(RolloutError::AmbiguousContainer(describe(input))),
+        };
+
+        Ok((field(container, "name"), field(container, "image")))
+    }
+
+    /// Image of the artifact, `None` when the workload is restarted instead
+    fn image(input: &Rollout, output: &Output) -> Result<Option<String>, RolloutError> {
+        let Some(ref artifact) = input.artifact else {
+            return Ok(None);
+        };
+
+        output
+            .builds
+            .iter()
+            .find(|build| build.image_name == *artifact)
+            .map(|build| Some(build.tag.clone()))
+            .ok_or_else(|| RolloutError::NoImage(artifact.clone()))
+    }
+
+    /// Patch setting the image of the container, or restarting the workload without one
+    fn patch(
+        input: &Rollout,
+        metadata: &Metadata,
+        container: Option<(&str, &str)>,
+    ) -> serde_json::Value {
+        match container {
+            Some((container, image)) => json!({
+                "metadata": {
+                    "labels": metadata.labels(),
+                    "annotations": { metadata::ARTIFACT: input.artifact },
+                },
+                "spec": { "template": { "spec": {
+                    "containers": [{ "name": container, "image": image }]
+                }}},
+            }),
+            // Same as `kubectl rollout restart`
+            None => json!({
+                "metadata": { "labels": metadata.labels() },
+                "spec": { "template": { "metadata": { "annotations": {
+                    "kubectl.kubernetes.io/restartedAt": chrono::Utc::now().to_rfc3339()
+                }}}},
+            }),
+        }
+    }
+}
+
+impl Deployer for RolloutDeployer {
+    type Error = RolloutError;
+    type Input = Rollout;
+
+    fn try_init() -> Result<Self, Self::Error>
+    where
+        Self: Sized,
+    {
+        Ok(Self)
+    }
+
+    async fn validate(&self, _input: &Self::Input) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    async fn deploy(
+        self,
+        mut progress: Item,
+        _release: String,
+        ctx: Context<Self::Input>,
+    ) -> Result<(), Self::Error> {
+        let input = &ctx.input;
+        let api = Self::api(input).await?;
+        let metadata = Metadata::from_output(&ctx.output);
+
+        let patch = match Self::image(input, &ctx.output)? {
+            Some(image) => {
+                let (container, current) = Self::container(input, &api.get(&input.name).await?)?;
+
+                if ctx.skip_unchanged && current == image {
+                    progress.done("image is up-to-date, skipping rollout".to_string());
+                    return Ok(());
+                }
+
+                progress.info(format!("setting image of {container} to {image}"));
+                Self::patch(input, &metadata, Some((&container, &image)))
+            }
+            None => {
+                progress.info(format!("restarting {}", describe(input)));
+                Self::patch(input, &metadata, None)
+            }
+        };
+
+        api.patch(
+            &input.name,
+            &PatchParams::default(),
+            &Patch::Strategic(patch),
+        )
+        .await?;
+        progress.done("rollout started".to_string());
+
+        Ok(())
+    }
+
+    async fn destroy(
+        self,
+        mut progress: Item,
+        _release: String,
+        input: Self::Input,
+    ) -> Result<(), Self::Error> {
+        // The workload isn't owned by steiger, it's left in place
+        progress.done(format!(
+            "{} is not managed by steiger, skipping",
+            describe(&input)
+        ));
+
+        Ok(())
+    }
+
+    async fn diff(
+        self,
+        mut progress: Item,
+        release: String,
+        ctx: Context<Self::Input>,
+    ) -> Result<Vec<Change>, Self::Error> {
+        let input = &ctx.input;
+        let api = Self::api(input).await?;
+        let metadata = Metadata::from_output(&ctx.output);
+        let live = api.get(&input.name).await?;
+
+        let patch = match Self::image(input, &ctx.output)? {
+            Some(image) => {
+                let (container, _) = Self::container(input, &live)?;
+                Self::patch(input, &metadata, Some((&container, &image)))
+            }
+            None => Self::patch(input, &metadata, None),
+        };
+        let applied = api
+            .patch(
+                &input.name,
+                &PatchParams::default().dry_run(),
+                &Patch::Strategic(patch),
+            )
+            .await?;
+        let resource = match input.namespace {
+            Some(ref namespace) => format!("{} in {namespace}", describe(input)),
+            None => describe(input),
+        };
+
+        let changes = native::compare(
+            &release,
+            input.context.as_deref(),
+            resource,
+            Some(live),
+            Some(applied),
+        )?
+        .into_iter()
+        .collect::<Vec<_>>();
+        progress.done(format!("{} resources would change", changes.len()));
+
+        Ok(changes)
+    }
+}
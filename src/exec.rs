@@ -1,18 +1,27 @@
 use std::{
+    collections::{HashMap, VecDeque},
     ffi::OsStr,
+    fs::File,
+    io::{BufWriter, ErrorKind, Seek, SeekFrom, Write},
     ops::{Deref, DerefMut},
-    process::{ExitStatus, Stdio},
-    sync::Arc,
+    path::{Path, PathBuf},
+    process::{ExitStatus, Output, Stdio},
+    sync::{Arc, Mutex, OnceLock},
+    time::Duration,
 };
 
+use chrono::{DateTime, SecondsFormat, Utc};
 use miette::Diagnostic;
 use prodash::Progress;
+use steiger::audit;
 use tokio::{
-    io::AsyncReadExt,
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader},
     process::{Child, ChildStderr, ChildStdout, Command},
+    sync::mpsc,
 };
+use uuid::Uuid;
 
-use crate::progress;
+use crate::{config::StallDetection, progress, tmp, ui};
 
 pub struct CmdBuilder(Command);
 
@@ -62,56 +71,602 @@ impl DerefMut for CmdBuilder {
     }
 }
 
+/// Working directory and environment applied to every command spawned within [`scope`]
+#[derive(Clone, Debug, Default)]
+pub struct Environment {
+    pub workdir: Option<PathBuf>,
+    pub env: HashMap<String, String>,
+    pub sandbox: Option<Sandbox>,
+    pub stall_detection: Option<StallDetection>,
+    pub failure: FailureTail,
+}
+
+/// Last lines of the most recent failed command within a scope, shared with nested scopes
+#[derive(Clone, Debug, Default)]
+pub struct FailureTail(Arc<Mutex<Option<String>>>);
+
+impl FailureTail {
+    fn set(&self, tail: String) {
+        *self.0.lock().unwrap_or_else(|e| e.into_inner()) = Some(tail);
+    }
+
+    pub fn take(&self) -> Option<String> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).take()
+    }
+}
+
+impl Environment {
+    fn apply(&self, cmd: &mut Command) {
+        if let Some(ref workdir) = self.workdir {
+            cmd.current_dir(workdir);
+        }
+
+        if let Some(ref sandbox) = self.sandbox {
+            sandbox.apply(cmd);
+        }
+
+        cmd.envs(&self.env);
+    }
+}
+
+/// Variables that are always passed through, commands can't do much without them
+#[cfg(not(windows))]
+const SANDBOX_ENV: &[&str] = &["PATH", "LANG", "LC_ALL", "LC_CTYPE", "TERM", "TZ"];
+#[cfg(windows)]
+const SANDBOX_ENV: &[&str] = &[
+    "PATH",
+    "PATHEXT",
+    "COMSPEC",
+    "SYSTEMROOT",
+    "SYSTEMDRIVE",
+    "WINDIR",
+    "PROCESSOR_ARCHITECTURE",
+    "NUMBER_OF_PROCESSORS",
+];
+
+/// Variables pointing to the home and temp directory
+#[cfg(not(windows))]
+const SANDBOX_DIRS: [(&str, &str); 2] = [("HOME", "home"), ("TMPDIR", "tmp")];
+#[cfg(windows)]
+const SANDBOX_DIRS: [(&str, &str); 4] = [
+    ("USERPROFILE", "home"),
+    ("HOME", "home"),
+    ("TEMP", "tmp"),
+    ("TMP", "tmp"),
+];
+
+/// Environment variable names are case-insensitive on Windows
+fn env_name_eq(a: &str, b: &str) -> bool {
+    match cfg!(windows) {
+        true => a.eq_ignore_ascii_case(b),
+        false => a == b,
+    }
+}
+
+/// Runs `command` using the platform's shell
+pub fn shell(command: &str) -> Command {
+    #[cfg(windows)]
+    let (program, flag) = ("cmd", "/C");
+    #[cfg(not(windows))]
+    let (program, flag) = ("sh", "-c");
+
+    let mut cmd = Command::new(program);
+    cmd.arg(flag).arg(command);
+    cmd
+}
+
+/// Isolates commands from the environment of the user, so builds can't depend on (or leak)
+/// credentials and other state that happens to be present
+#[derive(Clone, Debug)]
+pub struct Sandbox {
+    dir: PathBuf,
+    allow_env: Arc<[String]>,
+}
+
+impl Sandbox {
+    /// Creates a dedicated `HOME` and `TMPDIR` for the commands of `name`
+    pub async fn create(name: &str, allow_env: &[String]) -> Result<Self, std::io::Error> {
+        let dir = tmp::dir().join(format!("steiger-{name}-{}", Uuid::new_v4()));
+
+        for sub in ["home", "tmp"] {
+            tokio::fs::create_dir_all(dir.join(sub)).await?;
+        }
+
+        Ok(Self {
+            dir,
+            allow_env: allow_env.into(),
+        })
+    }
+
+    pub async fn remove(self) -> Result<(), std::io::Error> {
+        tokio::fs::remove_dir_all(&self.dir).await
+    }
+
+    fn apply(&self, cmd: &mut Command) {
+        // `env_clear` also drops the variables set by the builder itself, so keep those
+        let explicit = cmd
+            .as_std()
+            .get_envs()
+            .filter_map(|(key, value)| Some((key.to_os_string(), value?.to_os_string())))
+            .collect::<Vec<_>>();
+        let allowed = std::env::vars_os().filter(|(key, _)| {
+            key.to_str().is_some_and(|key| {
+                SANDBOX_ENV.iter().any(|name| env_name_eq(name, key))
+                    || self.allow_env.iter().any(|name| env_name_eq(name, key))
+            })
+        });
+
+        cmd.env_clear().envs(allowed);
+
+        for (var, sub) in SANDBOX_DIRS {
+            cmd.env(var, self.dir.join(sub));
+        }
+
+        cmd.envs(explicit);
+    }
+}
+
+tokio::task_local! {
+    static ENVIRONMENT: Environment;
+}
+
+/// Runs `future` with `env` applied to the commands it spawns
+pub async fn scope<F: Future>(env: Environment, future: F) -> F::Output {
+    ENVIRONMENT.scope(env, future).await
+}
+
+/// Carries the environment of the current scope over to a future that runs on another task
+pub fn inherit<F: Future>(future: F) -> impl Future<Output = F::Output> {
+    scope(
+        ENVIRONMENT.try_with(Clone::clone).unwrap_or_default(),
+        future,
+    )
+}
+
+/// Runs `future` in `dir`, relative to the working directory of the current scope
+pub async fn within<F: Future>(dir: &Path, future: F) -> F::Output {
+    let mut env = ENVIRONMENT.try_with(Clone::clone).unwrap_or_default();
+    let dir = resolve(dir);
+    env.workdir = Some(std::path::absolute(&dir).unwrap_or(dir));
+
+    scope(env, future).await
+}
+
+/// Resolves a relative path against the working directory of the current scope
+pub fn resolve(path: impl AsRef<Path>) -> PathBuf {
+    let path = path.as_ref();
+
+    ENVIRONMENT
+        .try_with(|env| env.workdir.as_ref().map(|dir| dir.join(path)))
+        .ok()
+        .flatten()
+        .unwrap_or_else(|| path.to_path_buf())
+}
+
+/// Child process that's recorded in the audit log once it exits or is dropped
+pub struct AuditedChild {
+    child: Child,
+    audit: Option<audit::Pending>,
+}
+
+impl AuditedChild {
+    pub async fn wait(&mut self) -> Result<ExitStatus, std::io::Error> {
+        let status = self.child.wait().await?;
+
+        if let Some(audit) = self.audit.take() {
+            audit.finish(status.code());
+        }
+
+        Ok(status)
+    }
+
+    pub async fn kill(&mut self) -> Result<(), std::io::Error> {
+        self.child.kill().await
+    }
+}
+
 pub struct ChildWithStdio {
-    pub inner: Child,
+    pub inner: AuditedChild,
     pub stdout: ChildStdout,
     pub stderr: ChildStderr,
 }
 
-impl ChildWithStdio {
-    async fn stdout(&mut self) -> Result<String, std::io::Error> {
-        let mut output = String::new();
-        self.stdout.read_to_string(&mut output).await?;
-        Ok(output)
-    }
+async fn read_all(mut reader: impl AsyncRead + Unpin) -> Result<String, std::io::Error> {
+    let mut output = String::new();
+    reader.read_to_string(&mut output).await?;
+    Ok(output)
+}
+
+static CACHE_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// Keeps the caches of spawned commands in `path`, e.g. a directory mounted into a CI
+/// runner that's restored between jobs
+pub fn set_cache_dir(path: &Path) -> Result<(), std::io::Error> {
+    std::fs::create_dir_all(path)?;
+    let _ = CACHE_DIR.set(std::path::absolute(path)?);
+    Ok(())
+}
+
+pub fn cache_dir() -> Option<&'static Path> {
+    CACHE_DIR.get().map(PathBuf::as_path)
+}
 
-    async fn stderr(&mut self) -> Result<String, std::io::Error> {
-        let mut output = String::new();
-        self.stderr.read_to_string(&mut output).await?;
-        Ok(output)
+/// Points `XDG_CACHE_HOME` of the command to the cache dir, tools like helm and go keep their
+/// downloads there. A value set by the build entry takes precedence.
+pub fn apply_cache_dir(cmd: &mut Command) {
+    if let Some(dir) = cache_dir()
+        && !cmd
+            .as_std()
+            .get_envs()
+            .any(|(key, _)| key == "XDG_CACHE_HOME")
+    {
+        cmd.env("XDG_CACHE_HOME", dir);
     }
 }
 
 pub async fn spawn(cmd: &mut Command) -> Result<ChildWithStdio, std::io::Error> {
-    let mut inner = cmd
+    let _ = ENVIRONMENT.try_with(|env| env.apply(cmd));
+    apply_cache_dir(cmd);
+
+    // Commands that fail to start are recorded without an exit code
+    let audit = audit::Pending::start(cmd.as_std());
+    // Only kills the child itself, processes it started outlive it, e.g. on `--fail-fast`
+    let mut child = cmd
+        .kill_on_drop(true)
         .stdin(Stdio::null())
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
         .spawn()?;
-    let stdout = inner.stdout.take().unwrap();
-    let stderr = inner.stderr.take().unwrap();
+    let stdout = child.stdout.take().unwrap();
+    let stderr = child.stderr.take().unwrap();
 
     Ok(ChildWithStdio {
-        inner,
+        inner: AuditedChild {
+            child,
+            audit: Some(audit),
+        },
         stdout,
         stderr,
     })
 }
 
+/// Runs the command to completion with its output captured, like [`Command::output`]
+pub async fn output(cmd: &mut Command) -> Result<Output, std::io::Error> {
+    let audit = audit::Pending::start(cmd.as_std());
+    let output = cmd.kill_on_drop(true).stdin(Stdio::null()).output().await?;
+    audit.finish(output.status.code());
+
+    Ok(output)
+}
+
+static LOG_FILE: OnceLock<Mutex<File>> = OnceLock::new();
+
+/// Writes the output of every command run with progress to `path`
+pub fn set_log_file(path: &Path) -> Result<(), std::io::Error> {
+    let _ = LOG_FILE.set(Mutex::new(File::create(path)?));
+    Ok(())
+}
+
+/// Whether command output is written to a log file
+pub fn logging() -> bool {
+    LOG_FILE.get().is_some()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+impl Stream {
+    fn as_str(self) -> &'static str {
+        match self {
+            Stream::Stdout => "stdout",
+            Stream::Stderr => "stderr",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Line {
+    pub time: DateTime<Utc>,
+    pub stream: Stream,
+    pub text: String,
+}
+
+impl Line {
+    fn write(&self, out: &mut impl Write) -> Result<(), std::io::Error> {
+        writeln!(
+            out,
+            "{} {} {}",
+            self.time.to_rfc3339_opts(SecondsFormat::Millis, true),
+            self.stream.as_str(),
+            self.text
+        )
+    }
+}
+
+/// Exit status and the last [`TAIL_LINES`] lines of output of a command, stdout and stderr
+/// merged in the order they were read
+#[derive(Debug)]
+pub struct CommandOutput {
+    pub status: ExitStatus,
+    pub lines: Vec<Line>,
+}
+
+impl CommandOutput {
+    pub fn success(&self) -> bool {
+        self.status.success()
+    }
+
+    pub fn code(&self) -> i32 {
+        self.status.code().unwrap_or_default()
+    }
+
+    /// Last `n` lines of output
+    pub fn tail(&self, n: usize) -> String {
+        let start = self.lines.len().saturating_sub(n);
+
+        self.lines[start..]
+            .iter()
+            .map(|line| line.text.as_str())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// Lines of output of a command kept in memory, the log file receives all of them
+const TAIL_LINES: usize = 100;
+
+/// Temporary file holding the output of a command until it's appended to the log file
+struct Spool {
+    file: BufWriter<File>,
+    // Dropped after the file, Windows can't remove files that are still open
+    _path: SpoolPath,
+}
+
+struct SpoolPath(PathBuf);
+
+impl Drop for SpoolPath {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+impl Spool {
+    fn create() -> Result<Self, std::io::Error> {
+        let path = tmp::dir().join(format!("steiger-log-{}", Uuid::new_v4().simple()));
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+
+        Ok(Self {
+            file: BufWriter::new(file),
+            _path: SpoolPath(path),
+        })
+    }
+}
+
+/// Output of a running command, only its tail is kept in memory. With a log file every line
+/// is spooled to disk and appended to it once the command exits, so the output of commands
+/// running in parallel doesn't interleave
+pub struct OutputLog {
+    spool: Option<Spool>,
+    tail: VecDeque<Line>,
+}
+
+impl OutputLog {
+    pub fn new() -> Self {
+        let spool = match logging() {
+            true => Spool::create()
+                .inspect_err(|e| ui::warn(format!("failed to write command log: {e}")))
+                .ok(),
+            false => None,
+        };
+
+        Self {
+            spool,
+            tail: VecDeque::with_capacity(TAIL_LINES),
+        }
+    }
+
+    pub fn push(&mut self, line: Line) {
+        if let Some(ref mut spool) = self.spool
+            && let Err(e) = line.write(&mut spool.file)
+        {
+            ui::warn(format!("failed to write command log: {e}"));
+            self.spool = None;
+        }
+
+        if self.tail.len() == TAIL_LINES {
+            self.tail.pop_front();
+        }
+
+        self.tail.push_back(line);
+    }
+
+    /// Writes the output to the log file, and keeps its tail for the scope when it failed
+    pub fn finish(self, cmd: &Command, status: ExitStatus) -> CommandOutput {
+        let output = CommandOutput {
+            status,
+            lines: self.tail.into(),
+        };
+
+        if !output.success() {
+            let _ = ENVIRONMENT.try_with(|env| env.failure.set(output.tail(FAILURE_TAIL_LINES)));
+        }
+
+        if let Some(spool) = self.spool
+            && let Err(e) = write_log(cmd, spool, output.code())
+        {
+            ui::warn(format!("failed to write command log: {e}"));
+        }
+
+        output
+    }
+}
+
+fn write_log(cmd: &Command, mut spool: Spool, code: i32) -> Result<(), std::io::Error> {
+    let Some(file) = LOG_FILE.get() else {
+        return Ok(());
+    };
+
+    spool.file.flush()?;
+    let spooled = spool.file.get_mut();
+    spooled.seek(SeekFrom::Start(0))?;
+
+    let mut file = file.lock().unwrap_or_else(|e| e.into_inner());
+    writeln!(
+        file,
+        "$ {} {}",
+        cmd.as_std().get_program().to_string_lossy(),
+        audit::redact(cmd.as_std()).join(" ")
+    )?;
+    std::io::copy(spooled, &mut *file)?;
+    writeln!(file, "exit code: {code}\n")
+}
+
+/// Lines of output included when a stalled command is killed
+const STALL_TAIL_LINES: usize = 10;
+
+/// Lines of output included with a failed build
+const FAILURE_TAIL_LINES: usize = 20;
+
+#[derive(Debug, thiserror::Error)]
+#[error(
+    "possible hang, killed after {}s without output, last output:\n{tail}",
+    timeout.as_secs()
+)]
+pub struct Stalled {
+    timeout: Duration,
+    tail: String,
+}
+
+fn read_lines<R>(reader: R, stream: Stream, tx: mpsc::UnboundedSender<Line>)
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    let mut lines = BufReader::new(reader).lines();
+
+    tokio::spawn(async move {
+        while let Ok(Some(text)) = lines.next_line().await {
+            let line = Line {
+                time: Utc::now(),
+                stream,
+                text,
+            };
+
+            if tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+}
+
 pub async fn run_with_progress<P>(
     cmd: &mut Command,
     progress: P,
-) -> Result<ExitStatus, std::io::Error>
+) -> Result<CommandOutput, std::io::Error>
 where
     P: Progress + 'static,
 {
-    let progress = Arc::new(progress);
+    let keep_colors = progress::keep_colors();
+
+    run_with_reporter(cmd, progress, |progress, text| {
+        progress.info(progress::sanitize(text, keep_colors));
+        vec![progress::sanitize(text, false)]
+    })
+    .await
+}
+
+/// Like [`run_with_progress`], with `report` turning each line of output into progress
+/// updates. It returns the text kept for the log file, which can be any number of lines
+pub async fn run_with_reporter<P, R>(
+    cmd: &mut Command,
+    mut progress: P,
+    mut report: R,
+) -> Result<CommandOutput, std::io::Error>
+where
+    P: Progress + 'static,
+    R: FnMut(&mut P, &str) -> Vec<String>,
+{
     let mut child = spawn(cmd).await?;
+    let (tx, mut rx) = mpsc::unbounded_channel();
+
+    read_lines(child.stdout, Stream::Stdout, tx.clone());
+    read_lines(child.stderr, Stream::Stderr, tx);
+
+    let mut log = OutputLog::new();
+    let stall = ENVIRONMENT
+        .try_with(|env| env.stall_detection.clone())
+        .ok()
+        .flatten();
+    let mut stalled = false;
 
-    progress::proxy_stdio(child.stdout, Arc::clone(&progress));
-    progress::proxy_stdio(child.stderr, Arc::clone(&progress));
+    loop {
+        let next = match stall {
+            Some(ref stall) => {
+                let timeout = Duration::from_secs(stall.timeout);
 
-    child.inner.wait().await
+                match tokio::time::timeout(timeout, rx.recv()).await {
+                    Ok(next) => next,
+                    Err(_) if stall.kill => {
+                        child.inner.kill().await?;
+                        let output = log.finish(cmd, child.inner.wait().await?);
+
+                        progress.fail(format!("killed after {}s without output", stall.timeout));
+                        return Err(std::io::Error::new(
+                            ErrorKind::TimedOut,
+                            Stalled {
+                                timeout,
+                                tail: output.tail(STALL_TAIL_LINES),
+                            },
+                        ));
+                    }
+                    Err(_) => {
+                        if !stalled {
+                            stalled = true;
+                            progress.fail(format!(
+                                "stalled, no output for {}s (possible hang)",
+                                stall.timeout
+                            ));
+                            ui::record_warning(format!(
+                                "{} produced no output for {}s",
+                                cmd.as_std().get_program().to_string_lossy(),
+                                stall.timeout
+                            ));
+                        }
+
+                        continue;
+                    }
+                }
+            }
+            None => rx.recv().await,
+        };
+
+        let Some(line) = next else {
+            break;
+        };
+
+        if stalled {
+            stalled = false;
+            progress.info("output resumed".to_string());
+        }
+
+        for text in report(&mut progress, &line.text) {
+            log.push(Line {
+                time: line.time,
+                stream: line.stream,
+                text,
+            });
+        }
+    }
+
+    let output = log.finish(cmd, child.inner.wait().await?);
+
+    Ok(output)
 }
 
 #[derive(Debug, Diagnostic, thiserror::Error)]
@@ -124,14 +679,17 @@ pub enum ExitError {
 
 pub async fn run_with_output(cmd: &mut Command) -> Result<String, ExitError> {
     let mut child = spawn(cmd).await?;
-    let status = child.inner.wait().await?;
+    // Read while waiting, output larger than the pipe buffer would block the command otherwise
+    let (status, stdout, stderr) = tokio::try_join!(
+        child.inner.wait(),
+        read_all(&mut child.stdout),
+        read_all(&mut child.stderr),
+    )?;
 
     if status.success() {
-        return Ok(child.stdout().await?);
+        return Ok(stdout);
     }
 
-    let stderr = child.stderr().await?;
-
     Err(ExitError::Status {
         code: status.code().unwrap_or_default(),
         stderr,
@@ -0,0 +1,25 @@
+//! Exit codes, these are part of the CLI contract so automation can branch on the failure type.
+
+pub const FAILURE: u8 = 1;
+pub const CONFIG: u8 = 2;
+pub const BUILD: u8 = 3;
+pub const PUSH: u8 = 4;
+pub const DEPLOY: u8 = 5;
+/// Some artifacts were pushed, while others failed their tests
+pub const PARTIAL: u8 = 6;
+/// Images are missing, unsigned or lack attestations
+pub const VERIFY: u8 = 7;
+/// Interrupted by Ctrl-C (128 + SIGINT)
+pub const CANCELLED: u8 = 130;
+
+pub const HELP: &str = "\
+Exit codes:
+  0    success
+  1    unexpected error
+  2    invalid usage or config
+  3    build failed
+  4    push failed
+  5    deploy failed
+  6    partial failure, some artifacts were not pushed
+  7    verification failed
+  130  cancelled";
@@ -1,6 +1,10 @@
-use std::convert::Infallible;
+use std::{
+    convert::Infallible,
+    env,
+    path::{Component, Path, PathBuf},
+};
 
-use gix::{Repository, refs::Category};
+use gix::{ObjectId, Repository, commit::describe::SelectRef};
 use miette::Diagnostic;
 
 #[derive(Debug, Diagnostic, thiserror::Error)]
@@ -11,6 +15,10 @@ pub enum GitError {
     FindRef(#[from] gix::reference::find::existing::Error),
     #[error("failed to retrieve dirty status")]
     Dirty(#[from] gix::status::is_dirty::Error),
+    #[error("failed to describe HEAD")]
+    Describe(#[from] gix::commit::describe::Error),
+    #[error("failed to walk commit history")]
+    Walk(#[from] gix::revision::walk::Error),
 }
 
 // Copied from gix but takes untracked files into account
@@ -50,30 +58,261 @@ fn is_dirty(repo: &Repository) -> Result<bool, gix::status::is_dirty::Error> {
 pub struct State {
     pub dirty: bool,
     pub tag: Option<String>,
+    /// Branch checked out, CI checkouts are often detached so it's taken from CI instead
+    pub branch: Option<String>,
     pub commit: Option<String>,
+    pub describe: Option<String>,
+    pub commit_count: Option<usize>,
+    /// Whether the commit is reachable from a remote branch
+    pub pushed: bool,
 }
 
-pub async fn state() -> Result<State, GitError> {
+/// Parts of the state that walk the commit history, which takes a while in large
+/// repositories, so they're only computed when asked for
+#[derive(Clone, Copy, Debug, Default)]
+pub struct History {
+    pub describe: bool,
+    pub commit_count: bool,
+}
+
+/// Environment variables holding the commit being built in common CI systems
+const CI_COMMIT_VARS: [&str; 5] = [
+    "GITHUB_SHA",
+    "CI_COMMIT_SHA",
+    "BUILDKITE_COMMIT",
+    "CIRCLE_SHA1",
+    "GIT_COMMIT",
+];
+
+/// Environment variables holding the tag being built in common CI systems
+const CI_TAG_VARS: [&str; 3] = ["CI_COMMIT_TAG", "BUILDKITE_TAG", "CIRCLE_TAG"];
+
+/// Environment variables holding the branch being built in common CI systems
+const CI_BRANCH_VARS: [&str; 4] = [
+    "GITHUB_HEAD_REF",
+    "CI_COMMIT_BRANCH",
+    "BUILDKITE_BRANCH",
+    "CIRCLE_BRANCH",
+];
+
+fn non_empty_var(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+fn ci_tag() -> Option<String> {
+    if non_empty_var("GITHUB_REF_TYPE").is_some_and(|ty| ty == "tag") {
+        return non_empty_var("GITHUB_REF_NAME");
+    }
+
+    CI_TAG_VARS.iter().find_map(|name| non_empty_var(name))
+}
+
+fn ci_branch() -> Option<String> {
+    // Pull requests are built on a detached merge ref, `GITHUB_HEAD_REF` holds the source branch
+    if let Some(branch) = non_empty_var(CI_BRANCH_VARS[0]) {
+        return Some(branch);
+    }
+
+    if non_empty_var("GITHUB_REF_TYPE").is_some_and(|ty| ty == "branch") {
+        return non_empty_var("GITHUB_REF_NAME");
+    }
+
+    CI_BRANCH_VARS[1..]
+        .iter()
+        .find_map(|name| non_empty_var(name))
+}
+
+fn ci_commit() -> Option<String> {
+    CI_COMMIT_VARS.iter().find_map(|name| non_empty_var(name))
+}
+
+fn ci_state() -> State {
+    let commit = ci_commit();
+
+    State {
+        pushed: commit.is_some(),
+        commit,
+        tag: ci_tag(),
+        branch: ci_branch(),
+        ..State::default()
+    }
+}
+
+/// Checks whether the commit is an ancestor of (or equal to) any remote branch
+fn is_pushed(repo: &Repository, id: ObjectId) -> bool {
+    let Ok(references) = repo.references() else {
+        return false;
+    };
+    let Ok(branches) = references.remote_branches() else {
+        return false;
+    };
+
+    branches.filter_map(Result::ok).any(|mut reference| {
+        reference
+            .peel_to_id()
+            .ok()
+            .and_then(|target| repo.merge_base(id, target.detach()).ok())
+            .is_some_and(|base| base.detach() == id)
+    })
+}
+
+/// Finds a tag pointing at the given commit, this also works for detached HEADs
+fn find_tag(repo: &Repository, id: ObjectId) -> Option<String> {
+    let mut tags = repo
+        .references()
+        .ok()?
+        .tags()
+        .ok()?
+        .filter_map(Result::ok)
+        .filter_map(|mut reference| {
+            let target = reference.peel_to_id().ok()?.detach();
+            (target == id).then(|| reference.name().shorten().to_string())
+        })
+        .collect::<Vec<_>>();
+
+    tags.sort();
+    tags.pop()
+}
+
+fn entry_id(commit: &gix::Commit<'_>, path: &Path) -> Option<ObjectId> {
+    commit
+        .tree()
+        .ok()?
+        .lookup_entry_by_path(path)
+        .ok()?
+        .map(|entry| entry.object_id())
+}
+
+/// Returns the most recent commit (following first parents) that changed the given path,
+/// or `None` when the path isn't tracked or there is no repository.
+pub fn last_commit_for(path: impl AsRef<Path>) -> Option<String> {
+    let path = path
+        .as_ref()
+        .components()
+        .filter(|c| matches!(c, Component::Normal(_)))
+        .collect::<PathBuf>();
+    let repo = gix::open(".").ok()?;
+    let head = repo.head_commit().ok()?;
+    let mut last = (head.id, entry_id(&head, &path)?);
+
+    for info in repo
+        .rev_walk([head.id])
+        .first_parent_only()
+        .all()
+        .ok()?
+        .filter_map(Result::ok)
+        .skip(1)
+    {
+        let commit = info.object().ok()?;
+
+        if entry_id(&commit, &path) != Some(last.1) {
+            break;
+        }
+
+        last.0 = commit.id;
+    }
+
+    Some(last.0.to_hex().to_string())
+}
+
+/// Drops the paths inside `.git` and the ones ignored by `.gitignore`, paths outside the
+/// worktree are kept
+pub fn without_ignored(paths: Vec<PathBuf>) -> Vec<PathBuf> {
+    let paths = paths
+        .into_iter()
+        .filter(|path| !path.components().any(|c| c.as_os_str() == ".git"))
+        .collect::<Vec<_>>();
+    let Ok(repo) = gix::open(".") else {
+        return paths;
+    };
+    let Some(worktree) = repo.worktree() else {
+        return paths;
+    };
+    let (Ok(workdir), Ok(mut excludes)) = (
+        std::path::absolute(worktree.base()),
+        worktree.excludes(None),
+    ) else {
+        return paths;
+    };
+
+    paths
+        .into_iter()
+        .filter(|path| {
+            let Ok(relative) = path.strip_prefix(&workdir) else {
+                return true;
+            };
+            // Removed paths can't be checked, these are matched as files
+            let mode = path.is_dir().then_some(gix::index::entry::Mode::DIR);
+
+            excludes
+                .at_path(relative, mode)
+                .map_or(true, |platform| !platform.is_excluded())
+        })
+        .collect()
+}
+
+pub async fn state(history: History) -> Result<State, GitError> {
     let repo = match gix::open(".") {
         Ok(repo) => repo,
-        Err(gix::open::Error::NotARepository { .. }) => return Ok(State::default()),
+        Err(gix::open::Error::NotARepository { .. }) => return Ok(ci_state()),
         Err(e) => return Err(GitError::Open(e)),
     };
 
     let mut head = repo.head()?;
     let mut state = State {
         dirty: is_dirty(&repo)?,
+        branch: head
+            .referent_name()
+            .map(|name| name.shorten().to_string())
+            .or_else(ci_branch),
         ..State::default()
     };
 
-    if let Some(ref_name) = head.referent_name()
-        && let Some((Category::Tag, name)) = ref_name.category_and_short_name() {
-            state.tag = Some(name.to_string());
+    if let Ok(commit) = head.peel_to_commit() {
+        let hex = commit.id.to_hex().to_string();
+        // CI checkouts don't always fetch remote branches, so trust the commit CI reports
+        state.pushed = is_pushed(&repo, commit.id) || ci_commit().is_some_and(|c| c == hex);
+        state.commit = Some(hex);
+        // Shallow CI checkouts often don't fetch tags, fall back to the tag CI reports
+        state.tag = find_tag(&repo, commit.id).or_else(ci_tag);
+
+        // Equivalent of `git describe --tags --always --dirty`
+        if history.describe
+            && let Some(mut format) = commit
+                .describe()
+                .names(SelectRef::AllTags)
+                .id_as_fallback(true)
+                .try_format()?
+        {
+            format.dirty_suffix = state.dirty.then(|| "dirty".to_string());
+            state.describe = Some(format.to_string());
         }
 
-    if let Ok(commit) = head.peel_to_commit() {
-        state.commit = Some(commit.id.to_hex().to_string());
+        if history.commit_count {
+            state.commit_count = Some(
+                repo.rev_walk([commit.id])
+                    .all()?
+                    .filter_map(Result::ok)
+                    .count(),
+            );
+        }
     }
 
     Ok(state)
 }
+
+/// Slug of a branch or tag name for use in tags and hostnames: lowercase alphanumerics and
+/// dashes, at most 63 characters (like GitLab's `CI_COMMIT_REF_SLUG`)
+pub fn ref_slug(name: &str) -> String {
+    let slug = name
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | '0'..='9' => c,
+            'A'..='Z' => c.to_ascii_lowercase(),
+            _ => '-',
+        })
+        .take(63)
+        .collect::<String>();
+
+    slug.trim_matches('-').to_string()
+}
@@ -0,0 +1,137 @@
+//! Client for the GitHub REST API, used for deployments and pull request comments when running
+//! in GitHub Actions.
+
+use std::env;
+
+use miette::Diagnostic;
+use reqwest::{
+    RequestBuilder,
+    header::{self, HeaderMap, InvalidHeaderValue},
+};
+use serde::de::DeserializeOwned;
+
+#[derive(Debug, Diagnostic, thiserror::Error)]
+pub enum GithubError {
+    #[error("request failed: {0}")]
+    Reqwest(#[from] reqwest::Error),
+    #[error("invalid header value: {0}")]
+    Header(#[from] InvalidHeaderValue),
+}
+
+/// Environment variable, `None` when it's unset or empty
+pub fn var(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+/// Repository the workflow runs for, `None` outside of GitHub Actions
+pub fn repository() -> Option<String> {
+    if var("GITHUB_ACTIONS").as_deref() != Some("true") {
+        return None;
+    }
+
+    var("GITHUB_REPOSITORY")
+}
+
+/// URL of the next page in a `Link` header, e.g. `<https://...?page=2>; rel="next"`
+fn next_page(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(header::LINK)?
+        .to_str()
+        .ok()?
+        .split(',')
+        .find_map(|link| {
+            let (url, params) = link.split_once(';')?;
+            params
+                .split(';')
+                .any(|param| param.trim() == "rel=\"next\"")
+                .then(|| {
+                    url.trim()
+                        .trim_start_matches('<')
+                        .trim_end_matches('>')
+                        .to_string()
+                })
+        })
+}
+
+pub struct Client {
+    repo_url: String,
+    http: reqwest::Client,
+}
+
+impl Client {
+    pub fn new(repository: &str, token: &str) -> Result<Self, GithubError> {
+        let api_url = var("GITHUB_API_URL").unwrap_or_else(|| "https://api.github.com".to_string());
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, format!("Bearer {token}").parse()?);
+        headers.insert(header::ACCEPT, "application/vnd.github+json".parse()?);
+        headers.insert(header::USER_AGENT, "steiger".parse()?);
+
+        Ok(Self {
+            repo_url: format!("{api_url}/repos/{repository}"),
+            http: reqwest::Client::builder()
+                .default_headers(headers)
+                .build()?,
+        })
+    }
+
+    /// Request to `path`, relative to the repository, e.g. `deployments`
+    pub fn get(&self, path: &str) -> RequestBuilder {
+        self.http.get(format!("{}/{path}", self.repo_url))
+    }
+
+    pub fn post(&self, path: &str) -> RequestBuilder {
+        self.http.post(format!("{}/{path}", self.repo_url))
+    }
+
+    pub fn patch(&self, path: &str) -> RequestBuilder {
+        self.http.patch(format!("{}/{path}", self.repo_url))
+    }
+
+    /// Items of every page of the list at `path`, lists are paginated at 100 items at most
+    pub async fn list<T: DeserializeOwned>(&self, path: &str) -> Result<Vec<T>, GithubError> {
+        let mut items = vec![];
+        let mut request = self.get(path).query(&[("per_page", "100")]);
+
+        loop {
+            let response = request.send().await?.error_for_status()?;
+            let next = next_page(response.headers());
+            items.extend(response.json::<Vec<T>>().await?);
+
+            match next {
+                Some(url) => request = self.http.get(url),
+                None => return Ok(items),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_next_page() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::LINK,
+            "<https://api.github.com/repositories/1/issues/2/comments?per_page=100&page=1>; rel=\"prev\", <https://api.github.com/repositories/1/issues/2/comments?per_page=100&page=3>; rel=\"next\""
+                .parse()
+                .unwrap(),
+        );
+
+        assert_eq!(
+            next_page(&headers).as_deref(),
+            Some("https://api.github.com/repositories/1/issues/2/comments?per_page=100&page=3")
+        );
+
+        headers.insert(
+            header::LINK,
+            "<https://api.github.com/repositories/1/issues/2/comments?per_page=100&page=1>; rel=\"first\""
+                .parse()
+                .unwrap(),
+        );
+
+        assert_eq!(next_page(&headers), None);
+        assert_eq!(next_page(&HeaderMap::new()), None);
+    }
+}
@@ -0,0 +1,245 @@
+use std::{
+    error::Error,
+    fmt::{self, Display},
+};
+
+use miette::{Diagnostic, LabeledSpan, Severity, SourceCode};
+use oci_client::errors::{OciDistributionError, OciErrorCode};
+
+use crate::{deploy::helm::HelmError, exec::ExitError};
+
+/// Remediation for a common failure that surfaces deep inside an error chain
+struct Hint {
+    help: &'static str,
+    url: &'static str,
+}
+
+const DOCKER_DAEMON: Hint = Hint {
+    help: "start Docker (or Docker Desktop) and check that `docker info` works, or point DOCKER_HOST to a running daemon",
+    url: "https://docs.docker.com/engine/daemon/start/",
+};
+
+const REGISTRY_AUTH: Hint = Hint {
+    help: "log in with `docker login <registry>`, credentials are read from the docker config and its credential helpers",
+    url: "https://docs.docker.com/reference/cli/docker/login/",
+};
+
+const KUBE_CONTEXT: Hint = Hint {
+    help: "no Kubernetes context found, set KUBECONFIG or select one with `kubectl config use-context`",
+    url: "https://kubernetes.io/docs/concepts/configuration/organize-cluster-access-kubeconfig/",
+};
+
+const CHART_PATH: Hint = Hint {
+    help: "check `path` of the release, local charts are directories containing a Chart.yaml",
+    url: "https://helm.sh/docs/topics/charts/",
+};
+
+const MISSING_TOOL: Hint = Hint {
+    help: "install the tool and make sure it's on PATH",
+    url: "https://github.com/brainhivenl/steiger#supported-builders",
+};
+
+fn registry_auth(e: &OciDistributionError) -> bool {
+    match e {
+        OciDistributionError::AuthenticationFailure(_)
+        | OciDistributionError::UnauthorizedError { .. }
+        | OciDistributionError::ServerError {
+            code: 401 | 403, ..
+        } => true,
+        OciDistributionError::RegistryError { envelope, .. } => envelope
+            .errors
+            .iter()
+            .any(|e| matches!(e.code, OciErrorCode::Unauthorized | OciErrorCode::Denied)),
+        _ => false,
+    }
+}
+
+fn kube_context(e: &kube::Error) -> bool {
+    matches!(e, kube::Error::InferConfig(_))
+}
+
+fn hint(e: &(dyn Error + 'static)) -> Option<Hint> {
+    if let Some(ExitError::Status { stderr, .. }) = e.downcast_ref::<ExitError>()
+        && (stderr.contains("Cannot connect to the Docker daemon")
+            || stderr.contains("Is the docker daemon running"))
+    {
+        return Some(DOCKER_DAEMON);
+    }
+
+    if e.downcast_ref::<OciDistributionError>()
+        .is_some_and(registry_auth)
+    {
+        return Some(REGISTRY_AUTH);
+    }
+
+    // Kube errors are boxed to keep the error enums small
+    if e.downcast_ref::<kube::Error>().is_some_and(kube_context)
+        || e.downcast_ref::<Box<kube::Error>>()
+            .is_some_and(|e| kube_context(e))
+    {
+        return Some(KUBE_CONTEXT);
+    }
+
+    match e.downcast_ref::<HelmError>() {
+        Some(HelmError::Chart(_) | HelmError::NotADir(_)) => return Some(CHART_PATH),
+        Some(HelmError::Path(_)) => return Some(MISSING_TOOL),
+        _ => {}
+    }
+
+    e.downcast_ref::<which::Error>().map(|_| MISSING_TOOL)
+}
+
+/// Adds a hint to errors without help of their own, when one of their sources is a common
+/// failure like an unreachable docker daemon or missing registry credentials
+pub struct Hinted<E> {
+    error: E,
+    hint: Option<Hint>,
+}
+
+/// First hint in the sources of `e`
+fn source_hint(e: &(dyn Error + 'static)) -> Option<Hint> {
+    let mut source = Some(e);
+
+    while let Some(e) = source {
+        if let Some(hint) = hint(e) {
+            return Some(hint);
+        }
+
+        source = e.source();
+    }
+
+    None
+}
+
+/// First hint in the sources of `diagnostic`, then in its related and diagnostic sources,
+/// e.g. the failed entries of a build. Those can't be downcast themselves, their sources can.
+fn diagnostic_hint(diagnostic: &dyn Diagnostic) -> Option<Hint> {
+    diagnostic
+        .source()
+        .and_then(source_hint)
+        .or_else(|| {
+            diagnostic
+                .related()
+                .into_iter()
+                .flatten()
+                .find_map(diagnostic_hint)
+        })
+        .or_else(|| diagnostic.diagnostic_source().and_then(diagnostic_hint))
+}
+
+impl<E: Diagnostic + 'static> Hinted<E> {
+    pub fn new(error: E) -> Self {
+        let hint = match error.help() {
+            Some(_) => None,
+            None => hint(&error).or_else(|| diagnostic_hint(&error)),
+        };
+
+        Self { error, hint }
+    }
+}
+
+impl<E: fmt::Debug> fmt::Debug for Hinted<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+impl<E: Display> Display for Hinted<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+impl<E: Error> Error for Hinted<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.error.source()
+    }
+}
+
+impl<E: Diagnostic> Diagnostic for Hinted<E> {
+    fn code<'a>(&'a self) -> Option<Box<dyn Display + 'a>> {
+        self.error.code()
+    }
+
+    fn severity(&self) -> Option<Severity> {
+        self.error.severity()
+    }
+
+    fn help<'a>(&'a self) -> Option<Box<dyn Display + 'a>> {
+        match self.hint {
+            Some(ref hint) => Some(Box::new(hint.help)),
+            None => self.error.help(),
+        }
+    }
+
+    fn url<'a>(&'a self) -> Option<Box<dyn Display + 'a>> {
+        match self.hint {
+            Some(ref hint) => Some(Box::new(hint.url)),
+            None => self.error.url(),
+        }
+    }
+
+    fn source_code(&self) -> Option<&dyn SourceCode> {
+        self.error.source_code()
+    }
+
+    fn labels(&self) -> Option<Box<dyn Iterator<Item = LabeledSpan> + '_>> {
+        self.error.labels()
+    }
+
+    fn related<'a>(&'a self) -> Option<Box<dyn Iterator<Item = &'a dyn Diagnostic> + 'a>> {
+        self.error.related()
+    }
+
+    fn diagnostic_source(&self) -> Option<&dyn Diagnostic> {
+        self.error.diagnostic_source()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        build::{BuildError, EntryError, MultiError},
+        cmd,
+        deploy::{self, DeployError},
+    };
+
+    use super::*;
+
+    fn help(error: impl Diagnostic + 'static) -> Option<String> {
+        Hinted::new(error).help().map(|help| help.to_string())
+    }
+
+    #[test]
+    fn hints_failed_build_entries() {
+        let error = cmd::build::Error::Build(BuildError::Multi(MultiError {
+            errors: vec![EntryError {
+                name: "api".to_string(),
+                error: BuildError::Docker(which::Error::CannotFindBinaryPath.into()),
+                tail: None,
+            }],
+        }));
+
+        assert_eq!(help(error).as_deref(), Some(MISSING_TOOL.help));
+    }
+
+    #[test]
+    fn hints_failed_releases() {
+        let chart = std::io::Error::from(std::io::ErrorKind::NotFound);
+        let error = DeployError::Multi(deploy::MultiError {
+            errors: vec![DeployError::Helm(HelmError::Chart(chart))],
+        });
+
+        assert_eq!(help(error).as_deref(), Some(CHART_PATH.help));
+    }
+
+    #[test]
+    fn keeps_help_of_the_error() {
+        let error = DeployError::Helm(HelmError::Unsupported("takeOwnership"));
+
+        assert_eq!(
+            help(error).as_deref(),
+            Some("remove it or use `engine: binary`")
+        );
+    }
+}
@@ -0,0 +1,138 @@
+use std::path::Path;
+
+use oci_client::manifest::{OCI_IMAGE_INDEX_MEDIA_TYPE, OCI_IMAGE_MEDIA_TYPE};
+use serde_json::json;
+
+use crate::image::{Image, ImageError};
+
+fn blob_path(digest: &str) -> String {
+    format!("blobs/{}", digest.replacen(':', "/", 1))
+}
+
+fn append(builder: &mut tar::Builder<Vec<u8>>, path: &str, data: &[u8]) -> std::io::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+
+    builder.append_data(&mut header, path, data)
+}
+
+/// Creates a `docker save` compatible archive, which can be loaded using `docker load`
+pub fn docker_archive(image: &Image, tag: &str) -> Result<Vec<u8>, ImageError> {
+    let mut builder = tar::Builder::new(vec![]);
+    let config = blob_path(&image.manifest().config.digest);
+    let mut layers = vec![];
+
+    append(&mut builder, &config, &image.config.data)?;
+
+    for (layer, descriptor) in image.layers.iter().zip(image.manifest().layers.iter()) {
+        let path = blob_path(&descriptor.digest);
+        append(&mut builder, &path, &layer.data)?;
+        layers.push(path);
+    }
+
+    let manifest = serde_json::to_vec(&json!([{
+        "Config": config,
+        "RepoTags": [tag],
+        "Layers": layers,
+    }]))?;
+
+    append(&mut builder, "manifest.json", &manifest)?;
+
+    Ok(builder.into_inner()?)
+}
+
+/// Destination of the files of an OCI image layout
+trait Layout {
+    fn write(&mut self, path: &str, data: &[u8]) -> std::io::Result<()>;
+}
+
+impl Layout for tar::Builder<Vec<u8>> {
+    fn write(&mut self, path: &str, data: &[u8]) -> std::io::Result<()> {
+        append(self, path, data)
+    }
+}
+
+struct Directory<'a>(&'a Path);
+
+impl Layout for Directory<'_> {
+    fn write(&mut self, path: &str, data: &[u8]) -> std::io::Result<()> {
+        let path = self.0.join(path);
+
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        std::fs::write(path, data)
+    }
+}
+
+/// Writes the blobs of `images` and an index listing them, the tag is stored in the
+/// `org.opencontainers.image.ref.name` annotation of every manifest
+fn write_layout(layout: &mut impl Layout, images: &[Image], tag: &str) -> Result<(), ImageError> {
+    layout.write(
+        "oci-layout",
+        &serde_json::to_vec(&json!({ "imageLayoutVersion": "1.0.0" }))?,
+    )?;
+
+    let mut manifests = vec![];
+
+    for image in images {
+        layout.write(
+            &blob_path(&image.manifest().config.digest),
+            &image.config.data,
+        )?;
+
+        for (layer, descriptor) in image.layers.iter().zip(image.manifest().layers.iter()) {
+            layout.write(&blob_path(&descriptor.digest), &layer.data)?;
+        }
+
+        let manifest = image.manifest_bytes();
+        layout.write(&blob_path(image.digest()), manifest)?;
+
+        let mut descriptor = json!({
+            "mediaType": image.manifest().media_type.as_deref().unwrap_or(OCI_IMAGE_MEDIA_TYPE),
+            "digest": image.digest(),
+            "size": manifest.len(),
+            "annotations": { "org.opencontainers.image.ref.name": tag },
+        });
+
+        if let Some(ref platform) = image.platform {
+            descriptor["platform"] = serde_json::to_value(platform)?;
+        }
+
+        manifests.push(descriptor);
+    }
+
+    let index = serde_json::to_vec(&json!({
+        "schemaVersion": 2,
+        "mediaType": OCI_IMAGE_INDEX_MEDIA_TYPE,
+        "manifests": manifests,
+    }))?;
+
+    layout.write("index.json", &index)?;
+
+    Ok(())
+}
+
+/// Creates an OCI image layout archive, the tag is stored in the
+/// `org.opencontainers.image.ref.name` annotation of the index
+pub fn oci_archive(image: &Image, tag: &str) -> Result<Vec<u8>, ImageError> {
+    let mut builder = tar::Builder::new(vec![]);
+    write_layout(&mut builder, std::slice::from_ref(image), tag)?;
+
+    Ok(builder.into_inner()?)
+}
+
+/// Writes an OCI image layout with all platform variants of an artifact to `dir`,
+/// replacing the layout of a previous build. Other directories are left alone, unless empty
+pub fn oci_layout(dir: &Path, images: &[Image], tag: &str) -> Result<(), ImageError> {
+    if dir.join("oci-layout").is_file() {
+        std::fs::remove_dir_all(dir)?;
+    } else if dir.exists() && std::fs::read_dir(dir)?.next().is_some() {
+        return Err(ImageError::NotALayout(dir.to_path_buf()));
+    }
+
+    write_layout(&mut Directory(dir), images, tag)
+}
@@ -0,0 +1,101 @@
+use std::{
+    io::{self, Write},
+    path::Path,
+};
+
+use flate2::{Compression, write::GzEncoder};
+use sha2::{Digest, Sha256};
+
+pub struct Layer {
+    /// Gzipped tarball
+    pub data: Vec<u8>,
+    /// Digest of the uncompressed tarball, as listed in the config's `rootfs.diff_ids`
+    pub diff_id: String,
+}
+
+type Builder = tar::Builder<Vec<u8>>;
+
+#[cfg(not(windows))]
+fn append_file(builder: &mut Builder, source: &Path, name: &Path) -> Result<(), io::Error> {
+    builder.append_path_with_name(source, name)
+}
+
+#[cfg(not(windows))]
+fn append_dir_all(
+    builder: &mut Builder,
+    destination: &Path,
+    source: &Path,
+) -> Result<(), io::Error> {
+    builder.append_dir_all(destination, source)
+}
+
+/// Windows has no executable bit, so like `docker build` every file is made executable
+#[cfg(windows)]
+fn append_file(builder: &mut Builder, source: &Path, name: &Path) -> Result<(), io::Error> {
+    let mut header = tar::Header::new_gnu();
+    header.set_metadata_in_mode(&std::fs::metadata(source)?, tar::HeaderMode::Deterministic);
+    header.set_mode(0o755);
+
+    builder.append_data(&mut header, name, std::fs::File::open(source)?)
+}
+
+#[cfg(windows)]
+fn append_dir_all(
+    builder: &mut Builder,
+    destination: &Path,
+    source: &Path,
+) -> Result<(), io::Error> {
+    builder.append_dir(destination, source)?;
+
+    let mut entries = std::fs::read_dir(source)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let name = destination.join(entry.file_name());
+
+        match entry.file_type()?.is_dir() {
+            true => append_dir_all(builder, &name, &entry.path())?,
+            false => append_file(builder, &entry.path(), &name)?,
+        }
+    }
+
+    Ok(())
+}
+
+/// Packs a file or directory into a layer, placing it at `destination` inside the image.
+/// Files end up at `<destination>/<file name>`, directory contents at `<destination>`.
+pub fn pack(source: &Path, destination: &Path) -> Result<Layer, io::Error> {
+    let destination = destination.strip_prefix("/").unwrap_or(destination);
+    let destination = match destination.as_os_str().is_empty() {
+        true => Path::new("."),
+        false => destination,
+    };
+
+    let mut builder = Builder::new(vec![]);
+    builder.mode(tar::HeaderMode::Deterministic);
+    builder.follow_symlinks(false);
+
+    if source.is_dir() {
+        append_dir_all(&mut builder, destination, source)?;
+    } else {
+        let name = source.file_name().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid output path: {}", source.display()),
+            )
+        })?;
+
+        append_file(&mut builder, source, &destination.join(name))?;
+    }
+
+    let tar = builder.into_inner()?;
+    let diff_id = format!("sha256:{}", hex::encode(Sha256::digest(&tar)));
+
+    let mut encoder = GzEncoder::new(vec![], Compression::default());
+    encoder.write_all(&tar)?;
+
+    Ok(Layer {
+        data: encoder.finish()?,
+        diff_id,
+    })
+}
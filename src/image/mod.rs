@@ -1,18 +1,31 @@
 use std::fmt::Debug;
-use std::path::Path;
+use std::{collections::HashMap, path::Path, sync::OnceLock};
 
+use futures::future;
 use miette::Diagnostic;
 use oci_client::{
     client::{Config, ImageLayer},
-    manifest::{OciImageIndex, OciImageManifest, Platform},
+    manifest::{
+        IMAGE_CONFIG_MEDIA_TYPE, IMAGE_DOCKER_LAYER_GZIP_MEDIA_TYPE, IMAGE_LAYER_GZIP_MEDIA_TYPE,
+        IMAGE_MANIFEST_MEDIA_TYPE, OCI_IMAGE_MEDIA_TYPE, OciDescriptor, OciImageIndex,
+        OciImageManifest, Platform,
+    },
 };
 use olpc_cjson::CanonicalFormatter;
+use prodash::tree::Item;
 use serde::Serialize;
+use serde_json::{Value, json};
 use sha2::{Digest, Sha256};
+use tokio::{sync::Semaphore, time::Instant};
 
-use crate::image::blob_store::BlobStore;
+use crate::image::{blob_store::BlobStore, layer::Layer};
 
+pub mod archive;
 mod blob_store;
+pub mod layer;
+
+const BASE_NAME_ANNOTATION: &str = "org.opencontainers.image.base.name";
+const BASE_DIGEST_ANNOTATION: &str = "org.opencontainers.image.base.digest";
 
 #[derive(Debug, Diagnostic, thiserror::Error)]
 pub enum ImageError {
@@ -20,20 +33,32 @@ pub enum ImageError {
     IO(#[from] std::io::Error),
     #[error("failed to (de)serialize")]
     Serde(#[from] serde_json::Error),
+    #[error("{} already exists and isn't an OCI image layout", .0.display())]
+    #[diagnostic(help("remove it or pick another `outputPath`, only layouts are replaced"))]
+    NotALayout(std::path::PathBuf),
 }
 
+#[derive(Clone)]
 pub struct Image {
-    pub digest: String,
     pub config: Config,
-    pub manifest: OciImageManifest,
     pub platform: Option<Platform>,
     pub layers: Vec<ImageLayer>,
+    manifest: OciImageManifest,
+    /// JSON and digest of `manifest`, canonical JSON computed on first use unless the image
+    /// was pulled, reset when the manifest changes
+    canonical: OnceLock<Canonical>,
+}
+
+#[derive(Clone)]
+struct Canonical {
+    body: Vec<u8>,
+    digest: String,
 }
 
 impl Debug for Image {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Image")
-            .field("digest", &self.digest)
+            .field("digest", &self.digest())
             .field("manifest", &self.manifest)
             .field("platform", &self.platform)
             .field("layer_count", &self.layers.len())
@@ -41,40 +66,309 @@ impl Debug for Image {
     }
 }
 
-fn compute_digest(manifest: &OciImageManifest) -> Result<String, serde_json::Error> {
+/// How an image was matched to the requested platform
+pub enum PlatformMatch {
+    Exact,
+    /// The image doesn't specify a platform
+    Unspecified,
+    /// The image is for another platform, which runs emulated
+    Emulated(String),
+}
+
+fn platform_of(image: &Image) -> Option<String> {
+    image
+        .platform
+        .as_ref()
+        .map(|p| format!("{}/{}", p.os, p.architecture))
+}
+
+/// Finds the image for the platform, falling back to an image without platform and then to
+/// images for one of the emulated platforms (in order of preference)
+pub fn match_platform(
+    images: &[Image],
+    platform: &str,
+    emulated: &[String],
+) -> Option<(usize, PlatformMatch)> {
+    if let Some(n) = images
+        .iter()
+        .position(|i| platform_of(i).as_deref() == Some(platform))
+    {
+        return Some((n, PlatformMatch::Exact));
+    }
+
+    if let Some(n) = images.iter().position(|i| i.platform.is_none()) {
+        return Some((n, PlatformMatch::Unspecified));
+    }
+
+    emulated.iter().find_map(|emulated| {
+        images
+            .iter()
+            .position(|i| platform_of(i).as_ref() == Some(emulated))
+            .map(|n| (n, PlatformMatch::Emulated(emulated.clone())))
+    })
+}
+
+/// Finds the image for the platform, falling back to an image without platform
+pub fn position_for_platform(images: &[Image], platform: &str) -> Option<usize> {
+    match_platform(images, platform, &[]).map(|(n, _)| n)
+}
+
+/// Descriptor of a blob holding `data`
+pub fn descriptor(media_type: &str, data: &[u8]) -> OciDescriptor {
+    OciDescriptor {
+        media_type: media_type.to_string(),
+        digest: format!("sha256:{}", hex::encode(Sha256::digest(data))),
+        size: data.len() as i64,
+        ..OciDescriptor::default()
+    }
+}
+
+impl Image {
+    pub fn new(
+        manifest: OciImageManifest,
+        config: Config,
+        platform: Option<Platform>,
+        layers: Vec<ImageLayer>,
+    ) -> Self {
+        Self {
+            config,
+            platform,
+            layers,
+            manifest,
+            canonical: OnceLock::new(),
+        }
+    }
+
+    /// Image of a manifest pulled as `body`, which is pushed byte for byte so the image keeps
+    /// its digest until it's changed
+    pub fn from_manifest_bytes(
+        body: Vec<u8>,
+        config: Config,
+        platform: Option<Platform>,
+        layers: Vec<ImageLayer>,
+    ) -> Result<Self, ImageError> {
+        let manifest = serde_json::from_slice(&body)?;
+        let digest = format!("sha256:{}", hex::encode(Sha256::digest(&body)));
+        let image = Self::new(manifest, config, platform, layers);
+        let _ = image.canonical.set(Canonical { body, digest });
+
+        Ok(image)
+    }
+
+    pub fn manifest(&self) -> &OciImageManifest {
+        &self.manifest
+    }
+
+    pub fn into_parts(self) -> (OciImageManifest, Config, Vec<ImageLayer>) {
+        (self.manifest, self.config, self.layers)
+    }
+
+    fn manifest_mut(&mut self) -> &mut OciImageManifest {
+        self.canonical.take();
+        &mut self.manifest
+    }
+
+    fn canonical(&self) -> &Canonical {
+        self.canonical.get_or_init(|| {
+            // Manifests only have string keys, so serializing them can't fail
+            let body = canonical_manifest(&self.manifest).expect("manifest is valid JSON");
+            let digest = format!("sha256:{}", hex::encode(Sha256::digest(&body)));
+
+            Canonical { body, digest }
+        })
+    }
+
+    /// Digest of the manifest
+    pub fn digest(&self) -> &str {
+        &self.canonical().digest
+    }
+
+    /// JSON of the manifest as it's pushed, the digest is computed over these bytes
+    pub fn manifest_bytes(&self) -> &[u8] {
+        &self.canonical().body
+    }
+
+    /// Creates an empty image for the given platform (e.g. `linux/amd64`)
+    pub fn scratch(platform: &str) -> Result<Self, ImageError> {
+        let (os, architecture) = platform.split_once('/').unwrap_or(("linux", platform));
+        let data = serde_json::to_vec(&json!({
+            "architecture": architecture,
+            "os": os,
+            "config": {},
+            "rootfs": { "type": "layers", "diff_ids": [] },
+        }))?;
+        let manifest = OciImageManifest {
+            schema_version: 2,
+            media_type: Some(OCI_IMAGE_MEDIA_TYPE.to_string()),
+            config: descriptor(IMAGE_CONFIG_MEDIA_TYPE, &data),
+            layers: vec![],
+            subject: None,
+            artifact_type: None,
+            annotations: None,
+        };
+
+        Ok(Self::new(
+            manifest,
+            Config::new(data, IMAGE_CONFIG_MEDIA_TYPE.to_string(), None),
+            Some(Platform {
+                architecture: architecture.to_string(),
+                os: os.to_string(),
+                os_version: None,
+                os_features: None,
+                variant: None,
+                features: None,
+            }),
+            vec![],
+        ))
+    }
+
+    /// Adds a layer on top of the image, optionally replacing the entrypoint
+    pub fn append_layer(
+        mut self,
+        layer: Layer,
+        created_by: &str,
+        entrypoint: Option<Vec<String>>,
+    ) -> Result<Self, ImageError> {
+        let mut config = serde_json::from_slice::<Value>(&self.config.data)?;
+
+        match config["rootfs"]["diff_ids"].as_array_mut() {
+            Some(diff_ids) => diff_ids.push(json!(layer.diff_id)),
+            None => config["rootfs"] = json!({ "type": "layers", "diff_ids": [layer.diff_id] }),
+        }
+
+        let history = json!({ "created_by": created_by });
+        match config["history"].as_array_mut() {
+            Some(entries) => entries.push(history),
+            None => config["history"] = json!([history]),
+        }
+
+        // Like a Dockerfile, setting the entrypoint resets the command of the base image
+        if let Some(entrypoint) = entrypoint {
+            config["config"]["Entrypoint"] = json!(entrypoint);
+            config["config"]["Cmd"] = Value::Null;
+        }
+
+        // Docker manifests can't reference OCI layers, so stick to the base image's flavor
+        let media_type = match self.manifest.media_type.as_deref() {
+            Some(IMAGE_MANIFEST_MEDIA_TYPE) => IMAGE_DOCKER_LAYER_GZIP_MEDIA_TYPE,
+            _ => IMAGE_LAYER_GZIP_MEDIA_TYPE,
+        };
+
+        let data = serde_json::to_vec(&config)?;
+        let manifest = self.manifest_mut();
+        manifest.config = descriptor(&manifest.config.media_type, &data);
+        manifest.layers.push(descriptor(media_type, &layer.data));
+        self.layers
+            .push(ImageLayer::new(layer.data, media_type.to_string(), None));
+        self.config.data = data.into();
+
+        Ok(self)
+    }
+
+    /// Base image as `name@digest`, taken from the standard OCI annotations
+    pub fn base(&self) -> Option<String> {
+        let annotations = self.manifest.annotations.as_ref()?;
+        let name = annotations.get(BASE_NAME_ANNOTATION)?;
+        let digest = annotations.get(BASE_DIGEST_ANNOTATION)?;
+
+        Some(format!("{name}@{digest}"))
+    }
+
+    /// Records the base image in the annotations of the manifest
+    pub fn with_base(mut self, name: &str, digest: &str) -> Self {
+        let annotations = self.manifest_mut().annotations.get_or_insert_default();
+        annotations.insert(BASE_NAME_ANNOTATION.to_string(), name.to_string());
+        annotations.insert(BASE_DIGEST_ANNOTATION.to_string(), digest.to_string());
+
+        self
+    }
+}
+
+/// Canonical JSON of the manifest, which is what its digest is computed over
+pub fn canonical_manifest(manifest: &OciImageManifest) -> Result<Vec<u8>, serde_json::Error> {
     let mut body = vec![];
     let mut ser = serde_json::Serializer::with_formatter(&mut body, CanonicalFormatter::new());
     manifest.serialize(&mut ser)?;
 
+    Ok(body)
+}
+
+pub fn compute_digest(manifest: &OciImageManifest) -> Result<String, serde_json::Error> {
     let mut hasher = Sha256::default();
-    hasher.update(body);
+    hasher.update(canonical_manifest(manifest)?);
 
     Ok(format!("sha256:{}", hex::encode(hasher.finalize())))
 }
 
-pub async fn load_from_path(dir: impl AsRef<Path>) -> Result<Vec<Image>, ImageError> {
+/// Number of blobs read concurrently when loading an image layout
+const LOAD_CONCURRENCY: usize = 8;
+
+async fn read_layer(
+    store: &BlobStore,
+    semaphore: &Semaphore,
+    layer: &OciDescriptor,
+) -> Result<(String, ImageLayer), ImageError> {
+    let _permit = semaphore
+        .acquire()
+        .await
+        .expect("semaphore is never closed");
+    let data = store.read_blob(&layer.digest).await?;
+
+    Ok((
+        layer.digest.clone(),
+        ImageLayer::new(data, layer.media_type.clone(), layer.annotations.clone()),
+    ))
+}
+
+pub async fn load_from_path(
+    dir: impl AsRef<Path>,
+    progress: &mut Item,
+) -> Result<Vec<Image>, ImageError> {
+    let started = Instant::now();
     let dir = dir.as_ref();
     let store = BlobStore::new(dir.to_path_buf());
     let index =
         serde_json::from_slice::<OciImageIndex>(&tokio::fs::read(dir.join("index.json")).await?)?;
-    let mut images = vec![];
+    let mut manifests = vec![];
 
     for entry in index.manifests {
         let manifest =
             serde_json::from_slice::<OciImageManifest>(&store.read_blob(&entry.digest).await?)?;
-        let mut layers = vec![];
+        manifests.push((entry.platform, manifest));
+    }
 
+    // Images for different platforms often share layers, these are only read once
+    let mut unique = HashMap::new();
+    for (_, manifest) in manifests.iter() {
         for layer in manifest.layers.iter() {
-            let data = store.read_blob(&layer.digest).await?;
-
-            layers.push(ImageLayer::new(
-                data,
-                layer.media_type.clone(),
-                layer.annotations.clone(),
-            ));
+            unique.entry(layer.digest.as_str()).or_insert(layer);
         }
+    }
+
+    let semaphore = Semaphore::new(LOAD_CONCURRENCY);
+    let blobs = future::try_join_all(
+        unique
+            .into_values()
+            .map(|layer| read_layer(&store, &semaphore, layer)),
+    )
+    .await?
+    .into_iter()
+    .collect::<HashMap<_, _>>();
+
+    let mut images = vec![];
+
+    // Layers shared by several platforms share their buffer as well, cloning `Bytes` doesn't copy
+    for (platform, manifest) in manifests {
+        let layers = manifest
+            .layers
+            .iter()
+            .map(|layer| ImageLayer {
+                data: blobs[&layer.digest].data.clone(),
+                media_type: layer.media_type.clone(),
+                annotations: layer.annotations.clone(),
+            })
+            .collect();
 
-        let digest = compute_digest(&manifest)?;
         let data = store.read_blob(&manifest.config.digest).await?;
         let config = Config {
             data: data.into(),
@@ -82,14 +376,17 @@ pub async fn load_from_path(dir: impl AsRef<Path>) -> Result<Vec<Image>, ImageEr
             annotations: manifest.config.annotations.clone(),
         };
 
-        images.push(Image {
-            manifest,
-            layers,
-            platform: entry.platform,
-            config,
-            digest,
-        });
+        images.push(Image::new(manifest, config, platform, layers));
     }
 
+    let size = blobs.values().map(|layer| layer.data.len()).sum::<usize>();
+    progress.info(format!(
+        "loaded {} images ({} layers, {:.1} MiB) in {:?}",
+        images.len(),
+        blobs.len(),
+        size as f64 / (1024.0 * 1024.0),
+        started.elapsed()
+    ));
+
     Ok(images)
 }
@@ -1,2 +1,6 @@
+pub mod audit;
 pub mod config;
 pub mod git;
+pub mod image;
+pub mod registry;
+pub mod ui;
@@ -0,0 +1,279 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+};
+
+use miette::Diagnostic;
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+
+use crate::{
+    audit,
+    cmd::lint,
+    config::{BuildKind, Config, Release},
+    exec,
+};
+
+pub const FILE_NAME: &str = "steiger.lock";
+
+#[derive(Debug, Diagnostic, thiserror::Error)]
+pub enum LockError {
+    #[error("failed to read {}", .0.display())]
+    Read(PathBuf, #[source] std::io::Error),
+    #[error("failed to write {}", .0.display())]
+    Write(PathBuf, #[source] std::io::Error),
+    #[error("failed to parse {}", .0.display())]
+    Parse(PathBuf, #[source] serde_yml::Error),
+    #[error("failed to serialize lockfile")]
+    Serialize(#[from] serde_yml::Error),
+    #[error("{} doesn't exist", .0.display())]
+    #[diagnostic(help("run without --frozen once to create it"))]
+    Missing(PathBuf),
+    #[error("resolved versions don't match {}:\n{}", .0.display(), .1.join("\n"))]
+    #[diagnostic(help("run without --frozen to update the lockfile, and commit it"))]
+    Mismatch(PathBuf, Vec<String>),
+}
+
+/// Versions and digests a successful run resolved, `--frozen` requires later runs to match
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Lockfile {
+    /// First line of the version output of every tool the config uses
+    #[serde(default)]
+    pub tools: BTreeMap<String, String>,
+    /// Base image as `name@digest`, keyed by artifact
+    #[serde(default)]
+    pub base_images: BTreeMap<String, String>,
+    /// Chart name and version, keyed by release
+    #[serde(default)]
+    pub charts: BTreeMap<String, String>,
+    /// Template variables besides the git and build variables, secrets and `--set` overrides
+    /// are left out as the lockfile is checked in
+    #[serde(default)]
+    pub variables: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct Chart {
+    name: String,
+    version: String,
+}
+
+/// Lockfile next to the config, every profile has its own as they resolve different values
+pub fn path(config_path: &Path, profile: Option<&str>) -> PathBuf {
+    match profile {
+        Some(profile) => config_path.with_file_name(format!("steiger.{profile}.lock")),
+        None => config_path.with_file_name(FILE_NAME),
+    }
+}
+
+/// Runs the tool and returns the first line of its output, `None` when it isn't installed
+async fn tool_version(tool: &str, args: &[&str]) -> Option<String> {
+    which::which(tool).ok()?;
+
+    let output = exec::run_with_output(Command::new(tool).args(args))
+        .await
+        .ok()?;
+
+    output
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .map(str::to_string)
+}
+
+/// Tools invoked by the builders and deployers of the config, with their version arguments
+fn tools(config: &Config) -> BTreeMap<&'static str, &'static [&'static str]> {
+    let mut tools = BTreeMap::new();
+
+    for build in config.build.values() {
+        match build.kind {
+            BuildKind::Docker(_) => {
+                tools.insert("docker", &["--version"][..]);
+            }
+            BuildKind::Ko(_) => {
+                tools.insert("ko", &["version"][..]);
+            }
+            BuildKind::Bazel(_) => {
+                tools.insert("bazel", &["--version"][..]);
+            }
+            BuildKind::Nix(_) => {
+                tools.insert("nix", &["--version"][..]);
+            }
+            BuildKind::Apko(_) => {
+                tools.insert("apko", &["version"][..]);
+            }
+            BuildKind::Cargo(_) => {
+                tools.insert("cargo", &["--version"][..]);
+            }
+            BuildKind::Script(_)
+            | BuildKind::Custom(_)
+            | BuildKind::Prebuilt(_)
+            | BuildKind::Mirror(_) => {}
+        }
+    }
+
+    if config
+        .deploy
+        .values()
+        .any(|release| matches!(release.source(), Release::Helm(_)))
+    {
+        tools.insert("helm", &["version", "--short"][..]);
+    }
+
+    if config
+        .deploy
+        .values()
+        .any(|release| matches!(release, Release::Gitops(_)))
+    {
+        tools.insert("git", &["--version"][..]);
+    }
+
+    if config
+        .deploy
+        .values()
+        .any(|release| matches!(release, Release::Compose(_)))
+    {
+        tools.insert("docker", &["--version"][..]);
+    }
+
+    tools
+}
+
+/// Name and version of a local chart, or of a remote one as resolved by `helm show chart`
+async fn chart_version(path: &str) -> Option<String> {
+    let data = match lint::chart_text(Path::new(path), &[]).await {
+        Some(_) => tokio::fs::read_to_string(Path::new(path).join("Chart.yaml"))
+            .await
+            .ok()?,
+        None => {
+            which::which("helm").ok()?;
+            exec::run_with_output(Command::new("helm").arg("show").arg("chart").arg(path))
+                .await
+                .ok()?
+        }
+    };
+    let chart = serde_yml::from_str::<Chart>(&data).ok()?;
+
+    Some(format!("{} {}", chart.name, chart.version))
+}
+
+impl Lockfile {
+    /// Resolves everything but the base images, which are only known after building
+    pub async fn resolve(config: &Config) -> Self {
+        let variables = config
+            .vars
+            .iter()
+            .filter(|(name, _)| {
+                !config.set_vars.contains(*name)
+                    && !config.secret_vars.contains(*name)
+                    && !audit::is_secret_name(name)
+            })
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+        let mut lockfile = Lockfile {
+            variables,
+            ..Default::default()
+        };
+
+        for (tool, args) in tools(config) {
+            if let Some(version) = tool_version(tool, args).await {
+                lockfile.tools.insert(tool.to_string(), version);
+            }
+        }
+
+        for (name, release) in config.deploy.iter() {
+            if let Release::Helm(helm) = release.source()
+                && let Some(version) = chart_version(&helm.path).await
+            {
+                lockfile.charts.insert(name.clone(), version);
+            }
+        }
+
+        lockfile
+    }
+
+    pub async fn read(path: &Path) -> Result<Option<Self>, LockError> {
+        let data = match tokio::fs::read_to_string(path).await {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(LockError::Read(path.to_path_buf(), e)),
+        };
+
+        serde_yml::from_str(&data)
+            .map(Some)
+            .map_err(|e| LockError::Parse(path.to_path_buf(), e))
+    }
+
+    /// Writes the lockfile, unless the file already has the same content, which would only
+    /// touch a checked in file on every run
+    pub async fn write(&self, path: &Path) -> Result<(), LockError> {
+        let data = format!(
+            "# Generated by steiger, check this file in and update it by running without --frozen\n{}",
+            serde_yml::to_string(self)?
+        );
+
+        if tokio::fs::read_to_string(path)
+            .await
+            .is_ok_and(|current| current == data)
+        {
+            return Ok(());
+        }
+
+        tokio::fs::write(path, data)
+            .await
+            .map_err(|e| LockError::Write(path.to_path_buf(), e))
+    }
+
+    /// Differences with the resolved lockfile, base images only for the artifacts in `current`
+    pub fn mismatches(&self, current: &Lockfile) -> Vec<String> {
+        let mut mismatches = vec![];
+        let mut compare = |section: &str,
+                           locked: &BTreeMap<String, String>,
+                           current: &BTreeMap<String, String>| {
+            let keys = locked.keys().chain(current.keys()).collect::<BTreeSet<_>>();
+
+            for key in keys {
+                match (locked.get(key), current.get(key)) {
+                    (Some(a), Some(b)) if a == b => {}
+                    (a, b) => mismatches.push(format!(
+                        "  {section}.{key}: {} → {}",
+                        a.map_or("(none)", String::as_str),
+                        b.map_or("(none)", String::as_str)
+                    )),
+                }
+            }
+        };
+
+        compare("tools", &self.tools, &current.tools);
+        compare("charts", &self.charts, &current.charts);
+        compare("variables", &self.variables, &current.variables);
+
+        // Artifacts that weren't built, e.g. when resuming, keep their locked base image
+        for (artifact, base) in current.base_images.iter() {
+            let locked = self.base_images.get(artifact);
+
+            if locked != Some(base) {
+                mismatches.push(format!(
+                    "  baseImages.{artifact}: {} → {base}",
+                    locked.map_or("(none)", String::as_str)
+                ));
+            }
+        }
+
+        mismatches
+    }
+
+    /// Fails with the differences when `current` doesn't match the lockfile at `path`
+    pub async fn check(path: &Path, current: &Lockfile) -> Result<(), LockError> {
+        let locked = Self::read(path)
+            .await?
+            .ok_or_else(|| LockError::Missing(path.to_path_buf()))?;
+        let mismatches = locked.mismatches(current);
+
+        match mismatches.is_empty() {
+            true => Ok(()),
+            false => Err(LockError::Mismatch(path.to_path_buf(), mismatches)),
+        }
+    }
+}
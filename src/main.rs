@@ -1,26 +1,85 @@
-use std::{env, error::Error, path::PathBuf};
+use std::{
+    env,
+    error::Error,
+    io::Read,
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
 
-use async_tempfile::TempFile;
 use clap::Parser;
 use miette::Diagnostic;
-use steiger::config;
+use steiger::{
+    audit,
+    config::{self, Purpose},
+    image, registry, ui,
+};
+
+use crate::{
+    deploy::metadata::Metadata,
+    report::{Recorder, Report},
+};
 
 mod build;
+mod ci;
 mod cmd;
 mod deploy;
 mod exec;
-mod image;
+mod exit;
+mod github;
+mod hint;
+mod lock;
+mod notify;
+mod pick;
 mod progress;
-mod registry;
+mod report;
+mod store;
+mod tmp;
 
 #[derive(Parser)]
+#[command(after_help = exit::HELP)]
 struct Opts {
-    #[arg(short, long)]
+    #[arg(short, long, env = "STEIGER_DIR")]
     dir: Option<PathBuf>,
 
-    #[arg(short, long)]
+    #[arg(short, long, env = "STEIGER_CONFIG")]
     config: Option<PathBuf>,
 
+    /// Don't read git state, git template variables will be empty
+    #[arg(long, global = true, env = "STEIGER_NO_GIT")]
+    no_git: bool,
+
+    /// Always write a JSON result with the outcome per artifact and release, even on failure
+    #[arg(long, global = true, env = "STEIGER_RESULT_FILE")]
+    result_file: Option<PathBuf>,
+
+    /// Write the timestamped output of builder and helm commands to a file
+    #[arg(long, global = true, env = "STEIGER_LOG_FILE")]
+    log_file: Option<PathBuf>,
+
+    /// Append every external command (redacted arguments, duration, exit code) as JSON lines
+    #[arg(long, global = true, env = "STEIGER_AUDIT_LOG")]
+    audit_log: Option<PathBuf>,
+
+    /// Preview environment id, suffixes tags, release names and namespaces
+    #[arg(long, global = true, env = "STEIGER_PREVIEW")]
+    preview: Option<String>,
+
+    /// Output mode for progress, warnings and summaries [default: human, plain for `ci`]
+    #[arg(long, global = true, value_enum, env = "STEIGER_UI")]
+    ui: Option<ui::Mode>,
+
+    /// Only show progress of tasks up to this depth, e.g. 2 to hide the steps of builders
+    #[arg(long, global = true, env = "STEIGER_PROGRESS_DEPTH", value_parser = clap::value_parser!(u8).range(1..))]
+    progress_depth: Option<u8>,
+
+    /// Start builds one at a time, and only while the load average is below this value
+    #[arg(long, global = true, env = "STEIGER_MAX_LOAD", value_parser = parse_max_load)]
+    max_load: Option<f64>,
+
+    /// Override a template variable or config field (e.g. `build.api.docker.target=debug`)
+    #[arg(long = "set", global = true, value_name = "KEY=VALUE", value_parser = parse_override)]
+    overrides: Vec<(String, String)>,
+
     #[clap(subcommand)]
     cmd: Cmd,
 }
@@ -44,6 +103,51 @@ enum Cmd {
         /// Profile name
         #[arg(short, long)]
         profile: Option<String>,
+
+        /// Cancel remaining builds as soon as one fails, overrides `failFast`
+        #[arg(long, overrides_with = "no_fail_fast")]
+        fail_fast: bool,
+
+        /// Let remaining builds finish when one fails, overrides `failFast`
+        #[arg(long, overrides_with = "fail_fast")]
+        no_fail_fast: bool,
+
+        /// Write the output file even if some artifacts failed, these are listed under `failed`
+        #[arg(long, requires = "output_file")]
+        partial_output: bool,
+
+        /// Result file of a previous run, artifacts that were pushed successfully are not rebuilt
+        #[arg(long)]
+        resume: Option<PathBuf>,
+
+        /// Write the pushed image references to a dotenv file
+        #[arg(long)]
+        env_output: Option<PathBuf>,
+
+        /// Run builder commands in a sandbox, see `sandbox` in the config
+        #[arg(long)]
+        sandbox: bool,
+
+        /// Output file of a previous build, artifacts of which the base image was updated
+        /// since are rebuilt with fresh base images
+        #[arg(long)]
+        rebuild_stale_bases: Option<PathBuf>,
+
+        /// Release channel recorded in the output file and build events, overrides `channel`
+        #[arg(long)]
+        channel: Option<String>,
+
+        /// Push the output file to this registry reference, for `artifactsFrom` in other projects
+        #[arg(long, requires = "output_file")]
+        publish_output: Option<String>,
+
+        /// Fail when tool versions, base images, charts or variables don't match steiger.lock
+        #[arg(long)]
+        frozen: bool,
+
+        /// Pick the artifacts to build from a searchable list, steiger.lock is left as is
+        #[arg(long, conflicts_with = "frozen")]
+        interactive: bool,
     },
 
     /// Deploy artifacts based on the output-file of the build command
@@ -55,8 +159,127 @@ enum Cmd {
         /// Profile name
         #[arg(short, long)]
         profile: Option<String>,
+
+        /// Pick the releases to deploy from a searchable list
+        #[arg(long)]
+        interactive: bool,
+    },
+
+    /// Show what deploying the output-file of the build command would change in the cluster
+    Diff {
+        /// Input file location
+        #[arg(short, long)]
+        input_file: PathBuf,
+
+        /// Profile name
+        #[arg(short, long)]
+        profile: Option<String>,
+    },
+
+    /// Remove all releases from the deploy section
+    #[command(visible_alias = "delete")]
+    Destroy {
+        /// Profile name
+        #[arg(short, long)]
+        profile: Option<String>,
+
+        /// Skip the confirmation prompt
+        #[arg(short, long)]
+        yes: bool,
+
+        /// Delete the namespaces of the releases after uninstalling them
+        #[arg(long)]
+        delete_namespaces: bool,
+    },
+
+    /// Check the config for common mistakes
+    Lint {
+        /// Profile name
+        #[arg(short, long)]
+        profile: Option<String>,
+
+        /// Rules to ignore, e.g. `missing-timeout`
+        #[arg(long)]
+        allow: Vec<String>,
+    },
+
+    /// Show the build entries, their artifacts and the releases consuming them
+    List {
+        /// Profile name
+        #[arg(short, long)]
+        profile: Option<String>,
+
+        /// Output format
+        #[arg(long, value_enum, default_value_t)]
+        output: cmd::list::Format,
+    },
+
+    /// Verify that the images of a build output exist, are signed and have attestations
+    Verify {
+        /// Input file location
+        #[arg(short, long)]
+        input_file: PathBuf,
+
+        /// Profile name
+        #[arg(short, long)]
+        profile: Option<String>,
+    },
+
+    /// Inspect the resolved config
+    Config {
+        #[clap(subcommand)]
+        cmd: ConfigCmd,
+    },
+
+    /// Show what steiger recorded about a pushed image
+    Inspect {
+        /// Print the build metadata attached to the image, e.g. registry.example.com/app:v1
+        #[arg(long, value_name = "REF")]
+        metadata: String,
+
+        /// Profile name
+        #[arg(short, long)]
+        profile: Option<String>,
+    },
+
+    /// Check whether the base images of a build output have newer digests
+    Outdated {
+        /// Input file location
+        #[arg(short, long)]
+        input_file: PathBuf,
+
+        /// Profile name
+        #[arg(short, long)]
+        profile: Option<String>,
+    },
+
+    /// Tag the images of a build output for the next release channel
+    Promote {
+        /// Input file location
+        #[arg(short, long)]
+        input_file: PathBuf,
+
+        /// Output file location
+        #[arg(short, long)]
+        output_file: PathBuf,
+
+        /// Channel to promote to (e.g. prod)
+        #[arg(long)]
+        to: String,
+
+        /// Tag to add to the images, defaults to the channel
+        #[arg(long)]
+        tag: Option<String>,
+
+        /// Profile name
+        #[arg(short, long)]
+        profile: Option<String>,
     },
 
+    /// Labels the resources rendered by helm, used as helm post-renderer
+    #[command(hide = true)]
+    PostRender,
+
     /// Run the build and deploy commands in sequence
     Run {
         /// OCI registry to use
@@ -70,6 +293,106 @@ enum Cmd {
         /// Profile name
         #[arg(short, long)]
         profile: Option<String>,
+
+        /// Cancel remaining builds as soon as one fails, overrides `failFast`
+        #[arg(long, overrides_with = "no_fail_fast")]
+        fail_fast: bool,
+
+        /// Let remaining builds finish when one fails, overrides `failFast`
+        #[arg(long, overrides_with = "fail_fast")]
+        no_fail_fast: bool,
+
+        /// Deploy releases even if their images and config are unchanged
+        #[arg(long)]
+        force_deploy: bool,
+
+        /// Run builder commands in a sandbox, see `sandbox` in the config
+        #[arg(long)]
+        sandbox: bool,
+
+        /// Fail when tool versions, base images, charts or variables don't match steiger.lock
+        #[arg(long)]
+        frozen: bool,
+    },
+
+    /// Build and deploy, then rebuild and redeploy the artifacts of which the sources change
+    /// until cancelled
+    Dev {
+        /// OCI registry to use
+        #[arg(short, long)]
+        repo: Option<String>,
+
+        /// Platform selector (e.g. linux/amd64)
+        #[arg(long)]
+        platform: Option<String>,
+
+        /// Profile name
+        #[arg(short, long)]
+        profile: Option<String>,
+    },
+
+    /// Build, push and optionally deploy on a CI runner, every option can be set through
+    /// the environment
+    Ci {
+        /// OCI registry to use
+        #[arg(short, long, env = "STEIGER_REPO")]
+        repo: Option<String>,
+
+        /// Platform selector (e.g. linux/amd64)
+        #[arg(long, env = "STEIGER_PLATFORM")]
+        platform: Option<String>,
+
+        /// Profile name
+        #[arg(short, long, env = "STEIGER_PROFILE")]
+        profile: Option<String>,
+
+        /// Output file location
+        #[arg(
+            short,
+            long,
+            env = "STEIGER_OUTPUT_FILE",
+            default_value = "steiger-output.json"
+        )]
+        output_file: PathBuf,
+
+        /// Write the pushed image references to a dotenv file
+        #[arg(long, env = "STEIGER_ENV_OUTPUT")]
+        env_output: Option<PathBuf>,
+
+        /// Deploy the releases after building
+        #[arg(long, env = "STEIGER_DEPLOY")]
+        deploy: bool,
+
+        /// Deploy releases even if their images and config are unchanged
+        #[arg(long, env = "STEIGER_FORCE_DEPLOY")]
+        force_deploy: bool,
+
+        /// Directory kept between jobs, the tools steiger runs keep their caches and downloads in it
+        #[arg(long, env = "STEIGER_CACHE_DIR")]
+        cache_dir: Option<PathBuf>,
+
+        /// Cancel remaining builds as soon as one fails, overrides `failFast`
+        #[arg(long, env = "STEIGER_FAIL_FAST", overrides_with = "no_fail_fast")]
+        fail_fast: bool,
+
+        /// Let remaining builds finish when one fails, overrides `failFast`
+        #[arg(long, overrides_with = "fail_fast")]
+        no_fail_fast: bool,
+
+        /// Fail when tool versions, base images, charts or variables don't match steiger.lock
+        #[arg(long, env = "STEIGER_FROZEN")]
+        frozen: bool,
+    },
+}
+
+#[derive(Parser)]
+enum ConfigCmd {
+    /// Show the fields, variables and values that differ between the resolved configs of two
+    /// profiles
+    Diff {
+        /// Profiles to compare, e.g. `--profile staging --profile production`
+        #[arg(short, long, required = true)]
+        profile: Vec<String>,
     },
 }
 
@@ -111,13 +434,76 @@ enum AppError {
     #[error(transparent)]
     #[diagnostic(transparent)]
     Deploy(#[from] cmd::deploy::Error),
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    Destroy(#[from] cmd::destroy::Error),
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    Dev(#[from] cmd::dev::Error),
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    Diff(#[from] cmd::diff::Error),
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    Verify(#[from] cmd::verify::Error),
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    Lint(#[from] cmd::lint::Error),
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    List(#[from] cmd::list::Error),
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    ConfigDiff(#[from] cmd::config::Error),
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    Inspect(#[from] cmd::inspect::Error),
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    Pick(#[from] pick::PickError),
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    Outdated(#[from] cmd::outdated::Error),
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    Promote(#[from] cmd::promote::Error),
     #[error("failed to set current dir")]
     SetCurrentDir(std::io::Error),
+    #[error("failed to label rendered manifest")]
+    PostRender(#[from] serde_yml::Error),
     #[error("failed to create temp file")]
     TempFile(#[from] async_tempfile::Error),
     #[error("no repository specified")]
     #[diagnostic(help("either set in config or pass via --repo"))]
     RepoRequired,
+    #[error("cancelled")]
+    Cancelled,
+}
+
+impl AppError {
+    fn exit_code(&self) -> u8 {
+        match self {
+            AppError::Config(_)
+            | AppError::LocateConfig(_)
+            | AppError::Lint(_)
+            | AppError::ConfigDiff(_)
+            | AppError::RepoRequired => exit::CONFIG,
+            AppError::Build(e) => e.exit_code(),
+            AppError::Deploy(_) | AppError::Destroy(_) | AppError::Diff(_) => exit::DEPLOY,
+            AppError::Verify(_) => exit::VERIFY,
+            AppError::IO(_)
+            | AppError::SetCurrentDir(_)
+            | AppError::TempFile(_)
+            | AppError::Dev(_)
+            | AppError::List(_)
+            | AppError::Inspect(_)
+            | AppError::Outdated(_)
+            | AppError::Promote(_)
+            | AppError::PostRender(_) => exit::FAILURE,
+            AppError::Pick(pick::PickError::Cancelled) | AppError::Cancelled => exit::CANCELLED,
+            AppError::Pick(_) => exit::CONFIG,
+        }
+    }
 }
 
 impl From<cmd::build::Error> for AppError {
@@ -126,7 +512,85 @@ impl From<cmd::build::Error> for AppError {
     }
 }
 
-async fn run(opts: Opts) -> Result<(), AppError> {
+fn parse_override(s: &str) -> Result<(String, String), String> {
+    s.split_once('=')
+        .filter(|(key, _)| !key.is_empty())
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .ok_or_else(|| format!("expected KEY=VALUE, got '{s}'"))
+}
+
+fn parse_max_load(s: &str) -> Result<f64, String> {
+    s.parse::<f64>()
+        .ok()
+        .filter(|load| *load > 0.0)
+        .ok_or_else(|| format!("expected a positive number, got '{s}'"))
+}
+
+/// Value of a `--flag` and `--no-flag` pair, `None` when neither is passed
+fn toggle(on: bool, off: bool) -> Option<bool> {
+    match (on, off) {
+        (true, _) => Some(true),
+        (_, true) => Some(false),
+        _ => None,
+    }
+}
+
+/// Loads the config with the global options, for the profile of a command
+struct Loader<'a> {
+    path: &'a Path,
+    no_git: bool,
+    overrides: &'a [(String, String)],
+    preview: Option<&'a str>,
+    recorder: &'a Recorder,
+}
+
+impl Loader<'_> {
+    async fn load(
+        &self,
+        profile: Option<&str>,
+        notifications: &mut config::Notifications,
+        purpose: Purpose,
+    ) -> Result<config::Config, config::Error> {
+        let mut config =
+            config::load_from_path(profile, self.path, self.no_git, self.overrides, purpose)
+                .await?;
+        *notifications = config.notifications.clone();
+        tmp::init(config.tmp_dir.as_deref()).await?;
+
+        if let Some(id) = self.preview {
+            config.apply_preview(id)?;
+            self.recorder.set_preview(id);
+        }
+
+        Ok(config)
+    }
+}
+
+async fn run(
+    opts: Opts,
+    recorder: Recorder,
+    notifications: &mut config::Notifications,
+) -> Result<(), AppError> {
+    // Runs as a child of helm, it doesn't need a config
+    if let Cmd::PostRender = opts.cmd {
+        let mut manifest = String::new();
+        std::io::stdin().read_to_string(&mut manifest)?;
+        print!(
+            "{}",
+            deploy::metadata::post_render(&manifest, &Metadata::from_env())?
+        );
+
+        return Ok(());
+    }
+
+    if let Some(ref path) = opts.log_file {
+        exec::set_log_file(path)?;
+    }
+
+    if let Some(ref path) = opts.audit_log {
+        audit::set_log_file(path)?;
+    }
+
     let config_path = config::locate(opts.dir.as_ref(), opts.config.as_ref())?;
     let detected_platform = detect_platform().await;
 
@@ -134,53 +598,311 @@ async fn run(opts: Opts) -> Result<(), AppError> {
         env::set_current_dir(dir).map_err(AppError::SetCurrentDir)?;
     }
 
+    let loader = Loader {
+        path: &config_path,
+        no_git: opts.no_git,
+        overrides: &opts.overrides,
+        preview: opts.preview.as_deref(),
+        recorder: &recorder,
+    };
+
     match opts.cmd {
         Cmd::Build {
             profile,
             repo,
             output_file,
             platform,
+            fail_fast,
+            no_fail_fast,
+            partial_output,
+            resume,
+            env_output,
+            sandbox,
+            rebuild_stale_bases,
+            channel,
+            publish_output,
+            frozen,
+            interactive,
         } => {
-            let config = config::load_from_path(profile.as_deref(), config_path).await?;
+            let resume = match resume {
+                Some(path) => Some(Report::read(&path).await?),
+                None => None,
+            };
+            let mut config = loader
+                .load(profile.as_deref(), notifications, Purpose::Build)
+                .await?;
+            config.check_build()?;
+            config.check_build_env()?;
+
+            if let Some(fail_fast) = toggle(fail_fast, no_fail_fast) {
+                config.fail_fast = fail_fast;
+            }
 
-            cmd::build::run(
-                config,
-                platform.unwrap_or(detected_platform),
+            if sandbox {
+                config.sandbox.get_or_insert_default();
+            }
+
+            if channel.is_some() {
+                config.channel = channel;
+                config.check_channel()?;
+            }
+
+            if interactive {
+                pick::artifacts(&mut config)?;
+            }
+
+            let opts = cmd::build::Options {
+                platform: platform.unwrap_or(detected_platform),
                 repo,
-                output_file.as_deref(),
-            )
-            .await?;
+                output_file,
+                partial_output,
+                env_output,
+                resume,
+                rebuild_stale_bases,
+                publish_output,
+                // The lockfile covers every entry, a selection would drop the others from it
+                lockfile: (!interactive)
+                    .then(|| lock::path(&config_path, config.profile.as_deref())),
+                frozen,
+            };
+
+            cmd::build::run(config, opts, recorder).await?;
         }
         Cmd::Deploy {
             profile,
             input_file,
+            interactive,
+        } => {
+            let mut config = loader
+                .load(profile.as_deref(), notifications, Purpose::Deploy)
+                .await?;
+
+            if interactive {
+                pick::releases(&mut config)?;
+            }
+
+            config.check_deploy_env()?;
+            cmd::deploy::run(config, &input_file, false, recorder).await?;
+        }
+        Cmd::Diff {
+            profile,
+            input_file,
         } => {
-            let config = config::load_from_path(profile.as_deref(), config_path).await?;
-            cmd::deploy::run(config, &input_file).await?;
+            let config = loader
+                .load(profile.as_deref(), notifications, Purpose::Deploy)
+                .await?;
+            config.check_deploy_env()?;
+            cmd::diff::run(config, &input_file, recorder).await?;
+        }
+        Cmd::Destroy {
+            profile,
+            yes,
+            delete_namespaces,
+        } => {
+            let config = loader
+                .load(profile.as_deref(), notifications, Purpose::Deploy)
+                .await?;
+            config.check_deploy_env()?;
+            cmd::destroy::run(config, yes, delete_namespaces, recorder).await?;
+        }
+        Cmd::Lint { profile, allow } => {
+            let config = loader
+                .load(profile.as_deref(), notifications, Purpose::Read)
+                .await?;
+            cmd::lint::run(config, &config_path, profile.as_deref(), &allow).await?;
         }
+        Cmd::List { profile, output } => {
+            let config = loader
+                .load(profile.as_deref(), notifications, Purpose::Read)
+                .await?;
+            cmd::list::run(&config, output).await?;
+        }
+        Cmd::Verify {
+            profile,
+            input_file,
+        } => {
+            let config = loader
+                .load(profile.as_deref(), notifications, Purpose::Deploy)
+                .await?;
+            cmd::verify::run(config, &input_file).await?;
+        }
+        Cmd::Config {
+            cmd: ConfigCmd::Diff { profile },
+        } => {
+            let [a, b] = <[String; 2]>::try_from(profile)
+                .map_err(|profiles| cmd::config::Error::Profiles(profiles.len()))?;
+            let mut configs = vec![];
+
+            for profile in [a, b] {
+                configs.push(
+                    loader
+                        .load(Some(&profile), notifications, Purpose::Read)
+                        .await?,
+                );
+            }
+
+            cmd::config::diff(&configs[0], &configs[1])?;
+        }
+        Cmd::Inspect { metadata, profile } => {
+            let config = loader
+                .load(profile.as_deref(), notifications, Purpose::Read)
+                .await?;
+            cmd::inspect::metadata(&config, &metadata).await?;
+        }
+        Cmd::Outdated {
+            profile,
+            input_file,
+        } => {
+            let config = loader
+                .load(profile.as_deref(), notifications, Purpose::Deploy)
+                .await?;
+            cmd::outdated::run(config, &input_file).await?;
+        }
+        Cmd::Promote {
+            profile,
+            input_file,
+            output_file,
+            to,
+            tag,
+        } => {
+            let config = loader
+                .load(profile.as_deref(), notifications, Purpose::Deploy)
+                .await?;
+            let opts = cmd::promote::Options {
+                input_file,
+                output_file,
+                to,
+                tag,
+            };
+            cmd::promote::run(config, opts).await?;
+        }
+        Cmd::PostRender => unreachable!(),
         Cmd::Run {
             profile,
             repo,
             platform,
+            fail_fast,
+            no_fail_fast,
+            force_deploy,
+            sandbox,
+            frozen,
         } => {
-            let dest = TempFile::new().await?;
-            let config = config::load_from_path(profile.as_deref(), config_path).await?;
+            let mut config = loader
+                .load(profile.as_deref(), notifications, Purpose::Build)
+                .await?;
+            config.check_build()?;
+            config.check_build_env()?;
+            config.check_deploy_env()?;
+
+            if let Some(fail_fast) = toggle(fail_fast, no_fail_fast) {
+                config.fail_fast = fail_fast;
+            }
+
+            if sandbox {
+                config.sandbox.get_or_insert_default();
+            }
 
             if repo.is_none() && config.default_repo.is_none() {
                 return Err(AppError::RepoRequired);
             }
 
-            cmd::build::run(
-                config.clone(),
-                platform.unwrap_or(detected_platform),
+            let dest = tmp::new_file().await?;
+            let opts = cmd::build::Options {
+                platform: platform.unwrap_or(detected_platform),
                 repo,
-                Some(dest.file_path()),
-            )
-            .await?;
+                output_file: Some(dest.file_path().to_path_buf()),
+                lockfile: Some(lock::path(&config_path, config.profile.as_deref())),
+                frozen,
+                ..Default::default()
+            };
+
+            cmd::build::run(config.clone(), opts, recorder.clone()).await?;
 
             dest.sync_all().await?;
 
-            cmd::deploy::run(config, dest.file_path()).await?;
+            cmd::deploy::run(config, dest.file_path(), !force_deploy, recorder).await?;
+        }
+        Cmd::Dev {
+            profile,
+            repo,
+            platform,
+        } => {
+            let mut reload = async || {
+                let config = loader
+                    .load(profile.as_deref(), notifications, Purpose::Build)
+                    .await?;
+                config.check_build()?;
+                config.check_build_env()?;
+                config.check_deploy_env()?;
+
+                Ok(config)
+            };
+            let config = reload().await?;
+
+            if repo.is_none() && config.default_repo.is_none() {
+                return Err(AppError::RepoRequired);
+            }
+
+            let opts = cmd::dev::Options {
+                platform: platform.unwrap_or(detected_platform),
+                repo,
+                config_path: config_path.clone(),
+            };
+
+            cmd::dev::run(config, opts, reload, recorder.clone()).await?;
+        }
+        Cmd::Ci {
+            profile,
+            repo,
+            platform,
+            output_file,
+            env_output,
+            deploy,
+            force_deploy,
+            cache_dir,
+            fail_fast,
+            no_fail_fast,
+            frozen,
+        } => {
+            if let Some(ref dir) = cache_dir {
+                exec::set_cache_dir(dir)?;
+            }
+
+            let mut config = loader
+                .load(profile.as_deref(), notifications, Purpose::Build)
+                .await?;
+            config.check_build()?;
+            config.check_build_env()?;
+
+            if let Some(fail_fast) = toggle(fail_fast, no_fail_fast) {
+                config.fail_fast = fail_fast;
+            }
+
+            if deploy {
+                config.check_deploy_env()?;
+            }
+
+            let build_opts = cmd::build::Options {
+                platform: platform.unwrap_or(detected_platform),
+                repo,
+                output_file: Some(output_file.clone()),
+                env_output,
+                lockfile: Some(lock::path(&config_path, config.profile.as_deref())),
+                frozen,
+                ..Default::default()
+            };
+
+            {
+                let _group = ci::group("build", "steiger build");
+                cmd::build::run(config.clone(), build_opts, recorder.clone()).await?;
+            }
+
+            if deploy {
+                let _group = ci::group("deploy", "steiger deploy");
+                cmd::deploy::run(config, &output_file, !force_deploy, recorder).await?;
+            }
+
+            ci::write_outputs(&output_file, deploy).await?;
         }
     }
 
@@ -188,9 +910,63 @@ async fn run(opts: Opts) -> Result<(), AppError> {
 }
 
 #[tokio::main]
-async fn main() -> miette::Result<()> {
+async fn main() -> ExitCode {
     let opts = Opts::parse();
-    run(opts).await?;
+    ui::set_mode(opts.ui.unwrap_or(match opts.cmd {
+        // Runners don't have a terminal, live progress would only garble the log
+        Cmd::Ci { .. } => ui::Mode::Plain,
+        _ => ui::Mode::Human,
+    }));
+    progress::set_max_depth(opts.progress_depth);
+    build::load::set_max_load(opts.max_load);
 
-    Ok(())
+    // Resolve before `--dir` changes the working directory
+    let result_file = opts
+        .result_file
+        .as_ref()
+        .map(std::path::absolute)
+        .transpose();
+    let recorder = Recorder::default();
+    let mut notifications = config::Notifications::default();
+
+    // Dropping the future kills running child processes
+    let result = tokio::select! {
+        result = run(opts, recorder.clone(), &mut notifications) => result,
+        _ = tokio::signal::ctrl_c() => {
+            ui::warn("cancelled");
+            Err(AppError::Cancelled)
+        }
+    };
+
+    let code = match result {
+        Ok(()) => 0,
+        Err(ref e) => e.exit_code(),
+    };
+
+    let mut report = recorder.finish(code, result.as_ref().err().map(|e| e as _));
+    report.warnings = ui::take_warnings();
+    report.commands = audit::take();
+    ui::warnings(&report.warnings);
+
+    if let Some(ref pr_comment) = notifications.pr_comment {
+        notify::pr_comment(pr_comment, &report).await;
+    }
+
+    match result_file {
+        Ok(Some(path)) => {
+            if let Err(e) = report.write(&path).await {
+                ui::warn(format!("failed to write result file: {e}"));
+            }
+        }
+        Ok(None) => {}
+        Err(e) => ui::warn(format!("invalid result file path: {e}")),
+    }
+
+    if let Err(e) = result
+        && !matches!(e, AppError::Cancelled)
+    {
+        eprintln!("{:?}", miette::Report::new(hint::Hinted::new(e)));
+    }
+
+    ExitCode::from(code)
 }
@@ -0,0 +1,66 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    github::{self, var},
+    notify::{NotifyError, token},
+};
+
+#[derive(Deserialize)]
+struct Comment {
+    id: u64,
+    #[serde(default)]
+    body: String,
+}
+
+#[derive(Serialize)]
+struct CommentRequest<'a> {
+    body: &'a str,
+}
+
+pub struct Client {
+    github: github::Client,
+    number: String,
+}
+
+impl Client {
+    /// Returns `None` when not running for a pull request in GitHub Actions
+    pub fn from_env(token_env: Option<&str>) -> Option<Result<Self, NotifyError>> {
+        let repository = github::repository()?;
+        // Pull request workflows run on `refs/pull/<number>/merge`
+        let number = var("GITHUB_REF")?
+            .strip_prefix("refs/pull/")?
+            .split('/')
+            .next()?
+            .to_string();
+
+        Some(token(token_env, "GITHUB_TOKEN").and_then(|token| {
+            Ok(Self {
+                github: github::Client::new(&repository, &token)?,
+                number,
+            })
+        }))
+    }
+
+    pub async fn upsert(&self, marker: &str, body: &str) -> Result<(), NotifyError> {
+        let comments_path = format!("issues/{}/comments", self.number);
+        let comments = self.github.list::<Comment>(&comments_path).await?;
+
+        let request = match comments
+            .iter()
+            .find(|comment| comment.body.starts_with(marker))
+        {
+            Some(comment) => self
+                .github
+                .patch(&format!("issues/comments/{}", comment.id)),
+            None => self.github.post(&comments_path),
+        };
+
+        request
+            .json(&CommentRequest { body })
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,96 @@
+use reqwest::header::HeaderMap;
+use serde::{Deserialize, Serialize};
+
+use crate::notify::{NotifyError, token, var};
+
+#[derive(Deserialize)]
+struct Note {
+    id: u64,
+    #[serde(default)]
+    body: String,
+}
+
+#[derive(Serialize)]
+struct NoteRequest<'a> {
+    body: &'a str,
+}
+
+pub struct Client {
+    notes_url: String,
+    http: reqwest::Client,
+}
+
+impl Client {
+    fn new(notes_url: String, token: &str) -> Result<Self, NotifyError> {
+        let mut headers = HeaderMap::new();
+        headers.insert("private-token", token.parse()?);
+
+        Ok(Self {
+            notes_url,
+            http: reqwest::Client::builder()
+                .default_headers(headers)
+                .build()?,
+        })
+    }
+
+    /// Returns `None` when not running in a GitLab CI merge request pipeline
+    pub fn from_env(token_env: Option<&str>) -> Option<Result<Self, NotifyError>> {
+        if var("GITLAB_CI").as_deref() != Some("true") {
+            return None;
+        }
+
+        let api_url = var("CI_API_V4_URL")?;
+        let project = var("CI_PROJECT_ID")?;
+        let iid = var("CI_MERGE_REQUEST_IID")?;
+        let notes_url = format!("{api_url}/projects/{project}/merge_requests/{iid}/notes");
+
+        // `CI_JOB_TOKEN` can't write notes, a project or personal access token is required
+        Some(token(token_env, "GITLAB_TOKEN").and_then(|token| Self::new(notes_url, &token)))
+    }
+
+    /// Notes of every page, GitLab returns 100 notes per page at most
+    async fn notes(&self) -> Result<Vec<Note>, NotifyError> {
+        let mut notes = vec![];
+        let mut page = "1".to_string();
+
+        loop {
+            let response = self
+                .http
+                .get(&self.notes_url)
+                .query(&[("per_page", "100"), ("page", &page)])
+                .send()
+                .await?
+                .error_for_status()?;
+            // Empty on the last page
+            let next = response
+                .headers()
+                .get("x-next-page")
+                .and_then(|value| value.to_str().ok())
+                .filter(|value| !value.is_empty())
+                .map(str::to_string);
+            notes.extend(response.json::<Vec<Note>>().await?);
+
+            match next {
+                Some(next) => page = next,
+                None => return Ok(notes),
+            }
+        }
+    }
+
+    pub async fn upsert(&self, marker: &str, body: &str) -> Result<(), NotifyError> {
+        let notes = self.notes().await?;
+
+        let request = match notes.iter().find(|note| note.body.starts_with(marker)) {
+            Some(note) => self.http.put(format!("{}/{}", self.notes_url, note.id)),
+            None => self.http.post(&self.notes_url),
+        };
+
+        request
+            .json(&NoteRequest { body })
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+}
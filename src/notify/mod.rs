@@ -0,0 +1,125 @@
+use std::fmt::Write;
+
+use miette::Diagnostic;
+use reqwest::header::InvalidHeaderValue;
+use steiger::{config::PrComment, ui};
+
+use crate::{
+    github::{GithubError, var},
+    report::Report,
+};
+
+mod github;
+mod gitlab;
+
+#[derive(Debug, Diagnostic, thiserror::Error)]
+pub enum NotifyError {
+    #[error("environment variable '{0}' with the API token is not set")]
+    MissingToken(String),
+    #[error("request failed: {0}")]
+    Reqwest(#[from] reqwest::Error),
+    #[error("invalid header value: {0}")]
+    Header(#[from] InvalidHeaderValue),
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    Github(#[from] GithubError),
+}
+
+fn token(token_env: Option<&str>, default: &str) -> Result<String, NotifyError> {
+    let name = token_env.unwrap_or(default);
+    var(name).ok_or_else(|| NotifyError::MissingToken(name.to_string()))
+}
+
+/// Identifies the comment so later runs update it rather than adding a new one,
+/// every preview environment gets its own comment
+fn marker(report: &Report) -> String {
+    match report.preview {
+        Some(ref id) => format!("<!-- steiger:{id} -->"),
+        None => "<!-- steiger -->".to_string(),
+    }
+}
+
+fn render(config: &PrComment, report: &Report) -> String {
+    let mut body = marker(report);
+
+    let _ = match report.success {
+        true => writeln!(body, "\n### ✅ Steiger succeeded"),
+        false => writeln!(body, "\n### ❌ Steiger failed"),
+    };
+
+    if let Some(ref id) = report.preview {
+        let _ = writeln!(body, "\n**Preview:** `{id}`");
+    }
+
+    if let Some(ref url) = config.url {
+        let _ = writeln!(body, "\n**Environment:** {url}");
+    }
+
+    if !report.images.is_empty() {
+        let _ = writeln!(body, "\n| Artifact | Image |\n| --- | --- |");
+
+        for (artifact, image_ref) in report.images.iter() {
+            let _ = writeln!(body, "| {artifact} | `{image_ref}` |");
+        }
+    }
+
+    let failed_builds = report
+        .builds
+        .iter()
+        .chain(report.tests.iter())
+        .chain(report.pushes.iter())
+        .filter(|(_, entry)| !entry.success)
+        .collect::<Vec<_>>();
+
+    if !failed_builds.is_empty() {
+        let _ = writeln!(body, "\n| Failed | Error |\n| --- | --- |");
+
+        for (name, entry) in failed_builds {
+            let error = entry.error.as_deref().unwrap_or_default();
+            let _ = writeln!(body, "| {name} | {} |", error.replace('|', "\\|"));
+        }
+    }
+
+    if !report.releases.is_empty() {
+        let _ = writeln!(body, "\n| Release | Result |\n| --- | --- |");
+
+        for (name, entry) in report.releases.iter() {
+            let result = match entry.error {
+                Some(ref error) => format!("❌ {}", error.replace('|', "\\|")),
+                None => "✅ deployed".to_string(),
+            };
+            let _ = writeln!(body, "| {name} | {result} |");
+        }
+    }
+
+    if let Some(ref error) = report.error {
+        let _ = writeln!(body, "\n```\n{error}\n```");
+    }
+
+    body
+}
+
+/// Posts or updates the summary comment on the current pull/merge request, does nothing
+/// outside of GitHub Actions and GitLab CI merge request pipelines
+pub async fn pr_comment(config: &PrComment, report: &Report) {
+    let token_env = config.token_env.as_deref();
+    let (marker, body) = (marker(report), render(config, report));
+
+    let result = if let Some(client) = github::Client::from_env(token_env) {
+        match client {
+            Ok(client) => client.upsert(&marker, &body).await,
+            Err(e) => Err(e),
+        }
+    } else if let Some(client) = gitlab::Client::from_env(token_env) {
+        match client {
+            Ok(client) => client.upsert(&marker, &body).await,
+            Err(e) => Err(e),
+        }
+    } else {
+        return;
+    };
+
+    if let Err(e) = result {
+        ui::warn(format!("failed to post pull request comment: {e}"));
+    }
+}
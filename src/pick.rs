@@ -0,0 +1,74 @@
+use std::{collections::BTreeSet, io::IsTerminal};
+
+use inquire::{InquireError, MultiSelect};
+use miette::Diagnostic;
+
+use crate::config::Config;
+
+/// Options shown at once, the rest is reached by scrolling or typing to filter
+const PAGE_SIZE: usize = 15;
+
+#[derive(Debug, Diagnostic, thiserror::Error)]
+pub enum PickError {
+    #[error("--interactive requires a terminal")]
+    #[diagnostic(help("leave out --interactive to operate on everything"))]
+    NotATerminal,
+    #[error("nothing selected")]
+    Empty,
+    #[error("selection cancelled")]
+    Cancelled,
+    #[error("failed to show selection")]
+    Prompt(#[source] InquireError),
+}
+
+/// Fuzzy-searchable multi-select of `options`, skipped when there is nothing to choose
+fn select(message: &str, options: Vec<String>) -> Result<BTreeSet<String>, PickError> {
+    if options.len() <= 1 {
+        return Ok(options.into_iter().collect());
+    }
+
+    if !std::io::stdin().is_terminal() || !std::io::stderr().is_terminal() {
+        return Err(PickError::NotATerminal);
+    }
+
+    let selected = MultiSelect::new(message, options)
+        .with_page_size(PAGE_SIZE)
+        .with_help_message("type to filter, space to select, → to select all, enter to confirm")
+        .prompt()
+        .map_err(|e| match e {
+            InquireError::OperationCanceled | InquireError::OperationInterrupted => {
+                PickError::Cancelled
+            }
+            InquireError::NotTTY => PickError::NotATerminal,
+            e => PickError::Prompt(e),
+        })?;
+
+    match selected.is_empty() {
+        true => Err(PickError::Empty),
+        false => Ok(selected.into_iter().collect()),
+    }
+}
+
+/// Asks which artifacts to build, entries without a selected artifact are removed
+pub fn artifacts(config: &mut Config) -> Result<(), PickError> {
+    let artifacts = config
+        .build
+        .iter()
+        .flat_map(|(name, build)| build.kind.artifacts(name))
+        .collect::<BTreeSet<_>>();
+    let selected = select("Artifacts to build", artifacts.into_iter().collect())?;
+
+    config.retain_artifacts(&selected);
+
+    Ok(())
+}
+
+/// Asks which releases to deploy, dependencies on releases that aren't selected are dropped
+pub fn releases(config: &mut Config) -> Result<(), PickError> {
+    let releases = config.deploy.keys().cloned().collect::<BTreeSet<_>>();
+    let selected = select("Releases to deploy", releases.into_iter().collect())?;
+
+    config.retain_releases(&selected);
+
+    Ok(())
+}
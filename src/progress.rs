@@ -1,13 +1,33 @@
-use std::sync::Arc;
+use std::{
+    collections::HashSet,
+    hash::{DefaultHasher, Hash, Hasher},
+    io::IsTerminal,
+    sync::{Arc, Mutex, OnceLock},
+};
 
 use prodash::{
-    Progress,
+    progress::{Id, UNKNOWN, key::Level},
     render::line::JoinHandle,
-    tree::{Root, root::Options},
+    tree::{Item, Root, root::Options},
 };
-use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use steiger::ui;
+
+/// Deepest level of tasks that is rendered, top-level tasks are at level 1
+static MAX_DEPTH: OnceLock<Level> = OnceLock::new();
+
+/// Names of the tasks in the current tree, by the id of their parent
+static TASKS: Mutex<Option<HashSet<(Id, String)>>> = Mutex::new(None);
+
+pub fn set_max_depth(depth: Option<Level>) {
+    if let Some(depth) = depth {
+        let _ = MAX_DEPTH.set(depth);
+    }
+}
 
 pub fn tree() -> Arc<Root> {
+    // Names only have to be unique within a tree
+    *TASKS.lock().unwrap() = None;
+
     Arc::new(
         Options {
             message_buffer_capacity: 200,
@@ -18,29 +38,144 @@ pub fn tree() -> Arc<Root> {
 }
 
 pub fn setup_line_renderer(progress: &Arc<Root>) -> JoinHandle {
+    let options = prodash::render::line::Options {
+        frames_per_second: 6.0,
+        initial_delay: None,
+        hide_cursor: false,
+        throughput: true,
+        level_filter: MAX_DEPTH.get().map(|depth| 1..=*depth),
+        ..prodash::render::line::Options::default()
+    };
+
+    let options = match ui::mode() {
+        ui::Mode::Human => options.auto_configure(prodash::render::line::StreamKind::Stderr),
+        // Only log messages, live progress would garble the output
+        ui::Mode::Plain | ui::Mode::Json => prodash::render::line::Options {
+            output_is_terminal: false,
+            colored: false,
+            ..options
+        },
+    };
+
     prodash::render::line(
         std::io::stderr(),
         std::sync::Arc::downgrade(progress),
-        prodash::render::line::Options {
-            frames_per_second: 6.0,
-            initial_delay: None,
-            hide_cursor: false,
-            ..prodash::render::line::Options::default()
-        }
-        .auto_configure(prodash::render::line::StreamKind::Stderr),
+        options,
     )
 }
 
-pub fn proxy_stdio<R, P>(reader: R, progress: Arc<P>)
-where
-    R: AsyncRead + Unpin + Send + 'static,
-    P: Progress + 'static,
-{
-    let mut lines = BufReader::new(reader).lines();
+/// Id of a task derived from its parent and its name, so it's the same on every run
+fn task_id(parent: Id, name: &str) -> Id {
+    let mut hasher = DefaultHasher::new();
+    parent.hash(&mut hasher);
+    name.hash(&mut hasher);
+
+    let hash = hasher.finish().to_be_bytes();
+    [hash[0], hash[1], hash[2], hash[3]]
+}
+
+/// Name not used by a sibling yet, duplicates are numbered (e.g. `api › docker (2)`)
+fn unique_name(parent: Id, name: String) -> (String, Id) {
+    let mut tasks = TASKS.lock().unwrap();
+    let tasks = tasks.get_or_insert_default();
+
+    (1..)
+        .map(|n| match n {
+            1 => name.clone(),
+            n => format!("{name} ({n})"),
+        })
+        .find(|name| tasks.insert((parent, name.clone())))
+        .map(|name| {
+            let id = task_id(parent, &name);
+            (name, id)
+        })
+        .expect("name is unique")
+}
 
-    tokio::spawn(async move {
-        while let Ok(Some(line)) = lines.next_line().await {
-            progress.info(line);
+/// Adds a top-level task to the tree
+pub fn task(root: &Root, name: impl Into<String>) -> Item {
+    let (name, id) = unique_name(UNKNOWN, name.into());
+    root.add_child_with_id(name, id)
+}
+
+/// Adds a child task with a name that is unique among its siblings. Tasks are ordered by
+/// creation, so parents add their children in a deterministic order.
+pub fn child(parent: &mut Item, name: impl Into<String>) -> Item {
+    let (name, id) = unique_name(parent.id(), name.into());
+    parent.add_child_with_id(name, id)
+}
+
+/// Longest line forwarded to the renderer, some tools print entire payloads on one line
+const MAX_LINE_LENGTH: usize = 1000;
+
+/// Whether colors of proxied output are kept, only when they end up on a terminal
+pub fn keep_colors() -> bool {
+    ui::mode() == ui::Mode::Human && std::io::stderr().is_terminal()
+}
+
+/// Makes a line of command output safe to render: carriage return updates (progress bars)
+/// collapse to their last state, escape sequences other than colors and control characters
+/// are stripped and the line is capped to [`MAX_LINE_LENGTH`] characters
+pub fn sanitize(line: &str, keep_colors: bool) -> String {
+    let line = line
+        .rsplit('\r')
+        .find(|segment| !segment.trim().is_empty())
+        .unwrap_or_default();
+    let mut output = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    let mut colored = false;
+    let mut length = 0;
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\x1b' => match chars.next() {
+                // CSI, parameters until a final byte
+                Some('[') => {
+                    let mut sequence = String::new();
+
+                    for c in chars.by_ref() {
+                        sequence.push(c);
+
+                        if ('\x40'..='\x7e').contains(&c) {
+                            break;
+                        }
+                    }
+
+                    if keep_colors && sequence.ends_with('m') {
+                        output.push_str("\x1b[");
+                        output.push_str(&sequence);
+                        colored = true;
+                    }
+                }
+                // OSC (e.g. window titles and hyperlinks), terminated by BEL or ST
+                Some(']') => {
+                    while let Some(c) = chars.next() {
+                        if c == '\x07' || (c == '\x1b' && chars.next() == Some('\\')) {
+                            break;
+                        }
+                    }
+                }
+                _ => {}
+            },
+            '\t' => {
+                output.push_str("    ");
+                length += 4;
+            }
+            c if c.is_control() => {}
+            _ if length >= MAX_LINE_LENGTH => {
+                output.push('…');
+                break;
+            }
+            c => {
+                output.push(c);
+                length += 1;
+            }
         }
-    });
+    }
+
+    if colored {
+        output.push_str("\x1b[0m");
+    }
+
+    output
 }
@@ -1,22 +1,84 @@
-use std::time::Duration;
+use std::{
+    collections::{BTreeMap, HashMap},
+    env,
+    sync::{Arc, LazyLock, Mutex},
+    time::Duration,
+};
 
+use bytes::Bytes;
 use docker_credential::{CredentialRetrievalError, DockerCredential};
 use futures::{StreamExt, TryStreamExt, future, stream};
 use miette::Diagnostic;
 use oci_client::{
     Client, Reference,
-    client::{ClientConfig, ClientProtocol, PushResponse},
+    client::{ClientConfig, ClientProtocol, Config, ImageLayer, PushResponse},
     errors::{OciDistributionError, OciErrorCode},
+    manifest::{
+        IMAGE_MANIFEST_LIST_MEDIA_TYPE, IMAGE_MANIFEST_MEDIA_TYPE, ImageIndexEntry,
+        OCI_IMAGE_INDEX_MEDIA_TYPE, OCI_IMAGE_MEDIA_TYPE, OciDescriptor, OciImageManifest,
+        OciManifest, annotations,
+    },
     secrets::RegistryAuth,
 };
-use prodash::tree::Item;
+use prodash::{
+    tree::Item,
+    unit::{self, Unit, display::Mode},
+};
+use reqwest::header::{HeaderValue, InvalidHeaderValue};
+
+use crate::{
+    config::RegistrySettings,
+    image::{self, Image, ImageError},
+};
+
+const MAX_CONCURRENT_UPLOADS: usize = 16;
+
+/// Number of tags requested at once, registries may return fewer
+const TAGS_PAGE_SIZE: usize = 1000;
+
+/// Attempts to push a blob before giving up, retried on errors that tend to be transient
+const PUSH_ATTEMPTS: u64 = 4;
 
-use crate::image::Image;
+/// Config of artifacts that aren't images, as recommended by the OCI image spec
+const EMPTY_MEDIA_TYPE: &str = "application/vnd.oci.empty.v1+json";
+
+/// Output file of `steiger build`, published with `--publish-output`
+pub const OUTPUT_MEDIA_TYPE: &str = "application/vnd.steiger.output.v1+json";
+
+/// Build metadata attached to pushed images as a referrer, read by `steiger inspect`
+pub const METADATA_MEDIA_TYPE: &str = "application/vnd.steiger.metadata.v1+json";
+
+/// Manifests accepted when copying an image, single images and indexes in both formats
+const MANIFEST_MEDIA_TYPES: &[&str] = &[
+    OCI_IMAGE_MEDIA_TYPE,
+    OCI_IMAGE_INDEX_MEDIA_TYPE,
+    IMAGE_MANIFEST_MEDIA_TYPE,
+    IMAGE_MANIFEST_LIST_MEDIA_TYPE,
+];
 
 #[derive(Debug, Diagnostic, thiserror::Error)]
 pub enum PushError {
     #[error("failed to push image")]
     Oci(#[from] OciDistributionError),
+    #[error("failed to parse reference")]
+    Parse(#[from] oci_client::ParseError),
+    #[error("failed to serialize manifest")]
+    Serialize(#[from] serde_json::Error),
+    #[error("invalid manifest media type")]
+    MediaType(#[from] InvalidHeaderValue),
+}
+
+#[derive(Debug, Diagnostic, thiserror::Error)]
+pub enum PullError {
+    #[error("failed to pull image")]
+    Oci(#[from] OciDistributionError),
+    #[error("no image found for platform '{0}'")]
+    NoPlatform(String),
+    #[error("failed to parse image")]
+    #[diagnostic(transparent)]
+    Image(#[from] ImageError),
+    #[error("manifest is not a '{0}' artifact")]
+    ArtifactType(String),
 }
 
 fn parse_host(repo: &str) -> &str {
@@ -36,34 +98,155 @@ pub fn load_credentials(repo: &str) -> Result<RegistryAuth, CredentialRetrievalE
     }
 }
 
+/// Repository each blob was pushed to during this session, keyed by digest. The inner lock is
+/// held while a blob is uploaded, so other artifacts wait for it instead of uploading it again.
+type Uploaded = Arc<Mutex<HashMap<String, Arc<tokio::sync::Mutex<Option<Reference>>>>>>;
+
+/// Digests resolved or pushed during this invocation, keyed by the whole reference. Shared by
+/// all registries, so e.g. verifying before a deploy doesn't resolve what was just pushed.
+type Digests = Arc<Mutex<HashMap<String, String>>>;
+
+static DIGESTS: LazyLock<Digests> = LazyLock::new(Digests::default);
+
+/// Proxy variables are conventionally set in either case
+fn proxy_env(name: &str) -> Option<String> {
+    env::var(name)
+        .or_else(|_| env::var(name.to_lowercase()))
+        .ok()
+        .filter(|value| !value.is_empty())
+}
+
+fn bytes(mode: Mode) -> Unit {
+    unit::dynamic_and_mode(unit::Bytes, mode)
+}
+
+/// Digest without algorithm, shortened like docker does
+fn short_digest(digest: &str) -> &str {
+    let hex = digest.split_once(':').map_or(digest, |(_, hex)| hex);
+    &hex[..hex.len().min(12)]
+}
+
 #[derive(Clone)]
 pub struct Registry {
     client: Client,
     auth: RegistryAuth,
+    uploaded: Uploaded,
+    digests: Digests,
+    max_concurrent_uploads: usize,
+}
+
+async fn push_layer(
+    client: &Client,
+    uploaded: &Uploaded,
+    image_ref: &Reference,
+    layer: ImageLayer,
+    digest: String,
+) -> Result<(), OciDistributionError> {
+    let entry = Arc::clone(uploaded.lock().unwrap().entry(digest.clone()).or_default());
+    let mut source = entry.lock().await;
+
+    match *source {
+        Some(ref source) if source.repository() == image_ref.repository() => return Ok(()),
+        // Mounting only links the existing blob into this repository, fall back to a regular
+        // upload when the registry doesn't support it
+        Some(ref source) if client.mount_blob(image_ref, source, &digest).await.is_ok() => {
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    if !client.blob_exists(image_ref, &digest).await? {
+        // Shared between attempts, cloning only bumps a reference count
+        let data = Bytes::from(layer.data);
+        let mut attempt = 1;
+
+        loop {
+            match client.push_blob(image_ref, data.clone(), &digest).await {
+                Ok(_) => break,
+                // Retry on digest mismatch (400) and invalid range (416) errors.
+                // Root cause unknown; we should probably look into this but retry is safe for now.
+                // Retrying on 5xx server errors is also acceptable.
+                Err(OciDistributionError::ServerError {
+                    code: 400 | 416 | 500..599,
+                    ..
+                }) if attempt < PUSH_ATTEMPTS => {
+                    tokio::time::sleep(Duration::from_secs(attempt * 2)).await;
+                    attempt += 1;
+                }
+                // The blob is only marked as uploaded once it was pushed
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    *source = Some(image_ref.clone());
+
+    Ok(())
 }
 
 impl Registry {
-    pub fn with_config(auth: RegistryAuth, insecure_registies: &[String]) -> Self {
+    pub fn with_config(
+        auth: RegistryAuth,
+        insecure_registies: &[String],
+        settings: &RegistrySettings,
+    ) -> Self {
+        let max_concurrent_uploads = settings
+            .max_concurrent_uploads
+            .unwrap_or(MAX_CONCURRENT_UPLOADS)
+            .max(1);
         let config = ClientConfig {
             protocol: ClientProtocol::HttpsExcept(
                 [insecure_registies, &["localhost".to_string()]].concat(),
             ),
+            http_proxy: settings
+                .http_proxy
+                .clone()
+                .or_else(|| proxy_env("HTTP_PROXY")),
+            https_proxy: settings
+                .https_proxy
+                .clone()
+                .or_else(|| proxy_env("HTTPS_PROXY")),
+            no_proxy: settings.no_proxy.clone().or_else(|| proxy_env("NO_PROXY")),
+            connect_timeout: settings.connect_timeout.map(Duration::from_secs),
+            read_timeout: settings.read_timeout.map(Duration::from_secs),
+            max_concurrent_upload: max_concurrent_uploads,
             ..ClientConfig::default()
         };
 
         Self {
             client: Client::new(config),
             auth,
+            uploaded: Uploaded::default(),
+            digests: Arc::clone(&DIGESTS),
+            max_concurrent_uploads,
         }
     }
 
+    /// Records that `reference` points to `digest`, as does the reference pinned to it
+    fn remember(&self, reference: &Reference, digest: &str) {
+        let mut digests = self.digests.lock().unwrap();
+        digests.insert(reference.whole(), digest.to_string());
+        digests.insert(
+            reference.clone_with_digest(digest.to_string()).whole(),
+            digest.to_string(),
+        );
+    }
+
     async fn try_resolve_digest(
         &self,
         auth: &RegistryAuth,
         reference: &Reference,
     ) -> Result<Option<String>, OciDistributionError> {
+        if let Some(digest) = self.digests.lock().unwrap().get(&reference.whole()) {
+            return Ok(Some(digest.clone()));
+        }
+
+        // Missing manifests aren't remembered, they're likely about to be pushed
         match self.client.fetch_manifest_digest(reference, auth).await {
-            Ok(digest) => Ok(Some(digest)),
+            Ok(digest) => {
+                self.remember(reference, &digest);
+                Ok(Some(digest))
+            }
             // If the manifest is not found, we assume the image does not exist
             Err(OciDistributionError::ImageManifestNotFoundError(_)) => Ok(None),
             // If the manifest is unknown, we assume the image does not exist
@@ -74,6 +257,464 @@ impl Registry {
         }
     }
 
+    /// Resolves the digest the reference currently points to, if it exists
+    pub async fn resolve_digest(
+        &self,
+        reference: &Reference,
+    ) -> Result<Option<String>, OciDistributionError> {
+        self.try_resolve_digest(&self.auth, reference).await
+    }
+
+    /// Digests the reference currently points to, for an image index these include the
+    /// digests of the images of every platform
+    pub async fn current_digests(
+        &self,
+        image_ref: &Reference,
+    ) -> Result<Vec<String>, OciDistributionError> {
+        self.client
+            .store_auth_if_needed(image_ref.resolve_registry(), &self.auth)
+            .await;
+
+        let (manifest, digest) = self.client.pull_manifest(image_ref, &self.auth).await?;
+        let mut digests = vec![digest];
+
+        if let OciManifest::ImageIndex(index) = manifest {
+            digests.extend(index.manifests.into_iter().map(|entry| entry.digest));
+        }
+
+        Ok(digests)
+    }
+
+    /// Points `tag` at the manifest (or index) `image_ref` refers to, in the same repository.
+    /// Returns the digest of the manifest.
+    pub async fn tag(&self, image_ref: &Reference, tag: &str) -> Result<String, PushError> {
+        self.client
+            .store_auth_if_needed(image_ref.resolve_registry(), &self.auth)
+            .await;
+
+        // Pushed as pulled, re-serializing could change the digest the tag points to
+        let (body, digest) = self
+            .client
+            .pull_manifest_raw(image_ref, &self.auth, MANIFEST_MEDIA_TYPES)
+            .await?;
+        let manifest = serde_json::from_slice::<OciManifest>(&body)?;
+        let target = Reference::with_tag(
+            image_ref.registry().to_string(),
+            image_ref.repository().to_string(),
+            tag.to_string(),
+        );
+        self.push_raw_manifest(&target, body.to_vec(), &manifest)
+            .await?;
+        self.remember(&target, &digest);
+
+        Ok(digest)
+    }
+
+    /// Tags of the repository `image_ref` is in, empty when nothing was pushed to it yet.
+    /// Pages with `last` until the registry returns an empty page, as registries may return
+    /// fewer tags than asked for.
+    pub async fn list_tags(
+        &self,
+        image_ref: &Reference,
+    ) -> Result<Vec<String>, OciDistributionError> {
+        self.client
+            .store_auth_if_needed(image_ref.resolve_registry(), &self.auth)
+            .await;
+
+        let mut tags = Vec::<String>::new();
+
+        loop {
+            let last = tags.last().map(String::as_str);
+            let page = match self
+                .client
+                .list_tags(image_ref, &self.auth, Some(TAGS_PAGE_SIZE), last)
+                .await
+            {
+                Ok(response) => response.tags,
+                Err(OciDistributionError::RegistryError { envelope, .. }) if matches!(envelope.errors.first(), Some(e) if e.code == OciErrorCode::NameUnknown) =>
+                {
+                    vec![]
+                }
+                Err(e) => return Err(e),
+            };
+
+            // A registry that ignores `last` returns the same page again
+            if page.is_empty() || page.last() == tags.last() {
+                return Ok(tags);
+            }
+
+            tags.extend(page);
+        }
+    }
+
+    /// Manifests referring to the image `image_ref` points to, like signatures, SBOMs and
+    /// attestations, optionally only those of `artifact_type`
+    pub async fn list_referrers(
+        &self,
+        image_ref: &Reference,
+        artifact_type: Option<&str>,
+    ) -> Result<Vec<ImageIndexEntry>, OciDistributionError> {
+        self.client
+            .store_auth_if_needed(image_ref.resolve_registry(), &self.auth)
+            .await;
+
+        // The referrers API only accepts digests
+        let reference = match image_ref.digest() {
+            Some(_) => image_ref.clone(),
+            None => {
+                let digest = self
+                    .client
+                    .fetch_manifest_digest(image_ref, &self.auth)
+                    .await?;
+                image_ref.clone_with_digest(digest)
+            }
+        };
+        let index = self
+            .client
+            .pull_referrers(&reference, artifact_type)
+            .await?;
+
+        Ok(index.manifests)
+    }
+
+    /// Pushes `data` as the only layer of an artifact manifest, returns its digest
+    pub async fn push_artifact(
+        &self,
+        image_ref: &Reference,
+        artifact_type: &str,
+        data: Vec<u8>,
+    ) -> Result<String, PushError> {
+        let digest = self
+            .push_artifact_manifest(image_ref, artifact_type, data, None, None)
+            .await?;
+        self.remember(image_ref, &digest);
+
+        Ok(digest)
+    }
+
+    /// Pushes `data` as an artifact referring to the image `image_ref` points to, so it's
+    /// listed by [`Registry::list_referrers`]. Returns the digest of the artifact.
+    pub async fn attach_artifact(
+        &self,
+        image_ref: &Reference,
+        artifact_type: &str,
+        data: Vec<u8>,
+    ) -> Result<String, PushError> {
+        self.client
+            .store_auth_if_needed(image_ref.resolve_registry(), &self.auth)
+            .await;
+
+        let (body, _) = self
+            .client
+            .pull_manifest_raw(image_ref, &self.auth, MANIFEST_MEDIA_TYPES)
+            .await?;
+        let manifest = serde_json::from_slice::<OciManifest>(&body)?;
+        let subject = image::descriptor(manifest.content_type(), &body);
+        // Referrers indexes include the annotations, so the newest can be found without
+        // pulling every manifest
+        let created = [(
+            annotations::ORG_OPENCONTAINERS_IMAGE_CREATED.to_string(),
+            chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        )]
+        .into();
+
+        self.push_artifact_manifest(image_ref, artifact_type, data, Some(subject), Some(created))
+            .await
+    }
+
+    /// Content of the newest artifact of `artifact_type` attached to the image `image_ref`
+    /// points to, `None` if there is none
+    pub async fn pull_attached(
+        &self,
+        image_ref: &Reference,
+        artifact_type: &str,
+    ) -> Result<Option<Vec<u8>>, PullError> {
+        let referrers = self.list_referrers(image_ref, Some(artifact_type)).await?;
+        // Every push of the image attaches metadata, the newest describes the latest build
+        let newest = referrers.iter().max_by_key(|entry| {
+            entry
+                .annotations
+                .as_ref()
+                .and_then(|values| values.get(annotations::ORG_OPENCONTAINERS_IMAGE_CREATED))
+                .cloned()
+        });
+
+        match newest {
+            Some(entry) => {
+                let reference = image_ref.clone_with_digest(entry.digest.clone());
+                Ok(Some(self.pull_artifact(&reference, artifact_type).await?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Pushes the blobs and manifest of an artifact. Artifacts with a subject are pushed by
+    /// digest, otherwise to `image_ref`.
+    async fn push_artifact_manifest(
+        &self,
+        image_ref: &Reference,
+        artifact_type: &str,
+        data: Vec<u8>,
+        subject: Option<OciDescriptor>,
+        annotations: Option<BTreeMap<String, String>>,
+    ) -> Result<String, PushError> {
+        self.client
+            .store_auth_if_needed(image_ref.resolve_registry(), &self.auth)
+            .await;
+
+        let config = image::descriptor(EMPTY_MEDIA_TYPE, b"{}");
+        let layer = image::descriptor(artifact_type, &data);
+
+        self.client
+            .push_blob(image_ref, b"{}".to_vec(), &config.digest)
+            .await?;
+        self.client
+            .push_blob(image_ref, data, &layer.digest)
+            .await?;
+
+        let manifest = OciImageManifest {
+            schema_version: 2,
+            media_type: Some(OCI_IMAGE_MEDIA_TYPE.to_string()),
+            config,
+            layers: vec![layer],
+            subject,
+            artifact_type: Some(artifact_type.to_string()),
+            annotations,
+        };
+        let digest = image::compute_digest(&manifest)?;
+        let target = match manifest.subject {
+            Some(_) => image_ref.clone_with_digest(digest.clone()),
+            None => image_ref.clone(),
+        };
+        self.client
+            .push_manifest(&target, &OciManifest::Image(manifest))
+            .await?;
+
+        Ok(digest)
+    }
+
+    /// Pulls the content of an artifact pushed with [`Registry::push_artifact`]
+    pub async fn pull_artifact(
+        &self,
+        image_ref: &Reference,
+        artifact_type: &str,
+    ) -> Result<Vec<u8>, PullError> {
+        self.client
+            .store_auth_if_needed(image_ref.resolve_registry(), &self.auth)
+            .await;
+
+        let (manifest, _) = self
+            .client
+            .pull_image_manifest(image_ref, &self.auth)
+            .await?;
+        let layer = manifest
+            .layers
+            .iter()
+            .find(|layer| layer.media_type == artifact_type)
+            .ok_or_else(|| PullError::ArtifactType(artifact_type.to_string()))?;
+
+        let mut data = vec![];
+        self.client.pull_blob(image_ref, layer, &mut data).await?;
+
+        Ok(data)
+    }
+
+    /// Copies a blob of `source` to `target` unless it's already there
+    async fn copy_blob(
+        &self,
+        source: &Reference,
+        target: &Reference,
+        descriptor: &OciDescriptor,
+    ) -> Result<(), OciDistributionError> {
+        if self.client.blob_exists(target, &descriptor.digest).await? {
+            return Ok(());
+        }
+
+        let mut data = vec![];
+        self.client.pull_blob(source, descriptor, &mut data).await?;
+
+        let layer = ImageLayer::new(data, descriptor.media_type.clone(), None);
+        push_layer(
+            &self.client,
+            &self.uploaded,
+            target,
+            layer,
+            descriptor.digest.clone(),
+        )
+        .await
+    }
+
+    /// Pushes the manifest as pulled, re-serializing it would change its digest
+    async fn push_raw_manifest(
+        &self,
+        target: &Reference,
+        body: Vec<u8>,
+        manifest: &OciManifest,
+    ) -> Result<String, PushError> {
+        let content_type = HeaderValue::from_str(manifest.content_type())?;
+
+        Ok(self
+            .client
+            .push_manifest_raw(target, body, content_type)
+            .await?)
+    }
+
+    /// Copies the image or index `source` points to, with the image of every platform, to
+    /// `target`. Manifests are copied byte for byte, so the digest is kept and returned.
+    pub async fn copy(
+        &self,
+        mut progress: Item,
+        source: &Reference,
+        source_auth: &RegistryAuth,
+        target: &Reference,
+    ) -> Result<String, PushError> {
+        self.client
+            .store_auth_if_needed(source.resolve_registry(), source_auth)
+            .await;
+        self.client
+            .store_auth_if_needed(target.resolve_registry(), &self.auth)
+            .await;
+
+        let (body, digest) = self
+            .client
+            .pull_manifest_raw(source, source_auth, MANIFEST_MEDIA_TYPES)
+            .await?;
+
+        if self.try_resolve_digest(&self.auth, target).await?.as_ref() == Some(&digest) {
+            progress.info("image already exists, skipping copy");
+            return Ok(digest);
+        }
+
+        let manifest = serde_json::from_slice::<OciManifest>(&body)?;
+        let mut images = vec![];
+
+        match manifest {
+            OciManifest::Image(ref image) => images.push((image.clone(), None)),
+            OciManifest::ImageIndex(ref index) => {
+                for entry in index.manifests.iter() {
+                    let (body, _) = self
+                        .client
+                        .pull_manifest_raw(
+                            &source.clone_with_digest(entry.digest.clone()),
+                            source_auth,
+                            MANIFEST_MEDIA_TYPES,
+                        )
+                        .await?;
+                    let image = serde_json::from_slice::<OciImageManifest>(&body)?;
+                    images.push((image, Some((entry.digest.clone(), body.to_vec()))));
+                }
+            }
+        }
+
+        let blobs = images
+            .iter()
+            .flat_map(|(image, _)| image.layers.iter().chain([&image.config]))
+            .collect::<Vec<_>>();
+        progress.init(Some(blobs.len()), Some(unit::label("blobs")));
+        progress.info(format!("copying {} image(s)", images.len()));
+
+        stream::iter(blobs)
+            .map(|descriptor| {
+                let progress = &progress;
+
+                async move {
+                    self.copy_blob(source, target, descriptor).await?;
+                    progress.inc();
+
+                    Ok(())
+                }
+            })
+            .boxed() // Workaround to rustc issue https://github.com/rust-lang/rust/issues/104382
+            .buffer_unordered(self.max_concurrent_uploads)
+            .try_for_each(future::ok::<(), OciDistributionError>)
+            .await?;
+
+        // Images of an index are pushed by digest before the index referring to them
+        for (image, raw) in images {
+            if let Some((digest, body)) = raw {
+                let reference = target.clone_with_digest(digest);
+                self.push_raw_manifest(&reference, body, &OciManifest::Image(image))
+                    .await?;
+            }
+        }
+
+        self.push_raw_manifest(target, body.to_vec(), &manifest)
+            .await?;
+        self.remember(target, &digest);
+
+        progress.done("image copied");
+
+        Ok(digest)
+    }
+
+    /// Pulls the image for the given platform, resolving image indexes
+    pub async fn pull(
+        &mut self,
+        image_ref: &Reference,
+        platform: &str,
+    ) -> Result<Image, PullError> {
+        let registry = image_ref.resolve_registry();
+        self.client.store_auth_if_needed(registry, &self.auth).await;
+
+        // Pulled raw, so pushing the image keeps its digest
+        let (body, _) = self
+            .client
+            .pull_manifest_raw(image_ref, &self.auth, MANIFEST_MEDIA_TYPES)
+            .await?;
+        let (body, platform) = match serde_json::from_slice(&body).map_err(ImageError::from)? {
+            OciManifest::Image(_) => (body, None),
+            OciManifest::ImageIndex(index) => {
+                let entry = index
+                    .manifests
+                    .into_iter()
+                    .find(|entry| matches!(entry.platform, Some(ref p) if format!("{}/{}", p.os, p.architecture) == platform))
+                    .ok_or_else(|| PullError::NoPlatform(platform.to_string()))?;
+                let (body, _) = self
+                    .client
+                    .pull_manifest_raw(
+                        &image_ref.clone_with_digest(entry.digest),
+                        &self.auth,
+                        MANIFEST_MEDIA_TYPES,
+                    )
+                    .await?;
+
+                (body, entry.platform)
+            }
+        };
+        let manifest =
+            serde_json::from_slice::<OciImageManifest>(&body).map_err(ImageError::from)?;
+
+        let mut layers = vec![];
+
+        for layer in manifest.layers.iter() {
+            let mut data = vec![];
+            self.client.pull_blob(image_ref, layer, &mut data).await?;
+            layers.push(ImageLayer::new(
+                data,
+                layer.media_type.clone(),
+                layer.annotations.clone(),
+            ));
+        }
+
+        let mut data = vec![];
+        self.client
+            .pull_blob(image_ref, &manifest.config, &mut data)
+            .await?;
+
+        let config = Config::new(
+            data,
+            manifest.config.media_type.clone(),
+            manifest.config.annotations.clone(),
+        );
+
+        Ok(Image::from_manifest_bytes(
+            body.to_vec(),
+            config,
+            platform,
+            layers,
+        )?)
+    }
+
     pub async fn push(
         &mut self,
         mut progress: Item,
@@ -85,64 +726,63 @@ impl Registry {
 
         if let Some(digest) = self.try_resolve_digest(&self.auth, image_ref).await? {
             // If the digest matches the image's digest, we can skip pushing
-            if digest == image.digest {
+            if digest == image.digest() {
                 progress.info("image already exists, skipping push");
                 return Ok(None);
             }
         }
 
-        progress.init(Some(image.layers.len()), None);
+        let digest = image.digest().to_string();
+        let body = image.manifest_bytes().to_vec();
+        let (manifest, config, layers) = image.into_parts();
+        let total = layers.iter().map(|layer| layer.data.len()).sum();
+        progress.init(
+            Some(total),
+            Some(bytes(Mode::with_throughput().and_percentage())),
+        );
         progress.info("pushing image");
 
-        // Push blobs with cache
-        stream::iter(image.layers)
+        // The client uploads each blob in one request, so layers only report progress once
+        // they're done
+        let layers = layers
+            .into_iter()
             .map(|layer| {
-                let client = self.client.clone();
+                let digest = layer.sha256_digest();
+                let item = progress.add_child(short_digest(&digest));
+                item.init(Some(layer.data.len()), Some(bytes(Mode::with_throughput())));
+                (layer, digest, item)
+            })
+            .collect::<Vec<_>>();
+
+        // Push blobs with cache, shared between all artifacts pushed with this registry
+        stream::iter(layers)
+            .map(|(layer, digest, item)| {
+                let client = &self.client;
+                let uploaded = &self.uploaded;
                 let progress = &progress;
 
                 async move {
-                    let digest = layer.sha256_digest();
-
-                    if !client.blob_exists(image_ref, &digest).await? {
-                        for i in 1..5 {
-                            match client
-                                .push_blob(image_ref, layer.data.clone(), &digest)
-                                .await
-                            {
-                                Ok(_) => break,
-                                // Retry on digest mismatch (400) and invalid range (416) errors.
-                                // Root cause unknown; we should probably look into this but retry is safe for now.
-                                // Retrying on 5xx server errors is also acceptable.
-                                Err(OciDistributionError::ServerError {
-                                    code: 400 | 416 | 500..599,
-                                    ..
-                                }) => {
-                                    tokio::time::sleep(Duration::from_secs(i * 2)).await;
-                                    continue;
-                                }
-                                Err(e) => return Err(e),
-                            }
-                        }
-                    }
-
-                    progress.inc();
+                    let size = layer.data.len();
+                    push_layer(client, uploaded, image_ref, layer, digest).await?;
+                    item.set(size);
+                    progress.inc_by(size);
 
                     Ok(())
                 }
             })
             .boxed() // Workaround to rustc issue https://github.com/rust-lang/rust/issues/104382
-            .buffer_unordered(16)
+            .buffer_unordered(self.max_concurrent_uploads)
             .try_for_each(future::ok::<(), OciDistributionError>)
             .await?;
 
         let config_url = self
             .client
-            .push_blob(image_ref, image.config.data, &image.manifest.config.digest)
+            .push_blob(image_ref, config.data, &manifest.config.digest)
             .await?;
         let manifest_url = self
-            .client
-            .push_manifest(image_ref, &image.manifest.into())
+            .push_raw_manifest(image_ref, body, &OciManifest::Image(manifest))
             .await?;
+        self.remember(image_ref, &digest);
 
         progress.done("image pushed");
 
@@ -0,0 +1,138 @@
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use steiger::audit::Invocation;
+use tokio::time::Instant;
+
+#[derive(Clone, Copy)]
+pub enum Stage {
+    Build,
+    Test,
+    Push,
+    Deploy,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Entry {
+    pub success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub duration_secs: f64,
+}
+
+#[derive(Clone, Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Report {
+    pub success: bool,
+    pub exit_code: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub duration_secs: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preview: Option<String>,
+    /// Keyed by build entry
+    pub builds: BTreeMap<String, Entry>,
+    /// Keyed by artifact
+    pub tests: BTreeMap<String, Entry>,
+    /// Keyed by artifact
+    pub pushes: BTreeMap<String, Entry>,
+    /// Keyed by release
+    pub releases: BTreeMap<String, Entry>,
+    /// Pushed image references including digest, keyed by artifact
+    #[serde(default)]
+    pub images: BTreeMap<String, String>,
+    /// Warnings of builders and deployers, e.g. platform fallbacks
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+    /// External commands that were run, with secrets in their arguments redacted
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub commands: Vec<Invocation>,
+}
+
+impl Report {
+    pub async fn read(path: &Path) -> Result<Self, std::io::Error> {
+        Ok(serde_json::from_slice(&tokio::fs::read(path).await?)?)
+    }
+
+    pub async fn write(&self, path: &Path) -> Result<(), std::io::Error> {
+        tokio::fs::write(path, serde_json::to_vec_pretty(self)?).await
+    }
+}
+
+/// Formats the error including its sources, e.g. `failed to build: docker error: ...`
+pub fn describe(e: &dyn Error) -> String {
+    let mut parts = vec![e.to_string()];
+    let mut source = e.source();
+
+    while let Some(e) = source {
+        parts.push(e.to_string());
+        source = e.source();
+    }
+
+    parts.join(": ")
+}
+
+/// Collects the outcome of every stage so a result can be written even when the run fails
+#[derive(Clone)]
+pub struct Recorder {
+    started: Instant,
+    report: Arc<Mutex<Report>>,
+}
+
+impl Default for Recorder {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            report: Arc::default(),
+        }
+    }
+}
+
+impl Recorder {
+    pub fn record(&self, stage: Stage, name: &str, elapsed: Duration, error: Option<&dyn Error>) {
+        let entry = Entry {
+            success: error.is_none(),
+            error: error.map(describe),
+            duration_secs: elapsed.as_secs_f64(),
+        };
+
+        let mut report = self.report.lock().unwrap();
+        let entries = match stage {
+            Stage::Build => &mut report.builds,
+            Stage::Test => &mut report.tests,
+            Stage::Push => &mut report.pushes,
+            Stage::Deploy => &mut report.releases,
+        };
+
+        entries.insert(name.to_string(), entry);
+    }
+
+    pub fn set_preview(&self, id: &str) {
+        self.report.lock().unwrap().preview = Some(id.to_string());
+    }
+
+    pub fn record_image(&self, artifact: &str, image_ref: &str) {
+        let mut report = self.report.lock().unwrap();
+        report
+            .images
+            .insert(artifact.to_string(), image_ref.to_string());
+    }
+
+    /// Completes the report with the overall outcome of the run
+    pub fn finish(&self, exit_code: u8, error: Option<&dyn Error>) -> Report {
+        let mut report = self.report.lock().unwrap();
+        report.success = exit_code == 0;
+        report.exit_code = exit_code;
+        report.error = error.map(describe);
+        report.duration_secs = self.started.elapsed().as_secs_f64();
+
+        report.clone()
+    }
+}
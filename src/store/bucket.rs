@@ -0,0 +1,78 @@
+use miette::Diagnostic;
+use prodash::tree::Item;
+use tokio::fs;
+
+use crate::{
+    exec::{self, CmdBuilder, ExitError},
+    image::{Image, ImageError, archive},
+    store::Store,
+    tmp,
+};
+
+#[derive(Debug, Diagnostic, thiserror::Error)]
+pub enum BucketError {
+    #[error("IO error")]
+    IO(#[from] std::io::Error),
+    #[error("failed to create temporary file")]
+    TempFile(#[from] async_tempfile::Error),
+    #[error("failed to create archive")]
+    #[diagnostic(transparent)]
+    Archive(#[from] ImageError),
+    #[error("unsupported bucket url '{0}'")]
+    #[diagnostic(help("use an `s3://` or `gs://` url"))]
+    Scheme(String),
+    #[error("failed to upload archive")]
+    #[diagnostic(transparent)]
+    Upload(#[from] ExitError),
+}
+
+/// Uploads OCI layout archives to a bucket, using the CLI of the cloud provider so its
+/// credentials are picked up like they would be outside of steiger
+#[derive(Clone)]
+pub struct BucketStore {
+    url: String,
+}
+
+impl BucketStore {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.trim_end_matches('/').to_string(),
+        }
+    }
+}
+
+impl Store for BucketStore {
+    type Error = BucketError;
+
+    async fn publish(
+        self,
+        mut progress: Item,
+        name: &str,
+        tag: &str,
+        image: Image,
+    ) -> Result<String, Self::Error> {
+        let url = format!("{}/{name}/{tag}.tar", self.url);
+        let mut cmd = match self.url.split_once("://") {
+            Some(("s3", _)) => {
+                let mut cmd = CmdBuilder::new("aws");
+                cmd.args(["s3", "cp"]);
+                cmd
+            }
+            Some(("gs", _)) => {
+                let mut cmd = CmdBuilder::new("gcloud");
+                cmd.args(["storage", "cp"]);
+                cmd
+            }
+            _ => return Err(BucketError::Scheme(self.url)),
+        };
+
+        let file = tmp::new_file().await?;
+        fs::write(file.file_path(), archive::oci_archive(&image, tag)?).await?;
+
+        progress.info(format!("uploading to {url}"));
+        exec::run_with_output(cmd.arg(file.file_path()).arg(&url)).await?;
+        progress.done("archive uploaded");
+
+        Ok(url)
+    }
+}
@@ -0,0 +1,155 @@
+use std::{fmt, path::Path};
+
+use kube::config::{Kubeconfig, KubeconfigError};
+use miette::Diagnostic;
+use prodash::tree::Item;
+use tokio::{fs, process::Command};
+
+use crate::{
+    exec::{self, ExitError},
+    image::{Image, ImageError, archive},
+    store::Store,
+    tmp,
+};
+
+#[derive(Debug, Diagnostic, thiserror::Error)]
+pub enum ClusterError {
+    #[error("IO error")]
+    IO(#[from] std::io::Error),
+    #[error("failed to create tempdir")]
+    TempDir(#[from] async_tempfile::Error),
+    #[error("failed to create archive")]
+    #[diagnostic(transparent)]
+    Archive(#[from] ImageError),
+    #[error("failed to read kubeconfig")]
+    Kubeconfig(#[from] KubeconfigError),
+    #[error("no current kube context to load images into")]
+    #[diagnostic(help("select the local cluster with `kubectl config use-context`"))]
+    NoContext,
+    #[error("can't load images into the cluster of context '{0}'")]
+    #[diagnostic(help(
+        "only kind, k3d, minikube, Docker Desktop and OrbStack clusters are supported, set `push: true` for other clusters"
+    ))]
+    Unsupported(String),
+    #[error("failed to load image into the cluster")]
+    #[diagnostic(transparent)]
+    Load(#[from] ExitError),
+}
+
+/// Local cluster of the current kube context
+#[derive(Clone, Debug)]
+pub enum LocalCluster {
+    Kind(String),
+    K3d(String),
+    Minikube(String),
+    /// Clusters running on the docker daemon, they see every image loaded into it
+    Docker(String),
+}
+
+impl LocalCluster {
+    /// Recognizes the cluster by the context names its tooling creates
+    pub fn detect() -> Result<Self, ClusterError> {
+        let context = Kubeconfig::read()?
+            .current_context
+            .ok_or(ClusterError::NoContext)?;
+
+        if let Some(name) = context.strip_prefix("kind-") {
+            return Ok(LocalCluster::Kind(name.to_string()));
+        }
+
+        if let Some(name) = context.strip_prefix("k3d-") {
+            return Ok(LocalCluster::K3d(name.to_string()));
+        }
+
+        match context.as_str() {
+            "docker-desktop" | "orbstack" => Ok(LocalCluster::Docker(context)),
+            // minikube names the context after its default profile
+            "minikube" => Ok(LocalCluster::Minikube(context)),
+            _ => Err(ClusterError::Unsupported(context)),
+        }
+    }
+
+    fn command(&self, archive: &Path) -> Command {
+        match self {
+            LocalCluster::Kind(name) => {
+                let mut cmd = Command::new("kind");
+                cmd.args(["load", "image-archive"])
+                    .arg(archive)
+                    .args(["--name", name]);
+                cmd
+            }
+            LocalCluster::K3d(name) => {
+                let mut cmd = Command::new("k3d");
+                cmd.args(["image", "import"])
+                    .arg(archive)
+                    .args(["--cluster", name]);
+                cmd
+            }
+            LocalCluster::Minikube(profile) => {
+                let mut cmd = Command::new("minikube");
+                cmd.args(["image", "load"])
+                    .arg(archive)
+                    .args(["--profile", profile]);
+                cmd
+            }
+            LocalCluster::Docker(_) => {
+                let mut cmd = Command::new("docker");
+                cmd.args(["load", "--quiet", "-i"]).arg(archive);
+                cmd
+            }
+        }
+    }
+}
+
+impl fmt::Display for LocalCluster {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LocalCluster::Kind(name) => write!(f, "kind cluster '{name}'"),
+            LocalCluster::K3d(name) => write!(f, "k3d cluster '{name}'"),
+            LocalCluster::Minikube(profile) => write!(f, "minikube profile '{profile}'"),
+            LocalCluster::Docker(context) => write!(f, "{context}"),
+        }
+    }
+}
+
+/// Loads images into the nodes of a local cluster, without a registry in between
+#[derive(Clone)]
+pub struct ClusterStore {
+    cluster: LocalCluster,
+    repo: Option<String>,
+}
+
+impl ClusterStore {
+    /// Images are named like they would be in `repo`, so the same chart values work for both
+    pub fn new(cluster: LocalCluster, repo: Option<String>) -> Self {
+        Self { cluster, repo }
+    }
+}
+
+impl Store for ClusterStore {
+    type Error = ClusterError;
+
+    async fn publish(
+        self,
+        mut progress: Item,
+        name: &str,
+        tag: &str,
+        image: Image,
+    ) -> Result<String, Self::Error> {
+        // Loaded images have no registry digest, they're referred to by tag
+        let reference = match self.repo {
+            Some(ref repo) => format!("{repo}/{name}:{tag}"),
+            None => format!("{name}:{tag}"),
+        };
+        let dir = tmp::new_dir().await?;
+        let path = dir.join("image.tar");
+
+        fs::write(&path, archive::docker_archive(&image, &reference)?).await?;
+
+        progress.info(format!("loading {reference} into {}", self.cluster));
+        exec::run_with_output(&mut self.cluster.command(&path)).await?;
+        progress.done("image loaded");
+
+        Ok(reference)
+    }
+}
@@ -0,0 +1,58 @@
+use std::path::{Path, PathBuf};
+
+use miette::Diagnostic;
+use prodash::tree::Item;
+use tokio::fs;
+
+use crate::{
+    image::{Image, ImageError, archive},
+    store::Store,
+};
+
+#[derive(Debug, Diagnostic, thiserror::Error)]
+pub enum DirectoryError {
+    #[error("IO error")]
+    IO(#[from] std::io::Error),
+    #[error("failed to create archive")]
+    #[diagnostic(transparent)]
+    Archive(#[from] ImageError),
+}
+
+/// Writes OCI layout archives to a local directory, e.g. for offline hand-off
+#[derive(Clone)]
+pub struct DirectoryStore {
+    path: PathBuf,
+}
+
+impl DirectoryStore {
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_path_buf(),
+        }
+    }
+}
+
+impl Store for DirectoryStore {
+    type Error = DirectoryError;
+
+    async fn publish(
+        self,
+        mut progress: Item,
+        name: &str,
+        tag: &str,
+        image: Image,
+    ) -> Result<String, Self::Error> {
+        let path = self.path.join(name).join(format!("{tag}.tar"));
+
+        progress.info(format!("writing {}", path.display()));
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+
+        fs::write(&path, archive::oci_archive(&image, tag)?).await?;
+        progress.done("archive written");
+
+        Ok(path.display().to_string())
+    }
+}
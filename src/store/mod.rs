@@ -0,0 +1,165 @@
+use miette::Diagnostic;
+use oci_client::{Reference, secrets::RegistryAuth};
+use prodash::tree::Item;
+
+use crate::{
+    config::ArtifactStore,
+    image::Image,
+    registry::{PushError, Registry},
+    store::{bucket::BucketStore, directory::DirectoryStore},
+};
+
+pub use cluster::{ClusterStore, LocalCluster};
+
+mod bucket;
+mod cluster;
+mod directory;
+
+#[derive(Debug, Diagnostic, thiserror::Error)]
+pub enum StoreError {
+    #[error("registry error")]
+    #[diagnostic(transparent)]
+    Registry(#[from] ErrorOf<RegistryStore>),
+    #[error("directory error")]
+    #[diagnostic(transparent)]
+    Directory(#[from] ErrorOf<DirectoryStore>),
+    #[error("bucket error")]
+    #[diagnostic(transparent)]
+    Bucket(#[from] ErrorOf<BucketStore>),
+    #[error("local cluster error")]
+    #[diagnostic(transparent)]
+    Cluster(#[from] ErrorOf<ClusterStore>),
+}
+
+/// Destination artifacts are published to
+pub trait Store {
+    type Error;
+
+    /// Publishes the image as `<name>:<tag>`, returns the reference it can be retrieved by
+    async fn publish(
+        self,
+        progress: Item,
+        name: &str,
+        tag: &str,
+        image: Image,
+    ) -> Result<String, Self::Error>;
+}
+
+type ErrorOf<T> = <T as Store>::Error;
+
+#[derive(Clone)]
+pub struct RegistryStore {
+    registry: Registry,
+    repo: String,
+}
+
+impl RegistryStore {
+    pub fn new(registry: Registry, repo: String) -> Self {
+        Self { registry, repo }
+    }
+
+    pub fn repo(&self) -> &str {
+        &self.repo
+    }
+
+    /// Attaches `data` as an artifact referring to the pushed `image_ref`, returns the digest
+    /// of the artifact
+    pub async fn attach(
+        &self,
+        image_ref: &str,
+        artifact_type: &str,
+        data: Vec<u8>,
+    ) -> Result<String, PushError> {
+        self.registry
+            .attach_artifact(&Reference::try_from(image_ref)?, artifact_type, data)
+            .await
+    }
+
+    /// Copies `source` with every platform as `<name>:<tag>`, returns the reference it can be
+    /// retrieved by
+    pub async fn copy(
+        self,
+        progress: Item,
+        name: &str,
+        tag: &str,
+        source: &Reference,
+        source_auth: &RegistryAuth,
+    ) -> Result<String, PushError> {
+        let reference = format!("{}/{name}:{tag}", self.repo);
+        let digest = self
+            .registry
+            .copy(
+                progress,
+                source,
+                source_auth,
+                &Reference::try_from(reference.as_str())?,
+            )
+            .await?;
+
+        Ok(format!("{reference}@{digest}"))
+    }
+}
+
+impl Store for RegistryStore {
+    type Error = PushError;
+
+    async fn publish(
+        mut self,
+        progress: Item,
+        name: &str,
+        tag: &str,
+        image: Image,
+    ) -> Result<String, Self::Error> {
+        let reference = format!("{}/{name}:{tag}", self.repo);
+        let output_ref = format!("{reference}@{}", image.digest());
+
+        self.registry
+            .push(progress, &Reference::try_from(reference)?, image)
+            .await?;
+
+        Ok(output_ref)
+    }
+}
+
+#[derive(Clone)]
+pub enum Target {
+    Registry(RegistryStore),
+    Directory(DirectoryStore),
+    Bucket(BucketStore),
+    Cluster(ClusterStore),
+}
+
+impl Target {
+    /// Target of the configured store, `None` when pushing to the registry without a repo
+    pub fn new(store: &ArtifactStore, registry: Option<&RegistryStore>) -> Option<Self> {
+        Some(match store {
+            ArtifactStore::Registry => Target::Registry(registry?.clone()),
+            ArtifactStore::Directory { path } => Target::Directory(DirectoryStore::new(path)),
+            ArtifactStore::Bucket { url } => Target::Bucket(BucketStore::new(url)),
+        })
+    }
+
+    /// Whether the target holds an image that can be pulled, rather than an archive
+    pub fn is_image(&self) -> bool {
+        matches!(self, Target::Registry(_) | Target::Cluster(_))
+    }
+}
+
+impl Store for Target {
+    type Error = StoreError;
+
+    async fn publish(
+        self,
+        progress: Item,
+        name: &str,
+        tag: &str,
+        image: Image,
+    ) -> Result<String, Self::Error> {
+        Ok(match self {
+            Target::Registry(store) => store.publish(progress, name, tag, image).await?,
+            Target::Directory(store) => store.publish(progress, name, tag, image).await?,
+            Target::Bucket(store) => store.publish(progress, name, tag, image).await?,
+            Target::Cluster(store) => store.publish(progress, name, tag, image).await?,
+        })
+    }
+}
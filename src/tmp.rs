@@ -0,0 +1,91 @@
+//! Location of temporary files, OCI layouts of every artifact end up here during a build.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use async_tempfile::{TempDir, TempFile};
+use miette::Diagnostic;
+use tokio::process::Command;
+
+use crate::exec::{self, ExitError};
+
+#[derive(Debug, Diagnostic, thiserror::Error)]
+pub enum FreeSpaceError {
+    #[error(transparent)]
+    Exec(#[from] ExitError),
+    #[error("unexpected output: {0}")]
+    Output(String),
+}
+
+/// Overrides the system temp dir, takes precedence over `tmpDir` in the config
+pub const ENV_VAR: &str = "STEIGER_TMPDIR";
+
+static DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// Uses `STEIGER_TMPDIR` or `dir` for temporary files, creating it when missing
+pub async fn init(dir: Option<&Path>) -> Result<(), std::io::Error> {
+    let Some(dir) = std::env::var_os(ENV_VAR)
+        .map(PathBuf::from)
+        .or_else(|| dir.map(Path::to_path_buf))
+    else {
+        return Ok(());
+    };
+
+    tokio::fs::create_dir_all(&dir).await?;
+    let _ = DIR.set(std::path::absolute(dir)?);
+
+    Ok(())
+}
+
+pub fn dir() -> PathBuf {
+    DIR.get().cloned().unwrap_or_else(std::env::temp_dir)
+}
+
+pub async fn new_dir() -> Result<TempDir, async_tempfile::Error> {
+    TempDir::new_in(dir()).await
+}
+
+pub async fn new_dir_with_name(name: impl AsRef<str>) -> Result<TempDir, async_tempfile::Error> {
+    TempDir::new_with_name_in(name, dir()).await
+}
+
+pub async fn new_file() -> Result<TempFile, async_tempfile::Error> {
+    TempFile::new_in(dir()).await
+}
+
+/// Available bytes on the volume of the temp dir
+#[cfg(not(windows))]
+pub async fn free_space() -> Result<u64, FreeSpaceError> {
+    let stdout = exec::run_with_output(Command::new("df").arg("-Pk").arg(dir())).await?;
+    let available = stdout
+        .lines()
+        .nth(1)
+        .and_then(|line| line.split_whitespace().nth(3))
+        .and_then(|kb| kb.parse::<u64>().ok())
+        .ok_or_else(|| FreeSpaceError::Output(stdout.trim().to_string()))?;
+
+    Ok(available * 1024)
+}
+
+/// Available bytes on the volume of the temp dir
+#[cfg(windows)]
+pub async fn free_space() -> Result<u64, FreeSpaceError> {
+    let script = format!(
+        "(Get-Item -LiteralPath '{}').PSDrive.Free",
+        dir().display().to_string().replace('\'', "''")
+    );
+    let stdout = exec::run_with_output(
+        Command::new("powershell")
+            .arg("-NoProfile")
+            .arg("-Command")
+            .arg(script),
+    )
+    .await?;
+
+    stdout
+        .trim()
+        .parse::<u64>()
+        .map_err(|_| FreeSpaceError::Output(stdout.trim().to_string()))
+}
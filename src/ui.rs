@@ -0,0 +1,267 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    mem,
+    sync::{Mutex, OnceLock},
+};
+
+use serde_json::{Value, json};
+
+/// How summaries and warnings are written, progress rendering follows the same mode
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Mode {
+    /// Live progress with colors and symbols
+    #[default]
+    Human,
+    /// Log lines only, suitable for CI logs
+    Plain,
+    /// Summaries and warnings as JSON lines on stdout, logs on stderr
+    Json,
+}
+
+static MODE: OnceLock<Mode> = OnceLock::new();
+
+/// Warnings of this run, repeated once progress rendering is done so they don't get lost
+static WARNINGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+pub fn set_mode(mode: Mode) {
+    let _ = MODE.set(mode);
+}
+
+pub fn mode() -> Mode {
+    MODE.get().copied().unwrap_or_default()
+}
+
+fn emit(kind: &str, mut value: Value) {
+    value["type"] = json!(kind);
+    println!("{value}");
+}
+
+pub fn warn(message: impl Display) {
+    record_warning(&message);
+
+    match mode() {
+        Mode::Human => eprintln!("⚠ {message}"),
+        Mode::Plain => eprintln!("warning: {message}"),
+        Mode::Json => emit("warning", json!({ "message": message.to_string() })),
+    }
+}
+
+/// Only adds the warning to the summary, for code that already reports it through progress
+pub fn record_warning(message: impl Display) {
+    WARNINGS.lock().unwrap().push(message.to_string());
+}
+
+/// Takes the warnings recorded so far
+pub fn take_warnings() -> Vec<String> {
+    mem::take(&mut WARNINGS.lock().unwrap())
+}
+
+/// Prints the warnings of the run, if any
+pub fn warnings(warnings: &[String]) {
+    if warnings.is_empty() {
+        return;
+    }
+
+    match mode() {
+        Mode::Human => {
+            eprintln!("\nWarnings:");
+
+            for warning in warnings.iter() {
+                eprintln!("- {warning}");
+            }
+        }
+        Mode::Plain => {
+            for warning in warnings.iter() {
+                eprintln!("warning: {warning}");
+            }
+        }
+        Mode::Json => emit("warnings", json!({ "warnings": warnings })),
+    }
+}
+
+pub fn info(message: impl Display) {
+    match mode() {
+        Mode::Human | Mode::Plain => println!("{message}"),
+        Mode::Json => emit("info", json!({ "message": message.to_string() })),
+    }
+}
+
+/// Prints the pushed artifacts and their image references
+pub fn artifacts(artifacts: &HashMap<String, String>) {
+    match mode() {
+        Mode::Human => {
+            println!("\nPushed artifacts:");
+
+            for (artifact, image_ref) in artifacts.iter() {
+                println!("- {artifact}: {image_ref}");
+            }
+        }
+        Mode::Plain => {
+            for (artifact, image_ref) in artifacts.iter() {
+                println!("{artifact} {image_ref}");
+            }
+        }
+        Mode::Json => emit("artifacts", json!({ "artifacts": artifacts })),
+    }
+}
+
+/// Prints lint findings as rule and message
+pub fn findings(findings: &[(&str, String)]) {
+    match mode() {
+        Mode::Human if findings.is_empty() => println!("✓ no issues found"),
+        Mode::Human => {
+            for (rule, message) in findings.iter() {
+                println!("⚠ {message} [{rule}]");
+            }
+        }
+        Mode::Plain => {
+            for (rule, message) in findings.iter() {
+                println!("{rule}: {message}");
+            }
+        }
+        Mode::Json => emit(
+            "findings",
+            json!({
+                "findings": findings
+                    .iter()
+                    .map(|(rule, message)| json!({ "rule": rule, "message": message }))
+                    .collect::<Vec<_>>()
+            }),
+        ),
+    }
+}
+
+/// Prints titled sections of entries, e.g. the build entries and releases of `list`. `value`
+/// is the same listing as JSON
+pub fn listing(sections: &[(&str, Vec<String>)], value: Value) {
+    match mode() {
+        Mode::Human | Mode::Plain => {
+            let sections = sections.iter().filter(|(_, entries)| !entries.is_empty());
+
+            for (i, (title, entries)) in sections.enumerate() {
+                if i > 0 {
+                    println!();
+                }
+
+                println!("{title}:");
+
+                for entry in entries.iter() {
+                    println!("{entry}");
+                }
+            }
+        }
+        Mode::Json => emit("listing", value),
+    }
+}
+
+/// Prints a JSON document as is, for output that's meant for scripts regardless of the mode
+pub fn document(value: &Value) {
+    println!("{value:#}");
+}
+
+/// Prints the fields in which the configs `a` and `b` differ, as path and the value in each,
+/// `None` when the field is only set in one of them
+pub fn differences(a: &str, b: &str, differences: &[(String, Option<Value>, Option<Value>)]) {
+    match mode() {
+        Mode::Human | Mode::Plain => {
+            let human = mode() == Mode::Human;
+
+            if differences.is_empty() {
+                if human {
+                    println!("✓ {a} and {b} resolve to the same config");
+                }
+
+                return;
+            }
+
+            if human {
+                println!("Differences between {a} (-) and {b} (+):");
+            }
+
+            for (path, a, b) in differences.iter() {
+                match (a, b) {
+                    (Some(a), Some(b)) => println!("~ {path}: {a} → {b}"),
+                    (Some(a), None) => println!("- {path}: {a}"),
+                    (None, Some(b)) => println!("+ {path}: {b}"),
+                    (None, None) => {}
+                }
+            }
+        }
+        Mode::Json => emit(
+            "differences",
+            json!({
+                "differences": differences
+                    .iter()
+                    .map(|(path, a, b)| json!({ "path": path, "a": a, "b": b }))
+                    .collect::<Vec<_>>()
+            }),
+        ),
+    }
+}
+
+/// Prints the changes a deploy would make as action, resource and diff, where the action is
+/// `create`, `update` or `delete`. `value` is the same list as JSON
+pub fn changes(changes: &[(&str, String, &str)], value: Value) {
+    match mode() {
+        Mode::Human | Mode::Plain => {
+            let human = mode() == Mode::Human;
+
+            if changes.is_empty() {
+                if human {
+                    println!("✓ the cluster is up-to-date");
+                }
+
+                return;
+            }
+
+            for (action, resource, diff) in changes.iter() {
+                let symbol = match *action {
+                    "create" => '+',
+                    "delete" => '-',
+                    _ => '~',
+                };
+
+                println!("{symbol} {resource}");
+                print!("{diff}");
+            }
+
+            if human {
+                let count = |action: &str| changes.iter().filter(|(a, ..)| *a == action).count();
+
+                println!(
+                    "\n{} resources would change ({} created, {} updated, {} deleted)",
+                    changes.len(),
+                    count("create"),
+                    count("update"),
+                    count("delete")
+                );
+            }
+        }
+        Mode::Json => emit("changes", json!({ "changes": value })),
+    }
+}
+
+/// Prints the fields of the metadata attached to `reference`, unset fields are left out.
+/// `value` is the same metadata as JSON
+pub fn metadata(reference: &str, fields: &[(&str, Option<&String>)], value: Value) {
+    let fields = fields
+        .iter()
+        .filter_map(|(name, value)| value.map(|value| (name, value)));
+
+    match mode() {
+        Mode::Human => {
+            println!("Metadata of {reference}:");
+
+            for (name, value) in fields {
+                println!("  {name}: {value}");
+            }
+        }
+        Mode::Plain => {
+            for (name, value) in fields {
+                println!("{}={value}", name.replace(' ', "-"));
+            }
+        }
+        Mode::Json => emit("metadata", value),
+    }
+}
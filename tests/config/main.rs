@@ -0,0 +1,247 @@
+//! Golden tests for loading configs, the fixtures are in `fixtures` and the expected results
+//! in `snapshots`. Review changes with `cargo insta review`.
+
+use std::path::Path;
+
+use miette::{GraphicalReportHandler, GraphicalTheme};
+use steiger::config::{self, Config, Purpose, Release};
+
+const FIXTURES: &str = "tests/config/fixtures";
+
+async fn load(name: &str, profile: Option<&str>) -> Result<Config, config::Error> {
+    load_with(name, profile, &[]).await
+}
+
+async fn load_with(
+    name: &str,
+    profile: Option<&str>,
+    overrides: &[(&str, &str)],
+) -> Result<Config, config::Error> {
+    let overrides = overrides
+        .iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect::<Vec<_>>();
+
+    config::load_from_path(
+        profile,
+        Path::new(FIXTURES).join(name),
+        true,
+        &overrides,
+        Purpose::Build,
+    )
+    .await
+}
+
+/// Renders the error like it's reported on the command line, without colors
+fn render(error: config::Error) -> String {
+    let mut out = String::new();
+    GraphicalReportHandler::new_themed(GraphicalTheme::unicode_nocolor())
+        .with_width(100)
+        .render_report(&mut out, &error)
+        .unwrap();
+
+    out
+}
+
+macro_rules! assert_config {
+    ($config:expr) => {
+        let config = $config;
+        insta::with_settings!({ sort_maps => true }, {
+            insta::assert_yaml_snapshot!(config);
+        });
+    };
+}
+
+#[tokio::test]
+async fn defaults() {
+    assert_config!(load("minimal.yml", None).await.unwrap());
+}
+
+#[test]
+fn from_str() {
+    let data = std::fs::read_to_string(Path::new(FIXTURES).join("minimal.yml")).unwrap();
+    let config = data.parse::<Config>().unwrap();
+
+    assert_config!(config);
+}
+
+#[tokio::test]
+async fn templating() {
+    assert_config!(load("templating.yml", None).await.unwrap());
+}
+
+#[tokio::test]
+async fn profile_dev() {
+    assert_config!(load("profiles.yml", Some("dev")).await.unwrap());
+}
+
+#[tokio::test]
+async fn profile_prod_with_values_files() {
+    assert_config!(load("profiles.yml", Some("prod")).await.unwrap());
+}
+
+#[tokio::test]
+async fn deploy_only() {
+    assert_config!(load("deploy-only.yml", None).await.unwrap());
+}
+
+#[test]
+fn empty() {
+    let error = "insecureRegistries: []".parse::<Config>().unwrap_err();
+    insta::assert_snapshot!(render(error));
+}
+
+#[tokio::test]
+async fn overrides() {
+    let overrides = [
+        ("env", "staging"),
+        ("build.api.docker.target", "debug"),
+        ("failFast", "true"),
+    ];
+
+    assert_config!(
+        load_with("profiles.yml", Some("dev"), &overrides)
+            .await
+            .unwrap()
+    );
+}
+
+#[tokio::test]
+async fn last_commit_of_selected_profile() {
+    // Without git, only the profile that uses `gitLastCommitFor` fails to load
+    assert!(load("last-commit.yml", Some("dev")).await.is_ok());
+    assert!(matches!(
+        load("last-commit.yml", Some("prod")).await,
+        Err(config::Error::LastCommit(path)) if path == "src"
+    ));
+}
+
+#[tokio::test]
+async fn unknown_profile() {
+    let error = load("profiles.yml", Some("staging")).await.unwrap_err();
+    insta::assert_snapshot!(render(error));
+}
+
+#[tokio::test]
+async fn undefined_variable() {
+    let error = load("undefined-variable.yml", Some("dev"))
+        .await
+        .unwrap_err();
+    insta::assert_snapshot!(render(error));
+}
+
+#[tokio::test]
+async fn invalid_yaml() {
+    let error = load("invalid-yaml.yml", None).await.unwrap_err();
+    insta::assert_snapshot!(render(error));
+}
+
+#[tokio::test]
+async fn invalid_type() {
+    let error = load("invalid-type.yml", None).await.unwrap_err();
+    insta::assert_snapshot!(render(error));
+}
+
+#[tokio::test]
+async fn unknown_builder() {
+    let error = load("unknown-builder.yml", None).await.unwrap_err();
+    insta::assert_snapshot!(render(error));
+}
+
+#[tokio::test]
+async fn dependency_cycle() {
+    let error = load("dependency-cycle.yml", None).await.unwrap_err();
+    insta::assert_snapshot!(render(error));
+}
+
+#[tokio::test]
+async fn functions() {
+    assert_config!(load("functions.yml", None).await.unwrap());
+}
+
+#[tokio::test]
+async fn file_outside_config_dir() {
+    let error = load("file-outside.yml", None).await.unwrap_err();
+    insta::assert_snapshot!(render(error));
+}
+
+#[tokio::test]
+async fn release_names_from_profile() {
+    assert_config!(load("release-names.yml", Some("staging")).await.unwrap());
+}
+
+#[tokio::test]
+async fn invalid_namespace() {
+    let error = load("release-names.yml", Some("qa")).await.unwrap_err();
+    insta::assert_snapshot!(render(error));
+}
+
+#[tokio::test]
+async fn gitops() {
+    assert_config!(load("gitops.yml", None).await.unwrap());
+}
+
+#[tokio::test]
+async fn resources() {
+    assert_config!(load("resources.yml", None).await.unwrap());
+}
+
+#[tokio::test]
+async fn watch_paths() {
+    let config = load("watch.yml", None).await.unwrap();
+    let paths = config
+        .build
+        .iter()
+        .map(|(name, build)| (name.clone(), build.watch_paths()))
+        .collect::<std::collections::BTreeMap<_, _>>();
+
+    insta::assert_yaml_snapshot!(paths);
+}
+
+#[tokio::test]
+async fn deploy_retry() {
+    assert_config!(load("deploy-retry.yml", None).await.unwrap());
+}
+
+#[tokio::test]
+async fn helm_adoption() {
+    assert_config!(load("helm-adoption.yml", None).await.unwrap());
+}
+
+/// Round-trips a value encrypted for the test key in `fixtures/age`
+#[tokio::test]
+#[ignore = "needs age, run with STEIGER_AGE_KEY_FILE=tests/config/fixtures/age/keys.txt"]
+async fn age_values() {
+    let config = load("age.yml", None).await.unwrap();
+    let Release::Helm(ref helm) = config.deploy["api"] else {
+        panic!("expected a helm release");
+    };
+
+    assert_eq!(helm.values["db.password"], "s3cr3t-value");
+    assert!(steiger::audit::contains_secret(
+        "--set db.password=s3cr3t-value"
+    ));
+}
+
+#[tokio::test]
+async fn age_values_of_other_profiles() {
+    let config = load("age-profiles.yml", Some("dev")).await.unwrap();
+    let Release::Helm(ref helm) = config.deploy["api"] else {
+        panic!("expected a helm release");
+    };
+
+    assert_eq!(helm.values["db.password"], "dev-password");
+}
+
+#[tokio::test]
+async fn age_values_without_key() {
+    let path = Path::new(FIXTURES).join("age.yml");
+    let config = config::load_from_path(None, path, true, &[], Purpose::Read)
+        .await
+        .unwrap();
+    let Release::Helm(ref helm) = config.deploy["api"] else {
+        panic!("expected a helm release");
+    };
+
+    assert!(helm.values["db.password"].starts_with("-----BEGIN AGE ENCRYPTED FILE-----"));
+}
@@ -0,0 +1,311 @@
+use std::{
+    process,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use flate2::{Compression, write::GzEncoder};
+use oci_client::{Reference, secrets::RegistryAuth};
+use prodash::tree::{Item, Root};
+use sha2::{Digest, Sha256};
+use steiger::{
+    config::RegistrySettings,
+    image::{Image, layer::Layer},
+    registry::Registry,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    process::Command,
+};
+
+/// Registry container that is removed again when dropped
+pub struct TestRegistry {
+    id: String,
+    host: String,
+}
+
+async fn docker(args: &[&str]) -> String {
+    let output = Command::new("docker")
+        .args(args)
+        .output()
+        .await
+        .expect("failed to run docker");
+
+    assert!(
+        output.status.success(),
+        "docker {} failed: {}",
+        args.join(" "),
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    String::from_utf8_lossy(&output.stdout).trim().to_string()
+}
+
+impl TestRegistry {
+    pub async fn start() -> Self {
+        let id = docker(&[
+            "run",
+            "--detach",
+            "--rm",
+            "--publish",
+            "127.0.0.1::5000",
+            "registry:3",
+        ])
+        .await;
+        let port = docker(&["port", &id, "5000/tcp"]).await;
+        let host = port.lines().next().unwrap_or_default().to_string();
+        let registry = Self { id, host };
+
+        for _ in 0..50 {
+            if reqwest::get(format!("http://{}/v2/", registry.host))
+                .await
+                .is_ok_and(|response| response.status().is_success())
+            {
+                return registry;
+            }
+
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        panic!("registry at {} didn't become ready", registry.host);
+    }
+
+    /// Client for this registry, it's accessed over plain HTTP
+    pub fn client(&self) -> Registry {
+        Registry::with_config(
+            RegistryAuth::Anonymous,
+            std::slice::from_ref(&self.host),
+            &RegistrySettings::default(),
+        )
+    }
+
+    /// Pushes `body` as the manifest of `repo:tag` as is, returning its digest
+    pub async fn put_manifest(&self, repo: &str, tag: &str, body: Vec<u8>) -> String {
+        self.put(
+            repo,
+            tag,
+            "application/vnd.oci.image.manifest.v1+json",
+            body,
+        )
+        .await
+    }
+
+    /// Pushes an index of the images per platform, which have to be pushed to `repo` already,
+    /// as `repo:tag`. Returns its digest.
+    pub async fn put_index(&self, repo: &str, tag: &str, images: &[(&str, &Image)]) -> String {
+        let manifests = images
+            .iter()
+            .map(|(platform, image)| {
+                let (os, architecture) = platform.split_once('/').unwrap();
+                serde_json::json!({
+                    "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                    "digest": image.digest(),
+                    "size": image.manifest_bytes().len(),
+                    "platform": { "os": os, "architecture": architecture },
+                })
+            })
+            .collect::<Vec<_>>();
+        let body = serde_json::to_vec(&serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.oci.image.index.v1+json",
+            "manifests": manifests,
+        }))
+        .unwrap();
+
+        self.put(repo, tag, "application/vnd.oci.image.index.v1+json", body)
+            .await
+    }
+
+    async fn put(&self, repo: &str, tag: &str, content_type: &str, body: Vec<u8>) -> String {
+        let digest = format!("sha256:{}", hex::encode(Sha256::digest(&body)));
+        let response = reqwest::Client::new()
+            .put(format!("http://{}/v2/{repo}/manifests/{tag}", self.host))
+            .header("Content-Type", content_type)
+            .body(body)
+            .send()
+            .await
+            .expect("failed to push manifest");
+
+        assert!(
+            response.status().is_success(),
+            "pushing manifest failed: {}",
+            response.status()
+        );
+
+        digest
+    }
+
+    /// Reference to `name` (e.g. `fixtures/hello:v1`) in this registry
+    pub fn reference(&self, name: &str) -> Reference {
+        format!("{}/{name}", self.host)
+            .parse()
+            .expect("invalid reference")
+    }
+}
+
+impl Drop for TestRegistry {
+    fn drop(&mut self) {
+        let _ = process::Command::new("docker")
+            .args(["rm", "--force", &self.id])
+            .output();
+    }
+}
+
+/// Proxy in front of a registry that fails the first blob uploads with a server error, like
+/// registries behind flaky load balancers do
+pub struct FlakyProxy {
+    pub host: String,
+    failures: Arc<AtomicUsize>,
+}
+
+impl FlakyProxy {
+    pub async fn start(registry: &TestRegistry, failures: usize) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let host = listener.local_addr().unwrap().to_string();
+        let failures = Arc::new(AtomicUsize::new(failures));
+        let upstream = registry.host.clone();
+        let remaining = Arc::clone(&failures);
+
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(proxy(stream, upstream.clone(), Arc::clone(&remaining)));
+            }
+        });
+
+        Self { host, failures }
+    }
+
+    /// Client for the registry behind this proxy
+    pub fn client(&self) -> Registry {
+        Registry::with_config(
+            RegistryAuth::Anonymous,
+            std::slice::from_ref(&self.host),
+            &RegistrySettings::default(),
+        )
+    }
+
+    /// Uploads that are still going to fail
+    pub fn remaining_failures(&self) -> usize {
+        self.failures.load(Ordering::SeqCst)
+    }
+}
+
+/// Forwards the requests of a connection one by one, the registry only sees successful uploads
+async fn proxy(stream: TcpStream, upstream: String, failures: Arc<AtomicUsize>) {
+    let client = reqwest::Client::new();
+    let mut stream = BufReader::new(stream);
+
+    loop {
+        let mut request_line = String::new();
+        if stream.read_line(&mut request_line).await.unwrap_or(0) == 0 {
+            return;
+        }
+        let mut parts = request_line.split_whitespace();
+        let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+            return;
+        };
+
+        let mut headers = vec![];
+        loop {
+            let mut line = String::new();
+            stream.read_line(&mut line).await.unwrap();
+            match line.trim_end().split_once(':') {
+                Some((name, value)) => headers.push((name.to_string(), value.trim().to_string())),
+                None => break,
+            }
+        }
+
+        let length = headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+            .map_or(0, |(_, value)| value.parse().unwrap());
+        let mut body = vec![0; length];
+        stream.read_exact(&mut body).await.unwrap();
+
+        let fail = method == "PUT"
+            && path.contains("/blobs/uploads/")
+            && failures
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .is_ok();
+
+        let (status, response_headers, response_body) = match fail {
+            true => (500, vec![], b"upload failed".to_vec()),
+            false => {
+                let mut request =
+                    client.request(method.parse().unwrap(), format!("http://{upstream}{path}"));
+                // The host is kept, so locations returned by the registry point to the proxy
+                for (name, value) in &headers {
+                    if !name.eq_ignore_ascii_case("content-length") {
+                        request = request.header(name, value);
+                    }
+                }
+                let response = request.body(body).send().await.unwrap();
+                let headers = response
+                    .headers()
+                    .iter()
+                    .filter(|(name, _)| *name != "content-length" && *name != "transfer-encoding")
+                    .map(|(name, value)| {
+                        (
+                            name.to_string(),
+                            value.to_str().unwrap_or_default().to_string(),
+                        )
+                    })
+                    .collect::<Vec<_>>();
+                let status = response.status().as_u16();
+
+                (status, headers, response.bytes().await.unwrap().to_vec())
+            }
+        };
+
+        let mut head = format!("HTTP/1.1 {status} Proxied\r\n");
+        for (name, value) in response_headers {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        head.push_str(&format!("Content-Length: {}\r\n\r\n", response_body.len()));
+
+        let stream = stream.get_mut();
+        if stream.write_all(head.as_bytes()).await.is_err()
+            || stream.write_all(&response_body).await.is_err()
+        {
+            return;
+        }
+    }
+}
+
+/// Progress item that isn't rendered anywhere
+pub fn progress() -> Item {
+    Root::new().add_child("test")
+}
+
+/// Layer with a single `hello.txt` holding `content`, like a builder would produce
+pub fn layer(content: &str) -> Layer {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(content.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+
+    let mut builder = tar::Builder::new(vec![]);
+    builder
+        .append_data(&mut header, "hello.txt", content.as_bytes())
+        .expect("failed to write tar");
+    let tar = builder.into_inner().expect("failed to write tar");
+
+    let mut encoder = GzEncoder::new(vec![], Compression::default());
+    std::io::Write::write_all(&mut encoder, &tar).expect("failed to compress layer");
+
+    Layer {
+        data: encoder.finish().expect("failed to compress layer"),
+        diff_id: format!("sha256:{}", hex::encode(Sha256::digest(&tar))),
+    }
+}
+
+/// Image for `platform` with a single layer holding `content`
+pub fn image(platform: &str, content: &str) -> Image {
+    Image::scratch(platform)
+        .and_then(|image| image.append_layer(layer(content), "fixture", None))
+        .expect("failed to create image")
+}
@@ -0,0 +1,6 @@
+//! End-to-end tests against a real registry, run with `cargo test --features integration`.
+//! Requires docker to start a throwaway `registry:3` container per test, it supports the
+//! referrers API.
+
+mod harness;
+mod registry;
@@ -0,0 +1,263 @@
+use oci_client::secrets::RegistryAuth;
+
+use crate::harness::{FlakyProxy, TestRegistry, image, progress};
+
+#[tokio::test]
+async fn push_and_pull() {
+    let registry = TestRegistry::start().await;
+    let mut client = registry.client();
+    let reference = registry.reference("fixtures/hello:v1");
+    let image = image("linux/amd64", "hello");
+
+    client
+        .push(progress(), &reference, image.clone())
+        .await
+        .unwrap();
+
+    let digest = client.resolve_digest(&reference).await.unwrap();
+    assert_eq!(digest.as_deref(), Some(image.digest()));
+
+    let pulled = client.pull(&reference, "linux/amd64").await.unwrap();
+    assert_eq!(pulled.digest(), image.digest());
+    assert_eq!(pulled.layers.len(), 1);
+}
+
+#[tokio::test]
+async fn skips_existing_image() {
+    let registry = TestRegistry::start().await;
+    let mut client = registry.client();
+    let reference = registry.reference("fixtures/hello:v1");
+    let image = image("linux/amd64", "hello");
+
+    let first = client
+        .push(progress(), &reference, image.clone())
+        .await
+        .unwrap();
+    let second = client.push(progress(), &reference, image).await.unwrap();
+
+    assert!(first.is_some());
+    assert!(second.is_none());
+}
+
+#[tokio::test]
+async fn shares_layers_between_repos() {
+    let registry = TestRegistry::start().await;
+    let mut client = registry.client();
+    let image = image("linux/amd64", "shared");
+
+    for name in ["fixtures/api:v1", "fixtures/worker:v1"] {
+        let reference = registry.reference(name);
+        client
+            .push(progress(), &reference, image.clone())
+            .await
+            .unwrap();
+
+        let pulled = client.pull(&reference, "linux/amd64").await.unwrap();
+        assert_eq!(pulled.digest(), image.digest());
+    }
+}
+
+#[tokio::test]
+async fn pushes_platform_variants() {
+    let registry = TestRegistry::start().await;
+    let mut client = registry.client();
+    let platforms = ["linux/amd64", "linux/arm64"];
+    let mut images = vec![];
+
+    for platform in platforms {
+        let tag = platform.replace('/', "-");
+        let reference = registry.reference(&format!("fixtures/hello:{tag}"));
+        let image = image(platform, platform);
+
+        client
+            .push(progress(), &reference, image.clone())
+            .await
+            .unwrap();
+        images.push(image);
+    }
+
+    let entries = platforms.into_iter().zip(&images).collect::<Vec<_>>();
+    let index = registry.put_index("fixtures/hello", "v1", &entries).await;
+    let reference = registry.reference("fixtures/hello:v1");
+
+    let digests = client.current_digests(&reference).await.unwrap();
+    assert_eq!(
+        digests,
+        [index.as_str(), images[0].digest(), images[1].digest()]
+    );
+
+    for (platform, image) in entries {
+        let pulled = client.pull(&reference, platform).await.unwrap();
+        assert_eq!(pulled.digest(), image.digest());
+    }
+
+    // Copying keeps the index and the image of every platform
+    let target = registry.reference("fixtures/copied:v1");
+    let copied = client
+        .copy(progress(), &reference, &RegistryAuth::Anonymous, &target)
+        .await
+        .unwrap();
+    assert_eq!(copied, index);
+    assert_eq!(client.current_digests(&target).await.unwrap(), digests);
+}
+
+#[tokio::test]
+async fn tags_and_lists_tags() {
+    let registry = TestRegistry::start().await;
+    let mut client = registry.client();
+    let reference = registry.reference("fixtures/hello:v1");
+    let image = image("linux/amd64", "hello");
+
+    client
+        .push(progress(), &reference, image.clone())
+        .await
+        .unwrap();
+
+    let digest = client.tag(&reference, "stable").await.unwrap();
+    assert_eq!(digest, image.digest());
+
+    // Resolved by a fresh client, the one that tagged remembers the digest it pushed
+    let stable = registry.reference("fixtures/hello:stable");
+    let resolved = registry.client().resolve_digest(&stable).await.unwrap();
+    assert_eq!(resolved.as_deref(), Some(image.digest()));
+
+    let mut tags = client.list_tags(&reference).await.unwrap();
+    tags.sort();
+    assert_eq!(tags, ["stable", "v1"]);
+
+    let missing = registry.reference("fixtures/missing:v1");
+    assert!(client.list_tags(&missing).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn retries_failed_uploads() {
+    let registry = TestRegistry::start().await;
+    let proxy = FlakyProxy::start(&registry, 1).await;
+    let mut client = proxy.client();
+    let reference = format!("{}/fixtures/hello:v1", proxy.host).parse().unwrap();
+    let image = image("linux/amd64", "hello");
+
+    client
+        .push(progress(), &reference, image.clone())
+        .await
+        .unwrap();
+    assert_eq!(proxy.remaining_failures(), 0);
+
+    let pulled = registry
+        .client()
+        .pull(&registry.reference("fixtures/hello:v1"), "linux/amd64")
+        .await
+        .unwrap();
+    assert_eq!(pulled.digest(), image.digest());
+}
+
+#[tokio::test]
+async fn lists_tags_across_pages() {
+    let registry = TestRegistry::start().await;
+    let mut client = registry.client();
+    let reference = registry.reference("fixtures/hello:v0");
+    let image = image("linux/amd64", "hello");
+
+    client
+        .push(progress(), &reference, image.clone())
+        .await
+        .unwrap();
+
+    // More than the 1000 tags asked for per page
+    for i in 1..=1100 {
+        client.tag(&reference, &format!("v{i}")).await.unwrap();
+    }
+
+    let tags = client.list_tags(&reference).await.unwrap();
+    assert_eq!(tags.len(), 1101);
+    assert_eq!(
+        tags.iter().collect::<std::collections::HashSet<_>>().len(),
+        1101
+    );
+}
+
+#[tokio::test]
+async fn keeps_digest_of_pulled_image() {
+    let registry = TestRegistry::start().await;
+    let mut client = registry.client();
+    let image = image("linux/amd64", "hello");
+
+    client
+        .push(
+            progress(),
+            &registry.reference("fixtures/hello:v1"),
+            image.clone(),
+        )
+        .await
+        .unwrap();
+
+    // Other tools rarely push canonical JSON, so the manifest is re-pushed indented
+    let body = serde_json::to_vec_pretty(image.manifest()).unwrap();
+    let digest = registry
+        .put_manifest("fixtures/hello", "pretty", body)
+        .await;
+    assert_ne!(digest, image.digest());
+
+    let pulled = client
+        .pull(&registry.reference("fixtures/hello:pretty"), "linux/amd64")
+        .await
+        .unwrap();
+    assert_eq!(pulled.digest(), digest);
+
+    let target = registry.reference("fixtures/prebuilt:v1");
+    client.push(progress(), &target, pulled).await.unwrap();
+    assert_eq!(
+        client.resolve_digest(&target).await.unwrap().as_deref(),
+        Some(digest.as_str())
+    );
+}
+
+#[tokio::test]
+async fn attaches_and_pulls_artifacts() {
+    let registry = TestRegistry::start().await;
+    let mut client = registry.client();
+    let reference = registry.reference("fixtures/hello:v1");
+    let image = image("linux/amd64", "hello");
+    let artifact_type = steiger::registry::METADATA_MEDIA_TYPE;
+
+    client
+        .push(progress(), &reference, image.clone())
+        .await
+        .unwrap();
+    assert_eq!(
+        client
+            .pull_attached(&reference, artifact_type)
+            .await
+            .unwrap(),
+        None
+    );
+
+    for build in ["first", "second"] {
+        client
+            .attach_artifact(&reference, artifact_type, build.as_bytes().to_vec())
+            .await
+            .unwrap();
+        // Artifacts are ordered by their creation time, which has millisecond precision
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+
+    // Every attached artifact is listed, the newest one is pulled
+    let referrers = client
+        .list_referrers(&reference, Some(artifact_type))
+        .await
+        .unwrap();
+    assert_eq!(referrers.len(), 2);
+    assert_eq!(
+        client
+            .pull_attached(&reference, artifact_type)
+            .await
+            .unwrap(),
+        Some(b"second".to_vec())
+    );
+
+    // Attaching doesn't move the tag
+    assert_eq!(
+        client.resolve_digest(&reference).await.unwrap().as_deref(),
+        Some(image.digest())
+    );
+}